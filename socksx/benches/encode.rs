@@ -0,0 +1,61 @@
+/// Compares serializing SOCKS requests into a fresh vector with `into_socks_bytes`, to serializing them into a reused
+/// buffer with `encode`, as a busy client or server would. See the `encode_allocations` example for the allocations.
+use bytes::BytesMut;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+
+use socksx::{Address, Command};
+use socksx::socks5::Socks5Request;
+use socksx::socks6::options::{AuthMethod, AuthMethodAdvertisementOption, MetadataOption, StackOption};
+use socksx::socks6::Socks6Request;
+
+fn socks5(c: &mut Criterion) {
+    let request = Socks5Request::new(Command::Connect, Address::new("example.com", 443));
+    let mut group = c.benchmark_group("socks5");
+
+    // The request is consumed by `into_socks_bytes`, cloning it is done outside of the measurement.
+    group.bench_function("into_socks_bytes", |b| {
+        b.iter_batched(|| request.clone(), |request| request.into_socks_bytes().unwrap(), BatchSize::SmallInput)
+    });
+
+    let mut buf = BytesMut::new();
+    group.bench_function("encode", |b| {
+        b.iter(|| {
+            buf.clear();
+            request.encode(&mut buf).unwrap();
+        })
+    });
+
+    group.finish();
+}
+
+fn socks6(c: &mut Criterion) {
+    let request = Socks6Request::new(
+        Command::Connect,
+        Address::new("example.com", 443),
+        0,
+        vec![
+            AuthMethodAdvertisementOption::new(0, vec![AuthMethod::UsernamePassword]).wrap(),
+            StackOption::tfo(512).wrap(),
+            MetadataOption::new(1, String::from("benchmark")).wrap(),
+        ],
+        None,
+    );
+    let mut group = c.benchmark_group("socks6");
+
+    group.bench_function("into_socks_bytes", |b| {
+        b.iter_batched(|| request.clone(), |request| request.into_socks_bytes().unwrap(), BatchSize::SmallInput)
+    });
+
+    let mut buf = BytesMut::new();
+    group.bench_function("encode", |b| {
+        b.iter(|| {
+            buf.clear();
+            request.encode(&mut buf).unwrap();
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, socks5, socks6);
+criterion_main!(benches);
@@ -64,7 +64,7 @@ async fn connect_v5(
     let (mut outgoing, _) = client.connect(dest_addr).await?;
 
     // Write a message to the destination.
-    outgoing.write(String::from("Hello, world!\n").as_bytes()).await?;
+    outgoing.write_all(String::from("Hello, world!\n").as_bytes()).await?;
 
     Ok(())
 }
@@ -81,7 +81,7 @@ async fn connect_v6(
     let (mut outgoing, _) = client.connect(dest_addr, None, None).await?;
 
     // Write a message to the destination.
-    outgoing.write(String::from("Hello, world!\n").as_bytes()).await?;
+    outgoing.write_all(String::from("Hello, world!\n").as_bytes()).await?;
 
     Ok(())
 }
@@ -0,0 +1,139 @@
+/// This benchmark counts the allocations (and measures the time) it takes to serialize SOCKS requests, either into a
+/// fresh vector with `into_socks_bytes`, or into a reused buffer with `encode`, as a busy client or server would.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use anyhow::Result;
+use bytes::BytesMut;
+use clap::Parser;
+
+use socksx::{Address, Command};
+use socksx::socks5::Socks5Request;
+use socksx::socks6::options::{AuthMethod, AuthMethodAdvertisementOption, MetadataOption, StackOption};
+use socksx::socks6::Socks6Request;
+
+
+/***** ALLOCATOR *****/
+/// Counts the allocations made by the process, on top of the system allocator.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+
+/***** ARGUMENTS *****/
+#[derive(Debug, Parser)]
+#[clap(name = "Encode allocations")]
+struct Arguments {
+    #[clap(name="ITERATIONS", short='i', long="iterations", default_value="1000000", help="The number of requests to serialize")]
+    iterations : usize,
+}
+
+
+
+
+
+/***** ENTRYPOINT *****/
+fn main() -> Result<()> {
+    let args = Arguments::parse();
+
+    let socks5 = Socks5Request::new(Command::Connect, Address::new("example.com", 443));
+    let socks6 = Socks6Request::new(
+        Command::Connect,
+        Address::new("example.com", 443),
+        0,
+        vec![
+            AuthMethodAdvertisementOption::new(0, vec![AuthMethod::UsernamePassword]).wrap(),
+            StackOption::tfo(512).wrap(),
+            MetadataOption::new(1, String::from("benchmark")).wrap(),
+        ],
+        None,
+    );
+
+    // The requests are consumed by `into_socks_bytes`, so cloning them is measured separately and subtracted.
+    let (clone5_allocations, clone5_elapsed) = measure(args.iterations, || {
+        drop(socks5.clone());
+        Ok(())
+    })?;
+    let (clone6_allocations, clone6_elapsed) = measure(args.iterations, || {
+        drop(socks6.clone());
+        Ok(())
+    })?;
+
+    let (allocations, elapsed) = measure(args.iterations, || {
+        socks5.clone().into_socks_bytes()?;
+        Ok(())
+    })?;
+    report("SOCKS5 into_socks_bytes", allocations - clone5_allocations, elapsed - clone5_elapsed);
+
+    let mut buf = BytesMut::new();
+    let (allocations, elapsed) = measure(args.iterations, || {
+        buf.clear();
+        socks5.encode(&mut buf)
+    })?;
+    report("SOCKS5 encode", allocations, elapsed);
+
+    let (allocations, elapsed) = measure(args.iterations, || {
+        socks6.clone().into_socks_bytes()?;
+        Ok(())
+    })?;
+    report("SOCKS6 into_socks_bytes", allocations - clone6_allocations, elapsed - clone6_elapsed);
+
+    let mut buf = BytesMut::new();
+    let (allocations, elapsed) = measure(args.iterations, || {
+        buf.clear();
+        socks6.encode(&mut buf)
+    })?;
+    report("SOCKS6 encode", allocations, elapsed);
+
+    Ok(())
+}
+
+
+
+
+
+/***** HELPERS *****/
+/// Runs `encode` `iterations` times, and returns the allocations per iteration and the nanoseconds per iteration.
+fn measure(
+    iterations: usize,
+    mut encode: impl FnMut() -> Result<()>,
+) -> Result<(f64, f64)> {
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..iterations {
+        encode()?;
+    }
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+
+    Ok((allocations as f64 / iterations as f64, elapsed.as_nanos() as f64 / iterations as f64))
+}
+
+/// Prints the measurements of a single serialization method.
+fn report(
+    name: &str,
+    allocations: f64,
+    elapsed: f64,
+) {
+    println!("{:<24} {:>5.2} allocations, {:>6.1}ns per request", name, allocations, elapsed);
+}
@@ -173,7 +173,7 @@ impl<RW: AsyncRead + AsyncWrite> AsyncRead for CryptStream<RW> {
         let nonce = Nonce::from_slice(b"secret nonce"); // TODO: random or implement counter ?
 
         // Apply keystream
-        let mut cipher = ChaCha20::new(&key, &nonce);
+        let mut cipher = ChaCha20::new(key, nonce);
         cipher.apply_keystream(&mut data);
 
         buf.put_slice(&data);
@@ -0,0 +1,98 @@
+/// This benchmark measures the throughput of a tunnel over loopback, for different relay buffer sizes.
+/// A client pushes data through the relay into a sink, which reads until the client closes the tunnel.
+use std::time::Instant;
+
+use anyhow::Result;
+use clap::Parser;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use socksx::RelayOptions;
+
+
+/***** ARGUMENTS *****/
+#[derive(Debug, Parser)]
+#[clap(name = "Relay throughput")]
+struct Arguments {
+    #[clap(name="MEGABYTES", short='m', long="megabytes", default_value="1024", help="The amount of data to transfer")]
+    megabytes    : usize,
+    #[clap(name="BUFFER_SIZES", short='b', long="buffer", default_values=["8", "64"], help="The buffer sizes in KB")]
+    buffer_sizes : Vec<usize>,
+    #[clap(name="ZERO_COPY", short='z', long="zero-copy", help="Moves data with splice(2), Linux only")]
+    zero_copy    : bool,
+}
+
+
+
+
+
+/***** ENTRYPOINT *****/
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Arguments::parse();
+
+    for buffer_size in args.buffer_sizes {
+        let options = RelayOptions {
+            buffer_size: buffer_size * 1024,
+            zero_copy: args.zero_copy,
+            ..Default::default()
+        };
+
+        let elapsed = transfer(args.megabytes * 1024 * 1024, options).await?;
+        let throughput = args.megabytes as f64 / elapsed;
+        println!("{:>4}KB buffer: {:.0} MB/s ({:.2}s)", buffer_size, throughput, elapsed);
+    }
+
+    Ok(())
+}
+
+
+
+
+
+/***** HELPERS *****/
+/// Pushes `length` bytes through a relay, and returns the seconds it took.
+async fn transfer(
+    length: usize,
+    options: RelayOptions,
+) -> Result<f64> {
+    let proxy = TcpListener::bind("127.0.0.1:0").await?;
+    let proxy_addr = proxy.local_addr()?;
+    let sink = TcpListener::bind("127.0.0.1:0").await?;
+    let sink_addr = sink.local_addr()?;
+
+    tokio::spawn(async move {
+        let (mut source, _) = proxy.accept().await?;
+        let mut destination = TcpStream::connect(sink_addr).await?;
+        socksx::relay_tcp(&mut source, &mut destination, &options).await?;
+
+        Ok::<_, anyhow::Error>(())
+    });
+
+    let received = tokio::spawn(async move {
+        let (mut stream, _) = sink.accept().await?;
+        let mut buffer = vec![0; 256 * 1024];
+        let mut received = 0;
+        loop {
+            match stream.read(&mut buffer).await? {
+                0 => return Ok::<_, anyhow::Error>(received),
+                length => received += length,
+            }
+        }
+    });
+
+    let start = Instant::now();
+    let mut client = TcpStream::connect(proxy_addr).await?;
+    let chunk = vec![0x42; 256 * 1024];
+    let mut remaining = length;
+    while remaining > 0 {
+        let length = remaining.min(chunk.len());
+        client.write_all(&chunk[..length]).await?;
+        remaining -= length;
+    }
+    client.shutdown().await?;
+
+    anyhow::ensure!(received.await?? == length, "Sink didn't receive all data.");
+
+    Ok(start.elapsed().as_secs_f64())
+}
@@ -1,11 +1,16 @@
+use std::borrow::Cow;
 use std::convert::{TryFrom, TryInto};
-use std::net::{IpAddr, SocketAddr};
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use bytes::{BufMut, BytesMut};
+use num_traits::FromPrimitive;
 use tokio::io::{AsyncRead, AsyncReadExt};
 use url::Url;
 
-use crate::{constants::*, Credentials};
+use crate::{Credentials, SocksError};
+use crate::util::split_host_port;
 
 /// Represents a SOCKS proxy address.
 #[derive(Clone, Debug, PartialEq)]
@@ -41,13 +46,21 @@ impl ProxyAddress {
     pub fn root() -> Self {
         ProxyAddress::new(6, String::from("root"), 1080, None)
     }
+
+    /// Returns the protocol of the proxy, or `None` if its version isn't one the clients speak.
+    pub fn protocol(&self) -> Option<SocksVersion> {
+        SocksVersion::from_byte(self.socks_version)
+    }
 }
 
 
-impl ToString for ProxyAddress {
-    // Converts the `ProxyAddress` to a string representation.
-    fn to_string(&self) -> String {
-        format!("socks{}://{}:{}", self.socks_version, self.host, self.port)
+impl fmt::Display for ProxyAddress {
+    // Formats the `ProxyAddress` as a string representation.
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        write!(f, "socks{}://{}:{}", self.socks_version, self.host, self.port)
     }
 }
 
@@ -64,22 +77,18 @@ impl TryFrom<String> for ProxyAddress {
         );
         ensure!(proxy_addr.port().is_some(), "Missing explicit port in proxy address.");
 
-        let socks_version = match proxy_addr.scheme() {
-            "socks5" => SOCKS_VER_5,
-            "socks6" => SOCKS_VER_6,
-            scheme => bail!("Unrecognized SOCKS scheme: {}", scheme),
-        };
+        let socks_version = SocksVersion::from_scheme(proxy_addr.scheme())
+            .ok_or_else(|| anyhow!("Unrecognized SOCKS scheme: {}", proxy_addr.scheme()))?;
 
+        // Either part may be empty, e.g. `socks5://user@host:1080` identifies by username only.
         let username = proxy_addr.username();
-        let credentials = if username.is_empty() {
-            None
-        } else {
-            let password = proxy_addr.password().unwrap_or_default();
-            Some(Credentials::new(username, password))
+        let credentials = match proxy_addr.password() {
+            None if username.is_empty() => None,
+            password => Some(Credentials::new(username, password.unwrap_or_default())?),
         };
 
         Ok(Self::new(
-            socks_version,
+            socks_version.to_byte(),
             proxy_addr.host().map(|h| h.to_string()).unwrap(),
             proxy_addr.port().unwrap(),
             credentials,
@@ -87,7 +96,72 @@ impl TryFrom<String> for ProxyAddress {
     }
 }
 
+/// Represents the versions of the SOCKS protocol a proxy (e.g. a link of a chain) can speak.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, FromPrimitive, PartialEq)]
+pub enum SocksVersion {
+    Socks5 = 0x05,
+    Socks6 = 0x06,
+}
+
+impl SocksVersion {
+    /// Returns the byte that identifies the version on the wire, as stored in `ProxyAddress::socks_version`.
+    pub fn to_byte(self) -> u8 {
+        self as u8
+    }
+
+    /// Returns the version identified by the given byte, or `None` if the version is unknown.
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        Self::from_u8(byte)
+    }
+
+    /// Returns the URL scheme of the version, e.g. `socks5`.
+    pub fn scheme(self) -> &'static str {
+        match self {
+            SocksVersion::Socks5 => "socks5",
+            SocksVersion::Socks6 => "socks6",
+        }
+    }
+
+    /// Returns the version of the given URL scheme, or `None` if it isn't a SOCKS scheme.
+    pub fn from_scheme(scheme: &str) -> Option<Self> {
+        match scheme {
+            "socks5" => Some(SocksVersion::Socks5),
+            "socks6" => Some(SocksVersion::Socks6),
+            _ => None,
+        }
+    }
+}
+
+/// Maximum length of a domain name, as limited by its one-byte length prefix.
+pub const MAX_DOMAIN_NAME_LENGTH: usize = 255;
+
+/// Represents the types of addresses in SOCKS5 and SOCKS6 requests and replies.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, FromPrimitive, PartialEq)]
+pub enum AddressType {
+    Ipv4 = 0x01,
+    DomainName = 0x03,
+    Ipv6 = 0x04,
+}
+
+impl AddressType {
+    /// Returns the byte that identifies the address type on the wire.
+    pub fn to_byte(self) -> u8 {
+        self as u8
+    }
+
+    /// Returns the address type identified by the given byte, or `None` if the type is unknown.
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        Self::from_u8(byte)
+    }
+}
+
 /// Represents a network address, which could be either a domain name or an IP address.
+///
+/// IP literals are always parsed into `Ip`, never kept as a domain name, so matching on the variant (and the
+/// `SocketAddr` within) is unambiguous, e.g. `Address::Ip(SocketAddr::V6(_))` is exactly the IPv6 case. The cases
+/// correspond to the `AddressType` returned by `kind`, and to how the address is encoded on the wire.
 #[derive(Clone, Debug, PartialEq)]
 pub enum Address {
     /// An address represented by a domain name.
@@ -105,63 +179,247 @@ impl Address {
     ) -> Self {
         let host = host.into();
 
-        if let Ok(host) = host.parse::<IpAddr>() {
-            Address::Ip(SocketAddr::new(host, port))
-        } else {
-            Address::Domainname { host, port }
+        // IPv6 hosts may come bracketed, e.g. from a URL, which still makes them an IP literal.
+        let literal = match host.strip_prefix('[').and_then(|host| host.strip_suffix(']')) {
+            Some(bracketed) => bracketed.parse::<Ipv6Addr>().map(IpAddr::V6).ok(),
+            None => host.parse::<IpAddr>().ok(),
+        };
+
+        match literal {
+            Some(ip) => Address::Ip(SocketAddr::new(ip, port)),
+            None => Address::Domainname { host, port },
+        }
+    }
+
+    /// Returns the host, i.e. the domain name or the IP address (without brackets for IPv6).
+    pub fn host(&self) -> Cow<'_, str> {
+        match self {
+            Address::Domainname { host, .. } => Cow::Borrowed(host),
+            Address::Ip(addr) => Cow::Owned(addr.ip().to_string()),
+        }
+    }
+
+    /// Returns the IP address, or `None` if the address is a domain name.
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.socket_addr().map(|addr| addr.ip())
+    }
+
+    /// Returns the socket address, or `None` if the address is a domain name (which has to be resolved first).
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Address::Domainname { .. } => None,
+            Address::Ip(addr) => Some(*addr),
         }
     }
 
-    /// Converts the `Address` into a byte sequence compatible with the SOCKS protocol.
-    pub fn as_socks_bytes(&self) -> Vec<u8> {
-        let mut bytes = vec![];
+    /// Returns the port.
+    pub fn port(&self) -> u16 {
+        match self {
+            Address::Domainname { port, .. } => *port,
+            Address::Ip(addr) => addr.port(),
+        }
+    }
 
+    /// Replaces an unspecified IP address (`0.0.0.0` or `::`), keeping the port. In replies, proxies use it to
+    /// refer to the address of the control connection, which is what `ip` should be.
+    pub fn replace_unspecified(
+        self,
+        ip: IpAddr,
+    ) -> Self {
+        match self {
+            Address::Ip(addr) if addr.ip().is_unspecified() => Address::Ip(SocketAddr::new(ip, addr.port())),
+            address => address,
+        }
+    }
+
+    /// Returns the type of the address, as it is encoded in the SOCKS protocol.
+    pub fn kind(&self) -> AddressType {
+        match self {
+            Address::Ip(SocketAddr::V4(_)) => AddressType::Ipv4,
+            Address::Ip(SocketAddr::V6(_)) => AddressType::Ipv6,
+            Address::Domainname { .. } => AddressType::DomainName,
+        }
+    }
+
+    /// Converts the `Address` into a byte sequence compatible with the SOCKS5 and SOCKS6 protocols:
+    /// the address type, the address itself, and the port.
+    ///
+    /// Fails if the domain name doesn't fit its one-byte length prefix, i.e. is longer than 255 bytes.
+    pub fn to_socks_bytes(&self) -> Result<Vec<u8>> {
+        let mut buf = BytesMut::with_capacity(self.encoded_len());
+        self.encode(&mut buf)?;
+
+        Ok(buf.into())
+    }
+
+    /// Returns the number of bytes the address occupies when encoded, e.g. to size a buffer up front.
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            Address::Ip(SocketAddr::V4(_)) => 1 + 4 + 2,
+            Address::Ip(SocketAddr::V6(_)) => 1 + 16 + 2,
+            Address::Domainname { host, .. } => 1 + 1 + host.len() + 2,
+        }
+    }
+
+    /// Appends the address, as by `to_socks_bytes`, to a caller-provided buffer, which can be reused to avoid
+    /// allocating per message. Nothing is written if the address can't be encoded.
+    pub fn encode(
+        &self,
+        buf: &mut BytesMut,
+    ) -> Result<()> {
         match self {
             Address::Ip(dst_addr) => {
+                buf.put_u8(self.kind().to_byte());
                 match dst_addr.ip() {
-                    IpAddr::V4(host) => {
-                        bytes.push(SOCKS_ATYP_IPV4);
-                        bytes.extend(host.octets().iter());
-                    }
-                    IpAddr::V6(host) => {
-                        bytes.push(SOCKS_ATYP_IPV6);
-                        bytes.extend(host.octets().iter());
-                    }
+                    IpAddr::V4(host) => buf.put_slice(&host.octets()),
+                    IpAddr::V6(host) => buf.put_slice(&host.octets()),
                 }
 
-                bytes.extend(dst_addr.port().to_be_bytes().iter())
+                buf.put_u16(dst_addr.port());
             }
             Address::Domainname { host, port } => {
-                bytes.push(SOCKS_ATYP_DOMAINNAME);
-
                 let host = host.as_bytes();
-                bytes.push(host.len() as u8);
-                bytes.extend(host);
-
-                bytes.extend(port.to_be_bytes().iter());
+                ensure!(
+                    host.len() <= MAX_DOMAIN_NAME_LENGTH,
+                    "Domain name MUST be at most {} bytes, got: {}.",
+                    MAX_DOMAIN_NAME_LENGTH,
+                    host.len()
+                );
+
+                buf.put_u8(self.kind().to_byte());
+                buf.put_u8(host.len() as u8);
+                buf.put_slice(host);
+
+                buf.put_u16(*port);
             }
         }
 
-        bytes
+        Ok(())
+    }
+
+    /// Reads an address, encoded as by `to_socks_bytes`, from a stream.
+    pub async fn from_socks_bytes<S>(stream: &mut S) -> Result<Self>
+    where
+        S: AsyncRead + Unpin,
+    {
+        // Read address type.
+        let mut address_type = [0; 1];
+        stream.read_exact(&mut address_type).await?;
+
+        let address_type = AddressType::from_byte(address_type[0])
+            .ok_or(SocksError::AddressTypeNotSupported(address_type[0]))?;
+
+        let address = match address_type {
+            AddressType::Ipv4 => {
+                let mut dst_addr = [0; 4];
+                stream.read_exact(&mut dst_addr).await?;
+
+                Address::Ip(SocketAddr::new(IpAddr::from(dst_addr), read_port(stream).await?))
+            }
+            AddressType::Ipv6 => {
+                let mut dst_addr = [0; 16];
+                stream.read_exact(&mut dst_addr).await?;
+
+                Address::Ip(SocketAddr::new(IpAddr::from(dst_addr), read_port(stream).await?))
+            }
+            AddressType::DomainName => {
+                let mut length = [0; 1];
+                stream.read_exact(&mut length).await?;
+
+                ensure!(length[0] > 0, "Domain name is empty.");
+
+                // The declared length comes straight from the peer, so a shorter domain name fails rather than being
+                // padded by whatever follows.
+                let mut dst_addr = vec![0; length[0] as usize];
+                stream
+                    .read_exact(&mut dst_addr)
+                    .await
+                    .with_context(|| format!("Domain name is truncated, expected {} bytes.", length[0]))?;
+
+                let host = String::from_utf8(dst_addr).map_err(|_| anyhow!("Domain name isn't valid UTF-8."))?;
+                ensure!(
+                    host.bytes().all(|byte| byte.is_ascii_graphic()),
+                    "Domain name contains invalid characters: {:?}.",
+                    host
+                );
+
+                Address::new(host, read_port(stream).await?)
+            }
+        };
+
+        Ok(address)
     }
 }
 
-impl ToString for Address {
-    // Converts the `Address` to a string representation.
-    fn to_string(&self) -> String {
+/// Converts a destination, as accepted by the clients, into an `Address`.
+pub(crate) fn into_address<A>(destination: A) -> Result<Address>
+where
+    A: TryInto<Address>,
+    A::Error: Into<anyhow::Error>,
+{
+    destination.try_into().map_err(Into::into)
+}
+
+/// Reads the port that follows an address.
+async fn read_port<S>(stream: &mut S) -> Result<u16>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut port = [0; 2];
+    stream.read_exact(&mut port).await?;
+
+    Ok(u16::from_be_bytes(port))
+}
+
+impl fmt::Display for Address {
+    // Formats the `Address` as a string representation.
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
         match self {
-            Address::Domainname { host, port } => format!("{}:{}", host, port),
-            Address::Ip(socket_addr) => socket_addr.to_string(),
+            Address::Domainname { host, port } => write!(f, "{}:{}", host, port),
+            Address::Ip(socket_addr) => write!(f, "{}", socket_addr),
         }
     }
 }
 
-/// Tries to convert a `SocketAddr` into an `Address`.
-impl TryFrom<SocketAddr> for Address {
+/// Converts a `SocketAddr` into an `Address`.
+impl From<SocketAddr> for Address {
+    fn from(addr: SocketAddr) -> Self {
+        Address::Ip(addr)
+    }
+}
+
+/// Converts a `SocketAddrV4` into an `Address`.
+impl From<SocketAddrV4> for Address {
+    fn from(addr: SocketAddrV4) -> Self {
+        Address::Ip(addr.into())
+    }
+}
+
+/// Converts a `SocketAddrV6` into an `Address`.
+impl From<SocketAddrV6> for Address {
+    fn from(addr: SocketAddrV6) -> Self {
+        Address::Ip(addr.into())
+    }
+}
+
+/// Converts an IP address and a port into an `Address`.
+impl From<(IpAddr, u16)> for Address {
+    fn from((ip, port): (IpAddr, u16)) -> Self {
+        Address::Ip(SocketAddr::new(ip, port))
+    }
+}
+
+/// Tries to convert a `&str` into an `Address`.
+impl TryFrom<&str> for Address {
     type Error = anyhow::Error;
 
-    fn try_from(addr: SocketAddr) -> Result<Self, Self::Error> {
-        addr.to_string().try_into()
+    fn try_from(addr: &str) -> Result<Self> {
+        let (host, port) = split_host_port(addr)?;
+
+        Ok(Address::new(host, port))
     }
 }
 
@@ -170,11 +428,7 @@ impl TryFrom<String> for Address {
     type Error = anyhow::Error;
 
     fn try_from(addr: String) -> Result<Self> {
-        if let Some((host, port)) = addr.split_once(':') {
-            Ok(Address::new(host, port.parse()?))
-        } else {
-            bail!("Address doesn't seperate host and port by ':'.")
-        }
+        addr.as_str().try_into()
     }
 }
 
@@ -187,49 +441,6 @@ impl TryFrom<&ProxyAddress> for Address {
     }
 }
 
-/// Reads the destination address from a stream and returns it as an `Address`.
-pub async fn read_address<S>(stream: &mut S) -> Result<Address>
-where
-    S: AsyncRead + Unpin,
-{
-    // Read address type.
-    let mut address_type = [0; 1];
-    stream.read_exact(&mut address_type).await?;
-
-    let dst_addr = match address_type[0] {
-        SOCKS_ATYP_IPV4 => {
-            let mut dst_addr = [0; 4];
-            stream.read_exact(&mut dst_addr).await?;
-
-            IpAddr::from(dst_addr).to_string()
-        }
-        SOCKS_ATYP_IPV6 => {
-            let mut dst_addr = [0; 16];
-            stream.read_exact(&mut dst_addr).await?;
-
-            IpAddr::from(dst_addr).to_string()
-        }
-        SOCKS_ATYP_DOMAINNAME => {
-            let mut length = [0; 1];
-            stream.read_exact(&mut length).await?;
-
-            let mut dst_addr = vec![0; length[0] as usize];
-            stream.read_exact(&mut dst_addr).await?;
-
-            String::from_utf8_lossy(&dst_addr[..]).to_string()
-        }
-        _ => unreachable!(),
-    };
-
-    // Read destination port.
-    let mut dst_port = [0; 2];
-    stream.read_exact(&mut dst_port).await?;
-
-    let dst_port = ((dst_port[0] as u16) << 8) | dst_port[1] as u16;
-
-    Ok(Address::new(dst_addr, dst_port))
-}
-
 #[cfg(test)]
 mod tests {
     use std::net::SocketAddr;
@@ -237,6 +448,16 @@ mod tests {
     use anyhow::Result;
 
     use super::*;
+    use crate::constants::*;
+
+    #[test]
+    fn test_address_replace_unspecified() {
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        assert_eq!(Address::new("0.0.0.0", 1080).replace_unspecified(ip), Address::new("192.0.2.1", 1080));
+        assert_eq!(Address::new("::", 1080).replace_unspecified(ip), Address::new("192.0.2.1", 1080));
+        assert_eq!(Address::new("10.0.0.1", 1080).replace_unspecified(ip), Address::new("10.0.0.1", 1080));
+        assert_eq!(Address::new("example.com", 1080).replace_unspecified(ip), Address::new("example.com", 1080));
+    }
 
     #[test]
     fn test_proxy_address_new() {
@@ -280,6 +501,30 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_address_accessors() {
+        let address = Address::new("example.com", 80);
+        assert_eq!((address.host(), address.port(), address.kind()), ("example.com".into(), 80, AddressType::DomainName));
+
+        let address = Address::new("10.0.0.1", 22);
+        assert_eq!((address.host(), address.port(), address.kind()), ("10.0.0.1".into(), 22, AddressType::Ipv4));
+
+        let address = Address::new("::1", 443);
+        assert_eq!((address.host(), address.port(), address.kind()), ("::1".into(), 443, AddressType::Ipv6));
+    }
+
+    #[test]
+    fn test_address_ip() {
+        let ip: IpAddr = "2001:db8::1".parse().unwrap();
+        let address = Address::from((ip, 443));
+        assert_eq!(address.ip(), Some(ip));
+        assert_eq!(address.socket_addr(), Some(SocketAddr::new(ip, 443)));
+        assert!(matches!(address, Address::Ip(SocketAddr::V6(_))));
+
+        let address = Address::new("example.com", 443);
+        assert_eq!((address.ip(), address.socket_addr()), (None, None));
+    }
+
     #[test]
     fn test_proxy_address_try_from_valid_string() -> Result<()> {
         let proxy_str = "socks5://localhost:1080".to_string();
@@ -290,6 +535,39 @@ mod tests {
         Ok(())
     }
 
+    // Tests that the protocol is taken from the scheme, and formatted back into it.
+    #[test]
+    fn test_proxy_address_protocol() -> Result<()> {
+        for (url, protocol) in [
+            ("socks5://localhost:1080", SocksVersion::Socks5),
+            ("socks6://localhost:1080", SocksVersion::Socks6),
+        ] {
+            let proxy_address: ProxyAddress = url.to_string().try_into()?;
+            assert_eq!(proxy_address.protocol(), Some(protocol));
+            assert_eq!(proxy_address.to_string(), url);
+            assert_eq!(ProxyAddress::try_from(proxy_address.to_string())?, proxy_address);
+        }
+
+        assert_eq!(ProxyAddress::new(SOCKS_VER_4, String::from("localhost"), 1080, None).protocol(), None);
+        assert!(ProxyAddress::try_from(String::from("socks4://localhost:1080")).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_proxy_address_try_from_credentials() -> Result<()> {
+        let proxy_address: ProxyAddress = "socks5://localhost:1080".to_string().try_into()?;
+        assert_eq!(proxy_address.credentials, None);
+
+        let proxy_address: ProxyAddress = "socks5://user@localhost:1080".to_string().try_into()?;
+        assert_eq!(proxy_address.credentials, Some(Credentials::new("user", "")?));
+
+        let proxy_address: ProxyAddress = "socks5://:secret@localhost:1080".to_string().try_into()?;
+        assert_eq!(proxy_address.credentials, Some(Credentials::new("", "secret")?));
+
+        Ok(())
+    }
+
     #[test]
     fn test_proxy_address_try_from_invalid_string() {
         let proxy_str = "invalid://localhost:1080".to_string();
@@ -311,6 +589,31 @@ mod tests {
         Ok(())
     }
 
+    // Tests that IP literals are encoded as IP address types, and only hostnames as domain names.
+    #[test]
+    fn test_address_type_of_literals() -> Result<()> {
+        assert!(Address::try_from("::1:80").is_err());
+        assert_eq!(Address::try_from("[::1]:80")?.kind(), AddressType::Ipv6);
+        assert_eq!(Address::try_from("127.0.0.1:1080")?.kind(), AddressType::Ipv4);
+        assert_eq!(Address::try_from("example.com:80")?.kind(), AddressType::DomainName);
+
+        // A bracketed host, e.g. from a URL, is an IP literal as well.
+        assert_eq!(Address::new("[::1]", 80), Address::Ip("[::1]:80".parse()?));
+        assert_eq!(Address::new("[::1]", 80).to_socks_bytes()?[0], AddressType::Ipv6.to_byte());
+        assert_eq!(Address::new("[127.0.0.1]", 80).kind(), AddressType::DomainName);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_address_try_from_ipv6_string() -> Result<()> {
+        let address: Address = String::from("[::1]:8000").try_into()?;
+        assert_eq!(address, Address::Ip("[::1]:8000".parse()?));
+        assert_eq!(address.to_string(), "[::1]:8000");
+
+        Ok(())
+    }
+
     #[test]
     fn test_address_try_from_invalid_string() {
         let addr_str = "localhost&8000".to_string();
@@ -319,9 +622,9 @@ mod tests {
     }
 
     #[test]
-    fn test_address_try_from_socket_addr() -> Result<()> {
+    fn test_address_from_socket_addr() -> Result<()> {
         let socket_addr: SocketAddr = "192.168.1.1:22".parse()?;
-        let address: Address = socket_addr.try_into()?;
+        let address: Address = socket_addr.into();
         match address {
             Address::Ip(addr) => {
                 assert_eq!(addr.ip().to_string(), "192.168.1.1");
@@ -332,6 +635,26 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_address_from_socket_addrs() {
+        let v4 = SocketAddrV4::new([10, 0, 0, 1].into(), 80);
+        assert_eq!(Address::from(v4), Address::new("10.0.0.1", 80));
+
+        let v6 = SocketAddrV6::new(std::net::Ipv6Addr::LOCALHOST, 443, 0, 0);
+        assert_eq!(Address::from(v6), Address::new("::1", 443));
+
+        let ip: IpAddr = "192.168.1.1".parse().unwrap();
+        assert_eq!(Address::from((ip, 22)), Address::new("192.168.1.1", 22));
+    }
+
+    #[test]
+    fn test_address_try_from_str() -> Result<()> {
+        assert_eq!(Address::try_from("example.com:443")?, Address::new("example.com", 443));
+        assert!(Address::try_from("example.com").is_err());
+
+        Ok(())
+    }
+
     #[test]
     fn test_address_try_from_proxy_address() -> Result<()> {
         let proxy_address = ProxyAddress::new(5, "localhost".to_string(), 1080, None);
@@ -346,5 +669,76 @@ mod tests {
         Ok(())
     }
 
-    // TODO: Add tests for `read_address` function once we have a way to mock the `AsyncRead`.
+    // Tests that every address type survives the encoding, and is framed as expected.
+    #[tokio::test]
+    async fn test_address_socks_bytes_roundtrip() -> Result<()> {
+        let addresses = [
+            (Address::new("10.0.0.1", 80), 1 + 4 + 2),
+            (Address::new("::1", 443), 1 + 16 + 2),
+            (Address::new("example.com", 8080), 1 + 1 + 11 + 2),
+            (Address::new("a".repeat(MAX_DOMAIN_NAME_LENGTH), 53), 1 + 1 + 255 + 2),
+        ];
+
+        for (address, length) in addresses.iter() {
+            let bytes = address.to_socks_bytes()?;
+            assert_eq!(bytes.len(), *length);
+            assert_eq!(bytes[0], address.kind().to_byte());
+            assert_eq!(Address::from_socks_bytes(&mut &bytes[..]).await?, *address);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_address_to_socks_bytes_ipv6() -> Result<()> {
+        let address = Address::new("2001:db8::1", 0x1F90);
+        let mut expected = vec![SOCKS_ATYP_IPV6, 0x20, 0x01, 0x0d, 0xb8];
+        expected.extend([0; 10].iter());
+        expected.extend([0x00, 0x01, 0x1F, 0x90].iter());
+        assert_eq!(address.to_socks_bytes()?, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_address_to_socks_bytes_oversized_domain() {
+        let address = Address::new("a".repeat(MAX_DOMAIN_NAME_LENGTH + 1), 80);
+        assert!(address.to_socks_bytes().is_err());
+    }
+
+    // Tests that unknown address types and truncated addresses are rejected.
+    #[tokio::test]
+    async fn test_address_from_socks_bytes_malformed() {
+        assert!(Address::from_socks_bytes(&mut &[0x02, 127, 0, 0, 1, 0, 80][..]).await.is_err());
+        assert!(Address::from_socks_bytes(&mut &[SOCKS_ATYP_IPV6, 0, 0, 0, 0, 0, 80][..]).await.is_err());
+        assert!(Address::from_socks_bytes(&mut &[SOCKS_ATYP_DOMAINNAME, 11, b'e', b'x'][..]).await.is_err());
+    }
+
+    // Tests that domain names which are truncated, empty, not UTF-8, or contain non-hostname characters are rejected
+    // with an error, at every possible length.
+    #[tokio::test]
+    async fn test_address_from_socks_bytes_malformed_domain() {
+        let valid = Address::new("example.com", 80).to_socks_bytes().unwrap();
+        for length in 0..valid.len() {
+            let error = Address::from_socks_bytes(&mut &valid[..length]).await.unwrap_err();
+            if length > 2 && length < valid.len() - 2 {
+                assert!(error.to_string().contains("truncated"), "{}", error);
+            }
+        }
+
+        let mut oversized = vec![SOCKS_ATYP_DOMAINNAME, 255];
+        oversized.extend(b"example.com");
+        let error = Address::from_socks_bytes(&mut &oversized[..]).await.unwrap_err();
+        assert_eq!(error.to_string(), "Domain name is truncated, expected 255 bytes.");
+
+        let malformed: [&[u8]; 4] = [
+            &[SOCKS_ATYP_DOMAINNAME, 0, 0, 80],
+            &[SOCKS_ATYP_DOMAINNAME, 2, 0xC3, 0x28, 0, 80],
+            &[SOCKS_ATYP_DOMAINNAME, 3, b'a', b' ', b'b', 0, 80],
+            &[SOCKS_ATYP_DOMAINNAME, 2, b'a', b'\n', 0, 80],
+        ];
+        for bytes in malformed.iter() {
+            assert!(Address::from_socks_bytes(&mut &bytes[..]).await.is_err(), "{:?}", bytes);
+        }
+    }
 }
@@ -0,0 +1,44 @@
+use num_traits::FromPrimitive;
+
+/// Represents the commands a client can request, shared by SOCKS5 and SOCKS6.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, FromPrimitive, PartialEq)]
+pub enum Command {
+    Connect = 0x01,
+    Bind = 0x02,
+    UdpAssociate = 0x03,
+}
+
+impl Command {
+    /// Returns the byte that identifies the command on the wire.
+    pub fn to_byte(self) -> u8 {
+        self as u8
+    }
+
+    /// Returns the command identified by the given byte, or `None` if the command is unknown.
+    ///
+    /// # Parameters
+    ///
+    /// * `byte`: The command byte of a request.
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        Self::from_u8(byte)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::*;
+
+    #[test]
+    fn test_command_bytes() {
+        for command in [Command::Connect, Command::Bind, Command::UdpAssociate] {
+            assert_eq!(Command::from_byte(command.to_byte()), Some(command));
+        }
+
+        assert_eq!(Command::Connect.to_byte(), SOCKS_CMD_CONNECT);
+        assert_eq!(Command::UdpAssociate.to_byte(), SOCKS_CMD_UDP_ASSOCIATE);
+        assert_eq!(Command::from_byte(SOCKS_CMD_NOOP), None);
+        assert_eq!(Command::from_byte(0x04), None);
+    }
+}
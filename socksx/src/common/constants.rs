@@ -1,12 +1,17 @@
+/// SOCKS protocol version 4 identifier.
+pub const SOCKS_VER_4: u8 = 0x04u8;
 /// SOCKS protocol version 5 identifier.
 pub const SOCKS_VER_5: u8 = 0x05u8;
 /// SOCKS protocol version 6 identifier.
 pub const SOCKS_VER_6: u8 = 0x06u8;
 
-/// Version identifier for SOCKS authentication.
+/// Version identifier of the username/password sub-negotiation (RFC 1929), which is `0x01` rather than the SOCKS
+/// version. It's the first byte of the authentication request and of the proxy's reply to it.
 pub const SOCKS_AUTH_VER: u8 = 0x01u8;
 /// Code for no authentication required.
 pub const SOCKS_AUTH_NOT_REQUIRED: u8 = 0x00u8;
+/// Code for GSSAPI authentication.
+pub const SOCKS_AUTH_GSSAPI: u8 = 0x01u8;
 /// Code for username/password authentication.
 pub const SOCKS_AUTH_USERNAME_PASSWORD: u8 = 0x02u8;
 /// Code for no acceptable authentication methods.
@@ -15,6 +20,12 @@ pub const SOCKS_AUTH_NO_ACCEPTABLE_METHODS: u8 = 0xFFu8;
 pub const SOCKS_AUTH_SUCCESS: u8 = 0x00u8;
 /// Code for failed authentication.
 pub const SOCKS_AUTH_FAILED: u8 = 0x01u8;
+/// Version identifier for GSSAPI sub-negotiation messages.
+pub const SOCKS_GSSAPI_VER: u8 = 0x01u8;
+/// GSSAPI message type of context establishment tokens.
+pub const SOCKS_GSSAPI_AUTHENTICATION: u8 = 0x01u8;
+/// GSSAPI message type that aborts the sub-negotiation.
+pub const SOCKS_GSSAPI_ABORT: u8 = 0xFFu8;
 
 /// Option kind for stack in SOCKS protocol.
 pub const SOCKS_OKIND_STACK: u16 = 0x01u16;
@@ -24,6 +35,8 @@ pub const SOCKS_OKIND_AUTH_METH_ADV: u16 = 0x02u16;
 pub const SOCKS_OKIND_AUTH_METH_SEL: u16 = 0x03u16;
 /// Option kind for authentication data.
 pub const SOCKS_OKIND_AUTH_DATA: u16 = 0x04u16;
+/// Option kind for the session ID, which the proxy assigns to a session.
+pub const SOCKS_OKIND_SESSION_ID: u16 = 0x06u16;
 
 /// Command code for no operation.
 pub const SOCKS_CMD_NOOP: u8 = 0x00u8;
@@ -34,6 +47,13 @@ pub const SOCKS_CMD_BIND: u8 = 0x02u8;
 /// Command code for associating a UDP port.
 pub const SOCKS_CMD_UDP_ASSOCIATE: u8 = 0x03u8;
 
+/// Maximum length of the initial data in a SOCKS6 request.
+pub const SOCKS_MAX_INITIAL_DATA_LENGTH: u16 = 16384u16;
+/// Default maximum total length of the options in a SOCKS6 request.
+pub const SOCKS_MAX_OPTIONS_LENGTH: u16 = 16384u16;
+/// Default maximum number of options in a SOCKS6 request or reply.
+pub const SOCKS_MAX_OPTIONS_COUNT: u16 = 64u16;
+
 /// Padding byte for SOCKS protocol.
 pub const SOCKS_PADDING: u8 = 0x00u8;
 /// Reserved byte for SOCKS protocol.
@@ -48,3 +68,10 @@ pub const SOCKS_ATYP_IPV6: u8 = 0x04u8;
 
 /// Reply code for succeeded operation.
 pub const SOCKS_REP_SUCCEEDED: u8 = 0x00u8;
+
+/// Version byte of SOCKS4 replies.
+pub const SOCKS_REPLY_VER_4: u8 = 0x00u8;
+/// Terminator of the SOCKS4 user ID and SOCKS4a domain name fields.
+pub const SOCKS_NULL: u8 = 0x00u8;
+/// Placeholder destination (0.0.0.x, x != 0) signalling that a SOCKS4a domain name follows.
+pub const SOCKS4A_MARKER_ADDR: std::net::Ipv4Addr = std::net::Ipv4Addr::new(0, 0, 0, 1);
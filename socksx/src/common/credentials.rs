@@ -1,3 +1,8 @@
+use anyhow::Result;
+
+/// Largest username or password that can be encoded, as its length is sent as a single byte.
+const MAX_CREDENTIAL_LENGTH: usize = 255;
+
 /// Represents the username and password credentials for SOCKS authentication.
 #[derive(Clone, Debug, PartialEq)]
 pub struct Credentials {
@@ -8,23 +13,53 @@ pub struct Credentials {
 }
 
 impl Credentials {
-    /// Creates a new `Credentials` instance.
+    /// Creates a new `Credentials` instance, prefer this over constructing one from its fields.
     ///
     /// # Parameters
     ///
     /// * `username`: The username as a byte vector or convertible to a byte vector.
     /// * `password`: The password as a byte vector or convertible to a byte vector.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Result` containing the credentials, or an error if the username or password exceeds 255 bytes.
     pub fn new<S: Into<Vec<u8>>>(
         username: S,
         password: S,
-    ) -> Self {
+    ) -> Result<Self> {
         let username = username.into();
         let password = password.into();
 
-        Credentials { username, password }
+        let credentials = Credentials { username, password };
+        credentials.validate()?;
+
+        Ok(credentials)
+    }
+
+    /// Checks that the username and password fit in the SOCKS authentication protocol.
+    ///
+    /// # Returns
+    ///
+    /// Returns an error if the username or password exceeds 255 bytes.
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            self.username.len() <= MAX_CREDENTIAL_LENGTH,
+            "Username MUST NOT be larger than {} bytes, got: {}.",
+            MAX_CREDENTIAL_LENGTH,
+            self.username.len()
+        );
+        ensure!(
+            self.password.len() <= MAX_CREDENTIAL_LENGTH,
+            "Password MUST NOT be larger than {} bytes, got: {}.",
+            MAX_CREDENTIAL_LENGTH,
+            self.password.len()
+        );
+
+        Ok(())
     }
 
     /// Converts the `Credentials` into a byte sequence compatible with the SOCKS authentication protocol.
+    /// An empty username or password is encoded with a length of zero, which some proxies use for anonymous access.
     ///
     /// # Returns
     ///
@@ -47,16 +82,36 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_credentials_new() {
-        let credentials = Credentials::new("username".to_string().into_bytes(), "password".to_string().into_bytes());
+    fn test_credentials_new() -> Result<()> {
+        let credentials = Credentials::new("username".to_string().into_bytes(), "password".to_string().into_bytes())?;
         assert_eq!(credentials.username, b"username".to_vec());
         assert_eq!(credentials.password, b"password".to_vec());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_credentials_too_long() {
+        assert!(Credentials::new(vec![b'u'; 255], vec![b'p'; 255]).is_ok());
+        assert!(Credentials::new(vec![b'u'; 256], vec![]).is_err());
+        assert!(Credentials::new(vec![], vec![b'p'; 256]).is_err());
     }
 
     #[test]
-    fn test_credentials_as_socks_bytes() {
-        let credentials = Credentials::new("username".to_string().into_bytes(), "password".to_string().into_bytes());
+    fn test_credentials_as_socks_bytes() -> Result<()> {
+        let credentials = Credentials::new("username".to_string().into_bytes(), "password".to_string().into_bytes())?;
         let socks_bytes = credentials.as_socks_bytes();
         assert_eq!(socks_bytes, vec![8, 117, 115, 101, 114, 110, 97, 109, 101, 8, 112, 97, 115, 115, 119, 111, 114, 100]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_credentials_as_socks_bytes_empty() -> Result<()> {
+        assert_eq!(Credentials::new("", "password")?.as_socks_bytes(), [&[0, 8][..], b"password"].concat());
+        assert_eq!(Credentials::new("username", "")?.as_socks_bytes(), [&[8][..], b"username", &[0]].concat());
+        assert_eq!(Credentials::new("", "")?.as_socks_bytes(), vec![0, 0]);
+
+        Ok(())
     }
 }
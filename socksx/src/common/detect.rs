@@ -0,0 +1,327 @@
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use anyhow::Result;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+
+use crate::{constants::*, Address, NativeSocksHandler, Socks5Handler, Socks6Handler, SocksSource, TransferStats};
+
+/// A handler that serves both SOCKS5 and SOCKS6 on the same listener.
+///
+/// The first byte sent by the client is its SOCKS version. This byte is handed back to the selected handler, so it
+/// can read the complete request itself.
+#[derive(Clone, Default)]
+pub struct VersionDetectHandler {
+    socks5: Socks5Handler,
+    socks6: Socks6Handler,
+}
+
+impl VersionDetectHandler {
+    /// Creates a new `VersionDetectHandler` dispatching to the given handlers.
+    ///
+    /// # Parameters
+    ///
+    /// * `socks5`: The handler for SOCKS5 clients.
+    /// * `socks6`: The handler for SOCKS6 clients.
+    ///
+    /// # Returns
+    ///
+    /// A new `VersionDetectHandler` instance.
+    pub fn new(
+        socks5: Socks5Handler,
+        socks6: Socks6Handler,
+    ) -> Self {
+        Self { socks5, socks6 }
+    }
+
+    /// Detects the SOCKS version of the client, which selects the handler.
+    /// Connections with an unknown version are shut down.
+    async fn detect<S: SocksSource>(
+        &self,
+        source: &mut S,
+    ) -> Result<Version> {
+        let mut version = [0; 1];
+        if source.read(&mut version).await? == 0 {
+            bail!("Client closed the connection before sending a request.");
+        }
+
+        match version[0] {
+            SOCKS_VER_5 => Ok(Version::Socks5),
+            SOCKS_VER_6 => Ok(Version::Socks6),
+            version => {
+                source.shutdown().await?;
+                bail!("Client uses an unsupported SOCKS version: {}.", version)
+            }
+        }
+    }
+}
+
+/// The SOCKS versions that are served.
+#[derive(Clone, Copy)]
+enum Version {
+    Socks5,
+    Socks6,
+}
+
+impl Version {
+    fn to_byte(self) -> u8 {
+        match self {
+            Version::Socks5 => SOCKS_VER_5,
+            Version::Socks6 => SOCKS_VER_6,
+        }
+    }
+}
+
+/// A source whose version byte was already read, which is read again before the rest of the stream.
+struct Rewound<'a, S> {
+    version: Option<u8>,
+    source: &'a mut S,
+}
+
+impl<'a, S: SocksSource> Rewound<'a, S> {
+    fn new(
+        version: Version,
+        source: &'a mut S,
+    ) -> Self {
+        Rewound {
+            version: Some(version.to_byte()),
+            source,
+        }
+    }
+}
+
+impl<S: SocksSource> AsyncRead for Rewound<'_, S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if let Some(version) = this.version.take() {
+            buf.put_slice(&[version]);
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut *this.source).poll_read(cx, buf)
+    }
+}
+
+impl<S: SocksSource> AsyncWrite for Rewound<'_, S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.get_mut().source).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().source).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().source).poll_shutdown(cx)
+    }
+}
+
+impl<S: SocksSource> SocksSource for Rewound<'_, S> {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.source.peer_addr()
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.source.local_addr()
+    }
+
+    fn as_tcp_stream(&mut self) -> Option<&mut TcpStream> {
+        // Once the version byte is read again, what's left is the stream itself.
+        match self.version {
+            Some(_) => None,
+            None => self.source.as_tcp_stream(),
+        }
+    }
+}
+
+impl VersionDetectHandler {
+    /// Accepts a request using the handler for the client's SOCKS version.
+    ///
+    /// # Parameters
+    ///
+    /// * `source`: A mutable reference to the source stream from which the request originates.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Result` containing the destination the client was connected to, and the data relayed.
+    pub async fn accept_request<S: SocksSource>(
+        &self,
+        source: &mut S,
+    ) -> Result<(Address, TransferStats)> {
+        let version = self.detect(source).await?;
+        let source = &mut Rewound::new(version, source);
+        match version {
+            Version::Socks5 => self.socks5.accept_request(source).await,
+            Version::Socks6 => self.socks6.accept_request(source).await,
+        }
+    }
+
+    /// Refuses a request using the handler for the client's SOCKS version.
+    ///
+    /// # Parameters
+    ///
+    /// * `source`: A mutable reference to the source stream from which the request originates.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Result<()>` indicating the success or failure of the operation.
+    pub async fn refuse_request<S: SocksSource>(
+        &self,
+        source: &mut S,
+    ) -> Result<()> {
+        let version = self.detect(source).await?;
+        let source = &mut Rewound::new(version, source);
+        match version {
+            Version::Socks5 => self.socks5.refuse_request(source).await,
+            Version::Socks6 => self.socks6.refuse_request(source).await,
+        }
+    }
+
+    /// Sets up the connection using the handler for the client's SOCKS version.
+    ///
+    /// # Parameters
+    ///
+    /// * `source`: A mutable reference to the source stream.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Result<TcpStream>` containing the prepared `TcpStream` or an error.
+    pub async fn setup<S: SocksSource>(
+        &self,
+        source: &mut S,
+    ) -> Result<TcpStream> {
+        let version = self.detect(source).await?;
+        let source = &mut Rewound::new(version, source);
+        match version {
+            Version::Socks5 => self.socks5.setup(source).await,
+            Version::Socks6 => self.socks6.setup(source).await,
+        }
+    }
+}
+
+impl NativeSocksHandler for VersionDetectHandler {
+    fn accept_request<S: SocksSource>(
+        &self,
+        source: &mut S,
+    ) -> impl Future<Output = Result<(Address, TransferStats)>> + Send {
+        VersionDetectHandler::accept_request(self, source)
+    }
+
+    fn refuse_request<S: SocksSource>(
+        &self,
+        source: &mut S,
+    ) -> impl Future<Output = Result<()>> + Send {
+        VersionDetectHandler::refuse_request(self, source)
+    }
+
+    fn setup<S: SocksSource>(
+        &self,
+        source: &mut S,
+    ) -> impl Future<Output = Result<TcpStream>> + Send {
+        VersionDetectHandler::setup(self, source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::socks5::{self, Socks5Request};
+    use crate::{Command, Socks5Client, Socks6Client};
+
+    // Spawns a `VersionDetectHandler` that serves a single connection.
+    async fn spawn_handler() -> Result<(String, tokio::task::JoinHandle<Result<TcpStream>>)> {
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?.to_string();
+
+        let handle = tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await?;
+            VersionDetectHandler::default().setup(&mut source).await
+        });
+
+        Ok((proxy_addr, handle))
+    }
+
+    #[tokio::test]
+    async fn test_detect_socks5_and_socks6() -> Result<()> {
+        let destination = TcpListener::bind("127.0.0.1:0").await?;
+        let destination_addr = destination.local_addr()?.to_string();
+
+        let (proxy_addr, _) = spawn_handler().await?;
+        let client = Socks5Client::new(proxy_addr, None).await?;
+        client.connect(destination_addr.clone()).await?;
+
+        let (proxy_addr, _) = spawn_handler().await?;
+        let client = Socks6Client::new(proxy_addr, None).await?;
+        client.connect(destination_addr, None, None).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_detect_unknown_version() -> Result<()> {
+        let (proxy_addr, handle) = spawn_handler().await?;
+
+        let mut stream = TcpStream::connect(proxy_addr).await?;
+        stream.write_all(&[0x42, 0x00]).await?;
+
+        assert!(handle.await?.is_err());
+        assert_eq!(stream.read(&mut [0; 1]).await?, 0);
+
+        Ok(())
+    }
+
+    // Tests that a request is served over an in-memory stream, which has no socket addresses.
+    #[tokio::test]
+    async fn test_detect_duplex_source() -> Result<()> {
+        let destination = TcpListener::bind("127.0.0.1:0").await?;
+        let destination_addr = destination.local_addr()?;
+
+        let (mut stream, mut source) = tokio::io::duplex(1024);
+        let handle = tokio::spawn(async move { VersionDetectHandler::default().accept_request(&mut source).await });
+
+        stream.write_all(&[SOCKS_VER_5, 1, SOCKS_AUTH_NOT_REQUIRED]).await?;
+        let mut selection = [0; 2];
+        stream.read_exact(&mut selection).await?;
+        assert_eq!(selection, [SOCKS_VER_5, SOCKS_AUTH_NOT_REQUIRED]);
+
+        let request = Socks5Request::new(Command::Connect, Address::from(destination_addr));
+        stream.write_all(&request.into_socks_bytes()?).await?;
+        socks5::read_reply(&mut stream).await?;
+
+        let (mut incoming, _) = destination.accept().await?;
+        stream.write_all(b"data").await?;
+        drop(stream);
+
+        let mut received = vec![];
+        incoming.read_to_end(&mut received).await?;
+        assert_eq!(received, b"data");
+        drop(incoming);
+
+        let (target, stats) = handle.await??;
+        assert_eq!(target, Address::from(destination_addr));
+        assert_eq!(stats.sent, 4);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,202 @@
+use std::fmt;
+use std::io;
+
+use thiserror::Error;
+
+use crate::socks5::Socks5AuthMethod;
+use crate::socks6::options::AuthMethod;
+use crate::socks6::UdpFraming;
+
+/// Errors returned by the SOCKS clients.
+///
+/// Each variant represents a distinct failure mode, so callers can, for example,
+/// retry on `Io` errors but give up on `AuthFailed`.
+#[derive(Debug, Error)]
+pub enum SocksError {
+    /// The proxy replied with an unexpected SOCKS version.
+    #[error("Proxy uses a different SOCKS version: {0}.")]
+    VersionMismatch(u8),
+    /// The proxy replied with an unexpected authentication sub-negotiation version.
+    #[error("Proxy uses a different authentication method version: {0}.")]
+    AuthVersionMismatch(u8),
+    /// The proxy accepted none of the offered authentication methods, e.g. because it requires credentials while
+    /// `UsernamePassword` wasn't offered, or forbids them. The methods are reported by their codes, which SOCKS5 and
+    /// SOCKS6 share.
+    #[error("Proxy accepted none of the offered authentication methods: {offered:?}.")]
+    AuthMethodRejected { offered: Vec<AuthMethod> },
+    /// The proxy selected an authentication method that wasn't offered.
+    #[error("Proxy selected authentication method {0:?}, which wasn't offered.")]
+    UnofferedAuthMethod(Socks5AuthMethod),
+    /// The proxy selected an authentication method that isn't supported.
+    #[error("Proxy proposed unsupported authentication method: {0}.")]
+    UnsupportedAuthMethod(u8),
+    /// The proxy demands authentication, but no credentials are provided.
+    #[error("Proxy demands authentication, but no credentials are provided.")]
+    CredentialsRequired,
+    /// The proxy rejected the provided credentials.
+    #[error("Authentication with the provided credentials failed.")]
+    AuthFailed,
+    /// The request contains a command that isn't supported.
+    #[error("Command not supported: {0}.")]
+    CommandNotSupported(u8),
+    /// An address has an address type (ATYP) that isn't supported.
+    #[error("Address type not supported: {0}.")]
+    AddressTypeNotSupported(u8),
+    /// The proxy replied to the request with a failure code.
+    #[error("Operation failed: {} ({:#04x}).", describe_reply(*.0), .0)]
+    ReplyFailure(u8),
+    /// Datagrams of a UDP association were to be carried in a way that isn't supported, i.e. DTLS.
+    #[error("UDP framing not supported: {0:?}.")]
+    UnsupportedUdpFraming(UdpFraming),
+    /// The proxy closed the connection before the handshake completed, e.g. because of an ACL or a rate limit.
+    #[error("Proxy closed the connection during {stage}.")]
+    ConnectionClosedDuringHandshake { stage: HandshakeStage },
+    /// Connecting was cancelled through its cancellation token, before the handshake completed.
+    #[error("Connecting was cancelled.")]
+    Cancelled,
+    /// An I/O error occurred while talking to the proxy.
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// Any other error, e.g. an invalid destination address.
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl SocksError {
+    /// Returns the name of the variant, in snake case, e.g. to label metrics by the kind of error.
+    pub fn variant_name(&self) -> &'static str {
+        match self {
+            SocksError::VersionMismatch(_) => "version_mismatch",
+            SocksError::AuthVersionMismatch(_) => "auth_version_mismatch",
+            SocksError::AuthMethodRejected { .. } => "auth_method_rejected",
+            SocksError::UnofferedAuthMethod(_) => "unoffered_auth_method",
+            SocksError::UnsupportedAuthMethod(_) => "unsupported_auth_method",
+            SocksError::CredentialsRequired => "credentials_required",
+            SocksError::AuthFailed => "auth_failed",
+            SocksError::CommandNotSupported(_) => "command_not_supported",
+            SocksError::AddressTypeNotSupported(_) => "address_type_not_supported",
+            SocksError::ReplyFailure(_) => "reply_failure",
+            SocksError::UnsupportedUdpFraming(_) => "unsupported_udp_framing",
+            SocksError::ConnectionClosedDuringHandshake { .. } => "connection_closed_during_handshake",
+            SocksError::Cancelled => "cancelled",
+            SocksError::Io(_) => "io",
+            SocksError::Other(_) => "other",
+        }
+    }
+}
+
+impl SocksError {
+    /// Converts an unexpected end of the stream, i.e. the proxy closing the connection, into
+    /// `ConnectionClosedDuringHandshake` for the given stage. Other errors are kept as they are.
+    pub(crate) fn closed_during(
+        self,
+        stage: HandshakeStage,
+    ) -> Self {
+        match self {
+            SocksError::Io(error) if error.kind() == io::ErrorKind::UnexpectedEof => {
+                SocksError::ConnectionClosedDuringHandshake { stage }
+            }
+            error => error,
+        }
+    }
+}
+
+/// The stages of a client handshake.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HandshakeStage {
+    /// Negotiating the authentication method (SOCKS5 only).
+    Negotiation,
+    /// Authenticating, or awaiting the authentication reply (SOCKS6).
+    Authentication,
+    /// Awaiting the reply to the request.
+    Reply,
+}
+
+impl fmt::Display for HandshakeStage {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        let stage = match self {
+            HandshakeStage::Negotiation => "negotiation",
+            HandshakeStage::Authentication => "authentication",
+            HandshakeStage::Reply => "reply",
+        };
+
+        f.write_str(stage)
+    }
+}
+
+impl From<anyhow::Error> for SocksError {
+    // Recovers the original error, if it was a `SocksError` or an I/O error.
+    fn from(error: anyhow::Error) -> Self {
+        match error.downcast::<SocksError>() {
+            Ok(error) => error,
+            Err(error) => match error.downcast::<io::Error>() {
+                Ok(error) => SocksError::Io(error),
+                Err(error) => SocksError::Other(error),
+            },
+        }
+    }
+}
+
+/// Describes a failure reply code of SOCKS4, SOCKS5, or SOCKS6 (their codes don't overlap).
+fn describe_reply(code: u8) -> &'static str {
+    match code {
+        0x01 => "general failure",
+        0x02 => "connection not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        0x09 => "connection attempt timed out",
+        0x5B => "request rejected or failed",
+        0x5C => "proxy could not reach identd on the client",
+        0x5D => "identd reported a different user ID",
+        _ => "unknown reply code",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_anyhow_recovers_socks_error() {
+        let error = anyhow::Error::from(SocksError::AuthFailed);
+        assert!(matches!(SocksError::from(error), SocksError::AuthFailed));
+    }
+
+    #[test]
+    fn test_from_anyhow_recovers_io_error() {
+        let error = anyhow::Error::from(io::Error::from(io::ErrorKind::ConnectionRefused));
+        assert!(matches!(SocksError::from(error), SocksError::Io(e) if e.kind() == io::ErrorKind::ConnectionRefused));
+    }
+
+    #[test]
+    fn test_from_anyhow_other() {
+        let error = anyhow!("Something else.");
+        assert!(matches!(SocksError::from(error), SocksError::Other(_)));
+    }
+
+    #[test]
+    fn test_closed_during() {
+        let error = SocksError::from(io::Error::from(io::ErrorKind::UnexpectedEof));
+        let error = error.closed_during(HandshakeStage::Reply);
+        assert!(matches!(error, SocksError::ConnectionClosedDuringHandshake { stage: HandshakeStage::Reply }));
+        assert_eq!(error.to_string(), "Proxy closed the connection during reply.");
+
+        let error = SocksError::AuthFailed.closed_during(HandshakeStage::Authentication);
+        assert!(matches!(error, SocksError::AuthFailed));
+    }
+
+    #[test]
+    fn test_reply_failure_display() {
+        assert_eq!(
+            SocksError::ReplyFailure(0x05).to_string(),
+            "Operation failed: connection refused (0x05)."
+        );
+    }
+}
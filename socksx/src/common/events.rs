@@ -0,0 +1,100 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+#[cfg(feature = "logging")]
+use tracing::{field, Span};
+
+use crate::Address;
+
+/// Describes where the time went while a handler set up a connection.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConnectionEvent {
+    /// The destination that was requested by the client.
+    pub destination: Address,
+    /// The destination that was connected to, which differs from `destination` if a rewriter changed it.
+    pub target: Address,
+    /// Time spent receiving and parsing the client's request.
+    pub handshake_latency: Duration,
+    /// Time from accepting the source until the destination (or the next hop) was established.
+    pub setup_latency: Duration,
+}
+
+/// A callback that is invoked with a `ConnectionEvent` for every connection that was set up.
+pub type EventHandler = Arc<dyn Fn(&ConnectionEvent) + Send + Sync>;
+
+/// Source of the ids that correlate everything logged for a connection.
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Returns a new, process-wide unique, connection id.
+pub fn next_connection_id() -> u64 {
+    NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// The span a connection is handled in, which is nothing without the `logging` feature.
+#[cfg(feature = "logging")]
+pub type ConnectionSpan = Span;
+#[cfg(not(feature = "logging"))]
+#[derive(Clone, Debug)]
+pub struct ConnectionSpan;
+
+#[cfg(feature = "logging")]
+pub(crate) use tracing::Instrument;
+
+/// Stand-in for `tracing::Instrument`, that runs futures as-is without the `logging` feature.
+#[cfg(not(feature = "logging"))]
+pub(crate) trait Instrument: Sized {
+    fn instrument(
+        self,
+        _span: ConnectionSpan,
+    ) -> Self {
+        self
+    }
+}
+
+#[cfg(not(feature = "logging"))]
+impl<F: std::future::Future> Instrument for F {}
+
+/// Creates the span a connection is handled in, carrying a generated connection id.
+/// The proxy address and destination are recorded once known, see `record_proxy` and `record_destination`.
+#[cfg(feature = "logging")]
+pub fn connection_span() -> ConnectionSpan {
+    info_span!("connection", id = next_connection_id(), proxy = field::Empty, destination = field::Empty)
+}
+
+#[cfg(not(feature = "logging"))]
+pub fn connection_span() -> ConnectionSpan {
+    ConnectionSpan
+}
+
+/// Records the destination of the connection in the current span, and in the connection registry if it's registered.
+pub fn record_destination(destination: &Address) {
+    crate::registry::record_destination(destination);
+
+    #[cfg(feature = "logging")]
+    Span::current().record("destination", field::display(destination));
+    #[cfg(not(feature = "logging"))]
+    let _ = destination;
+}
+
+/// Records the address of the proxy in the current span.
+pub fn record_proxy(proxy: &dyn fmt::Display) {
+    #[cfg(feature = "logging")]
+    Span::current().record("proxy", field::display(proxy));
+    #[cfg(not(feature = "logging"))]
+    let _ = proxy;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Tests that every connection gets its own id.
+    #[test]
+    fn test_next_connection_id() {
+        let first = next_connection_id();
+        let second = next_connection_id();
+        assert!(second > first);
+    }
+}
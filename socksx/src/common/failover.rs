@@ -0,0 +1,305 @@
+use std::convert::TryInto;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::join_all;
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+
+use crate::addresses::{self, Address, ProxyAddress, SocksVersion};
+use crate::{Socks5Client, Socks6Client, SocksError};
+
+/// How long a proxy that failed is skipped, by default.
+pub const PROXY_COOLDOWN: Duration = Duration::from_secs(30);
+
+/// The health of a proxy, as observed by connecting to and probing it.
+#[derive(Clone, Copy, Debug, Default)]
+struct ProxyHealth {
+    down_until: Option<Instant>,
+    latency: Option<Duration>,
+}
+
+impl ProxyHealth {
+    fn is_down(&self) -> bool {
+        self.down_until.is_some_and(|down_until| Instant::now() < down_until)
+    }
+}
+
+/// Connects through one of several interchangeable SOCKS5 or SOCKS6 proxies, failing over to the next one when a
+/// proxy can't be reached.
+///
+/// Proxies are tried in the configured order, or by the latency measured by `probe`. A proxy that fails is marked
+/// down, and skipped until its cooldown passes. If every proxy is down, all of them are tried anyway.
+#[derive(Clone)]
+pub struct MultiProxyClient {
+    proxies: Vec<ProxyAddress>,
+    health: Arc<Mutex<Vec<ProxyHealth>>>,
+    cooldown: Duration,
+    order_by_latency: bool,
+    handshake_deadline: Option<Duration>,
+}
+
+impl MultiProxyClient {
+    /// Creates a new `MultiProxyClient`.
+    ///
+    /// # Parameters
+    ///
+    /// * `proxies`: The proxies, in order of preference, whose version selects the client used for them.
+    pub fn new(proxies: Vec<ProxyAddress>) -> Self {
+        let health = vec![ProxyHealth::default(); proxies.len()];
+
+        MultiProxyClient {
+            proxies,
+            health: Arc::new(Mutex::new(health)),
+            cooldown: PROXY_COOLDOWN,
+            order_by_latency: false,
+            handshake_deadline: None,
+        }
+    }
+
+    /// Returns the configured proxies.
+    pub fn proxies(&self) -> &[ProxyAddress] {
+        &self.proxies
+    }
+
+    /// Returns the proxies that aren't marked down, in the order they're tried.
+    pub fn available_proxies(&self) -> Vec<ProxyAddress> {
+        let health = self.health.lock().unwrap();
+        self.order(&health)
+            .into_iter()
+            .filter(|index| !health[*index].is_down())
+            .map(|index| self.proxies[index].clone())
+            .collect()
+    }
+
+    /// Sets how long a proxy that failed is skipped.
+    ///
+    /// # Parameters
+    ///
+    /// * `cooldown`: The cooldown, defaults to 30s.
+    pub fn set_cooldown(
+        &mut self,
+        cooldown: Duration,
+    ) {
+        self.cooldown = cooldown;
+    }
+
+    /// Sets whether proxies are tried by the latency measured by `probe`, rather than in the configured order.
+    /// Proxies that weren't probed successfully are tried last.
+    ///
+    /// # Parameters
+    ///
+    /// * `order_by_latency`: Whether to order by latency, defaults to `false`.
+    pub fn set_order_by_latency(
+        &mut self,
+        order_by_latency: bool,
+    ) {
+        self.order_by_latency = order_by_latency;
+    }
+
+    /// Sets the time the handshake with each proxy is given, so that a proxy that hangs is failed over as well.
+    ///
+    /// # Parameters
+    ///
+    /// * `handshake_deadline`: The deadline, defaults to `None` (no deadline).
+    pub fn set_handshake_deadline(
+        &mut self,
+        handshake_deadline: Option<Duration>,
+    ) {
+        self.handshake_deadline = handshake_deadline;
+    }
+
+    /// Establishes a connection to the destination through the first proxy that succeeds.
+    ///
+    /// A proxy that fails is marked down, unless it replied to the request with a failure, which is about the
+    /// destination rather than the proxy. Either way, the next proxy is tried.
+    ///
+    /// # Parameters
+    ///
+    /// * `destination`: The target address and port to connect to.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Result` containing the `TcpStream` to the destination and the bound address, or the error of the
+    /// last proxy that was tried.
+    pub async fn connect<A>(
+        &self,
+        destination: A,
+    ) -> Result<(TcpStream, Address), SocksError>
+    where
+        A: TryInto<Address>,
+        A::Error: Into<anyhow::Error>,
+    {
+        let destination = addresses::into_address(destination)?;
+
+        let order = {
+            let health = self.health.lock().unwrap();
+            let order = self.order(&health);
+            let available: Vec<_> = order.iter().copied().filter(|index| !health[*index].is_down()).collect();
+
+            if available.is_empty() { order } else { available }
+        };
+
+        let mut last_error = None;
+        for index in order {
+            let proxy = &self.proxies[index];
+            match self.connect_through(proxy, destination.clone()).await {
+                Ok(connected) => {
+                    self.health.lock().unwrap()[index].down_until = None;
+                    return Ok(connected);
+                }
+                Err(error) => {
+                    info!("Connecting through proxy {} failed: {}", proxy, error);
+                    if !matches!(error, SocksError::ReplyFailure(_)) {
+                        self.mark_down(index);
+                    }
+
+                    last_error = Some(error);
+                }
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| anyhow!("No proxies are configured.").into()))
+    }
+
+    /// Probes every proxy concurrently, recording their latency, and marking down those that don't respond.
+    ///
+    /// # Returns
+    ///
+    /// Returns the number of proxies that responded.
+    pub async fn probe(&self) -> usize {
+        let probes = self.proxies.iter().map(|proxy| self.probe_proxy(proxy));
+        let results = join_all(probes).await;
+
+        let mut health = self.health.lock().unwrap();
+        let mut responded = 0;
+        for (index, result) in results.into_iter().enumerate() {
+            match result {
+                Ok(latency) => {
+                    health[index] = ProxyHealth {
+                        down_until: None,
+                        latency: Some(latency),
+                    };
+                    responded += 1;
+                }
+                Err(error) => {
+                    debug!("Probing proxy {} failed: {}", self.proxies[index], error);
+                    health[index] = ProxyHealth {
+                        down_until: Some(Instant::now() + self.cooldown),
+                        latency: None,
+                    };
+                }
+            }
+        }
+
+        responded
+    }
+
+    /// Returns the indices of the proxies, in the order they're tried.
+    fn order(
+        &self,
+        health: &[ProxyHealth],
+    ) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..self.proxies.len()).collect();
+        if self.order_by_latency {
+            order.sort_by_key(|index| (health[*index].latency.is_none(), health[*index].latency));
+        }
+
+        order
+    }
+
+    fn mark_down(
+        &self,
+        index: usize,
+    ) {
+        let mut health = self.health.lock().unwrap();
+        health[index].down_until = Some(Instant::now() + self.cooldown);
+    }
+
+    /// Connects to the destination through a single proxy, with the client for its version.
+    async fn connect_through(
+        &self,
+        proxy: &ProxyAddress,
+        destination: Address,
+    ) -> Result<(TcpStream, Address), SocksError> {
+        let proxy_addr = format!("{}:{}", proxy.host, proxy.port);
+        match proxy.protocol() {
+            Some(SocksVersion::Socks5) => {
+                let mut client = Socks5Client::new(proxy_addr, proxy.credentials.clone()).await?;
+                client.set_handshake_deadline(self.handshake_deadline);
+                client.connect(destination).await
+            }
+            Some(SocksVersion::Socks6) => {
+                let mut client = Socks6Client::new(proxy_addr, proxy.credentials.clone()).await?;
+                client.set_handshake_deadline(self.handshake_deadline);
+                client.connect(destination, None, None).await
+            }
+            None => Err(anyhow!("Unsupported SOCKS version: {}", proxy.socks_version).into()),
+        }
+    }
+
+    /// Probes a single proxy, with the client for its version.
+    async fn probe_proxy(
+        &self,
+        proxy: &ProxyAddress,
+    ) -> Result<Duration, SocksError> {
+        let proxy_addr = format!("{}:{}", proxy.host, proxy.port);
+        match proxy.protocol() {
+            Some(SocksVersion::Socks5) => Socks5Client::new(proxy_addr, proxy.credentials.clone()).await?.probe().await,
+            Some(SocksVersion::Socks6) => Socks6Client::new(proxy_addr, proxy.credentials.clone()).await?.probe().await,
+            None => Err(anyhow!("Unsupported SOCKS version: {}", proxy.socks_version).into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::Socks5Handler;
+
+    // Tests that a proxy that can't be reached is failed over, and skipped until its cooldown passes.
+    #[tokio::test]
+    async fn test_failover() -> anyhow::Result<()> {
+        let destination = TcpListener::bind("127.0.0.1:0").await?;
+        let destination_addr = destination.local_addr()?;
+        let down_addr = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        tokio::spawn(async move {
+            loop {
+                let (mut source, _) = proxy.accept().await.unwrap();
+                tokio::spawn(async move {
+                    if let Ok(mut destination) = Socks5Handler::default().setup(&mut source).await {
+                        crate::copy_bidirectional(&mut source, &mut destination).await.ok();
+                    }
+                });
+            }
+        });
+
+        let down = ProxyAddress::new(SocksVersion::Socks5.to_byte(), down_addr.ip().to_string(), down_addr.port(), None);
+        let up = ProxyAddress::new(SocksVersion::Socks5.to_byte(), proxy_addr.ip().to_string(), proxy_addr.port(), None);
+        let mut client = MultiProxyClient::new(vec![down.clone(), up.clone()]);
+        assert_eq!(client.available_proxies(), vec![down.clone(), up.clone()]);
+
+        client.connect(destination_addr).await?;
+        assert_eq!(client.available_proxies(), vec![up.clone()]);
+
+        // Once every proxy is down, they're all tried again.
+        client.set_cooldown(Duration::from_secs(60));
+        client.mark_down(1);
+        assert!(client.available_proxies().is_empty());
+        client.connect(destination_addr).await?;
+        assert_eq!(client.available_proxies(), vec![up.clone()]);
+
+        // Probing marks down the proxies that don't respond, and orders the others by latency.
+        let mut client = MultiProxyClient::new(vec![down, up.clone()]);
+        client.set_order_by_latency(true);
+        assert_eq!(client.probe().await, 1);
+        assert_eq!(client.available_proxies(), vec![up]);
+
+        Ok(())
+    }
+}
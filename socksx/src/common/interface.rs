@@ -1,49 +1,163 @@
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
 use anyhow::Result;
 use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream};
 use tokio::net::TcpStream;
 
+use crate::{Address, TransferStats};
+
+/// A callback that rewrites the destination of a request before it's dialed.
+/// It returns the destination to dial instead, or `None` to refuse the request.
+pub type DestinationRewriter = Arc<dyn Fn(Address) -> Option<Address> + Send + Sync>;
+
+/// A stream that a client's request is served over, e.g. a `TcpStream`, a TLS stream over one, or an in-memory
+/// `tokio::io::duplex` in tests.
+///
+/// Only the stream itself is needed to serve a request. The socket addresses are needed to detect a proxy chain that
+/// loops back, and to forward the address of the client, which fail (or are skipped) without them.
+pub trait SocksSource: AsyncRead + AsyncWrite + Unpin + Send {
+    /// Returns the address of the client, if the stream has one.
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "Source has no peer address."))
+    }
+
+    /// Returns the address the client connected to, if the stream has one.
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "Source has no local address."))
+    }
+
+    /// Returns the stream as a `TcpStream`, if it is a plain one, so data can be relayed with `relay_tcp`.
+    fn as_tcp_stream(&mut self) -> Option<&mut TcpStream> {
+        None
+    }
+}
+
+impl SocksSource for TcpStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::local_addr(self)
+    }
+
+    fn as_tcp_stream(&mut self) -> Option<&mut TcpStream> {
+        Some(self)
+    }
+}
+
+impl SocksSource for DuplexStream {}
+
+#[cfg(feature = "tls")]
+impl<S: SocksSource> SocksSource for tokio_rustls::server::TlsStream<S> {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().0.peer_addr()
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().0.local_addr()
+    }
+}
+
 /// An asynchronous trait defining the core functionalities required for handling SOCKS requests.
+///
+/// Requests are served over a `TcpStream` by default, e.g. by the `Server`, but can be served over any `SocksSource`.
 #[async_trait]
-pub trait SocksHandler {
+pub trait SocksHandler<S: SocksSource = TcpStream> {
     /// Accepts a SOCKS request from a client.
     ///
     /// # Parameters
     ///
-    /// * `source`: A mutable reference to the source `TcpStream` from which the request originates.
+    /// * `source`: A mutable reference to the source stream from which the request originates.
     ///
     /// # Returns
     ///
-    /// Returns `Result<()>` indicating the success or failure of the operation.
+    /// Returns a `Result` containing the destination the client was connected to, and the data relayed until the
+    /// connection closed, e.g. to log a record of every connection.
     async fn accept_request(
         &self,
-        source: &mut TcpStream,
-    ) -> Result<()>;
+        source: &mut S,
+    ) -> Result<(Address, TransferStats)>;
 
     /// Refuses a SOCKS request from a client.
     ///
     /// # Parameters
     ///
-    /// * `source`: A reference to the source `TcpStream` from which the request originates.
+    /// * `source`: A reference to the source stream from which the request originates.
     ///
     /// # Returns
     ///
     /// Returns `Result<()>` indicating the success or failure of the operation.
     async fn refuse_request(
         &self,
-        source: &mut TcpStream,
+        source: &mut S,
     ) -> Result<()>;
 
     /// Sets up the SOCKS connection for a given source.
     ///
     /// # Parameters
     ///
-    /// * `source`: A mutable reference to the source `TcpStream`.
+    /// * `source`: A mutable reference to the source stream.
     ///
     /// # Returns
     ///
     /// Returns a `Result<TcpStream>` containing the prepared `TcpStream` or an error.
     async fn setup(
         &self,
-        source: &mut TcpStream,
+        source: &mut S,
     ) -> Result<TcpStream>;
 }
+
+/// The counterpart of `SocksHandler` with native async functions, so calls aren't boxed.
+///
+/// This trait can't be used as a trait object. Every implementor also implements `SocksHandler`, for when the handler
+/// is only known at runtime, while the `Server` and other callers that know the concrete handler type can avoid the
+/// allocation per call. The handlers of this crate also have these methods as inherent ones, so calling them isn't
+/// ambiguous while both traits are in scope.
+pub trait NativeSocksHandler {
+    /// Accepts a SOCKS request from a client, see `SocksHandler::accept_request`.
+    fn accept_request<S: SocksSource>(
+        &self,
+        source: &mut S,
+    ) -> impl Future<Output = Result<(Address, TransferStats)>> + Send;
+
+    /// Refuses a SOCKS request from a client, see `SocksHandler::refuse_request`.
+    fn refuse_request<S: SocksSource>(
+        &self,
+        source: &mut S,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Sets up the SOCKS connection for a given source, see `SocksHandler::setup`.
+    fn setup<S: SocksSource>(
+        &self,
+        source: &mut S,
+    ) -> impl Future<Output = Result<TcpStream>> + Send;
+}
+
+#[async_trait]
+impl<S: SocksSource, T: NativeSocksHandler + Sync> SocksHandler<S> for T {
+    async fn accept_request(
+        &self,
+        source: &mut S,
+    ) -> Result<(Address, TransferStats)> {
+        NativeSocksHandler::accept_request(self, source).await
+    }
+
+    async fn refuse_request(
+        &self,
+        source: &mut S,
+    ) -> Result<()> {
+        NativeSocksHandler::refuse_request(self, source).await
+    }
+
+    async fn setup(
+        &self,
+        source: &mut S,
+    ) -> Result<TcpStream> {
+        NativeSocksHandler::setup(self, source).await
+    }
+}
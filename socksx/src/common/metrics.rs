@@ -0,0 +1,94 @@
+use std::time::Duration;
+
+use metrics::{counter, gauge, histogram, Gauge};
+
+use crate::{SocksError, TransferStats};
+
+/// Counter of the connections accepted by servers.
+pub const CONNECTIONS_TOTAL: &str = "connections_total";
+/// Gauge of the connections that are being handled by servers.
+pub const CONNECTIONS_ACTIVE: &str = "connections_active";
+/// Counter of the bytes relayed through tunnels, labeled by `direction` (`sent` or `received`), recorded once a tunnel
+/// is closed.
+pub const BYTES_TRANSFERRED_TOTAL: &str = "bytes_transferred_total";
+/// Counter of the client handshakes that failed, labeled by `reason` (the `SocksError` variant).
+pub const HANDSHAKE_ERRORS_TOTAL: &str = "handshake_errors_total";
+/// Histogram of the duration of client handshakes that succeeded, in seconds.
+pub const HANDSHAKE_DURATION_SECONDS: &str = "handshake_duration_seconds";
+
+/// Counts a connection as active, until it's dropped.
+pub(crate) struct ActiveConnection(Gauge);
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.0.decrement(1.0);
+    }
+}
+
+/// Records a connection accepted by a server, which is active until the returned guard is dropped.
+pub(crate) fn connection_opened() -> ActiveConnection {
+    counter!(CONNECTIONS_TOTAL).increment(1);
+    let active = gauge!(CONNECTIONS_ACTIVE);
+    active.increment(1.0);
+
+    ActiveConnection(active)
+}
+
+/// Records the data relayed through a tunnel.
+pub(crate) fn bytes_transferred(stats: &TransferStats) {
+    counter!(BYTES_TRANSFERRED_TOTAL, "direction" => "sent").increment(stats.sent);
+    counter!(BYTES_TRANSFERRED_TOTAL, "direction" => "received").increment(stats.received);
+}
+
+/// Records the outcome of a client handshake, i.e. its duration if it succeeded, or the kind of error otherwise.
+/// Handshakes are recorded by the SOCKS5 and SOCKS6 clients, including those the SOCKS6 handler uses to chain.
+pub(crate) fn handshake_finished<T>(
+    outcome: &Result<T, SocksError>,
+    duration: Duration,
+) {
+    match outcome {
+        Ok(_) => histogram!(HANDSHAKE_DURATION_SECONDS).record(duration.as_secs_f64()),
+        Err(error) => counter!(HANDSHAKE_ERRORS_TOTAL, "reason" => error.variant_name()).increment(1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+    use super::*;
+
+    // Tests that measurements are recorded with the installed recorder, under their names and labels.
+    #[test]
+    fn test_record() {
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+
+        metrics::with_local_recorder(&recorder, || {
+            let connection = connection_opened();
+            bytes_transferred(&TransferStats { sent: 5, received: 7, ..Default::default() });
+            handshake_finished::<()>(&Err(SocksError::AuthFailed), Duration::from_millis(1));
+            handshake_finished(&Ok(()), Duration::from_millis(30));
+            drop(connection);
+        });
+
+        let snapshot = snapshotter.snapshot().into_vec();
+        let value = |name: &str, label: Option<(&str, &str)>| {
+            snapshot.iter().find_map(|(key, _, _, value)| {
+                let key = key.key();
+                let labeled = match label {
+                    Some((label, value)) => key.labels().any(|l| l.key() == label && l.value() == value),
+                    None => true,
+                };
+                (key.name() == name && labeled).then_some(value)
+            })
+        };
+
+        assert_eq!(value(CONNECTIONS_TOTAL, None), Some(&DebugValue::Counter(1)));
+        assert_eq!(value(CONNECTIONS_ACTIVE, None), Some(&DebugValue::Gauge(0.0.into())));
+        assert_eq!(value(BYTES_TRANSFERRED_TOTAL, Some(("direction", "sent"))), Some(&DebugValue::Counter(5)));
+        assert_eq!(value(BYTES_TRANSFERRED_TOTAL, Some(("direction", "received"))), Some(&DebugValue::Counter(7)));
+        assert_eq!(value(HANDSHAKE_ERRORS_TOTAL, Some(("reason", "auth_failed"))), Some(&DebugValue::Counter(1)));
+        assert_eq!(value(HANDSHAKE_DURATION_SECONDS, None), Some(&DebugValue::Histogram(vec![0.03.into()])));
+    }
+}
@@ -0,0 +1,430 @@
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use anyhow::Result;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+use crate::constants::*;
+use crate::socks5::{self, Socks5Reply};
+use crate::socks6::{self, Socks6Reply};
+use crate::socks6::options::SocksOption;
+use crate::{Address, HandshakeStage};
+
+/// A running mock server, which serves a single connection on an ephemeral port.
+pub struct MockServer {
+    local_addr: SocketAddr,
+    received: Arc<Mutex<Vec<u8>>>,
+    conversation: JoinHandle<Result<()>>,
+}
+
+impl MockServer {
+    /// Listens on an ephemeral port, and runs the conversation on the first accepted connection.
+    async fn spawn<F, Fut>(converse: F) -> io::Result<Self>
+    where
+        F: FnOnce(Recorder) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let local_addr = listener.local_addr()?;
+        let received = Arc::new(Mutex::new(vec![]));
+
+        let recorded = Arc::clone(&received);
+        let conversation = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await?;
+            converse(Recorder { stream, received: recorded }).await
+        });
+
+        Ok(MockServer {
+            local_addr,
+            received,
+            conversation,
+        })
+    }
+
+    /// Returns the address the server is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Returns the bytes received from the client so far.
+    pub fn received(&self) -> Vec<u8> {
+        self.received.lock().unwrap().clone()
+    }
+
+    /// Waits until the client closed the connection.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Result` containing all bytes received from the client, or an error if the client deviated from
+    /// the protocol.
+    pub async fn finish(self) -> Result<Vec<u8>> {
+        self.conversation.await??;
+
+        let received = self.received.lock().unwrap().clone();
+        Ok(received)
+    }
+}
+
+/// A connection that records every byte read from it.
+struct Recorder {
+    stream: TcpStream,
+    received: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Recorder {
+    /// Reads, and records, until the client closes the connection.
+    async fn drain(&mut self) -> Result<()> {
+        let mut buffer = [0; 1024];
+        while self.read(&mut buffer).await? > 0 {}
+
+        Ok(())
+    }
+}
+
+impl AsyncRead for Recorder {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.stream).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            self.received.lock().unwrap().extend_from_slice(&buf.filled()[filled..]);
+        }
+
+        poll
+    }
+}
+
+/// A reader that yields its bytes one at a time, and isn't ready in between, as if every byte arrived in its own
+/// TCP segment. Parsers that rely on a single read returning a whole field fail on it.
+pub struct Trickle {
+    bytes: Vec<u8>,
+    position: usize,
+    ready: bool,
+}
+
+impl Trickle {
+    /// Creates a new `Trickle` that yields the given bytes.
+    pub fn new(bytes: Vec<u8>) -> Self {
+        Trickle {
+            bytes,
+            position: 0,
+            ready: false,
+        }
+    }
+}
+
+impl AsyncRead for Trickle {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        // Every other poll isn't ready, so every byte takes a separate wakeup.
+        self.ready = !self.ready;
+        if !self.ready {
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+
+        if let Some(byte) = self.bytes.get(self.position).copied() {
+            buf.put_slice(&[byte]);
+            self.position += 1;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// A scripted SOCKS5 server, that selects the configured method, authentication status, and reply.
+#[derive(Clone, Debug)]
+pub struct MockSocks5Server {
+    method: u8,
+    auth_status: u8,
+    reply: Socks5Reply,
+    binding: Address,
+    close_at: Option<HandshakeStage>,
+}
+
+impl Default for MockSocks5Server {
+    fn default() -> Self {
+        MockSocks5Server {
+            method: SOCKS_AUTH_NOT_REQUIRED,
+            auth_status: SOCKS_AUTH_SUCCESS,
+            reply: Socks5Reply::Success,
+            binding: Address::new("0.0.0.0", 0),
+            close_at: None,
+        }
+    }
+}
+
+impl MockSocks5Server {
+    /// Sets the authentication method that is selected, regardless of what the client offers.
+    ///
+    /// # Parameters
+    ///
+    /// * `method`: The method, defaults to no authentication required.
+    pub fn set_method(
+        &mut self,
+        method: u8,
+    ) {
+        self.method = method;
+    }
+
+    /// Sets the status of the username/password sub-negotiation, if that method is selected.
+    ///
+    /// # Parameters
+    ///
+    /// * `auth_status`: The status, defaults to success.
+    pub fn set_auth_status(
+        &mut self,
+        auth_status: u8,
+    ) {
+        self.auth_status = auth_status;
+    }
+
+    /// Sets the reply to the client's request.
+    ///
+    /// # Parameters
+    ///
+    /// * `reply`: The reply, defaults to success.
+    /// * `binding`: The bound address in the reply.
+    pub fn set_reply(
+        &mut self,
+        reply: Socks5Reply,
+        binding: Address,
+    ) {
+        self.reply = reply;
+        self.binding = binding;
+    }
+
+    /// Sets the stage at which the connection is closed, instead of answering the client, e.g. as a proxy that drops
+    /// connections because of an ACL would.
+    ///
+    /// # Parameters
+    ///
+    /// * `close_at`: The stage, defaults to `None` (the handshake is completed).
+    pub fn set_close_at(
+        &mut self,
+        close_at: Option<HandshakeStage>,
+    ) {
+        self.close_at = close_at;
+    }
+
+    /// Starts serving, the conversation ends early if authentication doesn't succeed.
+    pub async fn start(self) -> io::Result<MockServer> {
+        MockServer::spawn(move |mut stream| async move {
+            self.converse(&mut stream).await?;
+            if self.close_at.is_some() {
+                return Ok(());
+            }
+
+            stream.drain().await
+        })
+        .await
+    }
+
+    async fn converse(
+        &self,
+        stream: &mut Recorder,
+    ) -> Result<()> {
+        socks5::read_auth_methods(stream).await?;
+        if self.close_at == Some(HandshakeStage::Negotiation) {
+            return Ok(());
+        }
+
+        socks5::write_auth_method_selection(&mut stream.stream, self.method).await?;
+        match self.method {
+            SOCKS_AUTH_NO_ACCEPTABLE_METHODS => return Ok(()),
+            SOCKS_AUTH_USERNAME_PASSWORD => {
+                let mut header = [0; 2];
+                stream.read_exact(&mut header).await?;
+                let mut username = vec![0; header[1] as usize];
+                stream.read_exact(&mut username).await?;
+                let mut password = vec![0; stream.read_u8().await? as usize];
+                stream.read_exact(&mut password).await?;
+                if self.close_at == Some(HandshakeStage::Authentication) {
+                    return Ok(());
+                }
+
+                stream.stream.write_all(&[SOCKS_AUTH_VER, self.auth_status]).await?;
+                if self.auth_status != SOCKS_AUTH_SUCCESS {
+                    return Ok(());
+                }
+            }
+            _ => {}
+        }
+
+        socks5::read_request(stream).await?;
+        if self.close_at == Some(HandshakeStage::Reply) {
+            return Ok(());
+        }
+
+        socks5::write_reply(&mut stream.stream, self.reply.clone(), &self.binding).await?;
+
+        Ok(())
+    }
+}
+
+/// A scripted SOCKS6 server, that allows unauthenticated access and answers with the configured reply.
+#[derive(Clone, Debug)]
+pub struct MockSocks6Server {
+    reply: Socks6Reply,
+    binding: Address,
+    options: Vec<SocksOption>,
+}
+
+impl Default for MockSocks6Server {
+    fn default() -> Self {
+        MockSocks6Server {
+            reply: Socks6Reply::Success,
+            binding: Address::new("0.0.0.0", 0),
+            options: vec![],
+        }
+    }
+}
+
+impl MockSocks6Server {
+    /// Sets the reply to the client's request.
+    ///
+    /// # Parameters
+    ///
+    /// * `reply`: The reply, defaults to success.
+    /// * `binding`: The bound address in the reply.
+    /// * `options`: The options carried by the reply.
+    pub fn set_reply(
+        &mut self,
+        reply: Socks6Reply,
+        binding: Address,
+        options: Vec<SocksOption>,
+    ) {
+        self.reply = reply;
+        self.binding = binding;
+        self.options = options;
+    }
+
+    /// Starts serving.
+    pub async fn start(self) -> io::Result<MockServer> {
+        MockServer::spawn(move |mut stream| async move {
+            self.converse(&mut stream).await?;
+            stream.drain().await
+        })
+        .await
+    }
+
+    async fn converse(
+        &self,
+        stream: &mut Recorder,
+    ) -> Result<()> {
+        let request = socks6::read_request(stream).await?;
+        socks6::write_no_authentication(&mut stream.stream).await?;
+
+        let mut initial_data = vec![0; request.initial_data_length as usize];
+        stream.read_exact(&mut initial_data).await?;
+
+        let mut reply = vec![SOCKS_VER_6, self.reply.clone() as u8, SOCKS_PADDING];
+        reply.extend(self.binding.to_socks_bytes()?);
+
+        let options: Vec<_> = self.options.iter().flat_map(|o| o.as_socks_bytes()).collect();
+        reply.extend((options.len() as u16).to_be_bytes().iter());
+        reply.extend(options);
+        stream.stream.write_all(&reply).await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Command, Credentials, Socks5Client, Socks6Client, SocksError};
+
+    // Tests that the client sends the exact negotiation bytes, and parses the bound address.
+    #[tokio::test]
+    async fn test_socks5_negotiation() -> Result<()> {
+        let mut server = MockSocks5Server::default();
+        server.set_reply(Socks5Reply::Success, Address::new("192.0.2.1", 1080));
+        let server = server.start().await?;
+
+        let client = Socks5Client::new(server.local_addr().to_string(), None).await?;
+        let (mut stream, binding) = client.connect("10.0.0.1:80").await?;
+        assert_eq!(binding, Address::new("192.0.2.1", 1080));
+
+        stream.write_all(b"data").await?;
+        drop(stream);
+
+        let received = server.finish().await?;
+        assert_eq!(received, [&[5, 1, 0, 5, 1, 0, 1, 10, 0, 0, 1, 0, 80][..], b"data"].concat());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_socks5_reply_failure() -> Result<()> {
+        let mut server = MockSocks5Server::default();
+        server.set_reply(Socks5Reply::HostUnreachable, Address::new("0.0.0.0", 0));
+        let server = server.start().await?;
+
+        let client = Socks5Client::new(server.local_addr().to_string(), None).await?;
+        let error = client.connect("example.com:80").await.unwrap_err();
+        assert!(matches!(error, SocksError::ReplyFailure(code) if code == Socks5Reply::HostUnreachable as u8));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_socks5_method_rejected() -> Result<()> {
+        let mut server = MockSocks5Server::default();
+        server.set_method(SOCKS_AUTH_NO_ACCEPTABLE_METHODS);
+        let server = server.start().await?;
+
+        let client = Socks5Client::new(server.local_addr().to_string(), None).await?;
+        let error = client.connect("example.com:80").await.unwrap_err();
+        assert!(matches!(error, SocksError::AuthMethodRejected { .. }));
+
+        Ok(())
+    }
+
+    // Tests that a connection closed by the proxy is reported along with the stage of the handshake it was in.
+    #[tokio::test]
+    async fn test_socks5_closed_during_handshake() -> Result<()> {
+        for stage in [HandshakeStage::Negotiation, HandshakeStage::Authentication, HandshakeStage::Reply] {
+            let mut server = MockSocks5Server::default();
+            server.set_method(SOCKS_AUTH_USERNAME_PASSWORD);
+            server.set_close_at(Some(stage));
+            let server = server.start().await?;
+
+            let credentials = Credentials::new("user", "pass")?;
+            let client = Socks5Client::new(server.local_addr().to_string(), Some(credentials)).await?;
+            let error = client.connect("example.com:80").await.unwrap_err();
+            assert!(matches!(error, SocksError::ConnectionClosedDuringHandshake { stage: s } if s == stage));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_socks6_reply() -> Result<()> {
+        let mut server = MockSocks6Server::default();
+        server.set_reply(Socks6Reply::Success, Address::new("192.0.2.1", 1080), vec![]);
+        let server = server.start().await?;
+
+        let client = Socks6Client::new(server.local_addr().to_string(), None).await?;
+        let (stream, binding) = client.connect("10.0.0.1:80", None, None).await?;
+        assert_eq!(binding, Address::new("192.0.2.1", 1080));
+        drop(stream);
+
+        let received = server.finish().await?;
+        assert_eq!(received[..2], [SOCKS_VER_6, Command::Connect as u8]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,102 @@
+use std::net::{IpAddr, SocketAddr};
+
+/// The signature every PROXY protocol v2 header starts with.
+const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Version 2, and the PROXY command (the connection is relayed on behalf of another host).
+const VERSION_COMMAND: u8 = 0x21;
+/// TCP over IPv4.
+const TCP_OVER_IPV4: u8 = 0x11;
+/// TCP over IPv6.
+const TCP_OVER_IPV6: u8 = 0x21;
+
+/// A PROXY protocol v2 header, which tells the next hop the addresses of the original connection.
+///
+/// See https://www.haproxy.org/download/2.8/doc/proxy-protocol.txt
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ProxyHeader {
+    /// The address of the original client.
+    pub source: SocketAddr,
+    /// The address the original client connected to.
+    pub destination: SocketAddr,
+}
+
+impl ProxyHeader {
+    /// Creates a new `ProxyHeader`.
+    ///
+    /// # Parameters
+    ///
+    /// * `source`: The address of the original client, i.e. the peer address of its connection.
+    /// * `destination`: The address the original client connected to, i.e. the local address of its connection.
+    pub fn new(
+        source: SocketAddr,
+        destination: SocketAddr,
+    ) -> Self {
+        ProxyHeader { source, destination }
+    }
+
+    /// Converts the header into its binary (v2) form.
+    ///
+    /// Both addresses have to be of the same family, so if only one is IPv6 the other is sent as an IPv4-mapped
+    /// IPv6 address.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut header = SIGNATURE.to_vec();
+        header.push(VERSION_COMMAND);
+
+        let addresses = match (self.source.ip(), self.destination.ip()) {
+            (IpAddr::V4(source), IpAddr::V4(destination)) => {
+                header.push(TCP_OVER_IPV4);
+                [source.octets().to_vec(), destination.octets().to_vec()].concat()
+            }
+            (source, destination) => {
+                header.push(TCP_OVER_IPV6);
+                [to_ipv6_octets(source).to_vec(), to_ipv6_octets(destination).to_vec()].concat()
+            }
+        };
+
+        let length = addresses.len() + 4;
+        header.extend((length as u16).to_be_bytes().iter());
+        header.extend(addresses);
+        header.extend(self.source.port().to_be_bytes().iter());
+        header.extend(self.destination.port().to_be_bytes().iter());
+
+        header
+    }
+}
+
+/// Returns the octets of an IP address as IPv6, mapping IPv4 addresses.
+fn to_ipv6_octets(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_proxy_header_ipv4() {
+        let header = ProxyHeader::new("192.0.2.1:4000".parse().unwrap(), "198.51.100.1:1080".parse().unwrap());
+
+        let expected = [
+            &SIGNATURE[..],
+            &[0x21, 0x11, 0, 12],
+            &[192, 0, 2, 1, 198, 51, 100, 1],
+            &[0x0f, 0xa0, 0x04, 0x38],
+        ]
+        .concat();
+        assert_eq!(header.to_bytes(), expected);
+    }
+
+    // Tests that mixed families are sent as IPv6, with the IPv4 address mapped.
+    #[test]
+    fn test_proxy_header_mixed_families() {
+        let header = ProxyHeader::new("192.0.2.1:4000".parse().unwrap(), "[2001:db8::1]:1080".parse().unwrap());
+
+        let bytes = header.to_bytes();
+        assert_eq!(bytes[13..16], [0x21, 0, 36]);
+        assert_eq!(bytes[16..32], "::ffff:192.0.2.1".parse::<std::net::Ipv6Addr>().unwrap().octets());
+        assert_eq!(bytes.len(), 16 + 36);
+    }
+}
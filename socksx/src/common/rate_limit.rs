@@ -0,0 +1,180 @@
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Interval at which buckets of sources that have been idle long enough are dropped.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Limits the rate of new connections per source IP, using a token bucket for each source.
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    allowlist: HashSet<IpAddr>,
+    state: Mutex<State>,
+}
+
+struct State {
+    buckets: HashMap<IpAddr, Bucket>,
+    last_sweep: Instant,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    // Returns the tokens in the bucket at the given time, after refilling.
+    fn tokens_at(
+        &self,
+        now: Instant,
+        rate: f64,
+        burst: f64,
+    ) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * rate).min(burst)
+    }
+}
+
+impl RateLimiter {
+    /// Creates a new `RateLimiter`.
+    ///
+    /// # Parameters
+    ///
+    /// * `rate`: The number of new connections a source may open per second, on average.
+    /// * `burst`: The number of new connections a source may open at once.
+    pub fn new(
+        rate: f64,
+        burst: u32,
+    ) -> Self {
+        RateLimiter {
+            rate,
+            burst: burst.max(1) as f64,
+            allowlist: HashSet::new(),
+            state: Mutex::new(State {
+                buckets: HashMap::new(),
+                last_sweep: Instant::now(),
+            }),
+        }
+    }
+
+    /// Sets the sources that bypass the limiter.
+    ///
+    /// # Parameters
+    ///
+    /// * `allowlist`: The IPs that are never limited.
+    pub fn set_allowlist<I: IntoIterator<Item = IpAddr>>(
+        &mut self,
+        allowlist: I,
+    ) {
+        self.allowlist = allowlist.into_iter().collect();
+    }
+
+    /// Takes a token from the bucket of the given source.
+    ///
+    /// # Parameters
+    ///
+    /// * `ip`: The IP of the source that opens a new connection.
+    ///
+    /// # Returns
+    ///
+    /// Returns `true` if the connection is within the budget of the source, `false` if it should be refused.
+    pub fn check(
+        &self,
+        ip: IpAddr,
+    ) -> bool {
+        self.check_at(ip, Instant::now())
+    }
+
+    /// Returns the number of sources that are currently tracked.
+    pub fn tracked_sources(&self) -> usize {
+        self.state.lock().unwrap().buckets.len()
+    }
+
+    fn check_at(
+        &self,
+        ip: IpAddr,
+        now: Instant,
+    ) -> bool {
+        if self.allowlist.contains(&ip) {
+            return true;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if now.saturating_duration_since(state.last_sweep) >= SWEEP_INTERVAL {
+            // Full buckets carry no information, so they can be recreated on demand.
+            let (rate, burst) = (self.rate, self.burst);
+            state.buckets.retain(|_, bucket| bucket.tokens_at(now, rate, burst) < burst);
+            state.last_sweep = now;
+        }
+
+        let bucket = state.buckets.entry(ip).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+
+        bucket.tokens = bucket.tokens_at(now, self.rate, self.burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Tests that a source is limited to its burst, and regains tokens over time.
+    #[test]
+    fn test_check() {
+        let limiter = RateLimiter::new(2.0, 3);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+        let now = Instant::now();
+
+        assert!((0..3).all(|_| limiter.check_at(ip, now)));
+        assert!(!limiter.check_at(ip, now));
+        assert!(limiter.check_at(other, now));
+
+        assert!(limiter.check_at(ip, now + Duration::from_millis(500)));
+        assert!(!limiter.check_at(ip, now + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn test_check_allowlist() {
+        let mut limiter = RateLimiter::new(1.0, 1);
+        let ip: IpAddr = "::1".parse().unwrap();
+        limiter.set_allowlist(vec![ip]);
+
+        assert!((0..10).all(|_| limiter.check(ip)));
+        assert_eq!(limiter.tracked_sources(), 0);
+    }
+
+    // Tests that buckets of idle sources are dropped, but those of active sources are kept.
+    #[test]
+    fn test_sweep() {
+        let limiter = RateLimiter::new(1.0, 2);
+        let now = Instant::now();
+
+        for i in 0..100u8 {
+            limiter.check_at(IpAddr::from([10, 0, 0, i]), now);
+        }
+        assert_eq!(limiter.tracked_sources(), 100);
+
+        let later = now + SWEEP_INTERVAL;
+        let active: IpAddr = "10.0.1.1".parse().unwrap();
+        limiter.check_at(active, later - Duration::from_millis(1500));
+        limiter.check_at(active, later - Duration::from_millis(1500));
+
+        limiter.check_at(active, later);
+        assert_eq!(limiter.tracked_sources(), 1);
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::Address;
+use crate::events::next_connection_id;
+
+tokio::task_local! {
+    /// The entry of the connection that is handled by the current task, if it's registered.
+    static CURRENT: Arc<ConnectionEntry>;
+}
+
+/// The stage a registered connection is in.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConnectionState {
+    /// The SOCKS handshake is in progress, or the connection with the destination is being set up.
+    Handshake = 0,
+    /// Data is relayed between the source and the destination.
+    Relaying = 1,
+    /// The tunnel has ended, and the connection is about to be closed.
+    Closing = 2,
+}
+
+impl ConnectionState {
+    fn from_u8(state: u8) -> Self {
+        match state {
+            0 => ConnectionState::Handshake,
+            1 => ConnectionState::Relaying,
+            _ => ConnectionState::Closing,
+        }
+    }
+}
+
+/// A point-in-time view of a registered connection.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConnectionSnapshot {
+    /// The unique id of the connection.
+    pub id: u64,
+    /// The address of the client.
+    pub source: SocketAddr,
+    /// The destination requested by the client, once known.
+    pub destination: Option<Address>,
+    /// The number of bytes relayed from the source to the destination so far.
+    pub sent: u64,
+    /// The number of bytes relayed from the destination to the source so far.
+    pub received: u64,
+    /// The time the connection was accepted.
+    pub started: SystemTime,
+    /// The stage the connection is in.
+    pub state: ConnectionState,
+}
+
+/// The live state of a registered connection, which is updated while it's handled.
+/// The counters are atomics, so updating them on the data path doesn't contend with taking snapshots.
+#[derive(Debug)]
+pub(crate) struct ConnectionEntry {
+    id: u64,
+    source: SocketAddr,
+    started: SystemTime,
+    destination: Mutex<Option<Address>>,
+    state: AtomicU8,
+    pub(crate) sent: AtomicU64,
+    pub(crate) received: AtomicU64,
+}
+
+impl ConnectionEntry {
+    /// Sets the stage the connection is in.
+    pub(crate) fn set_state(
+        &self,
+        state: ConnectionState,
+    ) {
+        self.state.store(state as u8, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> ConnectionSnapshot {
+        ConnectionSnapshot {
+            id: self.id,
+            source: self.source,
+            destination: self.destination.lock().unwrap().clone(),
+            sent: self.sent.load(Ordering::Relaxed),
+            received: self.received.load(Ordering::Relaxed),
+            started: self.started,
+            state: ConnectionState::from_u8(self.state.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Keeps track of the open connections of a server, e.g. to show them on an admin endpoint.
+///
+/// The server registers every accepted connection, and handlers record its progress while handling it, until the
+/// connection is closed and removed again.
+#[derive(Debug, Default)]
+pub struct ConnectionRegistry {
+    connections: Mutex<HashMap<u64, Arc<ConnectionEntry>>>,
+}
+
+impl ConnectionRegistry {
+    /// Creates a new, empty, `ConnectionRegistry`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a connection, until the returned `Registration` is dropped.
+    ///
+    /// # Parameters
+    ///
+    /// * `source`: The address of the client.
+    ///
+    /// # Returns
+    ///
+    /// Returns the `Registration`, in whose scope the connection is to be handled.
+    pub fn register(
+        self: &Arc<Self>,
+        source: SocketAddr,
+    ) -> Registration {
+        let entry = Arc::new(ConnectionEntry {
+            id: next_connection_id(),
+            source,
+            started: SystemTime::now(),
+            destination: Mutex::new(None),
+            state: AtomicU8::new(ConnectionState::Handshake as u8),
+            sent: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+        });
+        self.connections.lock().unwrap().insert(entry.id, Arc::clone(&entry));
+
+        Registration {
+            registry: Arc::clone(self),
+            entry,
+        }
+    }
+
+    /// Returns the number of open connections.
+    pub fn len(&self) -> usize {
+        self.connections.lock().unwrap().len()
+    }
+
+    /// Returns whether there are no open connections.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Takes a snapshot of the open connections, ordered by id (i.e. the oldest first).
+    pub fn snapshot(&self) -> Vec<ConnectionSnapshot> {
+        let mut snapshot: Vec<_> = self.connections.lock().unwrap().values().map(|entry| entry.snapshot()).collect();
+        snapshot.sort_by_key(|connection| connection.id);
+
+        snapshot
+    }
+}
+
+/// A registered connection, which is removed from the registry once this is dropped.
+pub struct Registration {
+    registry: Arc<ConnectionRegistry>,
+    entry: Arc<ConnectionEntry>,
+}
+
+impl Registration {
+    /// Returns the unique id of the connection.
+    pub fn id(&self) -> u64 {
+        self.entry.id
+    }
+
+    /// Runs `future`, which handles the connection, so that its progress is recorded in the registry.
+    ///
+    /// # Parameters
+    ///
+    /// * `future`: The future that handles the connection.
+    pub async fn scope<F: Future>(
+        &self,
+        future: F,
+    ) -> F::Output {
+        CURRENT.scope(Arc::clone(&self.entry), future).await
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.registry.connections.lock().unwrap().remove(&self.entry.id);
+    }
+}
+
+/// Returns the entry of the connection that is handled by the current task, if it's registered.
+pub(crate) fn current() -> Option<Arc<ConnectionEntry>> {
+    CURRENT.try_with(Arc::clone).ok()
+}
+
+/// Records the destination of the connection handled by the current task, if it's registered.
+pub(crate) fn record_destination(destination: &Address) {
+    if let Some(entry) = current() {
+        *entry.destination.lock().unwrap() = Some(destination.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Tests that progress recorded in the scope of a registration is visible, until it's dropped.
+    #[tokio::test]
+    async fn test_registry() {
+        let registry = Arc::new(ConnectionRegistry::new());
+        let registration = registry.register("192.0.2.1:4000".parse().unwrap());
+
+        registration
+            .scope(async {
+                record_destination(&Address::new("example.com", 443));
+                let entry = current().unwrap();
+                entry.set_state(ConnectionState::Relaying);
+                entry.sent.fetch_add(42, Ordering::Relaxed);
+            })
+            .await;
+
+        let snapshot = registry.snapshot();
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot[0].id, registration.id());
+        assert_eq!(snapshot[0].destination, Some(Address::new("example.com", 443)));
+        assert_eq!(snapshot[0].state, ConnectionState::Relaying);
+        assert_eq!((snapshot[0].sent, snapshot[0].received), (42, 0));
+
+        // Outside of a scope, nothing is recorded.
+        assert!(current().is_none());
+
+        drop(registration);
+        assert!(registry.is_empty());
+    }
+}
@@ -0,0 +1,316 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::future::{BoxFuture, FutureExt, Shared};
+use tokio::net;
+use tokio::time::Instant;
+
+use crate::Address;
+
+/// How long successful resolutions are cached, by default.
+pub const DNS_CACHE_TTL: Duration = Duration::from_secs(60);
+/// How long failed resolutions are cached, by default.
+pub const DNS_CACHE_NEGATIVE_TTL: Duration = Duration::from_secs(5);
+
+/// Resolves the domain names of destinations, e.g. to cache them or to use another DNS server.
+#[async_trait]
+pub trait Resolver: Send + Sync {
+    /// Resolves a domain name.
+    ///
+    /// # Parameters
+    ///
+    /// * `host`: The domain name.
+    /// * `port`: The port of the returned socket addresses.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Result` containing a non-empty list of resolved `SocketAddr`s or an error.
+    async fn resolve(
+        &self,
+        host: &str,
+        port: u16,
+    ) -> Result<Vec<SocketAddr>>;
+}
+
+/// Resolves domain names with the resolver of the operating system.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemResolver;
+
+#[async_trait]
+impl Resolver for SystemResolver {
+    async fn resolve(
+        &self,
+        host: &str,
+        port: u16,
+    ) -> Result<Vec<SocketAddr>> {
+        let addresses: Vec<SocketAddr> = net::lookup_host((host, port)).await?.collect();
+        ensure!(!addresses.is_empty(), "Domain name didn't resolve to an IP address.");
+
+        Ok(addresses)
+    }
+}
+
+/// A lookup in progress or completed, along with the time its result expires.
+/// It's shared, so concurrent requests for the same name wait for a single lookup.
+type Lookup = Shared<BoxFuture<'static, (Result<Vec<SocketAddr>, String>, Instant)>>;
+
+struct CacheEntry {
+    lookup: Lookup,
+    last_used: u64,
+}
+
+impl CacheEntry {
+    /// Returns whether the entry can be used, i.e. its lookup is still in progress or hasn't expired yet.
+    fn is_fresh(&self) -> bool {
+        match self.lookup.peek() {
+            Some((_, expires)) => Instant::now() < *expires,
+            None => true,
+        }
+    }
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<(String, u16), CacheEntry>,
+    clock: u64,
+}
+
+/// Caches the resolutions of another `Resolver`, so that a burst of connections to the same host resolves it once.
+///
+/// Failures are cached too, but only briefly. The cache holds a bounded number of names, evicting the least
+/// recently used one when it's full. Expired entries stay until they're used again, which resolves them again.
+pub struct DnsCache {
+    resolver: Arc<dyn Resolver>,
+    capacity: usize,
+    ttl: Duration,
+    negative_ttl: Duration,
+    state: Mutex<CacheState>,
+}
+
+impl DnsCache {
+    /// Creates a new, empty, `DnsCache`.
+    ///
+    /// # Parameters
+    ///
+    /// * `resolver`: The resolver that resolves names which aren't cached, e.g. `SystemResolver`.
+    /// * `capacity`: The maximum number of names that are cached, at least one.
+    pub fn new(
+        resolver: Arc<dyn Resolver>,
+        capacity: usize,
+    ) -> Self {
+        DnsCache {
+            resolver,
+            capacity: capacity.max(1),
+            ttl: DNS_CACHE_TTL,
+            negative_ttl: DNS_CACHE_NEGATIVE_TTL,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    /// Sets how long successful resolutions are cached.
+    ///
+    /// # Parameters
+    ///
+    /// * `ttl`: The time to live, defaults to 60s.
+    pub fn set_ttl(
+        &mut self,
+        ttl: Duration,
+    ) {
+        self.ttl = ttl;
+    }
+
+    /// Sets how long failed resolutions are cached, so that a failing name isn't resolved for every connection.
+    ///
+    /// # Parameters
+    ///
+    /// * `negative_ttl`: The time to live, defaults to 5s.
+    pub fn set_negative_ttl(
+        &mut self,
+        negative_ttl: Duration,
+    ) {
+        self.negative_ttl = negative_ttl;
+    }
+
+    /// Returns the number of cached names, including expired ones.
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    /// Returns whether no names are cached.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Forgets all cached names, e.g. once DNS records are known to have changed.
+    pub fn clear(&self) {
+        self.state.lock().unwrap().entries.clear();
+    }
+
+    /// Returns the lookup of a name, starting a new one if it isn't cached or has expired.
+    fn lookup(
+        &self,
+        host: &str,
+        port: u16,
+    ) -> Lookup {
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let now = state.clock;
+
+        let key = (host.to_string(), port);
+        if let Some(entry) = state.entries.get_mut(&key).filter(|entry| entry.is_fresh()) {
+            entry.last_used = now;
+            return entry.lookup.clone();
+        }
+
+        if state.entries.len() >= self.capacity && !state.entries.contains_key(&key) {
+            let least_recently_used = state
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(key) = least_recently_used {
+                state.entries.remove(&key);
+            }
+        }
+
+        let resolver = Arc::clone(&self.resolver);
+        let (ttl, negative_ttl) = (self.ttl, self.negative_ttl);
+        let host = host.to_string();
+        let lookup = async move {
+            let result = resolver.resolve(&host, port).await.map_err(|error| error.to_string());
+            let ttl = if result.is_ok() { ttl } else { negative_ttl };
+
+            (result, Instant::now() + ttl)
+        }
+        .boxed()
+        .shared();
+
+        let entry = CacheEntry {
+            lookup: lookup.clone(),
+            last_used: now,
+        };
+        state.entries.insert(key, entry);
+
+        lookup
+    }
+}
+
+#[async_trait]
+impl Resolver for DnsCache {
+    async fn resolve(
+        &self,
+        host: &str,
+        port: u16,
+    ) -> Result<Vec<SocketAddr>> {
+        let (result, _) = self.lookup(host, port).await;
+
+        result.map_err(|error| anyhow!(error))
+    }
+}
+
+/// Resolves the address of a destination, with the given resolver if it's a domain name.
+/// Without a resolver, it's resolved as by `resolve_addrs`. Fails rather than resolving to no address at all.
+pub(crate) async fn resolve_address(
+    address: &Address,
+    resolver: Option<&dyn Resolver>,
+) -> Result<Vec<SocketAddr>> {
+    let addresses = match (address, resolver) {
+        (Address::Ip(addr), _) => vec![*addr],
+        (Address::Domainname { host, port }, Some(resolver)) => resolver.resolve(host, *port).await?,
+        (address, None) => crate::resolve_addrs(address.to_string()).await?,
+    };
+    ensure!(!addresses.is_empty(), "Domain name didn't resolve to an IP address.");
+
+    Ok(addresses)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    /// Resolves every name to the same address, or fails for `invalid.`, counting the lookups.
+    #[derive(Default)]
+    struct CountingResolver {
+        lookups: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl Resolver for CountingResolver {
+        async fn resolve(
+            &self,
+            host: &str,
+            port: u16,
+        ) -> Result<Vec<SocketAddr>> {
+            self.lookups.fetch_add(1, Ordering::Relaxed);
+            ensure!(host != "invalid.", "No such name: {}", host);
+            if host == "empty.example" {
+                return Ok(vec![]);
+            }
+
+            Ok(vec![SocketAddr::new([192, 0, 2, 1].into(), port)])
+        }
+    }
+
+    // Tests that a resolution is reused until it expires, after which it's resolved again.
+    #[tokio::test]
+    async fn test_dns_cache_ttl() -> Result<()> {
+        let resolver = Arc::new(CountingResolver::default());
+        let mut cache = DnsCache::new(resolver.clone(), 8);
+
+        let (first, second) = tokio::join!(cache.resolve("example.com", 80), cache.resolve("example.com", 80));
+        assert_eq!(first?, second?);
+        cache.resolve("example.com", 80).await?;
+        assert_eq!(resolver.lookups.load(Ordering::Relaxed), 1);
+
+        assert!(cache.resolve("invalid.", 80).await.is_err());
+        assert!(cache.resolve("invalid.", 80).await.is_err());
+        assert_eq!(resolver.lookups.load(Ordering::Relaxed), 2);
+
+        cache.set_ttl(Duration::ZERO);
+        cache.clear();
+        cache.resolve("example.com", 80).await?;
+        cache.resolve("example.com", 80).await?;
+        assert_eq!(resolver.lookups.load(Ordering::Relaxed), 4);
+
+        Ok(())
+    }
+
+    // Tests that the least recently used name is evicted once the cache is full.
+    #[tokio::test]
+    async fn test_dns_cache_eviction() -> Result<()> {
+        let resolver = Arc::new(CountingResolver::default());
+        let cache = DnsCache::new(resolver.clone(), 2);
+
+        cache.resolve("a.example", 80).await?;
+        cache.resolve("b.example", 80).await?;
+        cache.resolve("a.example", 80).await?;
+        cache.resolve("c.example", 80).await?;
+        assert_eq!(cache.len(), 2);
+
+        // `b.example` was evicted, while `a.example` is still cached.
+        cache.resolve("a.example", 80).await?;
+        assert_eq!(resolver.lookups.load(Ordering::Relaxed), 3);
+        cache.resolve("b.example", 80).await?;
+        assert_eq!(resolver.lookups.load(Ordering::Relaxed), 4);
+
+        Ok(())
+    }
+
+    // Tests that a resolver returning no addresses fails the resolution, rather than yielding nothing to connect to.
+    #[tokio::test]
+    async fn test_resolve_address_empty() -> Result<()> {
+        let resolver = CountingResolver::default();
+
+        let addresses = resolve_address(&Address::new("a.example", 80), Some(&resolver)).await?;
+        assert_eq!(addresses.len(), 1);
+        assert!(resolve_address(&Address::new("empty.example", 80), Some(&resolver)).await.is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,191 @@
+use std::future::Future;
+use std::io;
+use std::time::Duration;
+
+use anyhow::Result;
+use rand::Rng;
+
+/// Retries transient connection failures, with exponential backoff and jitter.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// The maximum number of attempts, including the first one.
+    pub max_attempts: u32,
+    /// The delay before the first retry, doubled for every subsequent retry.
+    pub base_delay: Duration,
+    /// The upper bound of the delay between two attempts.
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(2),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns the delay before the given retry, which is at least half of the (capped) exponential delay.
+    ///
+    /// # Parameters
+    ///
+    /// * `retry`: The number of the retry, starting at 1.
+    pub fn delay(
+        &self,
+        retry: u32,
+    ) -> Duration {
+        let exponent = retry.saturating_sub(1).min(31);
+        let delay = self.base_delay.saturating_mul(1 << exponent).min(self.max_delay);
+
+        let half = delay / 2;
+        half + rand::thread_rng().gen_range(Duration::ZERO..=half)
+    }
+
+    /// Runs `operation` until it succeeds, fails with an error that isn't transient, or attempts are exhausted.
+    ///
+    /// # Parameters
+    ///
+    /// * `operation`: Creates the future for a single attempt.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Result` with the outcome of the last attempt.
+    pub async fn retry<T, F, Fut>(
+        &self,
+        operation: F,
+    ) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        self.retry_if(operation, is_transient).await
+    }
+
+    /// Runs `operation` until it succeeds, fails with an error that `retryable` rejects, or attempts are exhausted.
+    ///
+    /// # Parameters
+    ///
+    /// * `operation`: Creates the future for a single attempt.
+    /// * `retryable`: Decides whether an error is worth another attempt, `retry` uses `is_transient`.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Result` with the outcome of the last attempt.
+    pub async fn retry_if<T, F, Fut, P>(
+        &self,
+        mut operation: F,
+        retryable: P,
+    ) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+        P: Fn(&anyhow::Error) -> bool,
+    {
+        let mut attempt = 1;
+        loop {
+            match operation().await {
+                Ok(value) => {
+                    if attempt > 1 {
+                        info!("Succeeded after {} attempts", attempt);
+                    }
+
+                    return Ok(value);
+                }
+                Err(error) if attempt < self.max_attempts && retryable(&error) => {
+                    let delay = self.delay(attempt);
+                    info!(
+                        "Attempt {}/{} failed ({}), retrying in {}ms",
+                        attempt,
+                        self.max_attempts,
+                        error,
+                        delay.as_millis()
+                    );
+
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(error) => return Err(error),
+            }
+        }
+    }
+}
+
+/// Determines whether an error is a connection-level failure that may go away by itself.
+pub fn is_transient(error: &anyhow::Error) -> bool {
+    use io::ErrorKind::*;
+
+    match error.downcast_ref::<io::Error>() {
+        Some(error) => matches!(
+            error.kind(),
+            ConnectionRefused
+                | ConnectionReset
+                | ConnectionAborted
+                | NotConnected
+                | AddrNotAvailable
+                | TimedOut
+                | HostUnreachable
+                | NetworkUnreachable
+        ),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    // Tests that the delay grows exponentially, but stays within bounds.
+    #[test]
+    fn test_delay() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(500),
+        };
+
+        for _ in 0..100 {
+            let first = policy.delay(1);
+            assert!(first >= Duration::from_millis(50) && first <= Duration::from_millis(100));
+
+            let third = policy.delay(3);
+            assert!(third >= Duration::from_millis(200) && third <= Duration::from_millis(400));
+
+            let capped = policy.delay(64);
+            assert!(capped >= Duration::from_millis(250) && capped <= Duration::from_millis(500));
+        }
+    }
+
+    // Tests that only transient errors are retried.
+    #[tokio::test]
+    async fn test_retry() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(1),
+        };
+
+        let attempts = AtomicU32::new(0);
+        let result: Result<()> = policy
+            .retry(|| async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(io::Error::from(io::ErrorKind::ConnectionRefused).into())
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+        let attempts = AtomicU32::new(0);
+        let result: Result<()> = policy
+            .retry(|| async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(anyhow!("Authentication with the provided credentials failed."))
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+}
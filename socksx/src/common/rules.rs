@@ -0,0 +1,191 @@
+use std::net::IpAddr;
+
+use anyhow::Result;
+
+use crate::{Address, ProxyAddress};
+
+/// A predicate on the destination of a request.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Matcher {
+    /// Matches IP destinations within the network, given as its address and prefix length.
+    Cidr(IpAddr, u8),
+    /// Matches domain name destinations that are the domain, or one of its subdomains.
+    DomainSuffix(String),
+    /// Matches destinations on the port.
+    Port(u16),
+    /// Matches every destination.
+    Any,
+}
+
+impl Matcher {
+    /// Parses a network in CIDR notation, e.g. `10.0.0.0/8` or `fd00::/8`, a bare IP matches only itself.
+    pub fn cidr(network: &str) -> Result<Self> {
+        let (ip, prefix) = match network.split_once('/') {
+            Some((ip, prefix)) => (ip.parse::<IpAddr>()?, Some(prefix.parse::<u8>()?)),
+            None => (network.parse::<IpAddr>()?, None),
+        };
+
+        let max_prefix = if ip.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max_prefix);
+        ensure!(prefix <= max_prefix, "Prefix length of {} exceeds {}.", network, max_prefix);
+
+        Ok(Matcher::Cidr(ip, prefix))
+    }
+
+    /// Matches domain names that are `suffix`, or end in `.suffix`.
+    pub fn domain_suffix<S: Into<String>>(suffix: S) -> Self {
+        let suffix: String = suffix.into();
+
+        Matcher::DomainSuffix(suffix.trim_start_matches('.').to_ascii_lowercase())
+    }
+
+    /// Checks whether the destination satisfies the predicate.
+    pub fn matches(
+        &self,
+        destination: &Address,
+    ) -> bool {
+        match (self, destination) {
+            (Matcher::Cidr(network, prefix), Address::Ip(addr)) => in_network(addr.ip(), *network, *prefix),
+            (Matcher::DomainSuffix(suffix), Address::Domainname { host, .. }) => {
+                let host = host.trim_end_matches('.').to_ascii_lowercase();
+                host == *suffix || host.ends_with(&format!(".{}", suffix))
+            }
+            (Matcher::Port(port), destination) => destination.port() == *port,
+            (Matcher::Any, _) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Checks whether the IP is within the network, IPv4 never matches an IPv6 network or vice versa.
+fn in_network(
+    ip: IpAddr,
+    network: IpAddr,
+    prefix: u8,
+) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+/// Where a request is sent to.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Route {
+    /// Connects to the destination without an upstream proxy (besides any chain requested by the client).
+    Direct,
+    /// Connects to the destination through the chain of upstream proxies.
+    Chain(Vec<ProxyAddress>),
+}
+
+impl Route {
+    /// Returns the upstream proxies of the route, which is empty for a direct route.
+    pub fn links(&self) -> &[ProxyAddress] {
+        match self {
+            Route::Direct => &[],
+            Route::Chain(links) => links,
+        }
+    }
+}
+
+/// Selects the route of a request based on its destination, the first matching rule wins.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RuleSet {
+    rules: Vec<(Matcher, Route)>,
+    default: Route,
+}
+
+impl RuleSet {
+    /// Creates a new `RuleSet` without rules.
+    ///
+    /// # Parameters
+    ///
+    /// * `default`: The route of destinations that no rule matches.
+    pub fn new(default: Route) -> Self {
+        RuleSet { rules: vec![], default }
+    }
+
+    /// Adds a rule, which is consulted after the rules that were added before it.
+    ///
+    /// # Parameters
+    ///
+    /// * `matcher`: The predicate the destination has to satisfy.
+    /// * `route`: The route of destinations that satisfy the predicate.
+    pub fn add_rule(
+        &mut self,
+        matcher: Matcher,
+        route: Route,
+    ) {
+        self.rules.push((matcher, route));
+    }
+
+    /// Returns the route of the first rule that matches the destination, or the default route.
+    pub fn route(
+        &self,
+        destination: &Address,
+    ) -> &Route {
+        self.rules
+            .iter()
+            .find(|(matcher, _)| matcher.matches(destination))
+            .map(|(_, route)| route)
+            .unwrap_or(&self.default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cidr() -> Result<()> {
+        let matcher = Matcher::cidr("10.0.0.0/8")?;
+        assert!(matcher.matches(&Address::new("10.1.2.3", 80)));
+        assert!(!matcher.matches(&Address::new("11.0.0.1", 80)));
+        assert!(!matcher.matches(&Address::new("::1", 80)));
+
+        assert!(Matcher::cidr("0.0.0.0/0")?.matches(&Address::new("192.0.2.1", 80)));
+        assert!(Matcher::cidr("fd00::/8")?.matches(&Address::new("fd12::1", 80)));
+        assert!(Matcher::cidr("127.0.0.1")?.matches(&Address::new("127.0.0.1", 80)));
+        assert!(!Matcher::cidr("127.0.0.1")?.matches(&Address::new("127.0.0.2", 80)));
+
+        assert!(Matcher::cidr("10.0.0.0/33").is_err());
+        assert!(Matcher::cidr("example.com/8").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_domain_suffix() {
+        let matcher = Matcher::domain_suffix(".Example.com");
+        assert!(matcher.matches(&Address::new("example.com", 80)));
+        assert!(matcher.matches(&Address::new("www.EXAMPLE.com", 80)));
+        assert!(!matcher.matches(&Address::new("badexample.com", 80)));
+        assert!(!matcher.matches(&Address::new("93.184.216.34", 80)));
+    }
+
+    // Tests that the first matching rule wins, and that unmatched destinations take the default route.
+    #[test]
+    fn test_route() -> Result<()> {
+        let upstream_a = ProxyAddress::new(6, String::from("a.proxy"), 1080, None);
+        let upstream_b = ProxyAddress::new(5, String::from("b.proxy"), 1080, None);
+
+        let mut rules = RuleSet::new(Route::Chain(vec![upstream_b.clone()]));
+        rules.add_rule(Matcher::cidr("10.0.0.0/8")?, Route::Direct);
+        rules.add_rule(Matcher::domain_suffix("example.com"), Route::Chain(vec![upstream_a.clone()]));
+        rules.add_rule(Matcher::Port(443), Route::Direct);
+
+        assert_eq!(rules.route(&Address::new("10.0.0.1", 443)), &Route::Direct);
+        assert_eq!(rules.route(&Address::new("www.example.com", 443)).links(), &[upstream_a]);
+        assert_eq!(rules.route(&Address::new("example.org", 443)), &Route::Direct);
+        assert_eq!(rules.route(&Address::new("example.org", 80)).links(), &[upstream_b]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,600 @@
+use std::future::{poll_fn, Future};
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::Duration;
+
+use anyhow::Result;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use crate::{Address, ConnectionRegistry, NativeSocksHandler, RateLimiter, SocksHandler, TransferStats};
+use crate::events::{connection_span, Instrument, record_proxy};
+
+/// Default time in-flight connections are given to finish once the server shuts down.
+pub const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+/// Backlog of the listeners created by `Server::bind_dual_stack`, the same as Tokio's default.
+const LISTEN_BACKLOG: i32 = 1024;
+
+/// Time the server waits before accepting again after a transient accept error, e.g. running out of file descriptors.
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+/// A handler the `Server` dispatches connections to.
+///
+/// It's implemented for every `NativeSocksHandler`, whose calls aren't boxed, and for `dyn SocksHandler`, e.g. for a
+/// handler that is picked at runtime.
+pub trait ServerHandler: Send + Sync + 'static {
+    /// Serves a connection, see `SocksHandler::accept_request`.
+    fn serve(
+        &self,
+        source: &mut TcpStream,
+    ) -> impl Future<Output = Result<(Address, TransferStats)>> + Send;
+
+    /// Refuses a connection, see `SocksHandler::refuse_request`.
+    fn refuse(
+        &self,
+        source: &mut TcpStream,
+    ) -> impl Future<Output = Result<()>> + Send;
+}
+
+impl<H: NativeSocksHandler + Send + Sync + 'static> ServerHandler for H {
+    fn serve(
+        &self,
+        source: &mut TcpStream,
+    ) -> impl Future<Output = Result<(Address, TransferStats)>> + Send {
+        NativeSocksHandler::accept_request(self, source)
+    }
+
+    fn refuse(
+        &self,
+        source: &mut TcpStream,
+    ) -> impl Future<Output = Result<()>> + Send {
+        NativeSocksHandler::refuse_request(self, source)
+    }
+}
+
+impl ServerHandler for dyn SocksHandler + Send + Sync {
+    async fn serve(
+        &self,
+        source: &mut TcpStream,
+    ) -> Result<(Address, TransferStats)> {
+        SocksHandler::accept_request(self, source).await
+    }
+
+    async fn refuse(
+        &self,
+        source: &mut TcpStream,
+    ) -> Result<()> {
+        SocksHandler::refuse_request(self, source).await
+    }
+}
+
+/// Accepts incoming connections and dispatches them to a SOCKS handler, e.g. a `Socks6Handler`, or a
+/// `dyn SocksHandler` (the default).
+pub struct Server<H: ?Sized = dyn SocksHandler + Send + Sync> {
+    listeners: Vec<TcpListener>,
+    handler: Arc<H>,
+    semaphore: Option<Arc<Semaphore>>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    registry: Option<Arc<ConnectionRegistry>>,
+    grace_period: Duration,
+}
+
+impl<H: ServerHandler + ?Sized> Server<H> {
+    /// Creates a new `Server` that accepts connections on the given listener.
+    ///
+    /// # Parameters
+    ///
+    /// * `listener`: The listener to accept connections on.
+    /// * `handler`: The SOCKS handler each connection is dispatched to.
+    pub fn new(
+        listener: TcpListener,
+        handler: Arc<H>,
+    ) -> Self {
+        Self::with_listeners(vec![listener], handler)
+    }
+
+    /// Creates a new `Server` that dispatches the connections of all listeners to the same handler.
+    fn with_listeners(
+        listeners: Vec<TcpListener>,
+        handler: Arc<H>,
+    ) -> Self {
+        Server {
+            listeners,
+            handler,
+            semaphore: None,
+            rate_limiter: None,
+            registry: None,
+            grace_period: SHUTDOWN_GRACE_PERIOD,
+        }
+    }
+
+    /// Creates a new `Server` listening on the given address.
+    ///
+    /// # Parameters
+    ///
+    /// * `addr`: The address to listen on.
+    /// * `handler`: The SOCKS handler each connection is dispatched to.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Result` containing the `Server` or an error if binding fails.
+    pub async fn bind<A: ToSocketAddrs>(
+        addr: A,
+        handler: Arc<H>,
+    ) -> Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+
+        Ok(Self::new(listener, handler))
+    }
+
+    /// Creates a new `Server` listening on the given port, on both IPv4 and IPv6.
+    /// A single dual-stack socket is used where the platform supports it, otherwise one listener per family.
+    ///
+    /// # Parameters
+    ///
+    /// * `port`: The port to listen on, if 0 the same ephemeral port is used for both families.
+    /// * `handler`: The SOCKS handler each connection is dispatched to.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Result` containing the `Server` or an error if binding fails.
+    pub async fn bind_dual_stack(
+        port: u16,
+        handler: Arc<H>,
+    ) -> Result<Self> {
+        let unspecified_v6 = SocketAddr::from((Ipv6Addr::UNSPECIFIED, port));
+        let listeners = match listen(unspecified_v6, false) {
+            Ok(listener) => vec![listener],
+            Err(error) => {
+                debug!("Dual-stack socket isn't available, listening per family: {}", error);
+
+                let listener_v4 = listen(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)), false)?;
+                let port = listener_v4.local_addr()?.port();
+                let listener_v6 = listen(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)), true)?;
+
+                vec![listener_v4, listener_v6]
+            }
+        };
+
+        Ok(Self::with_listeners(listeners, handler))
+    }
+
+    /// Returns the address the server is listening on, the first one if it listens on several.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listeners[0].local_addr()?)
+    }
+
+    /// Returns all addresses the server is listening on.
+    pub fn local_addrs(&self) -> Result<Vec<SocketAddr>> {
+        Ok(self.listeners.iter().map(TcpListener::local_addr).collect::<io::Result<_>>()?)
+    }
+
+    /// Sets the limit of concurrent connections, connections beyond it are refused.
+    ///
+    /// # Parameters
+    ///
+    /// * `limit`: The maximum number of concurrent connections, 0 (the default) means unlimited.
+    pub fn set_limit(
+        &mut self,
+        limit: usize,
+    ) {
+        self.semaphore = if limit > 0 {
+            Some(Arc::new(Semaphore::new(limit)))
+        } else {
+            None
+        };
+    }
+
+    /// Sets the limiter that is consulted for every new connection, connections beyond it are refused.
+    ///
+    /// # Parameters
+    ///
+    /// * `rate_limiter`: The limiter of new connections per source IP, by default there is none.
+    pub fn set_rate_limiter(
+        &mut self,
+        rate_limiter: RateLimiter,
+    ) {
+        self.rate_limiter = Some(Arc::new(rate_limiter));
+    }
+
+    /// Sets the registry that every accepted connection is registered in while it's open, and whose progress the
+    /// handler records in it.
+    ///
+    /// # Parameters
+    ///
+    /// * `registry`: The registry, which is typically shared with e.g. an admin endpoint.
+    pub fn set_connection_registry(
+        &mut self,
+        registry: Arc<ConnectionRegistry>,
+    ) {
+        self.registry = Some(registry);
+    }
+
+    /// Sets the time in-flight connections are given to finish once the server shuts down.
+    ///
+    /// # Parameters
+    ///
+    /// * `grace_period`: The grace period, defaults to 30 seconds.
+    pub fn set_grace_period(
+        &mut self,
+        grace_period: Duration,
+    ) {
+        self.grace_period = grace_period;
+    }
+
+    /// Accepts connections until `shutdown` is cancelled, then drains the in-flight connections.
+    /// Connections that are still running after the grace period are aborted. Transient accept errors, such as
+    /// running out of file descriptors, are logged and retried after a short backoff, while other accept errors stop
+    /// the server as a shutdown does, after which the error is returned.
+    ///
+    /// # Parameters
+    ///
+    /// * `shutdown`: The token that signals the server to stop accepting connections.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Result` containing the number of connections that were aborted, or the error accepting failed with.
+    pub async fn run(
+        self,
+        shutdown: CancellationToken,
+    ) -> Result<usize> {
+        let local_addrs = self.local_addrs()?;
+        let mut connections = JoinSet::new();
+        let mut accept_error = None;
+
+        loop {
+            // Reap connections that have finished in the meantime.
+            while connections.try_join_next().is_some() {}
+
+            let accepted = tokio::select! {
+                _ = shutdown.cancelled() => break,
+                accepted = accept(&self.listeners) => accepted,
+            };
+            let (incoming, peer_addr, local_addr) = match accepted {
+                Ok((index, (incoming, peer_addr))) => (incoming, peer_addr, local_addrs[index]),
+                Err(error) if is_transient_accept_error(&error) => {
+                    warn!("Accepting a connection failed, retrying: {}", error);
+                    tokio::select! {
+                        _ = shutdown.cancelled() => break,
+                        _ = tokio::time::sleep(ACCEPT_ERROR_BACKOFF) => continue,
+                    }
+                }
+                Err(error) => {
+                    warn!("Accepting a connection failed, shutting down: {}", error);
+                    accept_error = Some(error);
+                    break;
+                }
+            };
+
+            let handler = Arc::clone(&self.handler);
+            let semaphore = self.semaphore.clone();
+            let rate_limited = match &self.rate_limiter {
+                Some(rate_limiter) => !rate_limiter.check(peer_addr.ip()),
+                None => false,
+            };
+            let registration = self.registry.as_ref().map(|registry| registry.register(peer_addr));
+
+            let connection = async move {
+                record_proxy(&local_addr);
+                debug!("Accepted connection from {}", peer_addr);
+
+                if rate_limited {
+                    info!("Refusing connection from {}, rate limit exceeded", peer_addr);
+                }
+
+                let processed = process(incoming, handler, semaphore, rate_limited);
+                let processed = match &registration {
+                    Some(registration) => registration.scope(processed).await,
+                    None => processed.await,
+                };
+
+                if let Err(error) = processed {
+                    debug!("Request failed: {:?}", error);
+                }
+            };
+
+            connections.spawn(connection.instrument(connection_span()));
+        }
+
+        // Stop listening, and give in-flight connections a chance to finish.
+        drop(self.listeners);
+        info!("Shutting down, waiting for {} connection(s) to finish", connections.len());
+
+        let drained = tokio::time::timeout(self.grace_period, async {
+            while connections.join_next().await.is_some() {}
+        })
+        .await;
+
+        let aborted = if drained.is_ok() { 0 } else { connections.len() };
+        connections.shutdown().await;
+
+        match accept_error {
+            Some(error) => Err(error.into()),
+            None => Ok(aborted),
+        }
+    }
+}
+
+/// Creates a listener on the given address, without Tokio's defaults so `IPV6_V6ONLY` can be controlled.
+///
+/// # Parameters
+///
+/// * `addr`: The address to listen on.
+/// * `only_v6`: Whether an IPv6 socket only accepts IPv6 connections, ignored for IPv4.
+///
+/// # Returns
+///
+/// Returns a `Result` containing the listener, or an error if the socket can't be set up.
+fn listen(
+    addr: SocketAddr,
+    only_v6: bool,
+) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(only_v6)?;
+    }
+
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+
+    TcpListener::from_std(socket.into())
+}
+
+/// Accepts a connection on whichever listener has one first.
+///
+/// # Returns
+///
+/// Returns a `Result` containing the index of the listener, and the accepted connection and its peer address.
+async fn accept(listeners: &[TcpListener]) -> io::Result<(usize, (TcpStream, SocketAddr))> {
+    poll_fn(|cx| {
+        for (index, listener) in listeners.iter().enumerate() {
+            if let Poll::Ready(accepted) = listener.poll_accept(cx) {
+                return Poll::Ready(accepted.map(|accepted| (index, accepted)));
+            }
+        }
+
+        Poll::Pending
+    })
+    .await
+}
+
+/// Checks whether an accept error concerns only the connection being accepted, or resources that will likely be
+/// available again shortly, in which case the server keeps accepting.
+fn is_transient_accept_error(error: &io::Error) -> bool {
+    use io::ErrorKind::*;
+
+    #[cfg(unix)]
+    if matches!(error.raw_os_error(), Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM)) {
+        return true;
+    }
+
+    matches!(error.kind(), ConnectionAborted | ConnectionReset | Interrupted | WouldBlock | OutOfMemory)
+}
+
+/// Processes an incoming connection, or refuses it if the connection or rate limit is reached.
+///
+/// # Parameters
+///
+/// * `incoming`: The incoming `TcpStream`.
+/// * `handler`: The SOCKS handler.
+/// * `semaphore`: An optional semaphore for limiting concurrent connections.
+/// * `rate_limited`: Whether the source of the connection exceeded its rate limit.
+///
+/// # Returns
+///
+/// Returns a `Result` indicating the success or failure of the operation.
+async fn process<H: ServerHandler + ?Sized>(
+    mut incoming: TcpStream,
+    handler: Arc<H>,
+    semaphore: Option<Arc<Semaphore>>,
+    rate_limited: bool,
+) -> Result<()> {
+    let start_time = Instant::now();
+    let peer_addr = incoming.peer_addr()?;
+    #[cfg(feature = "metrics")]
+    let _active = crate::metrics::connection_opened();
+
+    // Handle the incoming connection based on the rate limit and the availability of permits
+    let permit = semaphore.as_ref().map(|semaphore| semaphore.try_acquire());
+    if rate_limited || matches!(permit, Some(Err(_))) {
+        handler.refuse(&mut incoming).await?;
+    } else {
+        let (destination, stats) = handler.serve(&mut incoming).await?;
+        #[cfg(feature = "metrics")]
+        crate::metrics::bytes_transferred(&stats);
+        info!("{} -> {}, {} bytes sent, {} bytes received", peer_addr, destination, stats.sent, stats.received);
+    }
+
+    // Log the time taken to process the request
+    info!("Request fulfilled in {}ms", Instant::now().saturating_duration_since(start_time).as_millis());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::constants::SOCKS_VER_6;
+    use crate::socks6::Socks6Reply;
+    use crate::{Socks6Client, Socks6Handler};
+
+    // Tests that running out of e.g. file descriptors, or a connection reset while queued, is retried rather than fatal.
+    #[test]
+    fn test_is_transient_accept_error() {
+        #[cfg(unix)]
+        assert!(is_transient_accept_error(&io::Error::from_raw_os_error(libc::EMFILE)));
+        assert!(is_transient_accept_error(&io::Error::from(io::ErrorKind::ConnectionAborted)));
+        assert!(!is_transient_accept_error(&io::Error::from(io::ErrorKind::InvalidInput)));
+    }
+
+    // Tests that in-flight tunnels are drained, and aborted once the grace period has passed.
+    #[tokio::test]
+    async fn test_run_graceful_shutdown() -> Result<()> {
+        let destination = TcpListener::bind("127.0.0.1:0").await?;
+        let destination_addr = destination.local_addr()?;
+
+        let mut server = Server::bind("127.0.0.1:0", Arc::new(Socks6Handler::default())).await?;
+        server.set_grace_period(Duration::from_millis(100));
+        let server_addr = server.local_addr()?;
+
+        let shutdown = CancellationToken::new();
+        let running = tokio::spawn(server.run(shutdown.clone()));
+
+        // Open a tunnel that stays idle, and therefore outlives the grace period.
+        let client = Socks6Client::new(server_addr.to_string(), None).await?;
+        let (mut tunnel, _) = client.connect(destination_addr.to_string(), None, None).await?;
+        let (_outgoing, _) = destination.accept().await?;
+
+        shutdown.cancel();
+        assert_eq!(running.await??, 1);
+        assert!(TcpStream::connect(server_addr).await.is_err());
+
+        // The aborted tunnel is closed.
+        assert_eq!(tunnel.read(&mut [0; 1]).await.unwrap_or(0), 0);
+
+        Ok(())
+    }
+
+    // Tests that connections beyond the budget of a source are refused.
+    #[tokio::test]
+    async fn test_run_rate_limited() -> Result<()> {
+        let mut server = Server::bind("127.0.0.1:0", Arc::new(Socks6Handler::default())).await?;
+        server.set_rate_limiter(RateLimiter::new(0.001, 1));
+        let server_addr = server.local_addr()?;
+
+        let shutdown = CancellationToken::new();
+        tokio::spawn(server.run(shutdown.clone()));
+
+        let _accepted = TcpStream::connect(server_addr).await?;
+        let mut refused = TcpStream::connect(server_addr).await?;
+
+        let mut reply = [0; 2];
+        refused.read_exact(&mut reply).await?;
+        assert_eq!(reply, [SOCKS_VER_6, Socks6Reply::ConnectionRefused as u8]);
+
+        shutdown.cancel();
+
+        Ok(())
+    }
+
+    // Tests that connections over both IPv4 and IPv6 are accepted, on the same port.
+    #[tokio::test]
+    async fn test_bind_dual_stack() -> Result<()> {
+        let server = Server::bind_dual_stack(0, Arc::new(Socks6Handler::default())).await?;
+        let local_addrs = server.local_addrs()?;
+        let port = local_addrs[0].port();
+        assert!(local_addrs.iter().all(|addr| addr.ip().is_unspecified() && addr.port() == port));
+
+        let shutdown = CancellationToken::new();
+        tokio::spawn(server.run(shutdown.clone()));
+
+        let destination = TcpListener::bind("127.0.0.1:0").await?;
+        let destination_addr = destination.local_addr()?;
+        for addr in [SocketAddr::from((Ipv4Addr::LOCALHOST, port)), SocketAddr::from((Ipv6Addr::LOCALHOST, port))] {
+            let client = Socks6Client::new(addr.to_string(), None).await?;
+            client.connect(destination_addr.to_string(), None, None).await?;
+            destination.accept().await?;
+        }
+
+        shutdown.cancel();
+
+        Ok(())
+    }
+
+    // Tests that open connections are registered with their progress, and removed once closed.
+    #[tokio::test]
+    async fn test_run_connection_registry() -> Result<()> {
+        use tokio::io::AsyncWriteExt;
+
+        use crate::{Address, ConnectionState};
+
+        let destination = TcpListener::bind("127.0.0.1:0").await?;
+        let destination_addr = destination.local_addr()?;
+
+        let registry = Arc::new(ConnectionRegistry::new());
+        let mut server = Server::bind("127.0.0.1:0", Arc::new(Socks6Handler::default())).await?;
+        server.set_connection_registry(Arc::clone(&registry));
+        let server_addr = server.local_addr()?;
+
+        let shutdown = CancellationToken::new();
+        tokio::spawn(server.run(shutdown.clone()));
+
+        let client = Socks6Client::new(server_addr.to_string(), None).await?;
+        let (mut tunnel, _) = client.connect(destination_addr.to_string(), None, None).await?;
+        let (mut outgoing, _) = destination.accept().await?;
+        tunnel.write_all(b"ping").await?;
+        outgoing.read_exact(&mut [0; 4]).await?;
+
+        let snapshot = registry.snapshot();
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot[0].source, tunnel.local_addr()?);
+        assert_eq!(snapshot[0].destination, Some(Address::Ip(destination_addr)));
+        assert_eq!(snapshot[0].state, ConnectionState::Relaying);
+        assert_eq!(snapshot[0].sent, 4);
+
+        drop(tunnel);
+        drop(outgoing);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !registry.is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+
+        shutdown.cancel();
+
+        Ok(())
+    }
+
+    // Tests that a handler picked at runtime is served as a `dyn SocksHandler`, and that calls to a handler aren't
+    // ambiguous while both handler traits are in scope.
+    #[tokio::test]
+    async fn test_run_dyn_handler() -> Result<()> {
+        let destination = TcpListener::bind("127.0.0.1:0").await?;
+        let destination_addr = destination.local_addr()?;
+
+        let handler: Arc<dyn SocksHandler + Send + Sync> = Arc::new(Socks6Handler::default());
+        let server = Server::bind("127.0.0.1:0", handler).await?;
+        let server_addr = server.local_addr()?;
+
+        let shutdown = CancellationToken::new();
+        tokio::spawn(server.run(shutdown.clone()));
+
+        let client = Socks6Client::new(server_addr.to_string(), None).await?;
+        client.connect(destination_addr.to_string(), None, None).await?;
+        destination.accept().await?;
+        shutdown.cancel();
+
+        let (mut source, mut refused) = tokio::io::duplex(64);
+        Socks6Handler::default().refuse_request(&mut source).await?;
+        let mut reply = [0; 2];
+        refused.read_exact(&mut reply).await?;
+        assert_eq!(reply, [SOCKS_VER_6, Socks6Reply::ConnectionRefused as u8]);
+
+        Ok(())
+    }
+
+    // Tests that a server without in-flight connections shuts down immediately.
+    #[tokio::test]
+    async fn test_run_idle_shutdown() -> Result<()> {
+        let server = Server::bind("127.0.0.1:0", Arc::new(Socks6Handler::default())).await?;
+
+        let shutdown = CancellationToken::new();
+        shutdown.cancel();
+        assert_eq!(server.run(shutdown).await?, 0);
+
+        Ok(())
+    }
+}
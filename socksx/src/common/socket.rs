@@ -0,0 +1,292 @@
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use anyhow::Result;
+use socket2::{SockRef, TcpKeepalive};
+use tokio::net::{TcpSocket, TcpStream};
+
+/// The address family outgoing connections are restricted to, e.g. to avoid a family that is broken in a network.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum AddressFamily {
+    #[default]
+    Any,
+    V4Only,
+    V6Only,
+}
+
+impl fmt::Display for AddressFamily {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        match self {
+            AddressFamily::Any => write!(f, "any"),
+            AddressFamily::V4Only => write!(f, "IPv4"),
+            AddressFamily::V6Only => write!(f, "IPv6"),
+        }
+    }
+}
+
+impl AddressFamily {
+    /// Keeps the candidate addresses of this family, in order.
+    ///
+    /// # Parameters
+    ///
+    /// * `addrs`: The candidate addresses, e.g. as resolved.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Result` containing the remaining addresses, or an error naming the family if none remain.
+    pub fn filter(
+        self,
+        addrs: &[SocketAddr],
+    ) -> Result<Vec<SocketAddr>> {
+        let filtered: Vec<_> = addrs
+            .iter()
+            .filter(|addr| match self {
+                AddressFamily::Any => true,
+                AddressFamily::V4Only => addr.is_ipv4(),
+                AddressFamily::V6Only => addr.is_ipv6(),
+            })
+            .copied()
+            .collect();
+
+        ensure!(
+            addrs.is_empty() || !filtered.is_empty(),
+            "No {} addresses to connect to, among: {:?}.",
+            self,
+            addrs
+        );
+
+        Ok(filtered)
+    }
+}
+
+/// Socket options that are applied to outgoing TCP connections.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TcpOptions {
+    /// Disables Nagle's algorithm (`TCP_NODELAY`), proxied traffic is usually interactive.
+    pub nodelay: bool,
+    /// Enables `SO_KEEPALIVE`, with the given idle time before probes are sent (where supported).
+    pub keepalive: Option<Duration>,
+    /// Marks outgoing packets (`SO_MARK`), e.g. for policy-based routing. Requires `CAP_NET_ADMIN`, and is ignored
+    /// on platforms other than Linux.
+    pub fwmark: Option<u32>,
+    /// Restricts connections to the addresses of a family, both to a proxy and to a destination.
+    pub family: AddressFamily,
+}
+
+impl Default for TcpOptions {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            keepalive: None,
+            fwmark: None,
+            family: AddressFamily::Any,
+        }
+    }
+}
+
+impl TcpOptions {
+    /// Connects to `addr`, with the options that have to be set before connecting, see `apply` for the others.
+    ///
+    /// # Parameters
+    ///
+    /// * `addr`: The address to connect to.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Result` containing the connected stream.
+    pub(crate) async fn connect(
+        &self,
+        addr: SocketAddr,
+    ) -> io::Result<TcpStream> {
+        let socket = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+        self.apply_before_connect(&SockRef::from(&socket))?;
+
+        socket.connect(addr).await
+    }
+
+    /// Applies the options that only take effect if set before connecting.
+    pub(crate) fn apply_before_connect(
+        &self,
+        socket: &SockRef<'_>,
+    ) -> io::Result<()> {
+        #[cfg(target_os = "linux")]
+        if let Some(mark) = self.fwmark {
+            socket.set_mark(mark)?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = socket;
+
+        Ok(())
+    }
+
+    /// Applies the options to a connected `TcpStream`.
+    ///
+    /// # Parameters
+    ///
+    /// * `stream`: The stream to configure.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Result` indicating whether all options could be set.
+    pub fn apply(
+        &self,
+        stream: &TcpStream,
+    ) -> Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+
+        let socket = SockRef::from(stream);
+        match self.keepalive {
+            Some(time) => socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(time))?,
+            None => socket.set_keepalive(false)?,
+        }
+
+        Ok(())
+    }
+}
+
+/// Connects to `addr` with TCP Fast Open, carrying `data` in the SYN if the kernel holds a cookie for the destination.
+/// Otherwise, the kernel falls back to a regular handshake and sends `data` once the connection is established.
+///
+/// # Parameters
+///
+/// * `addr`: The address to connect to.
+/// * `data`: The data to send on the connection.
+/// * `options`: The options to set before connecting.
+///
+/// # Returns
+///
+/// Returns a `Result` containing the stream, or `None` if TCP Fast Open isn't available, in which case nothing is sent.
+#[cfg(target_os = "linux")]
+pub(crate) async fn connect_fast_open(
+    addr: SocketAddr,
+    data: &[u8],
+    options: &TcpOptions,
+) -> Result<Option<TcpStream>> {
+    use std::os::unix::io::AsRawFd;
+
+    use socket2::{Domain, Protocol, Socket, Type};
+    use tokio::io::Interest;
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    options.apply_before_connect(&SockRef::from(&socket))?;
+    let enabled: libc::c_int = 1;
+    // SAFETY: the descriptor is owned by `socket`, and the value outlives the call.
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_FASTOPEN_CONNECT,
+            &enabled as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        debug!("TCP Fast Open isn't available: {}", io::Error::last_os_error());
+        return Ok(None);
+    }
+
+    // With TCP_FASTOPEN_CONNECT the handshake is deferred until the first write.
+    socket.set_nonblocking(true)?;
+    match socket.connect(&addr.into()) {
+        Ok(()) => {}
+        Err(error) if error.raw_os_error() == Some(libc::EINPROGRESS) => {}
+        Err(error) => return Err(error.into()),
+    }
+
+    let stream = TcpStream::from_std(socket.into())?;
+    let mut remaining = data;
+    while !remaining.is_empty() {
+        stream.writable().await?;
+
+        let sent = stream.try_io(Interest::WRITABLE, || {
+            SockRef::from(&stream).send(remaining).map_err(|error| match error.raw_os_error() {
+                // The handshake is still in progress, wait until the socket becomes writable again.
+                Some(libc::EINPROGRESS) => io::ErrorKind::WouldBlock.into(),
+                _ => error,
+            })
+        });
+        match sent {
+            Ok(length) => remaining = &remaining[length..],
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(error) => return Err(error.into()),
+        }
+    }
+
+    Ok(Some(stream))
+}
+
+/// Connects to `addr` with TCP Fast Open, which isn't supported on this platform.
+#[cfg(not(target_os = "linux"))]
+pub(crate) async fn connect_fast_open(
+    _addr: SocketAddr,
+    _data: &[u8],
+    _options: &TcpOptions,
+) -> Result<Option<TcpStream>> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_tcp_options_apply() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let stream = TcpStream::connect(listener.local_addr()?).await?;
+
+        TcpOptions::default().apply(&stream)?;
+        assert!(stream.nodelay()?);
+        assert!(!SockRef::from(&stream).keepalive()?);
+
+        let options = TcpOptions {
+            nodelay: false,
+            keepalive: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+        options.apply(&stream)?;
+        assert!(!stream.nodelay()?);
+        assert!(SockRef::from(&stream).keepalive()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_address_family_filter() -> Result<()> {
+        let addrs: Vec<SocketAddr> = vec!["127.0.0.1:1080".parse()?, "[::1]:1080".parse()?];
+
+        assert_eq!(AddressFamily::Any.filter(&addrs)?, addrs);
+        assert_eq!(AddressFamily::V4Only.filter(&addrs)?, &addrs[..1]);
+        assert_eq!(AddressFamily::V6Only.filter(&addrs)?, &addrs[1..]);
+
+        let error = AddressFamily::V6Only.filter(&addrs[..1]).unwrap_err();
+        assert!(error.to_string().starts_with("No IPv6 addresses"));
+
+        Ok(())
+    }
+
+    // Tests that the mark is set on outgoing connections, which needs `CAP_NET_ADMIN`.
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_tcp_options_fwmark() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let options = TcpOptions {
+            fwmark: Some(42),
+            ..Default::default()
+        };
+
+        match options.connect(listener.local_addr()?).await {
+            Ok(stream) => assert_eq!(SockRef::from(&stream).mark()?, 42),
+            Err(error) if error.kind() == io::ErrorKind::PermissionDenied => {}
+            Err(error) => return Err(error.into()),
+        }
+
+        Ok(())
+    }
+}
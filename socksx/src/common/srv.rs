@@ -0,0 +1,253 @@
+use std::net::SocketAddr;
+
+use anyhow::Result;
+use hickory_resolver::error::ResolveErrorKind;
+use hickory_resolver::TokioAsyncResolver;
+use rand::Rng;
+use tokio::net;
+
+/// Port of a proxy whose name has no SRV record, the well-known SOCKS port.
+const FALLBACK_PORT: u16 = 1080;
+
+/// A target of an SRV record (RFC 2782).
+#[derive(Clone, Debug, PartialEq)]
+pub struct SrvTarget {
+    /// Targets with a lower priority are tried first.
+    pub priority: u16,
+    /// Targets with a higher weight are more likely to be tried first, among those of the same priority.
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+/// Checks whether the address is an SRV name, i.e. `_service._proto.name` without a port.
+pub fn is_srv_name(addr: &str) -> bool {
+    let is_underscored = |label: &str| label.len() > 1 && label.starts_with('_');
+
+    let mut labels = addr.split('.');
+    match (labels.next(), labels.next(), labels.next()) {
+        (Some(service), Some(proto), Some(_)) => {
+            !addr.contains(':') && is_underscored(service) && is_underscored(proto)
+        }
+        _ => false,
+    }
+}
+
+/// Resolves an SRV name to the addresses of its targets, with the system's resolver configuration (e.g.
+/// `/etc/resolv.conf`, or the registry on Windows).
+///
+/// The targets are ordered by priority, and by the weighted random selection of RFC 2782 within a priority, and each
+/// is resolved to its A/AAAA records, so e.g. `connect_happy_eyeballs` tries the selected target first. If the name
+/// has no SRV record, the name without its service labels is resolved instead, on the well-known SOCKS port.
+///
+/// # Parameters
+///
+/// * `name`: The SRV name, e.g. `_socks._tcp.proxy.example`.
+///
+/// # Returns
+///
+/// Returns a `Result` containing a non-empty list of resolved `SocketAddr`s or an error.
+pub async fn resolve_srv(name: &str) -> Result<Vec<SocketAddr>> {
+    let resolver = TokioAsyncResolver::tokio_from_system_conf()?;
+
+    resolve_srv_with(&resolver, name).await
+}
+
+/// Resolves an SRV name with the given resolver, see `resolve_srv`.
+async fn resolve_srv_with(
+    resolver: &TokioAsyncResolver,
+    name: &str,
+) -> Result<Vec<SocketAddr>> {
+    let mut targets = lookup_srv(resolver, name).await?;
+
+    if targets.is_empty() {
+        let host = name.splitn(3, '.').nth(2).unwrap_or(name);
+        debug!("No SRV record for {}, falling back to {}:{}", name, host, FALLBACK_PORT);
+
+        let addresses: Vec<SocketAddr> = net::lookup_host((host, FALLBACK_PORT)).await?.collect();
+        ensure!(!addresses.is_empty(), "Domain name didn't resolve to an IP address.");
+
+        return Ok(addresses);
+    }
+
+    order_targets(&mut targets, &mut rand::thread_rng());
+
+    let mut addresses = vec![];
+    for target in targets {
+        match net::lookup_host((target.target.as_str(), target.port)).await {
+            Ok(resolved) => addresses.extend(resolved),
+            Err(error) => debug!("Failed to resolve SRV target {}: {}", target.target, error),
+        }
+    }
+    ensure!(!addresses.is_empty(), "None of the SRV targets of {} resolved to an IP address.", name);
+
+    Ok(addresses)
+}
+
+/// Looks up the SRV records of the name, an empty list means the name has none.
+async fn lookup_srv(
+    resolver: &TokioAsyncResolver,
+    name: &str,
+) -> Result<Vec<SrvTarget>> {
+    let lookup = match resolver.srv_lookup(name).await {
+        Ok(lookup) => lookup,
+        Err(error) if matches!(error.kind(), ResolveErrorKind::NoRecordsFound { .. }) => return Ok(vec![]),
+        Err(error) => return Err(error.into()),
+    };
+
+    let targets = lookup
+        .iter()
+        // A target of "." means the service is decidedly not available at this name.
+        .filter(|srv| !srv.target().is_root())
+        .map(|srv| SrvTarget {
+            priority: srv.priority(),
+            weight: srv.weight(),
+            port: srv.port(),
+            target: srv.target().to_utf8().trim_end_matches('.').to_string(),
+        })
+        .collect();
+
+    Ok(targets)
+}
+
+/// Orders targets by ascending priority, and within a priority by the weighted random selection of RFC 2782: each
+/// next target is picked with a probability proportional to its weight, where targets of weight 0 have a small chance
+/// of being picked.
+fn order_targets<R: Rng>(
+    targets: &mut [SrvTarget],
+    rng: &mut R,
+) {
+    targets.sort_by_key(|target| target.priority);
+
+    for group in targets.chunk_by_mut(|a, b| a.priority == b.priority) {
+        // Targets of weight 0 go first, so they're only picked if the random number is 0.
+        group.sort_by_key(|target| target.weight);
+
+        for i in 0..group.len() {
+            let total: u32 = group[i..].iter().map(|target| target.weight as u32).sum();
+            let pick = rng.gen_range(0..=total);
+
+            let mut running = 0;
+            let selected = group[i..]
+                .iter()
+                .position(|target| {
+                    running += target.weight as u32;
+                    running >= pick
+                })
+                .unwrap_or(0);
+
+            // Move the selected target to the front, keeping the order of the remaining ones.
+            group[i..=i + selected].rotate_right(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hickory_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use tokio::net::UdpSocket;
+
+    use super::*;
+
+    /// Builds a response to `query` with the given SRV answers, the owner names are compressed.
+    fn response(
+        query: &[u8],
+        answers: &[(u16, u16, u16, &str)],
+    ) -> Vec<u8> {
+        // Keep the header and the question, but drop e.g. the EDNS record of the query.
+        let mut end = 12;
+        while query[end] != 0 {
+            end += 1 + query[end] as usize;
+        }
+        let mut response = query[..end + 5].to_vec();
+        response[2] |= 0x80;
+        response[3] = 0x80;
+        response[7] = answers.len() as u8;
+        response[11] = 0;
+
+        for (priority, weight, port, target) in answers {
+            let mut data = [priority.to_be_bytes(), weight.to_be_bytes(), port.to_be_bytes()].concat();
+            for label in target.split('.').filter(|label| !label.is_empty()) {
+                data.push(label.len() as u8);
+                data.extend(label.as_bytes());
+            }
+            data.push(0);
+
+            response.extend([0xC0, 12]);
+            response.extend(33u16.to_be_bytes());
+            response.extend(1u16.to_be_bytes());
+            response.extend(300u32.to_be_bytes());
+            response.extend((data.len() as u16).to_be_bytes());
+            response.extend(data);
+        }
+
+        response
+    }
+
+    #[test]
+    fn test_is_srv_name() {
+        assert!(is_srv_name("_socks._tcp.proxy.example"));
+        assert!(!is_srv_name("_socks._tcp.proxy.example:1080"));
+        assert!(!is_srv_name("_socks._tcp"));
+        assert!(!is_srv_name("proxy.example"));
+        assert!(!is_srv_name("_._tcp.proxy.example"));
+    }
+
+    // Tests that targets are ordered by priority, and that heavier targets are more likely to go first.
+    #[test]
+    fn test_order_targets() {
+        let target = |priority, weight, name: &str| SrvTarget {
+            priority,
+            weight,
+            port: 1080,
+            target: name.to_string(),
+        };
+        let targets = [target(20, 0, "c"), target(10, 1, "a"), target(10, 0, "z"), target(10, 98, "b")];
+
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut heaviest_first = 0;
+        for _ in 0..1000 {
+            let mut ordered = targets.to_vec();
+            order_targets(&mut ordered, &mut rng);
+
+            let mut names: Vec<_> = ordered.iter().map(|t| t.target.as_str()).collect();
+            assert_eq!(names.pop(), Some("c"));
+            if names[0] == "b" {
+                heaviest_first += 1;
+            }
+            names.sort_unstable();
+            assert_eq!(names, ["a", "b", "z"]);
+        }
+        assert!((900..1000).contains(&heaviest_first), "{}", heaviest_first);
+    }
+
+    // Tests resolving an SRV name with a name server, whose targets are resolved on their ports.
+    #[tokio::test]
+    async fn test_resolve_srv() -> Result<()> {
+        let nameserver = UdpSocket::bind("127.0.0.1:0").await?;
+        let nameserver_addr = nameserver.local_addr()?;
+
+        tokio::spawn(async move {
+            let mut query = vec![0; 4096];
+            loop {
+                let (length, client) = nameserver.recv_from(&mut query).await.unwrap();
+                let answers = [(10, 0, 1081, "localhost"), (20, 0, 1082, "localhost")];
+                nameserver.send_to(&response(&query[..length], &answers), client).await.unwrap();
+            }
+        });
+
+        let nameservers = NameServerConfigGroup::from_ips_clear(&[nameserver_addr.ip()], nameserver_addr.port(), true);
+        let config = ResolverConfig::from_parts(None, vec![], nameservers);
+        let resolver = TokioAsyncResolver::tokio(config, ResolverOpts::default());
+
+        let addresses = resolve_srv_with(&resolver, "_socks._tcp.proxy.example.").await?;
+        let ports: Vec<_> = addresses.iter().map(SocketAddr::port).collect();
+        assert!(!ports.is_empty());
+        assert!(ports.windows(2).all(|pair| pair[0] <= pair[1]), "{:?}", ports);
+        assert_eq!((ports[0], ports[ports.len() - 1]), (1081, 1082));
+
+        Ok(())
+    }
+}
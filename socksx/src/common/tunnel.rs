@@ -0,0 +1,503 @@
+use std::future::Future;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::registry::{self, ConnectionEntry, ConnectionState};
+use crate::SocksSource;
+
+/// Default size of the buffer used for each direction of a tunnel.
+pub const DEFAULT_BUFFER_SIZE: usize = 8 * 1024;
+
+/// Largest buffer used for each direction of a tunnel, larger sizes are capped.
+pub const MAX_BUFFER_SIZE: usize = 256 * 1024;
+
+/// Options that control how data is relayed through a tunnel.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RelayOptions {
+    /// Closes the tunnel once it has been open this long, regardless of activity.
+    pub max_lifetime: Option<Duration>,
+    /// The size of the buffer for each direction, larger buffers mean fewer syscalls for bulk transfers.
+    pub buffer_size: usize,
+    /// Moves data between TCP sockets with `splice(2)`, without copying it through userspace (Linux only).
+    /// Only `relay_tcp` honors this, other platforms and streams (e.g. TLS-wrapped ones) use the buffered copy.
+    pub zero_copy: bool,
+}
+
+impl Default for RelayOptions {
+    fn default() -> Self {
+        Self {
+            max_lifetime: None,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            zero_copy: false,
+        }
+    }
+}
+
+/// Summary of the data relayed through a tunnel.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TransferStats {
+    /// The number of bytes copied from the first to the second stream, e.g. from source to destination.
+    pub sent: u64,
+    /// The number of bytes copied from the second to the first stream, e.g. from destination to source.
+    pub received: u64,
+    /// Whether the tunnel was closed because it reached its maximum lifetime.
+    pub lifetime_exceeded: bool,
+}
+
+/// Copies data in both directions between `a` and `b`, until both directions have reached EOF.
+///
+/// When one direction reaches EOF, the write half of its peer is shut down, so the EOF is passed on (as a TCP FIN),
+/// while the other direction keeps copying. This allows protocols where a side half-closes and still reads.
+///
+/// # Parameters
+///
+/// * `a`: The first stream, e.g. the source of a tunnel.
+/// * `b`: The second stream, e.g. the destination of a tunnel.
+///
+/// # Returns
+///
+/// Returns a `Result` containing the `TransferStats` of the tunnel.
+pub async fn relay<A, B>(
+    a: &mut A,
+    b: &mut B,
+) -> io::Result<TransferStats>
+where
+    A: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    B: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    relay_with_options(a, b, &RelayOptions::default()).await
+}
+
+/// Copies data in both directions between `a` and `b`, like `relay`, with the given options.
+///
+/// # Parameters
+///
+/// * `a`: The first stream, e.g. the source of a tunnel.
+/// * `b`: The second stream, e.g. the destination of a tunnel.
+/// * `options`: The options of the tunnel, e.g. its maximum lifetime.
+///
+/// # Returns
+///
+/// Returns a `Result` containing the `TransferStats` of the tunnel.
+pub async fn relay_with_options<A, B>(
+    a: &mut A,
+    b: &mut B,
+    options: &RelayOptions,
+) -> io::Result<TransferStats>
+where
+    A: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    B: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let (mut a_reader, mut a_writer) = tokio::io::split(a);
+    let (mut b_reader, mut b_writer) = tokio::io::split(b);
+    let entry = registry::current();
+    let (mut sent, mut received) = Progress::both(entry.as_deref());
+    let buffer_size = options.buffer_size.clamp(1, MAX_BUFFER_SIZE);
+
+    let copy = async {
+        tokio::try_join!(
+            copy_half(&mut a_reader, &mut b_writer, buffer_size, &mut sent),
+            copy_half(&mut b_reader, &mut a_writer, buffer_size, &mut received)
+        )
+    };
+
+    let lifetime_exceeded = copy_with_lifetime(copy, options.max_lifetime, entry.as_deref()).await?;
+    if lifetime_exceeded {
+        // Both streams are closed, the peers may already be gone.
+        a_writer.shutdown().await.ok();
+        b_writer.shutdown().await.ok();
+    }
+
+    Ok(TransferStats {
+        sent: sent.copied,
+        received: received.copied,
+        lifetime_exceeded,
+    })
+}
+
+/// Copies data in both directions between two TCP streams, like `relay_with_options`.
+///
+/// With `zero_copy` enabled on Linux, data is moved between the sockets with `splice(2)` through a pipe per direction,
+/// which avoids copying it into userspace. Otherwise, or if the pipes can't be created, the buffered copy is used.
+///
+/// # Parameters
+///
+/// * `a`: The first stream, e.g. the source of a tunnel.
+/// * `b`: The second stream, e.g. the destination of a tunnel.
+/// * `options`: The options of the tunnel, e.g. whether to use zero-copy.
+///
+/// # Returns
+///
+/// Returns a `Result` containing the `TransferStats` of the tunnel.
+pub async fn relay_tcp(
+    a: &mut TcpStream,
+    b: &mut TcpStream,
+    options: &RelayOptions,
+) -> io::Result<TransferStats> {
+    #[cfg(target_os = "linux")]
+    if options.zero_copy {
+        match (splice::Pipe::new(), splice::Pipe::new()) {
+            (Ok(a_to_b), Ok(b_to_a)) => return splice::relay(a, b, a_to_b, b_to_a, options).await,
+            (Err(error), _) | (_, Err(error)) => debug!("Falling back to buffered relay, no pipe available: {}", error),
+        }
+    }
+
+    relay_with_options(a, b, options).await
+}
+
+/// Copies data in both directions between the source of a request and its destination, with `relay_tcp` if the
+/// source is a plain `TcpStream`, and `relay_with_options` otherwise.
+pub(crate) async fn relay_source<S: SocksSource>(
+    source: &mut S,
+    destination: &mut TcpStream,
+    options: &RelayOptions,
+) -> io::Result<TransferStats> {
+    match source.as_tcp_stream() {
+        Some(source) => relay_tcp(source, destination, options).await,
+        None => relay_with_options(source, destination, options).await,
+    }
+}
+
+/// The number of bytes copied in one direction of a tunnel, which is also recorded in the connection registry if the
+/// tunnel's connection is registered.
+struct Progress<'a> {
+    copied: u64,
+    recorded: Option<&'a AtomicU64>,
+}
+
+impl<'a> Progress<'a> {
+    /// Returns the progress of both directions, from `a` to `b` (sent) and back (received).
+    fn both(entry: Option<&'a ConnectionEntry>) -> (Self, Self) {
+        let progress = |recorded| Progress { copied: 0, recorded };
+        (progress(entry.map(|entry| &entry.sent)), progress(entry.map(|entry| &entry.received)))
+    }
+
+    fn add(
+        &mut self,
+        length: usize,
+    ) {
+        self.copied += length as u64;
+        if let Some(recorded) = self.recorded {
+            recorded.fetch_add(length as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Runs the copy of both directions until it completes, or until the tunnel reaches its maximum lifetime.
+/// Returns whether the maximum lifetime was reached, in which case the streams still have to be shut down.
+async fn copy_with_lifetime<F>(
+    copy: F,
+    max_lifetime: Option<Duration>,
+    entry: Option<&ConnectionEntry>,
+) -> io::Result<bool>
+where
+    F: Future<Output = io::Result<((), ())>>,
+{
+    if let Some(entry) = entry {
+        entry.set_state(ConnectionState::Relaying);
+    }
+
+    let copied = match max_lifetime {
+        Some(max_lifetime) => tokio::time::timeout(max_lifetime, copy).await.ok(),
+        None => Some(copy.await),
+    };
+
+    if let Some(entry) = entry {
+        entry.set_state(ConnectionState::Closing);
+    }
+
+    match copied {
+        Some(result) => result.map(|_| false),
+        None => {
+            info!("Closing tunnel, it reached its maximum lifetime");
+            Ok(true)
+        }
+    }
+}
+
+/// Copies data from `reader` to `writer` until EOF, then shuts down `writer`.
+/// The number of bytes copied so far is kept in `copied`, so it remains available if the copy is cancelled.
+async fn copy_half<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    buffer_size: usize,
+    copied: &mut Progress<'_>,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut buffer = vec![0; buffer_size];
+
+    loop {
+        let length = reader.read(&mut buffer).await?;
+        if length == 0 {
+            break;
+        }
+
+        writer.write_all(&buffer[..length]).await?;
+        copied.add(length);
+    }
+
+    match writer.shutdown().await {
+        // The peer may already have closed the connection entirely.
+        Err(error) if error.kind() != io::ErrorKind::NotConnected => Err(error),
+        _ => Ok(()),
+    }
+}
+
+/// Zero-copy relaying between TCP sockets, with `splice(2)`.
+#[cfg(target_os = "linux")]
+mod splice {
+    use std::io;
+    use std::net::Shutdown;
+    use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+    use socket2::SockRef;
+    use tokio::io::Interest;
+    use tokio::net::TcpStream;
+
+    use super::{copy_with_lifetime, Progress, RelayOptions, TransferStats, MAX_BUFFER_SIZE};
+    use crate::registry;
+
+    /// A non-blocking pipe, through which data is moved from one socket to another inside the kernel.
+    pub(super) struct Pipe {
+        read: OwnedFd,
+        write: OwnedFd,
+    }
+
+    impl Pipe {
+        pub(super) fn new() -> io::Result<Self> {
+            let mut fds = [0; 2];
+            // SAFETY: `fds` has room for the two descriptors that are written by `pipe2`.
+            if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            // SAFETY: the descriptors were just created, and aren't owned by anything else.
+            let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+            Ok(Pipe { read, write })
+        }
+    }
+
+    /// Moves up to `length` bytes from `from` to `to`, without blocking.
+    fn splice(
+        from: RawFd,
+        to: RawFd,
+        length: usize,
+    ) -> io::Result<usize> {
+        let flags = libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK;
+        // SAFETY: both descriptors are valid for the duration of the call, and no offsets are passed.
+        let moved = unsafe { libc::splice(from, std::ptr::null_mut(), to, std::ptr::null_mut(), length, flags) };
+        if moved < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(moved as usize)
+        }
+    }
+
+    /// Copies data in both directions between `a` and `b`, through a pipe per direction.
+    pub(super) async fn relay(
+        a: &TcpStream,
+        b: &TcpStream,
+        a_to_b: Pipe,
+        b_to_a: Pipe,
+        options: &RelayOptions,
+    ) -> io::Result<TransferStats> {
+        let entry = registry::current();
+        let (mut sent, mut received) = Progress::both(entry.as_deref());
+        let length = options.buffer_size.clamp(1, MAX_BUFFER_SIZE);
+
+        let copy = async {
+            tokio::try_join!(
+                copy_half(a, b, &a_to_b, length, &mut sent),
+                copy_half(b, a, &b_to_a, length, &mut received)
+            )
+        };
+
+        let lifetime_exceeded = copy_with_lifetime(copy, options.max_lifetime, entry.as_deref()).await?;
+        if lifetime_exceeded {
+            // Both streams are closed, the peers may already be gone.
+            SockRef::from(a).shutdown(Shutdown::Write).ok();
+            SockRef::from(b).shutdown(Shutdown::Write).ok();
+        }
+
+        Ok(TransferStats {
+            sent: sent.copied,
+            received: received.copied,
+            lifetime_exceeded,
+        })
+    }
+
+    /// Moves data from `reader` to `writer` until EOF, then shuts down `writer`.
+    ///
+    /// Whatever is spliced into the pipe is drained into `writer` before reading again, so the pipe is empty whenever
+    /// `reader` is polled. Bytes count as copied once they've been spliced into `writer`.
+    async fn copy_half(
+        reader: &TcpStream,
+        writer: &TcpStream,
+        pipe: &Pipe,
+        length: usize,
+        copied: &mut Progress<'_>,
+    ) -> io::Result<()> {
+        loop {
+            reader.readable().await?;
+            let mut pending = match reader.try_io(Interest::READABLE, || {
+                splice(reader.as_raw_fd(), pipe.write.as_raw_fd(), length)
+            }) {
+                Ok(0) => break,
+                Ok(moved) => moved,
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(error) => return Err(error),
+            };
+
+            while pending > 0 {
+                writer.writable().await?;
+                match writer.try_io(Interest::WRITABLE, || {
+                    splice(pipe.read.as_raw_fd(), writer.as_raw_fd(), pending)
+                }) {
+                    Ok(moved) => {
+                        pending -= moved;
+                        copied.add(moved);
+                    }
+                    Err(error) if error.kind() == io::ErrorKind::WouldBlock => continue,
+                    Err(error) => return Err(error),
+                }
+            }
+        }
+
+        match SockRef::from(writer).shutdown(Shutdown::Write) {
+            // The peer may already have closed the connection entirely.
+            Err(error) if error.kind() != io::ErrorKind::NotConnected => Err(error),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    // Tests that a client that half-closes after its request still receives the complete response.
+    #[tokio::test]
+    async fn test_relay_half_close() -> io::Result<()> {
+        let destination = TcpListener::bind("127.0.0.1:0").await?;
+        let destination_addr = destination.local_addr()?;
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        // The destination only responds once it received the complete request.
+        tokio::spawn(async move {
+            let (mut stream, _) = destination.accept().await.unwrap();
+            let mut request = vec![];
+            stream.read_to_end(&mut request).await.unwrap();
+
+            stream.write_all(b"response to ").await.unwrap();
+            stream.write_all(&request).await.unwrap();
+        });
+
+        let relayed = tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await.unwrap();
+            let mut outgoing = TcpStream::connect(destination_addr).await.unwrap();
+            relay(&mut source, &mut outgoing).await.unwrap()
+        });
+
+        let mut client = TcpStream::connect(proxy_addr).await?;
+        client.write_all(b"request").await?;
+        client.shutdown().await?;
+
+        let mut response = vec![];
+        client.read_to_end(&mut response).await?;
+        assert_eq!(response, b"response to request");
+        let stats = relayed.await?;
+        assert_eq!((stats.sent, stats.received), (7, 19));
+        assert!(!stats.lifetime_exceeded);
+
+        Ok(())
+    }
+
+    // Tests that a tunnel is closed once it reaches its maximum lifetime, even if it's active.
+    #[tokio::test]
+    async fn test_relay_max_lifetime() -> io::Result<()> {
+        let destination = TcpListener::bind("127.0.0.1:0").await?;
+        let destination_addr = destination.local_addr()?;
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        // The destination keeps sending, and never closes the connection itself.
+        tokio::spawn(async move {
+            let (mut stream, _) = destination.accept().await.unwrap();
+            while stream.write_all(b"tick").await.is_ok() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        });
+
+        let relayed = tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await.unwrap();
+            let mut outgoing = TcpStream::connect(destination_addr).await.unwrap();
+            let options = RelayOptions {
+                max_lifetime: Some(Duration::from_millis(100)),
+                ..Default::default()
+            };
+            relay_with_options(&mut source, &mut outgoing, &options).await.unwrap()
+        });
+
+        let mut client = TcpStream::connect(proxy_addr).await?;
+        let mut received = vec![];
+        client.read_to_end(&mut received).await?;
+
+        let stats = relayed.await?;
+        assert!(stats.lifetime_exceeded);
+        assert!(stats.received > 0 && stats.received <= received.len() as u64);
+
+        Ok(())
+    }
+
+    // Tests that a zero-copy tunnel passes on half-closes, and accounts for every byte it moved.
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_relay_zero_copy() -> io::Result<()> {
+        let destination = TcpListener::bind("127.0.0.1:0").await?;
+        let destination_addr = destination.local_addr()?;
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        // The destination echoes the complete request, once the client half-closed.
+        tokio::spawn(async move {
+            let (mut stream, _) = destination.accept().await.unwrap();
+            let mut request = vec![];
+            stream.read_to_end(&mut request).await.unwrap();
+            stream.write_all(&request).await.unwrap();
+        });
+
+        let relayed = tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await.unwrap();
+            let mut outgoing = TcpStream::connect(destination_addr).await.unwrap();
+            let options = RelayOptions {
+                zero_copy: true,
+                ..Default::default()
+            };
+            relay_tcp(&mut source, &mut outgoing, &options).await.unwrap()
+        });
+
+        let request: Vec<u8> = (0..1024 * 1024).map(|i| i as u8).collect();
+        let mut client = TcpStream::connect(proxy_addr).await?;
+        client.write_all(&request).await?;
+        client.shutdown().await?;
+
+        let mut response = vec![];
+        client.read_to_end(&mut response).await?;
+        assert!(response == request);
+        let stats = relayed.await?;
+        assert_eq!((stats.sent, stats.received), (request.len() as u64, request.len() as u64));
+        assert!(!stats.lifetime_exceeded);
+
+        Ok(())
+    }
+}
@@ -1,8 +1,27 @@
-use std::net::SocketAddr;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::time::Duration;
 
 use anyhow::Result;
+use futures::stream::{FuturesUnordered, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::{self, TcpStream};
 
+use crate::{RetryPolicy, SocksError, TcpOptions};
+
+/// Default delay between staggered connection attempts, as recommended by RFC 8305.
+pub const HAPPY_EYEBALLS_DELAY: Duration = Duration::from_millis(250);
+
+/// A bidirectional stream, e.g. a `TcpStream` or a TLS stream over one, that can be used as a trait object.
+pub trait AsyncStream: AsyncRead + AsyncWrite + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Send> AsyncStream for S {}
+
+/// A stream of any transport, so that connections of mixed types can be held (or proxied) alike.
+pub type BoxedStream = Pin<Box<dyn AsyncStream>>;
+
 /// Retrieves the original destination address from a socket on a Linux system.
 ///
 /// # Parameters
@@ -71,21 +90,242 @@ pub fn get_original_dst<S>(_socket: S) -> Result<SocketAddr> {
 ///
 /// Returns a `Result` containing the resolved `SocketAddr` or an error.
 pub async fn resolve_addr<S: Into<String>>(addr: S) -> Result<SocketAddr> {
+    let addresses = resolve_addrs(addr).await?;
+
+    Ok(addresses[0])
+}
+
+/// Resolves a given address to all of its `SocketAddr`s.
+///
+/// With the `srv` feature, an address without a port such as `_socks._tcp.proxy.example` is resolved through its SRV
+/// records, see `srv::resolve_srv`.
+///
+/// # Parameters
+///
+/// * `addr`: The address, either as a domain name or IP address, IPv6 addresses are enclosed in brackets.
+///
+/// # Returns
+///
+/// Returns a `Result` containing a non-empty list of resolved `SocketAddr`s or an error.
+pub async fn resolve_addrs<S: Into<String>>(addr: S) -> Result<Vec<SocketAddr>> {
     let addr: String = addr.into();
 
     // First, try to parse address as socket address.
     if let Ok(addr) = addr.parse() {
-        return Ok(addr);
+        return Ok(vec![addr]);
+    }
+
+    #[cfg(feature = "srv")]
+    if crate::srv::is_srv_name(&addr) {
+        return crate::srv::resolve_srv(&addr).await;
+    }
+
+    let (host, port) = split_host_port(&addr)?;
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
     }
 
     // Otherwise, address is probably a domain name.
-    let addresses: Vec<SocketAddr> = net::lookup_host(addr).await?.collect();
-    match addresses[..] {
-        [first, ..] => Ok(first),
-        [] => bail!("Domain name didn't resolve to an IP address."),
+    let addresses: Vec<SocketAddr> = net::lookup_host((host, port)).await?.collect();
+    ensure!(!addresses.is_empty(), "Domain name didn't resolve to an IP address.");
+
+    Ok(addresses)
+}
+
+/// Splits an address into its host and port, stripping the brackets around an IPv6 host.
+///
+/// # Parameters
+///
+/// * `addr`: The address, e.g. `example.com:80`, `127.0.0.1:80` or `[::1]:80`.
+///
+/// # Returns
+///
+/// Returns a `Result` containing the host and port, or an error if the address is ambiguous or malformed.
+pub(crate) fn split_host_port(addr: &str) -> Result<(&str, u16)> {
+    let (host, port) = if let Some(addr) = addr.strip_prefix('[') {
+        addr.split_once("]:")
+            .ok_or_else(|| anyhow!("Address doesn't seperate bracketed host and port by ':'."))?
+    } else {
+        let (host, port) = addr
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow!("Address doesn't seperate host and port by ':'."))?;
+
+        // Without brackets, it isn't clear where an IPv6 address ends and the port begins.
+        ensure!(!host.contains(':'), "IPv6 address MUST be enclosed in brackets: {}", addr);
+        (host, port)
+    };
+
+    Ok((host, port.parse()?))
+}
+
+/// Connects to one of the given addresses, racing IPv4 and IPv6 attempts (RFC 8305).
+///
+/// The addresses are interleaved by family, and a new attempt is started every `delay`,
+/// or as soon as the previous attempt fails. The first successful connection wins, and
+/// the remaining attempts are cancelled.
+///
+/// # Parameters
+///
+/// * `addrs`: The candidate addresses, in order of preference.
+/// * `delay`: The stagger between consecutive connection attempts.
+///
+/// # Returns
+///
+/// Returns a `Result` containing the first established `TcpStream`, or the error of the last attempt.
+pub async fn connect_happy_eyeballs(
+    addrs: &[SocketAddr],
+    delay: Duration,
+) -> Result<TcpStream> {
+    connect_happy_eyeballs_with_options(addrs, delay, &TcpOptions::default()).await
+}
+
+/// Connects like `connect_happy_eyeballs`, to the addresses of the family allowed by the options, setting the options
+/// that have to be set before connecting on every attempt. The remaining options are left to the caller, see
+/// `TcpOptions::apply`.
+pub(crate) async fn connect_happy_eyeballs_with_options(
+    addrs: &[SocketAddr],
+    delay: Duration,
+    options: &TcpOptions,
+) -> Result<TcpStream> {
+    let addrs = options.family.filter(addrs)?;
+    let mut candidates = interleave_families(&addrs).into_iter();
+    let mut attempts = FuturesUnordered::new();
+
+    if let Some(addr) = candidates.next() {
+        attempts.push(options.connect(addr));
+    } else {
+        bail!("No addresses to connect to.");
+    }
+
+    loop {
+        let has_candidates = !candidates.as_slice().is_empty();
+
+        tokio::select! {
+            Some(result) = attempts.next() => match result {
+                Ok(stream) => return Ok(stream),
+                Err(error) => {
+                    // A failed attempt doesn't have to wait for the stagger.
+                    if let Some(addr) = candidates.next() {
+                        attempts.push(options.connect(addr));
+                    } else if attempts.is_empty() {
+                        return Err(error.into());
+                    }
+                }
+            },
+            _ = tokio::time::sleep(delay), if has_candidates => {
+                if let Some(addr) = candidates.next() {
+                    attempts.push(options.connect(addr));
+                }
+            }
+        }
+    }
+}
+
+/// Connects to a proxy at one of `addrs`, retrying transient failures according to `retry_policy`.
+///
+/// # Parameters
+///
+/// * `addrs`: The candidate addresses of the proxy, in order of preference.
+/// * `delay`: The stagger between consecutive connection attempts, see `connect_happy_eyeballs`.
+/// * `retry_policy`: An optional policy for retrying when all addresses fail.
+/// * `options`: The options to set before connecting.
+///
+/// # Returns
+///
+/// Returns a `Result` containing the established `TcpStream`, or the error of the last attempt.
+pub(crate) async fn connect_proxy(
+    addrs: &[SocketAddr],
+    delay: Duration,
+    retry_policy: Option<&RetryPolicy>,
+    options: &TcpOptions,
+) -> Result<TcpStream> {
+    let connect = || connect_happy_eyeballs_with_options(addrs, delay, options);
+    match retry_policy {
+        Some(retry_policy) => retry_policy.retry(connect).await,
+        None => connect().await,
+    }
+}
+
+/// Conducts a handshake with a proxy, giving up if it doesn't complete before the deadline, however steadily the proxy
+/// sends its part of it.
+///
+/// # Parameters
+///
+/// * `handshake`: The future that connects to the proxy and conducts the handshake.
+/// * `deadline`: The time the whole handshake is given, `None` imposes no deadline.
+///
+/// # Returns
+///
+/// Returns the outcome of `handshake`, or an error of kind `TimedOut` if the deadline passed.
+pub(crate) async fn with_handshake_deadline<T, F>(
+    handshake: F,
+    deadline: Option<Duration>,
+) -> Result<T, SocksError>
+where
+    F: Future<Output = Result<T, SocksError>>,
+{
+    #[cfg(feature = "metrics")]
+    let start = std::time::Instant::now();
+
+    let outcome = match deadline {
+        Some(deadline) => tokio::time::timeout(deadline, handshake).await.unwrap_or_else(|_| {
+            let message = format!("Handshake didn't complete within {}ms.", deadline.as_millis());
+            Err(io::Error::new(io::ErrorKind::TimedOut, message).into())
+        }),
+        None => handshake.await,
+    };
+
+    #[cfg(feature = "metrics")]
+    crate::metrics::handshake_finished(&outcome, start.elapsed());
+
+    outcome
+}
+
+/// Awaits the operation reply of a proxy, giving up if it doesn't arrive in time.
+///
+/// # Parameters
+///
+/// * `reply`: The future that reads the reply.
+/// * `timeout`: The time the proxy is given to reply, `None` waits indefinitely.
+///
+/// # Returns
+///
+/// Returns the outcome of `reply`, or an error of kind `TimedOut` if the proxy stalled.
+pub(crate) async fn with_reply_timeout<T, F>(
+    reply: F,
+    timeout: Option<Duration>,
+) -> Result<T, SocksError>
+where
+    F: Future<Output = Result<T, SocksError>>,
+{
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, reply).await.unwrap_or_else(|_| {
+            let message = format!("Proxy didn't reply within {}ms.", timeout.as_millis());
+            Err(io::Error::new(io::ErrorKind::TimedOut, message).into())
+        }),
+        None => reply.await,
     }
 }
 
+/// Reorders addresses so that address families alternate, starting with the family of the first address.
+fn interleave_families(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let (mut preferred, mut other): (Vec<_>, Vec<_>) = match addrs.first() {
+        Some(first) => addrs.iter().copied().partition(|a| a.is_ipv4() == first.is_ipv4()),
+        None => return vec![],
+    };
+
+    let mut interleaved = Vec::with_capacity(addrs.len());
+    preferred.reverse();
+    other.reverse();
+
+    while !preferred.is_empty() || !other.is_empty() {
+        interleaved.extend(preferred.pop());
+        interleaved.extend(other.pop());
+    }
+
+    interleaved
+}
+
 /// Attempts to read the initial data from a TCP stream.
 ///
 /// # Parameters
@@ -102,9 +342,7 @@ pub async fn try_read_initial_data(stream: &mut TcpStream) -> Result<Option<Vec<
     match stream.try_read_buf(&mut initial_data) {
         Ok(0) => Ok(None),
         Ok(_) => Ok(Some(initial_data)),
-        Err(e) => {
-            return Err(e.into());
-        }
+        Err(e) => Err(e.into()),
     }
 }
 
@@ -125,9 +363,9 @@ mod tests {
         }
     }
 
-    impl Into<String> for MockSocketAddr {
-        fn into(self) -> String {
-            self.addr
+    impl From<MockSocketAddr> for String {
+        fn from(mock: MockSocketAddr) -> Self {
+            mock.addr
         }
     }
 
@@ -150,4 +388,62 @@ mod tests {
         let result = resolve_addr(mock_addr).await;
         assert!(result.is_ok());
     }
+
+    // Test resolving IPv6 literals, which have to be enclosed in brackets
+    #[tokio::test]
+    async fn test_resolve_addr_ipv6() {
+        let result = resolve_addr("[2001:db8::1]:443").await.unwrap();
+        assert_eq!(result, "[2001:db8::1]:443".parse().unwrap());
+
+        let result = resolve_addr("127.0.0.1:1080").await.unwrap();
+        assert_eq!(result, "127.0.0.1:1080".parse().unwrap());
+
+        assert!(resolve_addr("::1:1080").await.is_err());
+    }
+
+    // Test splitting addresses into host and port
+    #[test]
+    fn test_split_host_port() {
+        assert_eq!(split_host_port("proxy.example:1080").unwrap(), ("proxy.example", 1080));
+        assert_eq!(split_host_port("127.0.0.1:1080").unwrap(), ("127.0.0.1", 1080));
+        assert_eq!(split_host_port("[2001:db8::1]:443").unwrap(), ("2001:db8::1", 443));
+
+        assert!(split_host_port("::1:1080").is_err());
+        assert!(split_host_port("[::1]1080").is_err());
+        assert!(split_host_port("proxy.example").is_err());
+        assert!(split_host_port("proxy.example:http").is_err());
+    }
+
+    // Test interleaving of address families
+    #[test]
+    fn test_interleave_families() {
+        let addrs: Vec<SocketAddr> = vec![
+            "[::1]:1".parse().unwrap(),
+            "[::2]:1".parse().unwrap(),
+            "127.0.0.1:1".parse().unwrap(),
+            "[::3]:1".parse().unwrap(),
+        ];
+
+        let interleaved = interleave_families(&addrs);
+        assert_eq!(interleaved, vec![addrs[0], addrs[2], addrs[1], addrs[3]]);
+    }
+
+    // Test that a failed attempt falls through to the next candidate
+    #[tokio::test]
+    async fn test_connect_happy_eyeballs() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listening = listener.local_addr().unwrap();
+
+        // Reserve a port that nothing listens on.
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+        let stream = connect_happy_eyeballs(&[closed, listening], Duration::from_secs(10)).await;
+        assert_eq!(stream.unwrap().peer_addr().unwrap(), listening);
+
+        let stream = connect_happy_eyeballs(&[closed], HAPPY_EYEBALLS_DELAY).await;
+        assert!(stream.is_err());
+
+        let stream = connect_happy_eyeballs(&[], HAPPY_EYEBALLS_DELAY).await;
+        assert!(stream.is_err());
+    }
 }
@@ -0,0 +1,94 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// The direction of bytes on the wire, as seen by the client.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    /// Bytes sent to the proxy.
+    Sent,
+    /// Bytes received from the proxy.
+    Received,
+}
+
+/// A callback that is passed the raw bytes of a handshake, e.g. to diagnose interoperability issues.
+/// The bytes are passed as they're written and read, so a single message may be passed in several pieces.
+pub type WireHook = Arc<dyn Fn(Direction, &[u8]) + Send + Sync>;
+
+/// Passes the bytes written to and read from a stream to a `WireHook`, if there is one.
+/// Without a hook, it only forwards to the stream.
+pub(crate) struct WireTap<'a, S> {
+    stream: &'a mut S,
+    on_wire: Option<&'a WireHook>,
+}
+
+impl<'a, S> WireTap<'a, S> {
+    /// Creates a new `WireTap`.
+    ///
+    /// # Parameters
+    ///
+    /// * `stream`: The stream with the proxy.
+    /// * `on_wire`: The hook, or `None` to pass nothing.
+    pub(crate) fn new(
+        stream: &'a mut S,
+        on_wire: Option<&'a WireHook>,
+    ) -> Self {
+        WireTap { stream, on_wire }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for WireTap<'_, S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut *this.stream).poll_read(cx, buf);
+
+        if let (Poll::Ready(Ok(())), Some(on_wire)) = (&poll, this.on_wire) {
+            if buf.filled().len() > filled {
+                on_wire(Direction::Received, &buf.filled()[filled..]);
+            }
+        }
+
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for WireTap<'_, S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut *this.stream).poll_write(cx, buf);
+
+        if let (Poll::Ready(Ok(written)), Some(on_wire)) = (&poll, this.on_wire) {
+            if *written > 0 {
+                on_wire(Direction::Sent, &buf[..*written]);
+            }
+        }
+
+        poll
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().stream).poll_shutdown(cx)
+    }
+}
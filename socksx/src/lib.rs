@@ -1,4 +1,5 @@
 //! This crate provides SOCKS proxy client and server implementations. It supports both SOCKS5 and SOCKS6 protocols.
+//! For legacy servers, a SOCKS4/SOCKS4a client is available as well.
 //! 
 //! While the crate is still in development, it is already usable. 
 //! 
@@ -9,39 +10,127 @@
 //!
 //! For `SOCKS version 6`, chaining is supported. It means that you can chain multiple SOCKS6 proxies together.
 //! Apart from working like version 5, it can also be used to do this - Eg. Client -> Socks6 -> Socks6 -> Destination
+//! The last hop of such a chain may also be a SOCKS5 proxy - Eg. Client -> Socks6 -> Socks5 -> Destination
 
 
 
 
 #[macro_use]
 extern crate anyhow;
+#[cfg(feature = "logging")]
 #[macro_use]
-extern crate log;
+extern crate tracing;
 #[macro_use]
 extern crate num_derive;
 
+/// Stand-ins for the logging macros, that compile to nothing without the `logging` feature.
+/// The arguments are still type-checked, so a build with and without logging accept the same code.
+#[cfg(not(feature = "logging"))]
+#[macro_use]
+mod no_logging {
+    macro_rules! debug {
+        ($($arg:tt)*) => {
+            if false {
+                let _ = format_args!($($arg)*);
+            }
+        };
+    }
+
+    macro_rules! info {
+        ($($arg:tt)*) => {
+            debug!($($arg)*)
+        };
+    }
+
+    macro_rules! warn {
+        ($($arg:tt)*) => {
+            debug!($($arg)*)
+        };
+    }
+}
+
 pub use tokio::io::copy_bidirectional;
+pub use tokio_util::sync::CancellationToken;
 
 /// Represents network addresses.
-pub use addresses::{Address, ProxyAddress};
+pub use addresses::{Address, AddressType, ProxyAddress, SocksVersion};
+/// Commands of SOCKS requests.
+pub use command::Command;
 /// Manages user credentials.
 pub use credentials::Credentials;
+/// Serves SOCKS5 and SOCKS6 on the same port.
+pub use detect::VersionDetectHandler;
+/// Typed client errors.
+pub use error::{HandshakeStage, SocksError};
+/// Fails over between interchangeable proxies.
+pub use failover::MultiProxyClient;
+/// Observes connection setup.
+pub use events::{ConnectionEvent, EventHandler};
 /// Handles SOCKS protocol.
-pub use interface::SocksHandler;
-/// SOCKS5 client and handler.
-pub use socks5::{Socks5Client, Socks5Handler};
-/// SOCKS6 client and handler.
-pub use socks6::{Socks6Client, Socks6Handler};
-pub use util::{get_original_dst, resolve_addr, try_read_initial_data};
+pub use interface::{DestinationRewriter, NativeSocksHandler, SocksHandler, SocksSource};
+/// Passes on the original client address to the next hop.
+pub use proxy_protocol::ProxyHeader;
+/// Tracks open connections live.
+pub use registry::{ConnectionRegistry, ConnectionSnapshot, ConnectionState};
+/// Resolves and caches domain names of destinations.
+pub use resolver::{DnsCache, Resolver, SystemResolver};
+/// Limits new connections per source IP.
+pub use rate_limit::RateLimiter;
+/// Selects upstream chains by destination.
+pub use rules::{Matcher, Route, RuleSet};
+/// Retries transient connection failures.
+pub use retry::RetryPolicy;
+/// Accepts connections and shuts down gracefully.
+pub use server::{Server, ServerHandler};
+/// Configures outgoing TCP connections.
+pub use socket::{AddressFamily, TcpOptions};
+/// SOCKS4 client.
+pub use socks4::Socks4Client;
+/// SOCKS5 client, its builder, handler, and connection pool.
+pub use socks5::{Socks5Client, Socks5ClientBuilder, Socks5Connection, Socks5Handler, Socks5Pool};
+/// SOCKS6 client, its builder, and handler.
+pub use socks6::{Socks6Client, Socks6ClientBuilder, Socks6Handler};
+/// Relays data in both directions, passing on half-closes.
+pub use tunnel::{relay, relay_tcp, relay_with_options, RelayOptions, TransferStats};
+/// Inspects the raw bytes of client handshakes.
+pub use wire::{Direction, WireHook};
+pub use util::{
+    AsyncStream, BoxedStream, connect_happy_eyeballs, get_original_dst, resolve_addr, resolve_addrs, try_read_initial_data,
+};
 
 /// Common network address representations
 #[path = "./common/addresses.rs"]
 pub mod addresses;
 
+/// Request commands shared by SOCKS5 and SOCKS6.
+#[path = "./common/command.rs"]
+pub mod command;
+
 /// SOCKS protocol Constants used across the crate.
 #[path = "./common/constants.rs"]
 pub mod constants;
 
+/// Handler that detects the SOCKS version of the client.
+#[path = "./common/detect.rs"]
+pub mod detect;
+
+/// Metrics of servers and clients, recorded with the `metrics` crate.
+#[cfg(feature = "metrics")]
+#[path = "./common/metrics.rs"]
+pub mod metrics;
+
+/// Typed errors of the SOCKS clients.
+#[path = "./common/error.rs"]
+pub mod error;
+
+/// Connection events emitted by the handlers.
+#[path = "./common/events.rs"]
+pub mod events;
+
+/// Failover between several proxies.
+#[path = "./common/failover.rs"]
+pub mod failover;
+
 /// Credential management for the SOCKS proxy.
 #[path = "./common/credentials.rs"]
 pub mod credentials;
@@ -50,6 +139,59 @@ pub mod credentials;
 #[path = "./common/interface.rs"]
 pub mod interface;
 
+/// Scripted SOCKS servers for testing clients.
+#[cfg(any(test, feature = "test-util"))]
+#[path = "./common/mock.rs"]
+pub mod mock;
+
+/// PROXY protocol headers, passing on the addresses of the original connection.
+#[path = "./common/proxy_protocol.rs"]
+pub mod proxy_protocol;
+
+/// Registry of the open connections of a server.
+#[path = "./common/registry.rs"]
+pub mod registry;
+
+/// Resolution of destination domain names.
+#[path = "./common/resolver.rs"]
+pub mod resolver;
+
+/// Rate limiting of new connections.
+#[path = "./common/rate_limit.rs"]
+pub mod rate_limit;
+
+/// Retry policies for connecting to proxies.
+#[path = "./common/retry.rs"]
+pub mod retry;
+
+/// Policy routing of requests to upstream chains.
+#[path = "./common/rules.rs"]
+pub mod rules;
+
+/// SRV record resolution of proxy names.
+#[cfg(feature = "srv")]
+#[path = "./common/srv.rs"]
+pub mod srv;
+
+/// Server loop dispatching connections to a handler.
+#[path = "./common/server.rs"]
+pub mod server;
+
+/// Socket configuration for outgoing connections.
+#[path = "./common/socket.rs"]
+pub mod socket;
+
+/// Relaying of data through established tunnels.
+#[path = "./common/tunnel.rs"]
+pub mod tunnel;
+
+/// Inspection of the raw bytes of client handshakes.
+#[path = "./common/wire.rs"]
+pub mod wire;
+
+/// SOCKS4-specific implementations.
+pub mod socks4;
+
 /// SOCKS5-specific implementations.
 pub mod socks5;
 
@@ -8,21 +8,18 @@
 #[macro_use]
 extern crate human_panic;
 
-use std::{convert::TryInto, sync::Arc};
+use std::{convert::TryInto, net::IpAddr, sync::Arc, time::Duration};
 
 use anyhow::Result;
 use clap::Parser;
 use dotenv::dotenv;
 use itertools::Itertools;
-use log::{info, LevelFilter};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Semaphore;
-use tokio::time::Instant;
+use log::{info, warn, LevelFilter};
 
-use socksx::{self, Socks5Handler, Socks6Handler, SocksHandler};
-
-// Alias for SOCKS handler with Arc and Sync/Send trait bounds
-type Handler = Arc<dyn SocksHandler + Sync + Send>;
+use socksx::{
+    self, CancellationToken, ProxyAddress, RateLimiter, Server, ServerHandler, Socks5Handler, Socks6Handler,
+    VersionDetectHandler,
+};
 
 /// CLI arguments structure
 #[derive(Parser)]
@@ -36,6 +33,10 @@ struct Args {
     #[clap(short, long, env = "DEBUG")]
     debug: bool,
 
+    /// Seconds in-flight connections are given to finish on shutdown
+    #[clap(short, long, env = "GRACE_PERIOD", default_value = "30")]
+    grace_period: u64,
+
     /// Host (IP) for the SOCKS server
     #[clap(short='H', long, env = "HOST", default_value = "0.0.0.0")]
     host: String,
@@ -44,11 +45,23 @@ struct Args {
     #[clap(short, long, env = "LIMIT", default_value = "256")]
     limit: usize,
 
+    /// New connections per second a single source IP may open (0=unlimited)
+    #[clap(long, env = "RATE", default_value = "0")]
+    rate: f64,
+
+    /// New connections a single source IP may open at once
+    #[clap(long, env = "BURST", default_value = "32")]
+    burst: u32,
+
+    /// Source IP that bypasses the rate limit
+    #[clap(long, env = "RATE_ALLOW")]
+    rate_allow: Vec<IpAddr>,
+
     /// Port for the SOCKS server
     #[clap(short, long, env = "PORT", default_value = "1080")]
     port: u16,
 
-    /// SOCKS version
+    /// SOCKS version (0=detect per connection)
     #[clap(short, long, env = "SOCKS", default_value = "6")]
     socks: u8,
 }
@@ -71,78 +84,58 @@ async fn main() -> Result<()> {
     }
 
     // Setup human-friendly panic messages
-    setup_panic!(Metadata {
-        name: "SOCKSX".into(),
-        version: env!("CARGO_PKG_VERSION").into(),
-        authors: env!("CARGO_PKG_AUTHORS").replace(":", ", ").into(),
-        homepage: env!("CARGO_PKG_HOMEPAGE").into(),
-    });
+    setup_panic!(human_panic::Metadata::new("SOCKSX", env!("CARGO_PKG_VERSION"))
+        .authors(env!("CARGO_PKG_AUTHORS").replace(':', ", "))
+        .homepage(env!("CARGO_PKG_HOMEPAGE")));
 
     // TODO: validate host
 
     // Convert and collect chain arguments
-    let chain = args.chain.iter().cloned().map(|c| c.try_into()).try_collect()?;
-
-    // Create a semaphore for connection limiting
-    let semaphore = if args.limit > 0 {
-        Some(Arc::new(Semaphore::new(args.limit)))
-    } else {
-        None
-    };
-
-    // Bind TCP listener to the specified host and port
-    let listener = TcpListener::bind(format!("{}:{}", args.host, args.port)).await?;
-    // Determine the appropriate SOCKS handler based on the specified version and restricting them to 5 and 6
-    let handler: Handler = match args.socks {
-        5 => Arc::new(Socks5Handler::new(chain)),
-        6 => Arc::new(Socks6Handler::new(chain)),
+    let chain: Vec<ProxyAddress> = args.chain.iter().cloned().map(|c| c.try_into()).try_collect()?;
+
+    // Determine the appropriate SOCKS handler based on the specified version and restricting them to 5 and 6,
+    // or detecting the version of each client
+    match args.socks {
+        0 => {
+            let handler = VersionDetectHandler::new(Socks5Handler::new(chain.clone()), Socks6Handler::new(chain));
+            serve(handler, &args).await
+        }
+        5 => serve(Socks5Handler::new(chain), &args).await,
+        6 => serve(Socks6Handler::new(chain), &args).await,
         _ => unreachable!(),
-    };
-
-    // Main event loop for accepting incoming connections
-    loop {
-        let (incoming, _) = listener.accept().await?;
-
-        let handler = Arc::clone(&handler);
-        let semaphore = semaphore.clone();
-
-        tokio::spawn(process(incoming, handler, semaphore));
     }
 }
 
-/// Asynchronously processes an incoming connection
-///
-/// # Parameters
-///
-/// - `incoming`: The incoming `TcpStream`.
-/// - `handler`: The SOCKS handler.
-/// - `semaphore`: An optional semaphore for limiting concurrent connections.
-///
-/// # Returns
-///
-/// Returns a `Result` indicating the success or failure of the operation.
-async fn process(
-    incoming: TcpStream,
-    handler: Handler,
-    semaphore: Option<Arc<Semaphore>>,
+/// Serves connections with the given handler until Ctrl-C is pressed
+async fn serve<H: ServerHandler>(
+    handler: H,
+    args: &Args,
 ) -> Result<()> {
-    let mut incoming = incoming;
-    let start_time = Instant::now();
-
-    // Handle the incoming connection based on the availability of permits
-    if let Some(semaphore) = semaphore {
-        let permit = semaphore.try_acquire();
-        if permit.is_ok() {
-            handler.accept_request(&mut incoming).await?;
-        } else {
-            handler.refuse_request(&mut incoming).await?;
-        }
-    } else {
-        handler.accept_request(&mut incoming).await?;
+    // Bind the server to the specified host and port
+    let mut server = Server::bind(format!("{}:{}", args.host, args.port), Arc::new(handler)).await?;
+    server.set_limit(args.limit);
+    server.set_grace_period(Duration::from_secs(args.grace_period));
+
+    if args.rate > 0.0 {
+        let mut rate_limiter = RateLimiter::new(args.rate, args.burst);
+        rate_limiter.set_allowlist(args.rate_allow.clone());
+        server.set_rate_limiter(rate_limiter);
     }
 
-    // Log the time taken to process the request
-    info!("Request fulfilled in {}ms", Instant::now().saturating_duration_since(start_time).as_millis());
+    // Stop accepting connections on Ctrl-C, and let in-flight connections finish
+    let shutdown = CancellationToken::new();
+    let signal = shutdown.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            info!("Received shutdown signal");
+            signal.cancel();
+        }
+    });
+
+    let aborted = server.run(shutdown).await?;
+    if aborted > 0 {
+        warn!("Aborted {} connection(s) that didn't finish within the grace period", aborted);
+    }
 
     Ok(())
 }
@@ -0,0 +1,164 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use num_traits::FromPrimitive;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+pub use s4_client::Socks4Client;
+
+use crate::addresses::Address;
+use crate::constants::*;
+use crate::SocksError;
+
+mod s4_client;
+
+/// Represents a SOCKS4 CONNECT request, or a SOCKS4a request if the destination is a domain name.
+#[derive(Clone, Debug)]
+pub struct Socks4Request {
+    pub destination: Address,
+    pub userid: Vec<u8>,
+}
+
+impl Socks4Request {
+    /// Creates a new SOCKS4 request.
+    ///
+    /// # Arguments
+    ///
+    /// * `destination` - The target address and port to connect to.
+    /// * `userid` - The user ID to present to the proxy, may be empty.
+    ///
+    /// # Returns
+    ///
+    /// A new `Socks4Request` instance.
+    pub fn new(
+        destination: Address,
+        userid: Vec<u8>,
+    ) -> Self {
+        Socks4Request { destination, userid }
+    }
+
+    /// Converts the request into bytes suitable for transmission over a SOCKS4 connection.
+    ///
+    /// Domain names are sent using the SOCKS4a extension, letting the proxy resolve them.
+    /// SOCKS4 has no way of expressing IPv6 destinations, these must be rejected beforehand.
+    ///
+    /// # Returns
+    ///
+    /// A vector of bytes representing the request.
+    pub fn into_socks_bytes(self) -> Vec<u8> {
+        let mut data = vec![SOCKS_VER_4, SOCKS_CMD_CONNECT];
+
+        let hostname = match self.destination {
+            Address::Ip(SocketAddr::V4(addr)) => {
+                data.extend(addr.port().to_be_bytes().iter());
+                data.extend(addr.ip().octets().iter());
+                None
+            }
+            Address::Ip(SocketAddr::V6(addr)) => {
+                data.extend(addr.port().to_be_bytes().iter());
+                data.extend(Ipv4Addr::UNSPECIFIED.octets().iter());
+                None
+            }
+            Address::Domainname { host, port } => {
+                data.extend(port.to_be_bytes().iter());
+                data.extend(SOCKS4A_MARKER_ADDR.octets().iter());
+                Some(host)
+            }
+        };
+
+        data.extend(self.userid);
+        data.push(SOCKS_NULL);
+
+        if let Some(hostname) = hostname {
+            data.extend(hostname.as_bytes());
+            data.push(SOCKS_NULL);
+        }
+
+        data
+    }
+}
+
+/// Represents different reply codes for SOCKS4 protocol.
+#[repr(u8)]
+#[derive(Clone, Debug, FromPrimitive, PartialEq)]
+pub enum Socks4Reply {
+    Granted = 0x5A,
+    RejectedOrFailed = 0x5B,
+    IdentdUnreachable = 0x5C,
+    IdentdMismatch = 0x5D,
+}
+
+/// Reads a SOCKS4 reply from the provided stream and returns the associated address.
+///
+/// # Arguments
+///
+/// * `stream` - The input stream where the reply will be read from.
+///
+/// # Returns
+///
+/// A `Result` containing the address associated with the reply if successful, or an error if the reply indicates failure.
+pub async fn read_reply<S>(stream: &mut S) -> Result<Address, SocksError>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut reply = [0; 8];
+    stream.read_exact(&mut reply).await?;
+
+    let [version, reply_code, port_0, port_1, ip_0, ip_1, ip_2, ip_3] = reply;
+    if version != SOCKS_REPLY_VER_4 {
+        return Err(SocksError::VersionMismatch(version));
+    }
+
+    // Rejections (91-93) are distinguished by their reply code.
+    if Socks4Reply::from_u8(reply_code) != Some(Socks4Reply::Granted) {
+        return Err(SocksError::ReplyFailure(reply_code));
+    }
+
+    let port = u16::from_be_bytes([port_0, port_1]);
+    let ip = Ipv4Addr::new(ip_0, ip_1, ip_2, ip_3);
+
+    Ok(Address::Ip(SocketAddr::new(IpAddr::V4(ip), port)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test conversion of a SOCKS4 request into a byte sequence.
+    #[test]
+    fn test_into_socks_bytes() {
+        let request = Socks4Request::new(Address::new("192.168.1.1", 80), b"bob".to_vec());
+        let expected_result: Vec<u8> = vec![4, 1, 0, 80, 192, 168, 1, 1, b'b', b'o', b'b', 0];
+        assert_eq!(request.into_socks_bytes(), expected_result);
+    }
+
+    // Test conversion of a SOCKS4a request, with a domain name, into a byte sequence.
+    #[test]
+    fn test_into_socks_bytes_socks4a() {
+        let request = Socks4Request::new(Address::new("a.io", 80), vec![]);
+        let expected_result: Vec<u8> = vec![4, 1, 0, 80, 0, 0, 0, 1, 0, b'a', b'.', b'i', b'o', 0];
+        assert_eq!(request.into_socks_bytes(), expected_result);
+    }
+
+    // Test reading a granted reply.
+    #[tokio::test]
+    async fn test_read_reply_granted() {
+        let reply: Vec<u8> = vec![0, 0x5A, 0, 80, 10, 0, 0, 1];
+        let binding = read_reply(&mut &reply[..]).await.unwrap();
+        assert_eq!(binding, Address::new("10.0.0.1", 80));
+    }
+
+    // Test that each rejection code results in a distinct error.
+    #[tokio::test]
+    async fn test_read_reply_rejected() {
+        let mut messages = vec![];
+        for code in [0x5B, 0x5C, 0x5D] {
+            let reply: Vec<u8> = vec![0, code, 0, 0, 0, 0, 0, 0];
+            let error = read_reply(&mut &reply[..]).await.unwrap_err();
+            assert!(matches!(error, SocksError::ReplyFailure(c) if c == code));
+            messages.push(error.to_string());
+        }
+
+        messages.dedup();
+        assert_eq!(messages.len(), 3);
+    }
+}
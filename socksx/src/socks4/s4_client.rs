@@ -0,0 +1,151 @@
+use std::convert::TryInto;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+use crate::{Address, SocksError};
+use crate::addresses;
+use crate::socks4::{self, Socks4Request};
+use crate::{RetryPolicy, TcpOptions};
+use crate::events::{connection_span, Instrument, record_destination, record_proxy};
+use crate::util::{connect_proxy, HAPPY_EYEBALLS_DELAY};
+
+/// Represents a SOCKS4/SOCKS4a client for connecting to legacy proxy servers.
+#[derive(Clone)]
+pub struct Socks4Client {
+    proxy_addrs: Vec<SocketAddr>,
+    userid: Option<String>,
+    happy_eyeballs_delay: Duration,
+    tcp_options: TcpOptions,
+    retry_policy: Option<RetryPolicy>,
+}
+
+impl Socks4Client {
+    /// Creates a new `Socks4Client`.
+    ///
+    /// # Arguments
+    ///
+    /// * `proxy_addr` - The address of the SOCKS4 proxy server.
+    /// * `userid` - Optional user ID to present to the proxy.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the new `Socks4Client` instance.
+    pub async fn new<A: Into<String>>(
+        proxy_addr: A,
+        userid: Option<String>,
+    ) -> Result<Self, SocksError> {
+        let proxy_addrs = crate::resolve_addrs(proxy_addr).await?;
+
+        Ok(Socks4Client {
+            proxy_addrs,
+            userid,
+            happy_eyeballs_delay: HAPPY_EYEBALLS_DELAY,
+            tcp_options: TcpOptions::default(),
+            retry_policy: None,
+        })
+    }
+
+    /// Sets the delay between staggered IPv4/IPv6 connection attempts to the proxy.
+    ///
+    /// # Arguments
+    ///
+    /// * `delay` - The connection attempt delay, defaults to 250ms.
+    pub fn set_happy_eyeballs_delay(
+        &mut self,
+        delay: Duration,
+    ) {
+        self.happy_eyeballs_delay = delay;
+    }
+
+    /// Sets the socket options applied to the connection with the proxy.
+    ///
+    /// # Arguments
+    ///
+    /// * `tcp_options` - The socket options, defaults to `TCP_NODELAY` without keepalive.
+    pub fn set_tcp_options(
+        &mut self,
+        tcp_options: TcpOptions,
+    ) {
+        self.tcp_options = tcp_options;
+    }
+
+    /// Sets the policy for retrying when connecting to the proxy fails, e.g. because it's briefly unavailable.
+    /// Only the connection to the proxy is retried, authentication failures and rejected requests fail fast.
+    ///
+    /// # Arguments
+    ///
+    /// * `retry_policy` - The retry policy, defaults to `None` (no retries).
+    pub fn set_retry_policy(
+        &mut self,
+        retry_policy: Option<RetryPolicy>,
+    ) {
+        self.retry_policy = retry_policy;
+    }
+
+    /// Establishes a SOCKS4 connection to the specified destination.
+    ///
+    /// Domain names are resolved by the proxy (SOCKS4a), IPv6 destinations are not supported.
+    ///
+    /// # Arguments
+    ///
+    /// * `destination` - The target address and port to connect to.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing a tuple with a `TcpStream` to the destination and the bound address.
+    pub async fn connect<A>(
+        &self,
+        destination: A,
+    ) -> Result<(TcpStream, Address), SocksError>
+    where
+        A: TryInto<Address>,
+        A::Error: Into<anyhow::Error>,
+    {
+        let destination = addresses::into_address(destination)?;
+        self.connect_to(destination).instrument(connection_span()).await
+    }
+
+    /// Establishes a SOCKS4 connection to the specified, already converted, destination.
+    async fn connect_to(
+        &self,
+        destination: Address,
+    ) -> Result<(TcpStream, Address), SocksError> {
+        record_destination(&destination);
+        if matches!(destination, Address::Ip(SocketAddr::V6(_))) {
+            return Err(anyhow!("SOCKS4 doesn't support IPv6 destinations.").into());
+        }
+
+        let userid = self.userid.clone().unwrap_or_default().into_bytes();
+        if userid.contains(&0) {
+            return Err(anyhow!("User ID MUST NOT contain NULL bytes.").into());
+        }
+
+        // Create SOCKS4 CONNECT request.
+        let request = Socks4Request::new(destination, userid);
+
+        let mut stream = connect_proxy(
+            &self.proxy_addrs,
+            self.happy_eyeballs_delay,
+            self.retry_policy.as_ref(),
+            &self.tcp_options,
+        )
+        .await?;
+        self.tcp_options.apply(&stream)?;
+        record_proxy(&stream.peer_addr()?);
+        info!("Connecting to socks address at {}", stream.peer_addr()?);
+
+        // Send SOCKS request information.
+        let request_bytes = request.into_socks_bytes();
+        stream.write_all(&request_bytes).await?;
+
+        // Read operation reply.
+        let binding = socks4::read_reply(&mut stream).await?;
+        debug!("Received reply, bound to {}", binding);
+
+        Ok((stream, binding))
+    }
+}
@@ -1,29 +1,49 @@
 use anyhow::Result;
-use num_traits::FromPrimitive;
+use bytes::{BufMut, BytesMut};
+use futures::FutureExt;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-pub use s5_client::Socks5Client;
+pub use s5_client::{Socks5Client, Socks5ClientBuilder};
+pub use s5_connection::Socks5Connection;
+pub use s5_gssapi::{GssapiAuthenticator, GssapiContext, GssapiStep};
+pub(crate) use s5_gssapi::accept_gssapi;
 pub use s5_handler::Socks5Handler;
+pub use s5_pool::Socks5Pool;
+pub use udp::{FragmentReassembler, Socks5UdpDatagram};
 
-use crate::addresses::{self, Address};
+use crate::addresses::Address;
 use crate::constants::*;
+use crate::socks6::options::AuthMethod;
+use crate::{Command, SocksError};
 
 mod s5_client;
+mod s5_connection;
+mod s5_gssapi;
 mod s5_handler;
+mod s5_pool;
+pub mod udp;
 
-/// Represents the different commands for SOCKS5 protocol.
+/// Represents the authentication methods a SOCKS5 client can negotiate.
 #[repr(u8)]
-#[derive(Clone, Debug, FromPrimitive, PartialEq)]
-pub enum Socks5Command {
-    Connect = 0x01,
-    Bind = 0x02,
-    UdpAssociate = 0x03,
+#[derive(Clone, Copy, Debug, FromPrimitive, PartialEq)]
+pub enum Socks5AuthMethod {
+    NoAuthentication = 0x00,
+    UsernamePassword = 0x02,
+}
+
+impl From<Socks5AuthMethod> for AuthMethod {
+    fn from(method: Socks5AuthMethod) -> Self {
+        match method {
+            Socks5AuthMethod::NoAuthentication => AuthMethod::NoAuthentication,
+            Socks5AuthMethod::UsernamePassword => AuthMethod::UsernamePassword,
+        }
+    }
 }
 
 /// Represents a SOCKS5 request.
 #[derive(Clone, Debug)]
 pub struct Socks5Request {
-    pub command: Socks5Command,
+    pub command: Command,
     pub destination: Address,
 }
 
@@ -39,11 +59,11 @@ impl Socks5Request {
     ///
     /// A new `Socks5Request` instance.
     pub fn new(
-        command: u8,
+        command: Command,
         destination: Address,
     ) -> Self {
         Socks5Request {
-            command: Socks5Command::from_u8(command).unwrap(),
+            command,
             destination,
         }
     }
@@ -52,15 +72,123 @@ impl Socks5Request {
     ///
     /// # Returns
     ///
-    /// A vector of bytes representing the request.
-    pub fn into_socks_bytes(self) -> Vec<u8> {
-        let mut data = vec![SOCKS_VER_5, SOCKS_CMD_CONNECT, SOCKS_RSV];
-        data.extend(self.destination.as_socks_bytes());
+    /// A `Result` containing the bytes representing the request, or an error if the destination can't be encoded.
+    pub fn into_socks_bytes(self) -> Result<Vec<u8>> {
+        let mut buf = BytesMut::with_capacity(3 + self.destination.encoded_len());
+        self.encode(&mut buf)?;
+
+        Ok(buf.into())
+    }
+
+    /// Appends the request, as by `into_socks_bytes`, to a caller-provided buffer.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf` - The buffer to write into, which can be reused across requests to avoid allocating per request.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success, or an error if the destination can't be encoded.
+    pub fn encode(
+        &self,
+        buf: &mut BytesMut,
+    ) -> Result<()> {
+        buf.put_slice(&[SOCKS_VER_5, self.command.to_byte(), SOCKS_RSV]);
+        self.destination.encode(buf)
+    }
 
-        data
+    /// Parses a SOCKS5 request from an in-memory buffer, without the need for a socket.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf` - The buffer starting with the request, trailing bytes are left untouched.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the parsed request and the number of bytes it occupied in `buf`.
+    pub fn parse(buf: &[u8]) -> Result<(Self, usize)> {
+        let mut remaining = buf;
+        let request = read_request(&mut remaining)
+            .now_or_never()
+            .expect("Reading from a slice never blocks.")?;
+
+        Ok((request, buf.len() - remaining.len()))
     }
 }
 
+/// Reads the authentication methods a SOCKS5 client proposes in its greeting from the provided stream.
+///
+/// # Arguments
+///
+/// * `stream` - The input stream where the greeting will be read from.
+///
+/// # Returns
+///
+/// A `Result` containing the proposed methods, or an error if the version is invalid.
+pub async fn read_auth_methods<S>(stream: &mut S) -> Result<Vec<u8>>
+    where
+        S: AsyncRead + Unpin,
+{
+    let mut greeting = [0; 2];
+    stream.read_exact(&mut greeting).await?;
+
+    let [version, nmethods] = greeting;
+    ensure!(version == SOCKS_VER_5, "Client uses a different SOCKS version: {}.", version);
+
+    let mut methods = vec![0; nmethods as usize];
+    stream.read_exact(&mut methods).await?;
+
+    Ok(methods)
+}
+
+/// Writes the authentication method selected by the server to the provided stream.
+///
+/// # Arguments
+///
+/// * `stream` - The output stream where the selection will be written.
+/// * `method` - The selected method, or `SOCKS_AUTH_NO_ACCEPTABLE_METHODS` if none of the proposed ones is acceptable.
+///
+/// # Returns
+///
+/// A `Result` indicating success or an error.
+pub async fn write_auth_method_selection<S>(
+    stream: &mut S,
+    method: u8,
+) -> Result<()>
+    where
+        S: AsyncWrite + Unpin,
+{
+    stream.write_all(&[SOCKS_VER_5, method]).await?;
+
+    Ok(())
+}
+
+/// Reads a SOCKS5 request, following the authentication sub-negotiation, from the provided stream.
+///
+/// # Arguments
+///
+/// * `stream` - The input stream where the request will be read from.
+///
+/// # Returns
+///
+/// A `Result` containing the request, or an error if the version is invalid.
+/// Unknown commands are rejected with `SocksError::CommandNotSupported`.
+pub async fn read_request<S>(stream: &mut S) -> Result<Socks5Request>
+    where
+        S: AsyncRead + Unpin,
+{
+    let mut request = [0; 3];
+    stream.read_exact(&mut request).await?;
+
+    let [version, command, _] = request;
+    ensure!(version == SOCKS_VER_5, "Client uses a different SOCKS version: {}.", version);
+    let command = Command::from_byte(command).ok_or(SocksError::CommandNotSupported(command))?;
+
+    let destination = Address::from_socks_bytes(stream).await?;
+
+    Ok(Socks5Request::new(command, destination))
+}
+
 /// Represents different reply codes for SOCKS5 protocol.
 #[repr(u8)]
 #[derive(Clone, Debug, FromPrimitive, PartialEq)]
@@ -77,43 +205,68 @@ pub enum Socks5Reply {
     ConnectionAttemptTimeOut = 0x09,
 }
 
+impl Socks5Reply {
+    /// Selects the reply that tells the client why the destination couldn't be reached.
+    ///
+    /// # Arguments
+    ///
+    /// * `error` - The error connecting to the destination failed with.
+    ///
+    /// # Returns
+    ///
+    /// The reply describing the error, or `GeneralFailure` if there's no more specific one.
+    pub fn from_dial_error(error: &anyhow::Error) -> Self {
+        use std::io::ErrorKind::*;
+
+        let kind = match error.downcast_ref::<SocksError>() {
+            Some(SocksError::Io(error)) => Some(error.kind()),
+            _ => error.downcast_ref::<std::io::Error>().map(|e| e.kind()),
+        };
+
+        match kind {
+            Some(ConnectionRefused) => Socks5Reply::ConnectionRefused,
+            Some(HostUnreachable) => Socks5Reply::HostUnreachable,
+            Some(NetworkUnreachable) => Socks5Reply::NetworkUnreachable,
+            Some(TimedOut) => Socks5Reply::TTLExpired,
+            _ => Socks5Reply::GeneralFailure,
+        }
+    }
+}
+
 /// Writes a SOCKS5 reply to the provided stream.
 ///
 /// # Arguments
 ///
 /// * `stream` - The output stream where the reply will be written.
 /// * `reply` - The SOCKS5 reply code to be written.
+/// * `binding` - The bound address, e.g. the local address of the connection with the destination.
+///   Replies that don't establish a connection usually carry the unspecified address, `0.0.0.0:0`.
 ///
 /// # Returns
 ///
-/// A `Result` indicating success or an error.
+/// A `Result` indicating success or an error, e.g. if the bound address can't be encoded.
 pub async fn write_reply<S>(
     stream: &mut S,
     reply: Socks5Reply,
+    binding: &Address,
 ) -> Result<()>
     where
         S: AsyncWrite + Unpin,
 {
-    let reply = [
-        SOCKS_VER_5,
-        reply as u8,
-        SOCKS_RSV,
-        SOCKS_ATYP_IPV4,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-    ];
-
-    stream.write(&reply).await?;
+    let mut data = BytesMut::with_capacity(3 + binding.encoded_len());
+    data.put_slice(&[SOCKS_VER_5, reply as u8, SOCKS_RSV]);
+    binding.encode(&mut data)?;
+
+    stream.write_all(&data).await?;
 
     Ok(())
 }
 
 /// Reads a SOCKS5 reply from the provided stream and returns the associated address.
 ///
+/// The address is returned as is, so it is `0.0.0.0` or `::` if the proxy means the address of the connection
+/// itself, see `Address::replace_unspecified`. `Socks5Client` substitutes the proxy's address in that case.
+///
 /// # Arguments
 ///
 /// * `stream` - The input stream where the reply will be read from.
@@ -121,7 +274,7 @@ pub async fn write_reply<S>(
 /// # Returns
 ///
 /// A `Result` containing the address associated with the reply if successful, or an error if the reply indicates failure.
-pub async fn read_reply<S>(stream: &mut S) -> Result<Address>
+pub async fn read_reply<S>(stream: &mut S) -> Result<Address, SocksError>
     where
         S: AsyncRead + Unpin,
 {
@@ -129,13 +282,96 @@ pub async fn read_reply<S>(stream: &mut S) -> Result<Address>
     stream.read_exact(&mut operation_reply).await?;
 
     let reply_code = operation_reply[1];
-    ensure!(
-        reply_code == SOCKS_REP_SUCCEEDED,
-        "CONNECT operation failed: {}",
-        reply_code
-    );
+    if reply_code != SOCKS_REP_SUCCEEDED {
+        return Err(SocksError::ReplyFailure(reply_code));
+    }
 
-    let binding = addresses::read_address(stream).await?;
+    let binding = Address::from_socks_bytes(stream).await?;
 
     Ok(binding)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Tests that a request is parsed from a buffer, and trailing bytes are not consumed.
+    #[test]
+    fn test_parse_request() -> Result<()> {
+        let mut buf = Socks5Request::new(Command::Bind, Address::new("example.com", 443)).into_socks_bytes()?;
+        let length = buf.len();
+        buf.extend(b"GET /");
+
+        let (request, consumed) = Socks5Request::parse(&buf)?;
+        assert_eq!(request.command, Command::Bind);
+        assert_eq!(request.destination, Address::new("example.com", 443));
+        assert_eq!(consumed, length);
+
+        Ok(())
+    }
+
+    // Tests that a reply is read completely, even if every byte arrives separately.
+    #[tokio::test]
+    async fn test_read_reply_one_byte_at_a_time() -> Result<()> {
+        use crate::mock::Trickle;
+
+        let binding = Address::new("a".repeat(255), 1080);
+        let mut reply = vec![];
+        write_reply(&mut reply, Socks5Reply::Success, &binding).await?;
+
+        assert_eq!(read_reply(&mut Trickle::new(reply)).await?, binding);
+
+        Ok(())
+    }
+
+    // Tests that an IPv6 bound address is read whole, leaving what follows the reply unread.
+    #[tokio::test]
+    async fn test_read_reply_ipv6_binding() -> Result<()> {
+        let mut reply = vec![SOCKS_VER_5, SOCKS_REP_SUCCEEDED, SOCKS_RSV, SOCKS_ATYP_IPV6];
+        reply.extend([0x20, 0x01, 0x0d, 0xb8].iter());
+        reply.extend([0; 11].iter());
+        reply.extend([0x01, 0x04, 0x38].iter());
+        reply.extend(b"data");
+
+        let mut stream = &reply[..];
+        let binding = read_reply(&mut stream).await?;
+        assert_eq!(binding, Address::new("2001:db8::1", 1080));
+        assert!(matches!(binding, Address::Ip(addr) if addr.is_ipv6()));
+        assert_eq!(stream, b"data");
+
+        Ok(())
+    }
+
+    // Tests that the server side of the handshake is framed like the client expects it.
+    #[tokio::test]
+    async fn test_server_toolkit() -> Result<()> {
+        let mut greeting: &[u8] = &[5, 2, 0, 2];
+        assert_eq!(read_auth_methods(&mut greeting).await?, vec![0, 2]);
+        assert!(read_auth_methods(&mut &[4, 1, 0][..]).await.is_err());
+
+        let mut selection = vec![];
+        write_auth_method_selection(&mut selection, SOCKS_AUTH_USERNAME_PASSWORD).await?;
+        assert_eq!(selection, vec![5, 2]);
+
+        let mut reply = vec![];
+        write_reply(&mut reply, Socks5Reply::Success, &Address::new("192.0.2.1", 1080)).await?;
+        assert_eq!(reply, vec![5, 0, 0, 1, 192, 0, 2, 1, 4, 56]);
+        assert_eq!(read_reply(&mut &reply[..]).await?, Address::new("192.0.2.1", 1080));
+
+        Ok(())
+    }
+
+    // Tests that malformed requests are rejected.
+    #[test]
+    fn test_parse_malformed_request() {
+        // Truncated domain name.
+        assert!(Socks5Request::parse(&[5, 1, 0, 3, 11, b'e', b'x']).is_err());
+        // Unknown address type.
+        assert!(Socks5Request::parse(&[5, 1, 0, 9, 0, 0]).is_err());
+        // Unknown command.
+        let error = Socks5Request::parse(&[5, 9, 0, 1, 127, 0, 0, 1, 0, 80]).unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(SocksError::CommandNotSupported(9))));
+        // Different SOCKS version.
+        assert!(Socks5Request::parse(&[4, 1, 0, 1, 127, 0, 0, 1, 0, 80]).is_err());
+    }
+}
@@ -1,22 +1,51 @@
 use std::convert::TryInto;
-use std::net::SocketAddr;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use log::info;
 use anyhow::Result;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio_util::sync::CancellationToken;
+#[cfg(feature = "tls")]
+use tokio_rustls::{client::TlsStream, rustls::pki_types::ServerName, rustls::ClientConfig, rustls::RootCertStore, TlsConnector};
 
-use crate::{Address, constants::*, Credentials};
-use crate::socks5::{self, Socks5Request};
+use crate::{Address, Command, constants::*, Credentials, HandshakeStage, SocksError};
+use crate::addresses;
+use crate::{BoxedStream, ProxyHeader, RetryPolicy, TcpOptions, WireHook};
+use crate::events::{connection_span, Instrument, record_destination, record_proxy};
+use crate::wire::WireTap;
+use crate::util::{connect_proxy, with_handshake_deadline, with_reply_timeout, HAPPY_EYEBALLS_DELAY};
+use crate::socks5::{self, Socks5AuthMethod, Socks5Connection, Socks5Request};
+use crate::socks6::options::AuthMethod;
 
 /// Represents a SOCKS5 client for connecting to proxy servers.
 #[derive(Clone)]
 pub struct Socks5Client {
-    proxy_addr: SocketAddr,
+    proxy_addrs: Vec<SocketAddr>,
     credentials: Option<Credentials>,
+    auth_methods: Option<Vec<Socks5AuthMethod>>,
+    happy_eyeballs_delay: Duration,
+    tcp_options: TcpOptions,
+    retry_policy: Option<RetryPolicy>,
+    resolve_locally: bool,
+    strict_version: bool,
+    reply_timeout: Option<Duration>,
+    handshake_deadline: Option<Duration>,
+    proxy_header: Option<ProxyHeader>,
+    on_wire: Option<WireHook>,
+    #[cfg(feature = "tls")]
+    tls_config: Option<Arc<ClientConfig>>,
 }
 
 impl Socks5Client {
+    /// Returns a builder, to configure a `Socks5Client` with chained setters.
+    pub fn builder() -> Socks5ClientBuilder {
+        Socks5ClientBuilder::new()
+    }
+
     /// Creates a new `Socks5Client`.
     ///
     /// # Arguments
@@ -30,13 +59,215 @@ impl Socks5Client {
     pub async fn new<A: Into<String>>(
         proxy_addr: A,
         credentials: Option<Credentials>,
-    ) -> Result<Self> {
-        let proxy_addr = crate::resolve_addr(proxy_addr).await?;
+    ) -> Result<Self, SocksError> {
+        let proxy_addrs = crate::resolve_addrs(proxy_addr).await?;
+
+        Ok(Self::with_proxy_addrs(proxy_addrs, credentials))
+    }
+
+    /// Creates a new `Socks5Client` for a proxy whose address is already resolved, without a DNS lookup.
+    ///
+    /// # Arguments
+    ///
+    /// * `proxy_addr` - The socket address of the SOCKS5 proxy server.
+    /// * `credentials` - Optional SOCKS5 authentication credentials.
+    ///
+    /// # Returns
+    ///
+    /// The new `Socks5Client` instance.
+    pub fn from_socket_addr(
+        proxy_addr: SocketAddr,
+        credentials: Option<Credentials>,
+    ) -> Self {
+        Self::with_proxy_addrs(vec![proxy_addr], credentials)
+    }
+
+    /// Creates a new `Socks5Client` for a proxy whose addresses are already resolved, e.g. cached ones, which are
+    /// tried in order (racing IPv4 and IPv6).
+    ///
+    /// # Arguments
+    ///
+    /// * `proxy_addrs` - The socket addresses of the SOCKS5 proxy server.
+    /// * `credentials` - Optional SOCKS5 authentication credentials.
+    ///
+    /// # Returns
+    ///
+    /// The new `Socks5Client` instance.
+    pub fn from_socket_addrs(
+        proxy_addrs: Vec<SocketAddr>,
+        credentials: Option<Credentials>,
+    ) -> Self {
+        Self::with_proxy_addrs(proxy_addrs, credentials)
+    }
 
-        Ok(Socks5Client {
-            proxy_addr,
+    fn with_proxy_addrs(
+        proxy_addrs: Vec<SocketAddr>,
+        credentials: Option<Credentials>,
+    ) -> Self {
+        Socks5Client {
+            proxy_addrs,
             credentials,
-        })
+            auth_methods: None,
+            happy_eyeballs_delay: HAPPY_EYEBALLS_DELAY,
+            tcp_options: TcpOptions::default(),
+            retry_policy: None,
+            resolve_locally: false,
+            strict_version: true,
+            reply_timeout: None,
+            handshake_deadline: None,
+            proxy_header: None,
+            on_wire: None,
+            #[cfg(feature = "tls")]
+            tls_config: None,
+        }
+    }
+
+    /// Returns the credentials used to authenticate with the proxy.
+    pub fn credentials(&self) -> Option<&Credentials> {
+        self.credentials.as_ref()
+    }
+
+    /// Sets the credentials used to authenticate with the proxy, e.g. to rotate a password.
+    /// Connections that are already established are unaffected, they keep using the old credentials.
+    ///
+    /// # Arguments
+    ///
+    /// * `credentials` - The new credentials, or `None` to stop authenticating.
+    pub fn set_credentials(
+        &mut self,
+        credentials: Option<Credentials>,
+    ) {
+        self.credentials = credentials;
+    }
+
+    /// Sets the authentication methods offered to the proxy, in order of preference.
+    /// Offering only username/password fails closed, a proxy that would let the client through without
+    /// authentication can't downgrade it.
+    ///
+    /// # Arguments
+    ///
+    /// * `auth_methods` - The offered methods, or `None` (the default) to offer no authentication, and
+    ///   username/password if credentials are set.
+    pub fn set_auth_methods(
+        &mut self,
+        auth_methods: Option<Vec<Socks5AuthMethod>>,
+    ) {
+        self.auth_methods = auth_methods;
+    }
+
+    /// Sets the delay between staggered IPv4/IPv6 connection attempts to the proxy.
+    ///
+    /// # Arguments
+    ///
+    /// * `delay` - The connection attempt delay, defaults to 250ms.
+    pub fn set_happy_eyeballs_delay(
+        &mut self,
+        delay: Duration,
+    ) {
+        self.happy_eyeballs_delay = delay;
+    }
+
+    /// Sets the socket options applied to the connection with the proxy.
+    ///
+    /// # Arguments
+    ///
+    /// * `tcp_options` - The socket options, defaults to `TCP_NODELAY` without keepalive.
+    pub fn set_tcp_options(
+        &mut self,
+        tcp_options: TcpOptions,
+    ) {
+        self.tcp_options = tcp_options;
+    }
+
+    /// Sets the PROXY protocol header that is sent to the proxy before the handshake, e.g. to pass on the address of
+    /// the original client when chaining.
+    ///
+    /// # Arguments
+    ///
+    /// * `proxy_header` - The header, defaults to `None` (no header is sent).
+    pub fn set_proxy_header(
+        &mut self,
+        proxy_header: Option<ProxyHeader>,
+    ) {
+        self.proxy_header = proxy_header;
+    }
+
+    /// Sets a callback that is passed the raw bytes of every handshake, as they're sent to and received from the
+    /// proxy, e.g. to diagnose interoperability issues without a packet capture. Relayed data isn't passed.
+    ///
+    /// # Arguments
+    ///
+    /// * `on_wire` - The callback, defaults to `None` (the handshake isn't inspected).
+    pub fn set_on_wire(
+        &mut self,
+        on_wire: Option<WireHook>,
+    ) {
+        self.on_wire = on_wire;
+    }
+
+    /// Sets the policy for retrying when connecting to the proxy fails, e.g. because it's briefly unavailable.
+    /// Only the connection to the proxy is retried, authentication failures and rejected requests fail fast.
+    ///
+    /// # Arguments
+    ///
+    /// * `retry_policy` - The retry policy, defaults to `None` (no retries).
+    pub fn set_retry_policy(
+        &mut self,
+        retry_policy: Option<RetryPolicy>,
+    ) {
+        self.retry_policy = retry_policy;
+    }
+
+    /// Sets the time the proxy is given to send its operation reply, once the request is sent.
+    /// This is independent of how long connecting to the proxy may take.
+    ///
+    /// # Arguments
+    ///
+    /// * `reply_timeout` - The reply timeout, defaults to `None` (wait indefinitely).
+    pub fn set_reply_timeout(
+        &mut self,
+        reply_timeout: Option<Duration>,
+    ) {
+        self.reply_timeout = reply_timeout;
+    }
+
+    /// Sets the time the whole handshake is given, from connecting to the proxy until its reply is read. Unlike the
+    /// reply timeout, this also bounds a proxy that keeps the handshake going by sending it slowly.
+    ///
+    /// # Arguments
+    ///
+    /// * `handshake_deadline` - The deadline, defaults to `None` (no deadline).
+    pub fn set_handshake_deadline(
+        &mut self,
+        handshake_deadline: Option<Duration>,
+    ) {
+        self.handshake_deadline = handshake_deadline;
+    }
+
+    /// Sets whether domain name destinations are resolved by the client, rather than by the proxy.
+    /// Resolving locally leaks DNS queries outside of the proxy, so it's only useful for proxies without a resolver.
+    ///
+    /// # Arguments
+    ///
+    /// * `resolve_locally` - Whether to resolve locally, defaults to `false` (sending the domain name to the proxy).
+    pub fn set_resolve_locally(
+        &mut self,
+        resolve_locally: bool,
+    ) {
+        self.resolve_locally = resolve_locally;
+    }
+
+    /// Sets whether the version byte of the method selection must be exactly 5. When it's not strict, a version byte
+    /// that buggy proxies are known to send (0 or 1) is logged and accepted, while any other one is still rejected.
+    ///
+    /// # Arguments
+    ///
+    /// * `strict_version` - Whether to be strict, defaults to `true`.
+    pub fn set_strict_version(
+        &mut self,
+        strict_version: bool,
+    ) {
+        self.strict_version = strict_version;
     }
 
     /// Establishes a SOCKS5 connection to the specified destination.
@@ -51,122 +282,1148 @@ impl Socks5Client {
     pub async fn connect<A>(
         &self,
         destination: A,
-    ) -> Result<(TcpStream, Address)>
+    ) -> Result<(TcpStream, Address), SocksError>
+        where
+            A: TryInto<Address>,
+            A::Error: Into<anyhow::Error>,
+    {
+        let (stream, binding, _) = self.connect_negotiated(destination).await?;
+
+        Ok((stream, binding))
+    }
+
+    /// Establishes a SOCKS5 connection to the specified destination, as by `connect`, but returns the stream boxed.
+    /// This lets it be held alongside streams of other types, e.g. TLS-wrapped or direct connections.
+    ///
+    /// # Arguments
+    ///
+    /// * `destination` - The target address and port to connect to.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing a tuple with a boxed stream to the destination and the bound address.
+    pub async fn connect_boxed<A>(
+        &self,
+        destination: A,
+    ) -> Result<(BoxedStream, Address), SocksError>
+        where
+            A: TryInto<Address>,
+            A::Error: Into<anyhow::Error>,
+    {
+        let (stream, binding) = self.connect(destination).await?;
+
+        Ok((Box::pin(stream), binding))
+    }
+
+    /// Establishes a SOCKS5 connection to the specified destination, as by `connect`, but returns it wrapped in a
+    /// `Socks5Connection`. This counts the data transferred, which `Socks5Connection::close` returns.
+    ///
+    /// # Arguments
+    ///
+    /// * `destination` - The target address and port to connect to.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the `Socks5Connection` to the destination.
+    pub async fn connect_tracked<A>(
+        &self,
+        destination: A,
+    ) -> Result<Socks5Connection, SocksError>
+        where
+            A: TryInto<Address>,
+            A::Error: Into<anyhow::Error>,
+    {
+        let (stream, binding) = self.connect(destination).await?;
+
+        Ok(Socks5Connection::new(stream, binding))
+    }
+
+    /// Establishes a SOCKS5 connection to the specified destination, and reports the negotiated authentication method.
+    ///
+    /// # Arguments
+    ///
+    /// * `destination` - The target address and port to connect to.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing a tuple with a `TcpStream` to the destination, the bound address, and the authentication
+    /// method selected by the proxy.
+    pub async fn connect_negotiated<A>(
+        &self,
+        destination: A,
+    ) -> Result<(TcpStream, Address, Socks5AuthMethod), SocksError>
         where
-            A: TryInto<Address, Error = anyhow::Error>,
+            A: TryInto<Address>,
+            A::Error: Into<anyhow::Error>,
     {
-        if let Some(Credentials { username, password }) = &self.credentials {
-            ensure!(username.len() > 255, "Username MUST NOT be larger than 255 bytes.");
-            ensure!(password.len() > 255, "Password MUST NOT be larger than 255 bytes.");
+        self.connect_to(addresses::into_address(destination)?, None).instrument(connection_span()).await
+    }
+
+    /// Establishes a SOCKS5 connection to the specified destination, unless the token is cancelled first.
+    /// On cancellation, the connection with the proxy is shut down, rather than left with a partial handshake.
+    ///
+    /// # Arguments
+    ///
+    /// * `destination` - The target address and port to connect to.
+    /// * `token` - The token that cancels connecting.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing a tuple with a `TcpStream` to the destination and the bound address, or
+    /// `SocksError::Cancelled` if the token was cancelled first.
+    pub async fn connect_with_cancel<A>(
+        &self,
+        destination: A,
+        token: CancellationToken,
+    ) -> Result<(TcpStream, Address), SocksError>
+        where
+            A: TryInto<Address>,
+            A::Error: Into<anyhow::Error>,
+    {
+        let destination = addresses::into_address(destination)?;
+        let (stream, binding, _) = self.connect_to(destination, Some(&token)).instrument(connection_span()).await?;
+
+        Ok((stream, binding))
+    }
+
+    /// Establishes a SOCKS5 connection to the specified destination, and completes a TLS handshake over the tunnel.
+    ///
+    /// # Arguments
+    ///
+    /// * `destination` - The target address and port to connect to.
+    /// * `server_name` - The name to present (SNI) and verify, defaults to the destination host.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the TLS stream to the destination.
+    #[cfg(feature = "tls")]
+    pub async fn connect_tls<A>(
+        &self,
+        destination: A,
+        server_name: Option<String>,
+    ) -> Result<TlsStream<TcpStream>, SocksError>
+        where
+            A: TryInto<Address>,
+            A::Error: Into<anyhow::Error>,
+    {
+        let destination = addresses::into_address(destination)?;
+        let server_name = server_name.unwrap_or_else(|| default_server_name(&destination));
+        let server_name: ServerName<'static> = server_name.try_into().map_err(anyhow::Error::from)?;
+
+        let (stream, _, _) = self.connect_to(destination, None).instrument(connection_span()).await?;
+
+        let tls_config = self.tls_config.clone().unwrap_or_else(default_tls_config);
+        let stream = TlsConnector::from(tls_config).connect(server_name, stream).await?;
+
+        Ok(stream)
+    }
+
+    /// Sets the TLS configuration used by `connect_tls`.
+    ///
+    /// # Arguments
+    ///
+    /// * `tls_config` - The configuration, defaults to verifying against the Mozilla root certificates.
+    #[cfg(feature = "tls")]
+    pub fn set_tls_config(
+        &mut self,
+        tls_config: Option<Arc<ClientConfig>>,
+    ) {
+        self.tls_config = tls_config;
+    }
+
+    /// Checks that the SOCKS5 proxy is up, by connecting and negotiating the authentication method only.
+    /// If username/password authentication is selected, the credentials are checked as well. No request is sent,
+    /// the connection is closed right after.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the time it took to connect and be authenticated, e.g. to pick the fastest proxy.
+    pub async fn probe(&self) -> Result<Duration, SocksError> {
+        if let Some(credentials) = &self.credentials {
+            credentials.validate()?;
         }
+        let auth_methods = self.offered_auth_methods()?;
+
+        let start = Instant::now();
+        let mut stream = connect_proxy(
+            &self.proxy_addrs,
+            self.happy_eyeballs_delay,
+            self.retry_policy.as_ref(),
+            &self.tcp_options,
+        )
+        .await?;
+
+        let negotiated = async {
+            let mut stream = WireTap::new(&mut stream, self.on_wire.as_ref());
+            let auth_method = self
+                .negotiate_auth_method(&mut stream, &auth_methods)
+                .await
+                .map_err(|error| error.closed_during(HandshakeStage::Negotiation))?;
+            if let (Socks5AuthMethod::UsernamePassword, Some(credentials)) = (auth_method, &self.credentials) {
+                self.authenticate(&mut stream, credentials)
+                    .await
+                    .map_err(|error| error.closed_during(HandshakeStage::Authentication))?;
+            }
+
+            Ok(())
+        };
+        with_reply_timeout(negotiated, self.reply_timeout).await?;
+        let elapsed = start.elapsed();
+        stream.shutdown().await.ok();
+
+        Ok(elapsed)
+    }
+
+    /// Establishes a SOCKS5 connection to the specified, already converted, destination.
+    /// Connecting is abandoned once the token, if any, is cancelled, or the handshake deadline passed.
+    async fn connect_to(
+        &self,
+        destination: Address,
+        token: Option<&CancellationToken>,
+    ) -> Result<(TcpStream, Address, Socks5AuthMethod), SocksError> {
+        with_handshake_deadline(self.connect_within_deadline(destination, token), self.handshake_deadline).await
+    }
+
+    /// Establishes a SOCKS5 connection, see `connect_to`, regardless of the handshake deadline.
+    async fn connect_within_deadline(
+        &self,
+        destination: Address,
+        token: Option<&CancellationToken>,
+    ) -> Result<(TcpStream, Address, Socks5AuthMethod), SocksError> {
+        record_destination(&destination);
+
+        // Credentials may have been constructed from their fields, rather than checked by `Credentials::new`.
+        if let Some(credentials) = &self.credentials {
+            credentials.validate()?;
+        }
+        let auth_methods = self.offered_auth_methods()?;
+
+        // Domain names are sent as-is (ATYP 0x03), unless asked to resolve them here.
+        let destination = match destination {
+            Address::Domainname { .. } if self.resolve_locally => {
+                let resolved = unless_cancelled(crate::resolve_addr(destination.to_string()), token).await;
+                Address::Ip(resolved.ok_or(SocksError::Cancelled)??)
+            }
+            destination => destination,
+        };
 
         // Create SOCKS5 CONNECT request.
-        let request = Socks5Request::new(SOCKS_CMD_CONNECT, destination.try_into()?);
+        let request = Socks5Request::new(Command::Connect, destination);
 
-        let mut stream = TcpStream::connect(&self.proxy_addr).await?;
+        let dialed = connect_proxy(
+            &self.proxy_addrs,
+            self.happy_eyeballs_delay,
+            self.retry_policy.as_ref(),
+            &self.tcp_options,
+        );
+        let mut stream = unless_cancelled(dialed, token).await.ok_or(SocksError::Cancelled)??;
+        self.tcp_options.apply(&stream)?;
+        record_proxy(&stream.peer_addr()?);
         info!("Connecting to socks address at {}", stream.peer_addr()?);
-        
-        // Enter authentication negotiation.
-        let auth_method = self.negotiate_auth_method(&mut stream).await?;
-        if auth_method == SOCKS_AUTH_USERNAME_PASSWORD {
-            if let Some(credentials) = &self.credentials {
-                self.authenticate(&mut stream, credentials).await?;
-            } else {
-                unreachable!();
+
+        // The PROXY protocol header precedes anything else the proxy receives.
+        if let Some(proxy_header) = &self.proxy_header {
+            WireTap::new(&mut stream, self.on_wire.as_ref()).write_all(&proxy_header.to_bytes()).await?;
+        }
+
+        let handshaken = unless_cancelled(self.handshake(&mut stream, request, &auth_methods), token).await;
+        match handshaken {
+            Some(result) => {
+                let (binding, auth_method) = result?;
+                Ok((stream, binding, auth_method))
             }
+            None => {
+                // Close the connection, so the proxy doesn't keep waiting for the remainder of the handshake.
+                stream.shutdown().await.ok();
+                Err(SocksError::Cancelled)
+            }
+        }
+    }
+
+    /// Negotiates authentication and sends the request over an established connection with the proxy.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - The TCP stream connected to the proxy server.
+    /// * `request` - The request to send once authenticated.
+    /// * `auth_methods` - The authentication methods to offer, in order of preference.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the bound address and the authentication method selected by the proxy.
+    async fn handshake(
+        &self,
+        stream: &mut TcpStream,
+        request: Socks5Request,
+        auth_methods: &[Socks5AuthMethod],
+    ) -> Result<(Address, Socks5AuthMethod), SocksError> {
+        let proxy_ip = stream.peer_addr()?.ip();
+        let mut stream = WireTap::new(stream, self.on_wire.as_ref());
+        self.handshake_over(&mut stream, request, auth_methods, proxy_ip).await
+    }
+
+    /// Conducts the handshake over a stream with the proxy, see `handshake`.
+    async fn handshake_over<S>(
+        &self,
+        stream: &mut S,
+        request: Socks5Request,
+        auth_methods: &[Socks5AuthMethod],
+        proxy_ip: IpAddr,
+    ) -> Result<(Address, Socks5AuthMethod), SocksError>
+        where
+            S: AsyncRead + AsyncWrite + Unpin,
+    {
+        // Enter authentication negotiation.
+        let auth_method = self
+            .negotiate_auth_method(stream, auth_methods)
+            .await
+            .map_err(|error| error.closed_during(HandshakeStage::Negotiation))?;
+        debug!("Proxy selected authentication method: {:?}", auth_method);
+        if auth_method == Socks5AuthMethod::UsernamePassword {
+            // The proxy selected username/password, so it demands the credentials even if they're missing.
+            let credentials = self.credentials.as_ref().ok_or(SocksError::CredentialsRequired)?;
+            self.authenticate(stream, credentials)
+                .await
+                .map_err(|error| error.closed_during(HandshakeStage::Authentication))?;
+            debug!("Authenticated with the proxy");
         }
 
         // Send SOCKS request information.
-        let request_bytes = request.into_socks_bytes();
-        stream.write(&request_bytes).await?;
+        let request_bytes = request.into_socks_bytes()?;
+        stream.write_all(&request_bytes).await?;
 
         // Read operation reply.
-        let binding = socks5::read_reply(&mut stream).await?;
+        let binding = with_reply_timeout(socks5::read_reply(stream), self.reply_timeout)
+            .await
+            .map_err(|error| error.closed_during(HandshakeStage::Reply))?;
+        // An unspecified bound address refers to the proxy itself, substitute it so it can be advertised (e.g. BIND).
+        let binding = binding.replace_unspecified(proxy_ip);
+        debug!("Received reply, bound to {}", binding);
 
-        Ok((stream, binding))
+        Ok((binding, auth_method))
+    }
+
+    /// Returns the authentication methods to offer, checking that they can be used.
+    fn offered_auth_methods(&self) -> Result<Vec<Socks5AuthMethod>, SocksError> {
+        let auth_methods = match &self.auth_methods {
+            Some(auth_methods) => auth_methods.clone(),
+            None if self.credentials.is_some() => {
+                vec![Socks5AuthMethod::NoAuthentication, Socks5AuthMethod::UsernamePassword]
+            }
+            None => vec![Socks5AuthMethod::NoAuthentication],
+        };
+
+        if auth_methods.is_empty() || auth_methods.len() > 255 {
+            return Err(anyhow!("Between 1 and 255 authentication methods MUST be offered.").into());
+        }
+        if auth_methods.contains(&Socks5AuthMethod::UsernamePassword) && self.credentials.is_none() {
+            return Err(anyhow!("Username/password authentication is offered, but no credentials are set.").into());
+        }
+
+        Ok(auth_methods)
     }
 
     /// Negotiates the SOCKS5 authentication method with the proxy server.
     ///
     /// # Arguments
     ///
-    /// * `stream` - The TCP stream connected to the proxy server.
+    /// * `stream` - The stream connected to the proxy server.
+    /// * `auth_methods` - The authentication methods to offer, in order of preference.
     ///
     /// # Returns
     ///
     /// A `Result` containing the selected authentication method.
-    async fn negotiate_auth_method(
+    async fn negotiate_auth_method<S>(
         &self,
-        stream: &mut TcpStream,
-    ) -> Result<u8> {
-        let mut request = vec![SOCKS_VER_5, 0x01, SOCKS_AUTH_NOT_REQUIRED];
-        if self.credentials.is_some() {
-            request[1] = 0x02;
-            request.push(SOCKS_AUTH_USERNAME_PASSWORD);
-        }
+        stream: &mut S,
+        auth_methods: &[Socks5AuthMethod],
+    ) -> Result<Socks5AuthMethod, SocksError>
+        where
+            S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut request = vec![SOCKS_VER_5, auth_methods.len() as u8];
+        request.extend(auth_methods.iter().map(|method| *method as u8));
 
-        stream.write(&request).await?;
+        stream.write_all(&request).await?;
 
         let mut reply = [0; 2];
         stream.read_exact(&mut reply).await?;
 
         let socks_version = reply[0];
         if socks_version != SOCKS_VER_5 {
-            bail!("Proxy uses a different SOCKS version: {}.", socks_version);
+            // Some proxies send 0 (as in a SOCKS4 reply) or 1 (as in the username/password subnegotiation).
+            if self.strict_version || !matches!(socks_version, 0x00 | 0x01) {
+                return Err(SocksError::VersionMismatch(socks_version));
+            }
+
+            warn!("Proxy replied with SOCKS version {}, proceeding as SOCKS5", socks_version);
         }
 
-        let auth_method = reply[1];
-        match auth_method {
-            0x00 => Ok(auth_method),
-            0x02 => {
-                if self.credentials.is_none() {
-                    bail!("Proxy demands authentication, but no credentials are provided.");
-                } else {
-                    Ok(auth_method)
-                }
+        let auth_method = match reply[1] {
+            0x00 => Socks5AuthMethod::NoAuthentication,
+            0x02 => Socks5AuthMethod::UsernamePassword,
+            0xFF => {
+                let offered = auth_methods.iter().map(|method| AuthMethod::from(*method)).collect();
+                return Err(SocksError::AuthMethodRejected { offered });
             }
-            0xFF => bail!("Proxy did not accept authentication method."),
-            _ => bail!("Proxy proposed unsupported authentication method: {}.", auth_method),
+            auth_method => return Err(SocksError::UnsupportedAuthMethod(auth_method)),
+        };
+
+        // Accepting a method that wasn't offered would defeat failing closed.
+        if !auth_methods.contains(&auth_method) {
+            return Err(SocksError::UnofferedAuthMethod(auth_method));
         }
+
+        Ok(auth_method)
     }
 
     /// Authenticates with the SOCKS5 proxy using the provided credentials.
     ///
     /// # Arguments
     ///
-    /// * `stream` - The TCP stream connected to the proxy server.
+    /// * `stream` - The stream connected to the proxy server.
     /// * `credentials` - The authentication credentials.
     ///
     /// # Returns
     ///
     /// A `Result` indicating success or an error if authentication fails.
-    async fn authenticate(
+    async fn authenticate<S>(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut S,
         credentials: &Credentials,
-    ) -> Result<()> {
+    ) -> Result<(), SocksError>
+        where
+            S: AsyncRead + AsyncWrite + Unpin,
+    {
         let mut request = vec![SOCKS_AUTH_VER];
         request.extend(credentials.as_socks_bytes());
 
-        stream.write(&request).await?;
+        stream.write_all(&request).await?;
 
         let mut reply = [0; 2];
         stream.read_exact(&mut reply).await?;
 
         let auth_version = reply[0];
         if auth_version != SOCKS_AUTH_VER {
-            bail!(
-                "Proxy uses a different authentication method version: {}.",
-                auth_version
-            );
+            return Err(SocksError::AuthVersionMismatch(auth_version));
         }
 
-        // Check if status indicates success. If not, bail to close the connection.
+        // Check if status indicates success. If not, return an error to close the connection.
         let status = reply[1];
         if status != SOCKS_AUTH_SUCCESS {
-            bail!("Authentication with the provided credentials failed.");
+            return Err(SocksError::AuthFailed);
+        }
+
+        Ok(())
+    }
+}
+
+/// Builds a `Socks5Client` with chained setters, for when more than the proxy address and credentials are configured.
+/// Options that aren't set keep the defaults of `Socks5Client::new`.
+#[derive(Clone)]
+pub struct Socks5ClientBuilder {
+    proxy_addr: Option<String>,
+    client: Socks5Client,
+}
+
+impl Default for Socks5ClientBuilder {
+    fn default() -> Self {
+        Socks5ClientBuilder {
+            proxy_addr: None,
+            client: Socks5Client::with_proxy_addrs(vec![], None),
+        }
+    }
+}
+
+impl Socks5ClientBuilder {
+    /// Creates a new `Socks5ClientBuilder`, without a proxy address.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the address of the SOCKS5 proxy server, which is resolved by `build`.
+    pub fn proxy_addr<A: Into<String>>(
+        mut self,
+        proxy_addr: A,
+    ) -> Self {
+        self.proxy_addr = Some(proxy_addr.into());
+        self
+    }
+
+    /// Sets the already resolved socket addresses of the SOCKS5 proxy server, instead of `proxy_addr`.
+    pub fn proxy_socket_addrs(
+        mut self,
+        proxy_addrs: Vec<SocketAddr>,
+    ) -> Self {
+        self.proxy_addr = None;
+        self.client.proxy_addrs = proxy_addrs;
+        self
+    }
+
+    /// Sets the credentials, see `Socks5Client::set_credentials`.
+    pub fn credentials(
+        mut self,
+        credentials: Credentials,
+    ) -> Self {
+        self.client.set_credentials(Some(credentials));
+        self
+    }
+
+    /// Sets the offered authentication methods, see `Socks5Client::set_auth_methods`.
+    pub fn auth_methods(
+        mut self,
+        auth_methods: Vec<Socks5AuthMethod>,
+    ) -> Self {
+        self.client.set_auth_methods(Some(auth_methods));
+        self
+    }
+
+    /// Sets the Happy Eyeballs delay, see `Socks5Client::set_happy_eyeballs_delay`.
+    pub fn happy_eyeballs_delay(
+        mut self,
+        delay: Duration,
+    ) -> Self {
+        self.client.set_happy_eyeballs_delay(delay);
+        self
+    }
+
+    /// Sets the socket options, see `Socks5Client::set_tcp_options`.
+    pub fn tcp_options(
+        mut self,
+        tcp_options: TcpOptions,
+    ) -> Self {
+        self.client.set_tcp_options(tcp_options);
+        self
+    }
+
+    /// Sets the PROXY protocol header, see `Socks5Client::set_proxy_header`.
+    pub fn proxy_header(
+        mut self,
+        proxy_header: ProxyHeader,
+    ) -> Self {
+        self.client.set_proxy_header(Some(proxy_header));
+        self
+    }
+
+    /// Sets the callback passed the raw bytes of handshakes, see `Socks5Client::set_on_wire`.
+    pub fn on_wire(
+        mut self,
+        on_wire: WireHook,
+    ) -> Self {
+        self.client.set_on_wire(Some(on_wire));
+        self
+    }
+
+    /// Sets the retry policy, see `Socks5Client::set_retry_policy`.
+    pub fn retry_policy(
+        mut self,
+        retry_policy: RetryPolicy,
+    ) -> Self {
+        self.client.set_retry_policy(Some(retry_policy));
+        self
+    }
+
+    /// Sets the reply timeout, see `Socks5Client::set_reply_timeout`.
+    pub fn reply_timeout(
+        mut self,
+        reply_timeout: Duration,
+    ) -> Self {
+        self.client.set_reply_timeout(Some(reply_timeout));
+        self
+    }
+
+    /// Sets the handshake deadline, see `Socks5Client::set_handshake_deadline`.
+    pub fn handshake_deadline(
+        mut self,
+        handshake_deadline: Duration,
+    ) -> Self {
+        self.client.set_handshake_deadline(Some(handshake_deadline));
+        self
+    }
+
+    /// Sets whether destinations are resolved locally, see `Socks5Client::set_resolve_locally`.
+    pub fn resolve_locally(
+        mut self,
+        resolve_locally: bool,
+    ) -> Self {
+        self.client.set_resolve_locally(resolve_locally);
+        self
+    }
+
+    /// Sets whether the version byte is checked strictly, see `Socks5Client::set_strict_version`.
+    pub fn strict_version(
+        mut self,
+        strict_version: bool,
+    ) -> Self {
+        self.client.set_strict_version(strict_version);
+        self
+    }
+
+    /// Sets the TLS configuration, see `Socks5Client::set_tls_config`.
+    #[cfg(feature = "tls")]
+    pub fn tls_config(
+        mut self,
+        tls_config: Arc<ClientConfig>,
+    ) -> Self {
+        self.client.set_tls_config(Some(tls_config));
+        self
+    }
+
+    /// Builds the client, resolving the proxy address if it was given by `proxy_addr`.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the new `Socks5Client` instance, or an error if no proxy address is set or it can't
+    /// be resolved.
+    pub async fn build(self) -> Result<Socks5Client, SocksError> {
+        let mut client = self.client;
+        if let Some(proxy_addr) = self.proxy_addr {
+            client.proxy_addrs = crate::resolve_addrs(proxy_addr).await?;
+        }
+
+        if client.proxy_addrs.is_empty() {
+            return Err(anyhow!("No proxy address is set.").into());
+        }
+
+        Ok(client)
+    }
+}
+
+/// Returns the destination host, which is the name a TLS server is expected to present.
+#[cfg(feature = "tls")]
+fn default_server_name(destination: &Address) -> String {
+    match destination {
+        Address::Domainname { host, .. } => host.clone(),
+        Address::Ip(addr) => addr.ip().to_string(),
+    }
+}
+
+/// Runs the future to completion, or returns `None` if the token, if any, is cancelled first.
+async fn unless_cancelled<F: Future>(
+    future: F,
+    token: Option<&CancellationToken>,
+) -> Option<F::Output> {
+    match token {
+        Some(token) => tokio::select! {
+            output = future => Some(output),
+            _ = token.cancelled() => None,
+        },
+        None => Some(future.await),
+    }
+}
+
+/// Creates a TLS configuration that trusts the Mozilla root certificates.
+#[cfg(feature = "tls")]
+fn default_tls_config() -> Arc<ClientConfig> {
+    let roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+
+    let config = ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+    Arc::new(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::mock::MockSocks5Server;
+    use crate::socks5::Socks5Reply;
+    use crate::{Direction, Socks5Handler};
+
+    // Tests that the authentication method selected by the proxy is reported.
+    #[tokio::test]
+    async fn test_connect_negotiated_auth_method() -> Result<()> {
+        let destination = TcpListener::bind("127.0.0.1:0").await?;
+        let destination_addr = destination.local_addr()?;
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await.unwrap();
+            Socks5Handler::default().setup(&mut source).await.unwrap();
+        });
+
+        let client = Socks5Client::from_socket_addr(proxy_addr, None);
+        let (_, _, auth_method) = client.connect_negotiated(destination_addr).await?;
+        assert_eq!(auth_method, Socks5AuthMethod::NoAuthentication);
+
+        Ok(())
+    }
+
+    // Tests that a boxed stream can be held alongside a direct one, and relays through the proxy.
+    #[tokio::test]
+    async fn test_connect_boxed() -> Result<()> {
+        let destination = TcpListener::bind("127.0.0.1:0").await?;
+        let destination_addr = destination.local_addr()?;
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await.unwrap();
+            let mut destination = Socks5Handler::default().setup(&mut source).await.unwrap();
+            crate::copy_bidirectional(&mut source, &mut destination).await.ok();
+        });
+        tokio::spawn(async move {
+            loop {
+                let (mut incoming, _) = destination.accept().await.unwrap();
+                incoming.write_all(b"hello").await.unwrap();
+            }
+        });
+
+        let client = Socks5Client::from_socket_addr(proxy_addr, None);
+        let (proxied, _) = client.connect_boxed(destination_addr).await?;
+        let direct: BoxedStream = Box::pin(TcpStream::connect(destination_addr).await?);
+
+        for mut stream in [proxied, direct] {
+            let mut greeting = [0; 5];
+            stream.read_exact(&mut greeting).await?;
+            assert_eq!(&greeting, b"hello");
+        }
+
+        Ok(())
+    }
+
+    // Tests that a tracked connection counts the data in both directions, and reports it once closed.
+    #[tokio::test]
+    async fn test_connect_tracked() -> Result<()> {
+        let destination = TcpListener::bind("127.0.0.1:0").await?;
+        let destination_addr = destination.local_addr()?;
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await.unwrap();
+            let mut destination = Socks5Handler::default().setup(&mut source).await.unwrap();
+            crate::copy_bidirectional(&mut source, &mut destination).await.ok();
+        });
+        let echo = tokio::spawn(async move {
+            let (mut incoming, _) = destination.accept().await.unwrap();
+            let mut received = vec![];
+            incoming.read_to_end(&mut received).await.unwrap();
+            incoming.write_all(b"bye").await.unwrap();
+            received
+        });
+
+        let client = Socks5Client::from_socket_addr(proxy_addr, None);
+        let mut connection = client.connect_tracked(destination_addr).await?;
+        assert!(matches!(connection.binding(), Address::Ip(_)));
+
+        connection.write_all(b"hello").await?;
+        connection.shutdown().await?;
+        let mut reply = vec![];
+        connection.read_to_end(&mut reply).await?;
+        assert_eq!(reply, b"bye");
+
+        let stats = connection.close().await;
+        assert_eq!((stats.sent, stats.received), (5, 3));
+        assert_eq!(echo.await?, b"hello");
+
+        Ok(())
+    }
+
+    // Tests that a built client connects with the configured options, and that building fails without a proxy address.
+    #[tokio::test]
+    async fn test_builder() -> Result<()> {
+        let destination = TcpListener::bind("127.0.0.1:0").await?;
+        let destination_addr = destination.local_addr()?;
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await.unwrap();
+            Socks5Handler::default().setup(&mut source).await.unwrap();
+        });
+
+        let client = Socks5Client::builder()
+            .proxy_addr(proxy_addr.to_string())
+            .auth_methods(vec![Socks5AuthMethod::NoAuthentication])
+            .reply_timeout(Duration::from_secs(5))
+            .build()
+            .await?;
+        assert_eq!(client.proxy_addrs, vec![proxy_addr]);
+        assert_eq!(client.reply_timeout, Some(Duration::from_secs(5)));
+        client.connect(destination_addr).await?;
+
+        assert!(Socks5Client::builder().build().await.is_err());
+
+        Ok(())
+    }
+
+    // Tests that credentials can be replaced after construction, without affecting clones.
+    #[tokio::test]
+    async fn test_set_credentials() -> Result<()> {
+        let mut client = Socks5Client::new("127.0.0.1:1080", None).await?;
+        let previous = client.clone();
+
+        client.set_credentials(Some(Credentials::new("user", "password")?));
+        assert_eq!(client.credentials(), Some(&Credentials::new("user", "password")?));
+        assert_eq!(previous.credentials(), None);
+
+        client.set_credentials(None);
+        assert_eq!(client.credentials(), None);
+
+        Ok(())
+    }
+
+    // Tests that valid credentials are sent in the username/password sub-negotiation.
+    #[tokio::test]
+    async fn test_connect_with_credentials() -> Result<()> {
+        let mut server = MockSocks5Server::default();
+        server.set_method(SOCKS_AUTH_USERNAME_PASSWORD);
+        let server = server.start().await?;
+
+        let credentials = Credentials::new("user", "password")?;
+        let client = Socks5Client::new(server.local_addr().to_string(), Some(credentials)).await?;
+        let (stream, _, auth_method) = client.connect_negotiated("10.0.0.1:80").await?;
+        assert_eq!(auth_method, Socks5AuthMethod::UsernamePassword);
+        drop(stream);
+
+        let received = server.finish().await?;
+        assert_eq!(received[..19], [&[5, 2, 0, 2, 1, 4][..], b"user", &[8], b"password"].concat()[..]);
+
+        Ok(())
+    }
+
+    // Tests that the raw bytes of the handshake are passed to the hook, as they're sent and received.
+    #[tokio::test]
+    async fn test_on_wire() -> Result<()> {
+        let server = MockSocks5Server::default().start().await?;
+
+        let wire = Arc::new(Mutex::new((vec![], vec![])));
+        let hook_wire = Arc::clone(&wire);
+        let mut client = Socks5Client::from_socket_addr(server.local_addr(), None);
+        client.set_on_wire(Some(Arc::new(move |direction: Direction, bytes: &[u8]| {
+            let mut wire = hook_wire.lock().unwrap();
+            match direction {
+                Direction::Sent => wire.0.extend_from_slice(bytes),
+                Direction::Received => wire.1.extend_from_slice(bytes),
+            }
+        })));
+        let (stream, _) = client.connect("10.0.0.1:80").await?;
+        drop(stream);
+
+        let received = server.finish().await?;
+        let (sent, replied) = wire.lock().unwrap().clone();
+        assert_eq!(sent, received);
+        assert_eq!(replied[..2], [SOCKS_VER_5, SOCKS_AUTH_NOT_REQUIRED]);
+        assert_eq!(replied[2..4], [SOCKS_VER_5, 0]);
+
+        Ok(())
+    }
+
+    // Tests that the username/password request starts with the sub-negotiation version of RFC 1929, which is easily
+    // confused with the SOCKS version. The expected byte is spelled out, as the mock proxy uses the constant too.
+    #[tokio::test]
+    async fn test_auth_request_version() -> Result<()> {
+        assert_eq!(SOCKS_AUTH_VER, 0x01);
+
+        let mut server = MockSocks5Server::default();
+        server.set_method(SOCKS_AUTH_USERNAME_PASSWORD);
+        let server = server.start().await?;
+
+        let client = Socks5Client::new(server.local_addr().to_string(), Some(Credentials::new("user", "pw")?)).await?;
+        let (stream, _) = client.connect("10.0.0.1:80").await?;
+        drop(stream);
+
+        // The greeting offers two methods, so the authentication request follows its first 4 bytes.
+        let received = server.finish().await?;
+        assert_eq!(received[..4], [0x05, 2, 0x00, 0x02]);
+        assert_eq!(received[4], 0x01);
+
+        Ok(())
+    }
+
+    // Tests that an empty password is framed with a zero length, for proxies that identify by username only.
+    #[tokio::test]
+    async fn test_connect_with_empty_password() -> Result<()> {
+        let mut server = MockSocks5Server::default();
+        server.set_method(SOCKS_AUTH_USERNAME_PASSWORD);
+        let server = server.start().await?;
+
+        let client = Socks5Client::new(server.local_addr().to_string(), Some(Credentials::new("user", "")?)).await?;
+        let (stream, _) = client.connect("10.0.0.1:80").await?;
+        drop(stream);
+
+        let received = server.finish().await?;
+        assert_eq!(received[4..11], [&[1, 4][..], b"user", &[0]].concat()[..]);
+        assert_eq!(received[11..13], [SOCKS_VER_5, Command::Connect as u8]);
+
+        Ok(())
+    }
+
+    // Tests that only the configured methods are offered, and that a proxy can't downgrade to no authentication.
+    #[tokio::test]
+    async fn test_connect_auth_methods_fail_closed() -> Result<()> {
+        let server = MockSocks5Server::default().start().await?;
+
+        let mut client = Socks5Client::new(server.local_addr().to_string(), Some(Credentials::new("user", "")?)).await?;
+        client.set_auth_methods(Some(vec![Socks5AuthMethod::UsernamePassword]));
+        assert!(client.connect("10.0.0.1:80").await.is_err());
+
+        // The proxy read the offered methods before selecting one, the client then closed without a request.
+        assert_eq!(server.received(), [SOCKS_VER_5, 1, SOCKS_AUTH_USERNAME_PASSWORD]);
+
+        client.set_credentials(None);
+        assert!(client.connect("10.0.0.1:80").await.is_err());
+
+        Ok(())
+    }
+
+    // Tests that credentials constructed from their fields are still checked before connecting.
+    #[tokio::test]
+    async fn test_connect_credentials_too_long() -> Result<()> {
+        let credentials = Credentials {
+            username: vec![b'u'; 256],
+            password: vec![],
+        };
+        let client = Socks5Client::new("127.0.0.1:1080", Some(credentials)).await?;
+        assert!(client.connect("10.0.0.1:80").await.is_err());
+
+        Ok(())
+    }
+
+    // Tests that cancelling mid-handshake returns an error, and closes the connection with the proxy.
+    #[tokio::test]
+    async fn test_connect_with_cancel() -> Result<()> {
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        // The proxy reads the offered methods, but never selects one.
+        let closed = tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await.unwrap();
+            let mut methods = [0; 3];
+            source.read_exact(&mut methods).await.unwrap();
+            source.read(&mut [0; 1]).await.unwrap() == 0
+        });
+
+        let token = CancellationToken::new();
+        let cancel = token.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            cancel.cancel();
+        });
+
+        let client = Socks5Client::from_socket_addr(proxy_addr, None);
+        let error = client.connect_with_cancel("10.0.0.1:80", token).await.unwrap_err();
+        assert!(matches!(error, SocksError::Cancelled));
+        assert!(closed.await?);
+
+        Ok(())
+    }
+
+    // Tests that connecting is aborted if the proxy goes silent after the handshake.
+    #[tokio::test]
+    async fn test_connect_reply_timeout() -> Result<()> {
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await.unwrap();
+            let mut methods = [0; 3];
+            source.read_exact(&mut methods).await.unwrap();
+            source.write_all(&[SOCKS_VER_5, SOCKS_AUTH_NOT_REQUIRED]).await.unwrap();
+
+            // Read the request, but never reply.
+            let _ = source.read(&mut [0; 1024]).await;
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        });
+
+        let mut client = Socks5Client::from_socket_addr(proxy_addr, None);
+        client.set_reply_timeout(Some(Duration::from_millis(100)));
+
+        let error = client.connect(String::from("127.0.0.1:80")).await.unwrap_err();
+        assert!(matches!(error, SocksError::Io(e) if e.kind() == std::io::ErrorKind::TimedOut));
+
+        Ok(())
+    }
+
+    // Tests that connecting is aborted once the deadline passed, even if the proxy keeps sending its reply.
+    #[tokio::test]
+    async fn test_connect_handshake_deadline() -> Result<()> {
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await.unwrap();
+            let mut methods = [0; 3];
+            source.read_exact(&mut methods).await.unwrap();
+
+            // Drip-feed the method selection.
+            for byte in [SOCKS_VER_5, SOCKS_AUTH_NOT_REQUIRED] {
+                tokio::time::sleep(Duration::from_millis(80)).await;
+                let _ = source.write_all(&[byte]).await;
+            }
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        });
+
+        let mut client = Socks5Client::from_socket_addr(proxy_addr, None);
+        client.set_reply_timeout(Some(Duration::from_millis(100)));
+        client.set_handshake_deadline(Some(Duration::from_millis(100)));
+
+        let error = client.connect(String::from("127.0.0.1:80")).await.unwrap_err();
+        assert!(matches!(error, SocksError::Io(e) if e.to_string().starts_with("Handshake")));
+
+        Ok(())
+    }
+
+    // Tests that a proxy rejecting every offered authentication method surfaces a typed error.
+    #[tokio::test]
+    async fn test_connect_auth_method_rejected() -> Result<()> {
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await.unwrap();
+            let mut request = [0; 3];
+            source.read_exact(&mut request).await.unwrap();
+            source.write_all(&[SOCKS_VER_5, 0xFF]).await.unwrap();
+        });
+
+        let client = Socks5Client::new(proxy_addr.to_string(), None).await?;
+        let error = client.connect(String::from("127.0.0.1:80")).await.unwrap_err();
+        assert!(
+            matches!(&error, SocksError::AuthMethodRejected { offered } if offered == &[AuthMethod::NoAuthentication])
+        );
+        assert!(error.to_string().contains("[NoAuthentication]"), "{}", error);
+
+        Ok(())
+    }
+
+    // Tests that selecting a method that wasn't offered fails, rather than authenticating anyway.
+    #[tokio::test]
+    async fn test_connect_auth_method_not_offered() -> Result<()> {
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        tokio::spawn(async move {
+            loop {
+                let (mut source, _) = proxy.accept().await.unwrap();
+                let mut request = [0; 3];
+                source.read_exact(&mut request).await.unwrap();
+                source.write_all(&[SOCKS_VER_5, SOCKS_AUTH_USERNAME_PASSWORD]).await.unwrap();
+            }
+        });
+
+        let client = Socks5Client::from_socket_addr(proxy_addr, None);
+        let error = client.connect(String::from("127.0.0.1:80")).await.unwrap_err();
+        assert!(matches!(error, SocksError::UnofferedAuthMethod(Socks5AuthMethod::UsernamePassword)));
+
+        let mut client = Socks5Client::from_socket_addr(proxy_addr, Some(Credentials::new("user", "pass")?));
+        client.set_auth_methods(Some(vec![Socks5AuthMethod::NoAuthentication]));
+        let error = client.connect(String::from("127.0.0.1:80")).await.unwrap_err();
+        assert!(matches!(error, SocksError::UnofferedAuthMethod(Socks5AuthMethod::UsernamePassword)));
+
+        Ok(())
+    }
+
+    // Tests that an off version byte is rejected by default, and only a known-benign one is accepted otherwise.
+    #[tokio::test]
+    async fn test_connect_lenient_version() -> Result<()> {
+        let destination = TcpListener::bind("127.0.0.1:0").await?;
+        let destination_addr = destination.local_addr()?;
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        tokio::spawn(async move {
+            for version in [0x00, 0x00, 0x04] {
+                let (mut source, _) = proxy.accept().await.unwrap();
+                let mut request = [0; 3];
+                source.read_exact(&mut request).await.unwrap();
+                source.write_all(&[version, SOCKS_AUTH_NOT_REQUIRED]).await.unwrap();
+
+                // The client hangs up, unless the version is accepted.
+                if let Ok(request) = socks5::read_request(&mut source).await {
+                    socks5::write_reply(&mut source, Socks5Reply::Success, &request.destination).await.unwrap();
+                }
+            }
+        });
+
+        let mut client = Socks5Client::from_socket_addr(proxy_addr, None);
+        let error = client.connect(destination_addr).await.unwrap_err();
+        assert!(matches!(error, SocksError::VersionMismatch(0x00)));
+
+        client.set_strict_version(false);
+        let (_, binding) = client.connect(destination_addr).await?;
+        assert_eq!(binding, Address::from(destination_addr));
+
+        let error = client.connect(destination_addr).await.unwrap_err();
+        assert!(matches!(error, SocksError::VersionMismatch(0x04)));
+
+        Ok(())
+    }
+
+    // Tests that a probe only negotiates the authentication method, without sending a request.
+    #[tokio::test]
+    async fn test_probe() -> Result<()> {
+        let server = MockSocks5Server::default().start().await?;
+
+        let client = Socks5Client::new(server.local_addr().to_string(), None).await?;
+        client.probe().await?;
+        assert_eq!(server.received(), [SOCKS_VER_5, 1, SOCKS_AUTH_NOT_REQUIRED]);
+
+        // The proxy never receives a request.
+        assert!(server.finish().await.is_err());
+
+        Ok(())
+    }
+
+    // Tests that an unspecified bound address is substituted by the proxy's address.
+    #[tokio::test]
+    async fn test_connect_unspecified_binding() -> Result<()> {
+        let mut server = MockSocks5Server::default();
+        server.set_reply(Socks5Reply::Success, Address::new("::", 4000));
+        let server = server.start().await?;
+
+        let client = Socks5Client::new(server.local_addr().to_string(), None).await?;
+        let (_, binding) = client.connect("10.0.0.1:80").await?;
+        assert_eq!(binding, Address::new("127.0.0.1", 4000));
+
+        Ok(())
+    }
+
+    // Tests that domain names are resolved by the proxy, unless asked to resolve them locally.
+    #[tokio::test]
+    async fn test_connect_resolution() -> Result<()> {
+        for resolve_locally in [false, true] {
+            let proxy = TcpListener::bind("127.0.0.1:0").await?;
+            let proxy_addr = proxy.local_addr()?;
+
+            let received = tokio::spawn(async move {
+                let (mut source, _) = proxy.accept().await.unwrap();
+                let methods = socks5::read_auth_methods(&mut source).await.unwrap();
+                assert_eq!(methods, vec![SOCKS_AUTH_NOT_REQUIRED]);
+                socks5::write_auth_method_selection(&mut source, SOCKS_AUTH_NOT_REQUIRED).await.unwrap();
+
+                let request = socks5::read_request(&mut source).await.unwrap();
+                let binding = Address::new("0.0.0.0", 0);
+                socks5::write_reply(&mut source, socks5::Socks5Reply::Success, &binding).await.unwrap();
+                request.destination
+            });
+
+            let mut client = Socks5Client::new(proxy_addr.to_string(), None).await?;
+            client.set_resolve_locally(resolve_locally);
+            client.connect(String::from("localhost:80")).await?;
+
+            let destination = received.await?;
+            if resolve_locally {
+                assert!(matches!(destination, Address::Ip(addr) if addr.ip().is_loopback()));
+            } else {
+                assert_eq!(destination, Address::new("localhost", 80));
+            }
         }
 
         Ok(())
     }
+
+    // Tests that the TLS server name defaults to the destination host.
+    #[cfg(feature = "tls")]
+    #[test]
+    fn test_default_server_name() {
+        assert_eq!(default_server_name(&Address::new("example.com", 443)), "example.com");
+        assert_eq!(default_server_name(&Address::new("10.0.0.1", 443)), "10.0.0.1");
+    }
 }
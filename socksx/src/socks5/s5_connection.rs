@@ -0,0 +1,121 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+
+use crate::{Address, TransferStats};
+
+/// Represents a tunnel established through a SOCKS5 proxy, which counts the data sent and received through it.
+///
+/// It's read from and written to like the `TcpStream` it wraps. Once done, `close` shuts the tunnel down and
+/// returns what was transferred.
+#[derive(Debug)]
+pub struct Socks5Connection {
+    stream: TcpStream,
+    binding: Address,
+    stats: TransferStats,
+}
+
+impl Socks5Connection {
+    /// Creates a new `Socks5Connection`, e.g. for a tunnel established by `Socks5Client::connect`.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - The stream to the destination, after the handshake.
+    /// * `binding` - The address the proxy bound for the tunnel.
+    ///
+    /// # Returns
+    ///
+    /// A new `Socks5Connection` instance, with nothing transferred yet.
+    pub fn new(
+        stream: TcpStream,
+        binding: Address,
+    ) -> Self {
+        Socks5Connection {
+            stream,
+            binding,
+            stats: TransferStats::default(),
+        }
+    }
+
+    /// Returns the address the proxy bound for the tunnel.
+    pub fn binding(&self) -> &Address {
+        &self.binding
+    }
+
+    /// Returns the data transferred so far, `sent` to and `received` from the destination.
+    pub fn stats(&self) -> TransferStats {
+        self.stats
+    }
+
+    /// Returns the stream to the destination, e.g. to relay it with `relay_tcp`. Data transferred through the
+    /// returned stream isn't counted.
+    pub fn into_inner(self) -> TcpStream {
+        self.stream
+    }
+
+    /// Shuts the tunnel down, so the destination sees the end of the stream, and closes it.
+    /// Shutting down fails if the proxy already closed the connection, which is only logged.
+    ///
+    /// # Returns
+    ///
+    /// The data transferred through the tunnel.
+    pub async fn close(mut self) -> TransferStats {
+        if let Err(error) = self.stream.shutdown().await {
+            debug!("Shutting down the tunnel to {} failed: {}", self.binding, error);
+        }
+
+        self.stats
+    }
+}
+
+impl AsyncRead for Socks5Connection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut this.stream).poll_read(cx, buf);
+
+        if let Poll::Ready(Ok(())) = poll {
+            this.stats.received += (buf.filled().len() - filled) as u64;
+        }
+
+        poll
+    }
+}
+
+impl AsyncWrite for Socks5Connection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.stream).poll_write(cx, buf);
+
+        if let Poll::Ready(Ok(written)) = poll {
+            this.stats.sent += written as u64;
+        }
+
+        poll
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
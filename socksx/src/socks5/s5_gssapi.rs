@@ -0,0 +1,203 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::constants::*;
+
+/// The outcome of processing a context establishment token of the client.
+#[derive(Clone, Debug, PartialEq)]
+pub enum GssapiStep {
+    /// The context isn't established yet, the token is sent to the client, which answers with another token.
+    Continue(Vec<u8>),
+    /// The context is established, the token (if not empty) is sent to the client as the final one.
+    Complete(Vec<u8>),
+    /// The client is rejected, the sub-negotiation is aborted.
+    Reject,
+}
+
+/// A server-side GSSAPI security context, that is established with a single client (RFC 1961).
+///
+/// Implementations typically delegate to a GSS library, i.e. every step is a call to `gss_accept_sec_context`.
+pub trait GssapiContext: Send {
+    /// Processes a context establishment token of the client.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - The token, as sent by the client.
+    ///
+    /// # Returns
+    ///
+    /// Whether the context is established, needs another round, or the client is rejected.
+    fn step(
+        &mut self,
+        token: &[u8],
+    ) -> GssapiStep;
+}
+
+impl<F: FnMut(&[u8]) -> GssapiStep + Send> GssapiContext for F {
+    fn step(
+        &mut self,
+        token: &[u8],
+    ) -> GssapiStep {
+        self(token)
+    }
+}
+
+/// Creates a GSSAPI security context for every client that selects GSSAPI authentication.
+pub type GssapiAuthenticator = Arc<dyn Fn() -> Box<dyn GssapiContext> + Send + Sync>;
+
+/// Drives the context establishment of the GSSAPI sub-negotiation, until the context is established.
+///
+/// Only context establishment is supported. The per-message protection of RFC 1961 (the protection level
+/// sub-negotiation, and encapsulation of the request and tunnel) isn't, so the request follows unprotected.
+///
+/// # Arguments
+///
+/// * `stream` - The connection with the client, which selected GSSAPI authentication.
+/// * `context` - The security context that validates the client's tokens.
+///
+/// # Returns
+///
+/// A `Result` indicating the context is established, or an error if the client is rejected or aborted.
+pub(crate) async fn accept_gssapi<S>(
+    stream: &mut S,
+    mut context: Box<dyn GssapiContext>,
+) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        let (message_type, token) = read_gssapi_message(stream).await?;
+        match message_type {
+            SOCKS_GSSAPI_AUTHENTICATION => {}
+            SOCKS_GSSAPI_ABORT => bail!("Client aborted the GSSAPI sub-negotiation."),
+            message_type => bail!("Unexpected GSSAPI message type: {}.", message_type),
+        }
+
+        match context.step(&token) {
+            GssapiStep::Continue(token) => {
+                write_gssapi_message(stream, SOCKS_GSSAPI_AUTHENTICATION, &token).await?;
+            }
+            GssapiStep::Complete(token) => {
+                if !token.is_empty() {
+                    write_gssapi_message(stream, SOCKS_GSSAPI_AUTHENTICATION, &token).await?;
+                }
+
+                return Ok(());
+            }
+            GssapiStep::Reject => {
+                // The abort message carries no token, not even its length.
+                stream.write_all(&[SOCKS_GSSAPI_VER, SOCKS_GSSAPI_ABORT]).await?;
+                bail!("GSSAPI authentication failed.");
+            }
+        }
+    }
+}
+
+/// Reads a GSSAPI sub-negotiation message, as its type and token.
+async fn read_gssapi_message<S>(stream: &mut S) -> Result<(u8, Vec<u8>)>
+    where
+        S: AsyncRead + Unpin,
+{
+    let mut header = [0; 2];
+    stream.read_exact(&mut header).await?;
+
+    let [version, message_type] = header;
+    ensure!(version == SOCKS_GSSAPI_VER, "Client uses a different GSSAPI message version: {}.", version);
+    if message_type == SOCKS_GSSAPI_ABORT {
+        return Ok((message_type, vec![]));
+    }
+
+    let mut token = vec![0; stream.read_u16().await? as usize];
+    stream.read_exact(&mut token).await?;
+
+    Ok((message_type, token))
+}
+
+/// Writes a GSSAPI sub-negotiation message.
+async fn write_gssapi_message<S>(
+    stream: &mut S,
+    message_type: u8,
+    token: &[u8],
+) -> Result<()>
+    where
+        S: AsyncWrite + Unpin,
+{
+    ensure!(token.len() <= u16::MAX as usize, "GSSAPI token MUST NOT be larger than {} bytes.", u16::MAX);
+
+    let mut message = vec![SOCKS_GSSAPI_VER, message_type];
+    message.extend((token.len() as u16).to_be_bytes().iter());
+    message.extend(token);
+    stream.write_all(&message).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::{Address, Command, Socks5Handler};
+    use crate::socks5::{self, Socks5Request};
+
+    /// Serves a single client with a handler that accepts GSSAPI through the given context.
+    async fn serve<C: GssapiContext + Clone + Sync + 'static>(context: C) -> Result<TcpStream> {
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        let mut handler = Socks5Handler::default();
+        handler.set_gssapi_authenticator(Some(Arc::new(move || Box::new(context.clone()))));
+        tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await.unwrap();
+            handler.setup(&mut source).await
+        });
+
+        let mut client = TcpStream::connect(proxy_addr).await?;
+        client.write_all(&[SOCKS_VER_5, 2, SOCKS_AUTH_NOT_REQUIRED, SOCKS_AUTH_GSSAPI]).await?;
+        let mut selection = [0; 2];
+        client.read_exact(&mut selection).await?;
+        assert_eq!(selection, [SOCKS_VER_5, SOCKS_AUTH_GSSAPI]);
+
+        Ok(client)
+    }
+
+    // Tests that tokens are exchanged until the context is established, after which the request follows.
+    #[tokio::test]
+    async fn test_accept_gssapi() -> Result<()> {
+        let destination = TcpListener::bind("127.0.0.1:0").await?;
+        let destination_addr = destination.local_addr()?;
+
+        let context = |token: &[u8]| match token {
+            b"first" => GssapiStep::Continue(b"challenge".to_vec()),
+            b"second" => GssapiStep::Complete(vec![]),
+            _ => GssapiStep::Reject,
+        };
+        let mut client = serve(context).await?;
+
+        write_gssapi_message(&mut client, SOCKS_GSSAPI_AUTHENTICATION, b"first").await?;
+        let (message_type, token) = read_gssapi_message(&mut client).await?;
+        assert_eq!((message_type, &token[..]), (SOCKS_GSSAPI_AUTHENTICATION, &b"challenge"[..]));
+        write_gssapi_message(&mut client, SOCKS_GSSAPI_AUTHENTICATION, b"second").await?;
+
+        let request = Socks5Request::new(Command::Connect, Address::Ip(destination_addr));
+        client.write_all(&request.into_socks_bytes()?).await?;
+        socks5::read_reply(&mut client).await?;
+        destination.accept().await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_accept_gssapi_reject() -> Result<()> {
+        let mut client = serve(|_: &[u8]| GssapiStep::Reject).await?;
+
+        write_gssapi_message(&mut client, SOCKS_GSSAPI_AUTHENTICATION, b"token").await?;
+        let mut abort = vec![];
+        client.read_to_end(&mut abort).await?;
+        assert_eq!(abort, [SOCKS_GSSAPI_VER, SOCKS_GSSAPI_ABORT]);
+
+        Ok(())
+    }
+}
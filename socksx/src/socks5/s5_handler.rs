@@ -1,17 +1,36 @@
+use std::future::Future;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
 use anyhow::Result;
-use async_trait::async_trait;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio::time::Instant;
 
-use crate::{constants::*, Credentials};
-use crate::addresses::{self, ProxyAddress};
-use crate::socks5::{self, Socks5Reply};
-use crate::SocksHandler;
+use crate::{
+    constants::*, Command, ConnectionEvent, Credentials, DestinationRewriter, EventHandler, RelayOptions, Resolver,
+    SocksError, SocksSource, TcpOptions, TransferStats,
+};
+use crate::addresses::{Address, ProxyAddress};
+use crate::socks5::{self, GssapiAuthenticator, Socks5Reply};
+use crate::events::record_destination;
+use crate::resolver::resolve_address;
+use crate::tunnel::MAX_BUFFER_SIZE;
+use crate::util::{connect_happy_eyeballs_with_options, HAPPY_EYEBALLS_DELAY};
+use crate::NativeSocksHandler;
 
 /// Represents a SOCKS5 handler for processing client requests.
 #[derive(Clone)]
 pub struct Socks5Handler {
     credentials: Option<Credentials>,
+    gssapi_authenticator: Option<GssapiAuthenticator>,
+    happy_eyeballs_delay: Duration,
+    tcp_options: TcpOptions,
+    relay_options: RelayOptions,
+    destination_rewriter: Option<DestinationRewriter>,
+    resolver: Option<Arc<dyn Resolver>>,
+    event_handler: Option<EventHandler>,
     //chain: Vec<ProxyAddress>,
 }
 
@@ -34,82 +53,198 @@ impl Socks5Handler {
     pub fn new(_chain: Vec<ProxyAddress>) -> Self {
         Socks5Handler {
             credentials: None,
+            gssapi_authenticator: None,
+            happy_eyeballs_delay: HAPPY_EYEBALLS_DELAY,
+            tcp_options: TcpOptions::default(),
+            relay_options: RelayOptions::default(),
+            destination_rewriter: None,
+            resolver: None,
+            event_handler: None,
             //chain,
         }
     }
-}
 
-#[async_trait]
-impl SocksHandler for Socks5Handler {
-    /// Accepts a SOCKS5 client request and sets up a bidirectional connection.
+    /// Sets the socket options applied to the connection with the destination.
     ///
     /// # Arguments
     ///
-    /// * `source` - The TCP stream representing the client connection.
+    /// * `tcp_options` - The socket options, defaults to `TCP_NODELAY` without keepalive.
+    pub fn set_tcp_options(
+        &mut self,
+        tcp_options: TcpOptions,
+    ) {
+        self.tcp_options = tcp_options;
+    }
+
+    /// Sets the mark (`SO_MARK`) of the connection with the destination, see `TcpOptions::fwmark`.
     ///
-    /// # Returns
+    /// # Arguments
     ///
-    /// A `Result` indicating success or an error.
-    async fn accept_request(
-        &self,
-        source: &mut TcpStream,
-    ) -> Result<()> {
-        let mut destination = self.setup(source).await?;
+    /// * `fwmark` - The mark, or `None` (the default) to leave packets unmarked.
+    pub fn set_fwmark(
+        &mut self,
+        fwmark: Option<u32>,
+    ) {
+        self.tcp_options.fwmark = fwmark;
+    }
 
-        // Start bidirectional copy, after this the connection closes.
-        tokio::io::copy_bidirectional(source, &mut destination).await?;
+    /// Sets the maximum time a tunnel is kept open, after which it's closed regardless of activity.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_lifetime` - The maximum lifetime, or `None` (the default) to keep tunnels open until they're closed.
+    pub fn set_max_lifetime(
+        &mut self,
+        max_lifetime: Option<Duration>,
+    ) {
+        self.relay_options.max_lifetime = max_lifetime;
+    }
 
-        Ok(())
+    /// Sets the size of the buffer used for each direction of a tunnel.
+    ///
+    /// # Arguments
+    ///
+    /// * `buffer_size` - The size in bytes, defaults to 8KB and is capped at 256KB.
+    pub fn set_buffer_size(
+        &mut self,
+        buffer_size: usize,
+    ) {
+        self.relay_options.buffer_size = buffer_size.min(MAX_BUFFER_SIZE);
     }
 
-    /// Refuses a SOCKS5 client request and notifies the client.
+    /// Sets whether tunnels move data with `splice(2)` instead of copying it through a buffer (Linux only).
+    ///
+    /// # Arguments
+    ///
+    /// * `zero_copy` - Whether to use zero-copy, defaults to `false`, ignored on other platforms.
+    pub fn set_zero_copy(
+        &mut self,
+        zero_copy: bool,
+    ) {
+        self.relay_options.zero_copy = zero_copy;
+    }
+
+    /// Sets the hook that validates clients which select GSSAPI authentication, GSSAPI is only accepted if it's set.
+    /// It's preferred over the other methods, if the client offers it.
+    ///
+    /// # Arguments
+    ///
+    /// * `gssapi_authenticator` - The hook, creating a security context per client, or `None` (the default).
+    pub fn set_gssapi_authenticator(
+        &mut self,
+        gssapi_authenticator: Option<GssapiAuthenticator>,
+    ) {
+        self.gssapi_authenticator = gssapi_authenticator;
+    }
+
+    /// Sets a callback that rewrites the destination of every request before it's dialed, e.g. to redirect it.
+    ///
+    /// # Arguments
+    ///
+    /// * `destination_rewriter` - The callback, returning `None` refuses the request, or `None` to dial as requested.
+    pub fn set_destination_rewriter(
+        &mut self,
+        destination_rewriter: Option<DestinationRewriter>,
+    ) {
+        self.destination_rewriter = destination_rewriter;
+    }
+
+    /// Sets the resolver of destination domain names.
+    ///
+    /// # Arguments
+    ///
+    /// * `resolver` - The resolver, e.g. a shared `DnsCache`, or `None` (the default) to resolve every connection with
+    ///   the resolver of the operating system.
+    pub fn set_resolver(
+        &mut self,
+        resolver: Option<Arc<dyn Resolver>>,
+    ) {
+        self.resolver = resolver;
+    }
+
+    /// Sets a callback that receives a `ConnectionEvent` for every connection that was set up.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_handler` - The callback, or `None` to stop emitting events.
+    pub fn set_event_handler(
+        &mut self,
+        event_handler: Option<EventHandler>,
+    ) {
+        self.event_handler = event_handler;
+    }
+
+    /// Sets the delay between staggered IPv4/IPv6 connection attempts to the destination.
     ///
     /// # Arguments
     ///
-    /// * `source` - The TCP stream representing the client connection.
+    /// * `delay` - The connection attempt delay, defaults to 250ms.
+    pub fn set_happy_eyeballs_delay(
+        &mut self,
+        delay: Duration,
+    ) {
+        self.happy_eyeballs_delay = delay;
+    }
+
+    /// Refuses a SOCKS5 client request, telling the client why, e.g. `CommandNotSupported` for a command that isn't
+    /// allowed. `refuse_request` refuses with `ConnectionRefused`.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - The stream representing the client connection.
+    /// * `reply` - The reply that is sent to the client.
     ///
     /// # Returns
     ///
     /// A `Result` indicating success or an error.
-    async fn refuse_request(
+    pub async fn refuse_request_with<S: SocksSource>(
         &self,
-        source: &mut TcpStream,
+        source: &mut S,
+        reply: Socks5Reply,
     ) -> Result<()> {
         // Notify source that the connection is refused.
-        socks5::write_reply(source, Socks5Reply::ConnectionRefused).await?;
+        socks5::write_reply(source, reply, &unbound()).await?;
 
         Ok(())
     }
 
-    /// Sets up the SOCKS5 connection with a client.
+    /// Sets up the SOCKS5 connection with a client, like `setup`.
     ///
     /// # Arguments
     ///
-    /// * `source` - The TCP stream representing the client connection.
+    /// * `source` - The stream representing the client connection.
     ///
     /// # Returns
     ///
-    /// A `Result` containing a TCP stream representing the destination connection.
-    async fn setup(
+    /// A `Result` containing a TCP stream representing the destination connection, and the destination it's connected
+    /// to (as rewritten, if a rewriter is set).
+    async fn setup_destination<S: SocksSource>(
         &self,
-        source: &mut TcpStream,
-    ) -> Result<TcpStream> {
-        let mut request = [0; 2];
-        source.read_exact(&mut request).await?;
+        source: &mut S,
+    ) -> Result<(TcpStream, Address)> {
+        let negotiated = self.negotiate_destination(source).await;
 
-        let socks_version = request[0];
-
-        if socks_version != SOCKS_VER_5 {
-            bail!("Client uses a different SOCKS version: {}.", socks_version);
+        // Close the connection gracefully, after any reply, so the client doesn't see a reset instead.
+        if negotiated.is_err() {
+            source.shutdown().await.ok();
         }
 
-        // Get all authentication methods the client proposes.
-        let nmethods = request[1] as usize;
+        negotiated
+    }
+
+    /// Negotiates with a client and connects to the destination it requests, for `setup_destination`.
+    async fn negotiate_destination<S: SocksSource>(
+        &self,
+        source: &mut S,
+    ) -> Result<(TcpStream, Address)> {
+        let start_time = Instant::now();
 
-        let mut methods = vec![0; nmethods];
-        source.read_exact(&mut methods).await?;
+        // Get all authentication methods the client proposes.
+        let methods = socks5::read_auth_methods(source).await?;
 
-        let method = if self.credentials.is_some() && methods.contains(&SOCKS_AUTH_USERNAME_PASSWORD) {
+        let method = if self.gssapi_authenticator.is_some() && methods.contains(&SOCKS_AUTH_GSSAPI) {
+            SOCKS_AUTH_GSSAPI
+        } else if self.credentials.is_some() && methods.contains(&SOCKS_AUTH_USERNAME_PASSWORD) {
             SOCKS_AUTH_USERNAME_PASSWORD
         } else if methods.contains(&SOCKS_AUTH_NOT_REQUIRED) {
             SOCKS_AUTH_NOT_REQUIRED
@@ -117,13 +252,15 @@ impl SocksHandler for Socks5Handler {
             SOCKS_AUTH_NO_ACCEPTABLE_METHODS
         };
 
-        info!("Use authentication method: {}", method);
+        debug!("Use authentication method: {}", method);
 
-        let response = [SOCKS_VER_5, method];
-        source.write(&response).await?;
+        socks5::write_auth_method_selection(source, method).await?;
 
         // Enter method-specific sub-negotiation
-        if method == SOCKS_AUTH_USERNAME_PASSWORD {
+        if let (SOCKS_AUTH_GSSAPI, Some(authenticator)) = (method, &self.gssapi_authenticator) {
+            socks5::accept_gssapi(source, authenticator()).await?;
+            debug!("Established GSSAPI context with client");
+        } else if method == SOCKS_AUTH_USERNAME_PASSWORD {
             let mut request = [0; 2];
             source.read_exact(&mut request).await?;
 
@@ -154,26 +291,276 @@ impl SocksHandler for Socks5Handler {
             };
 
             let response = [SOCKS_VER_5, status];
-            source.write(&response).await?;
+            source.write_all(&response).await?;
 
             ensure!(status == SOCKS_AUTH_SUCCESS, "Username/password authentication failed.");
+            debug!("Authenticated client");
         }
 
-        let mut request = [0; 3];
-        source.read_exact(&mut request).await?;
+        let request = match socks5::read_request(source).await {
+            Ok(request) => request,
+            Err(error) => {
+                let reply = match error.downcast_ref() {
+                    Some(SocksError::CommandNotSupported(_)) => Some(Socks5Reply::CommandNotSupported),
+                    Some(SocksError::AddressTypeNotSupported(_)) => Some(Socks5Reply::AddressTypeNotSupported),
+                    _ => None,
+                };
+                if let Some(reply) = reply {
+                    socks5::write_reply(source, reply, &unbound()).await?;
+                }
+
+                return Err(error);
+            }
+        };
 
-        let command = request[1];
-        if command != SOCKS_CMD_CONNECT {
-            unimplemented!();
+        let handshake_latency = start_time.elapsed();
+        record_destination(&request.destination);
+        if request.command != Command::Connect {
+            socks5::write_reply(source, Socks5Reply::CommandNotSupported, &unbound()).await?;
+            bail!("Only CONNECT is supported, got: {:?}.", request.command);
         }
 
-        let destination = addresses::read_address(source).await?;
-        let destination = TcpStream::connect(destination.to_string()).await?;
+        let target = match &self.destination_rewriter {
+            Some(rewriter) => match rewriter(request.destination.clone()) {
+                Some(destination) => destination,
+                None => {
+                    socks5::write_reply(source, Socks5Reply::ConnectionNotAllowed, &unbound()).await?;
+                    bail!("Destination {} was refused by the rewriter.", request.destination);
+                }
+            },
+            None => request.destination.clone(),
+        };
 
-        // Notify source that the connection has been set up.
-        socks5::write_reply(source, Socks5Reply::Success).await?;
+        let dialed: Result<_> = async {
+            let addrs = resolve_address(&target, self.resolver.as_deref()).await.map_err(|error| {
+                let message = format!("Failed to resolve {}: {}", target, error);
+                io::Error::new(io::ErrorKind::HostUnreachable, message)
+            })?;
+            let destination = connect_happy_eyeballs_with_options(&addrs, self.happy_eyeballs_delay, &self.tcp_options)
+                .await?;
+            self.tcp_options.apply(&destination)?;
+
+            Ok(destination)
+        }
+        .await;
+
+        // Tell the source why the destination couldn't be reached, before closing the connection.
+        let destination = match dialed {
+            Ok(destination) => destination,
+            Err(error) => {
+                socks5::write_reply(source, Socks5Reply::from_dial_error(&error), &unbound()).await?;
+                source.flush().await?;
+                return Err(error);
+            }
+        };
+
+        let event = ConnectionEvent {
+            destination: request.destination,
+            target: target.clone(),
+            handshake_latency,
+            setup_latency: start_time.elapsed(),
+        };
+        info!(
+            "Destination established in {}ms (handshake took {}ms)",
+            event.setup_latency.as_millis(),
+            event.handshake_latency.as_millis()
+        );
+        if let Some(event_handler) = &self.event_handler {
+            event_handler(&event);
+        }
+
+        // Notify source that the connection has been set up, and where it's bound to.
+        let binding = Address::from(destination.local_addr()?);
+        socks5::write_reply(source, Socks5Reply::Success, &binding).await?;
         source.flush().await?;
+        debug!("Sent reply");
+
+        Ok((destination, target))
+    }
+}
+
+impl Socks5Handler {
+    /// Accepts a SOCKS5 client request and sets up a bidirectional connection.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - The stream representing the client connection.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the destination the client was connected to, and the data relayed, or an error.
+    pub async fn accept_request<S: SocksSource>(
+        &self,
+        source: &mut S,
+    ) -> Result<(Address, TransferStats)> {
+        let (mut destination, target) = self.setup_destination(source).await?;
+
+        // Relay in both directions, passing on half-closes, until both directions are closed.
+        let stats = crate::tunnel::relay_source(source, &mut destination, &self.relay_options).await?;
+
+        Ok((target, stats))
+    }
+
+    /// Refuses a SOCKS5 client request and notifies the client.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - The stream representing the client connection.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or an error.
+    pub async fn refuse_request<S: SocksSource>(
+        &self,
+        source: &mut S,
+    ) -> Result<()> {
+        self.refuse_request_with(source, Socks5Reply::ConnectionRefused).await
+    }
+
+    /// Sets up the SOCKS5 connection with a client.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - The stream representing the client connection.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing a TCP stream representing the destination connection.
+    pub async fn setup<S: SocksSource>(
+        &self,
+        source: &mut S,
+    ) -> Result<TcpStream> {
+        let (destination, _) = self.setup_destination(source).await?;
 
         Ok(destination)
     }
 }
+
+impl NativeSocksHandler for Socks5Handler {
+    fn accept_request<S: SocksSource>(
+        &self,
+        source: &mut S,
+    ) -> impl Future<Output = Result<(Address, TransferStats)>> + Send {
+        Socks5Handler::accept_request(self, source)
+    }
+
+    fn refuse_request<S: SocksSource>(
+        &self,
+        source: &mut S,
+    ) -> impl Future<Output = Result<()>> + Send {
+        Socks5Handler::refuse_request(self, source)
+    }
+
+    fn setup<S: SocksSource>(
+        &self,
+        source: &mut S,
+    ) -> impl Future<Output = Result<TcpStream>> + Send {
+        Socks5Handler::setup(self, source)
+    }
+}
+
+/// Returns the unspecified address, which replies that don't establish a connection carry.
+fn unbound() -> Address {
+    Address::new("0.0.0.0", 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::socks5::Socks5Client;
+
+    // Tests that the setup latency is reported, and covers the handshake, as well as the requested and rewritten
+    // destinations.
+    #[tokio::test]
+    async fn test_setup_latency_event() -> Result<()> {
+        let destination = TcpListener::bind("127.0.0.1:0").await?;
+        let destination_addr = destination.local_addr()?;
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        let events = Arc::new(Mutex::new(vec![]));
+        let mut handler = Socks5Handler::new(vec![]);
+        let sink = Arc::clone(&events);
+        handler.set_event_handler(Some(Arc::new(move |e: &ConnectionEvent| {
+            sink.lock().unwrap().push(e.clone())
+        })));
+        handler.set_destination_rewriter(Some(Arc::new(move |_| Some(Address::Ip(destination_addr)))));
+
+        tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await.unwrap();
+            handler.setup(&mut source).await.unwrap();
+        });
+
+        let client = Socks5Client::new(proxy_addr.to_string(), None).await?;
+        client.connect(String::from("example.com:80")).await?;
+        destination.accept().await?;
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].destination, Address::new("example.com", 80));
+        assert_eq!(events[0].target, Address::Ip(destination_addr));
+        assert!(events[0].setup_latency >= events[0].handshake_latency);
+        assert!(events[0].setup_latency < Duration::from_secs(5));
+
+        Ok(())
+    }
+
+    // Tests that the handler replies to a request with an unknown address type, rather than failing generally.
+    #[tokio::test]
+    async fn test_connect_address_type_not_supported() -> Result<()> {
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        let handler = tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await.unwrap();
+            Socks5Handler::default().setup(&mut source).await
+        });
+
+        let mut stream = TcpStream::connect(proxy_addr).await?;
+        stream.write_all(&[SOCKS_VER_5, 1, SOCKS_AUTH_NOT_REQUIRED]).await?;
+        let mut selection = [0; 2];
+        stream.read_exact(&mut selection).await?;
+        stream.write_all(&[SOCKS_VER_5, SOCKS_CMD_CONNECT, SOCKS_RSV, 0x02]).await?;
+
+        let error = socks5::read_reply(&mut stream).await.unwrap_err();
+        assert!(matches!(error, SocksError::ReplyFailure(code) if code == Socks5Reply::AddressTypeNotSupported as u8));
+        let error = handler.await?.unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(SocksError::AddressTypeNotSupported(0x02))));
+
+        Ok(())
+    }
+
+    // Tests that the handler replies why the destination couldn't be reached, then closes the connection gracefully.
+    #[tokio::test]
+    async fn test_connect_destination_refused() -> Result<()> {
+        let closed_addr = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        let handler = tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await.unwrap();
+            Socks5Handler::default().setup(&mut source).await
+        });
+
+        let mut stream = TcpStream::connect(proxy_addr).await?;
+        stream.write_all(&[SOCKS_VER_5, 1, SOCKS_AUTH_NOT_REQUIRED]).await?;
+        let mut selection = [0; 2];
+        stream.read_exact(&mut selection).await?;
+
+        let port = closed_addr.port().to_be_bytes();
+        stream.write_all(&[SOCKS_VER_5, SOCKS_CMD_CONNECT, SOCKS_RSV, SOCKS_ATYP_IPV4, 127, 0, 0, 1]).await?;
+        stream.write_all(&port).await?;
+
+        let mut reply = vec![];
+        stream.read_to_end(&mut reply).await?;
+        assert_eq!(reply.len(), 10);
+        assert_eq!(reply[1], Socks5Reply::ConnectionRefused as u8);
+        assert!(handler.await?.is_err());
+
+        Ok(())
+    }
+}
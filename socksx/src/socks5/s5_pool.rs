@@ -0,0 +1,109 @@
+use std::convert::TryInto;
+use std::sync::Arc;
+
+use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
+
+use crate::{Address, Socks5Client, SocksError};
+
+/// Shares a `Socks5Client` between tasks, and caps the number of handshakes that are in flight at once.
+///
+/// The proxy address is resolved once, when the client is created, and reused by every connection.
+/// Note that CONNECT streams themselves are not pooled: a tunnel is bound to its destination and
+/// can't be reused, so every `connect` still opens a fresh connection to the proxy.
+#[derive(Clone)]
+pub struct Socks5Pool {
+    client: Arc<Socks5Client>,
+    semaphore: Arc<Semaphore>,
+}
+
+impl Socks5Pool {
+    /// Creates a new `Socks5Pool`.
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - The client used for every connection.
+    /// * `max_handshakes` - The maximum number of concurrent handshakes with the proxy.
+    ///
+    /// # Returns
+    ///
+    /// A new `Socks5Pool` instance.
+    pub fn new(
+        client: Socks5Client,
+        max_handshakes: usize,
+    ) -> Self {
+        Socks5Pool {
+            client: Arc::new(client),
+            semaphore: Arc::new(Semaphore::new(max_handshakes)),
+        }
+    }
+
+    /// Returns the client used for every connection.
+    pub fn client(&self) -> &Socks5Client {
+        &self.client
+    }
+
+    /// Returns the number of handshakes that can be started right away.
+    pub fn available_handshakes(&self) -> usize {
+        self.semaphore.available_permits()
+    }
+
+    /// Establishes a SOCKS5 connection to the specified destination, once a handshake slot is available.
+    /// The slot is released as soon as the handshake completes, not when the tunnel closes.
+    ///
+    /// # Arguments
+    ///
+    /// * `destination` - The target address and port to connect to.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing a tuple with a `TcpStream` to the destination and the bound address.
+    pub async fn connect<A>(
+        &self,
+        destination: A,
+    ) -> Result<(TcpStream, Address), SocksError>
+        where
+            A: TryInto<Address>,
+            A::Error: Into<anyhow::Error>,
+    {
+        let _permit = self.semaphore.acquire().await.expect("The semaphore is never closed.");
+
+        self.client.connect(destination).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::Socks5Handler;
+
+    // Tests that handshakes beyond the limit wait for a slot, rather than fail.
+    #[tokio::test]
+    async fn test_pool_connect() -> Result<()> {
+        let destination = TcpListener::bind("127.0.0.1:0").await?;
+        let destination_addr = destination.local_addr()?;
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        tokio::spawn(async move {
+            loop {
+                let (mut source, _) = proxy.accept().await.unwrap();
+                tokio::spawn(async move { Socks5Handler::default().setup(&mut source).await.unwrap() });
+            }
+        });
+
+        let pool = Socks5Pool::new(Socks5Client::new(proxy_addr.to_string(), None).await?, 1);
+        let (first, second) = tokio::join!(
+            pool.connect(destination_addr.to_string()),
+            pool.connect(destination_addr.to_string()),
+        );
+        first?;
+        second?;
+        assert_eq!(pool.available_handshakes(), 1);
+
+        Ok(())
+    }
+}
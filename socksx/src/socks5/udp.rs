@@ -0,0 +1,267 @@
+// SOCKS5 UDP request headers, and reassembly of fragmented datagrams.
+use std::time::Duration;
+
+use anyhow::Result;
+use bytes::{BufMut, BytesMut};
+use futures::FutureExt;
+use tokio::time::Instant;
+
+use crate::addresses::Address;
+use crate::constants::SOCKS_RSV;
+
+/// The bit of the FRAG field that marks the last fragment of a sequence.
+const FRAG_END_OF_SEQUENCE: u8 = 0x80;
+
+/// How long an incomplete sequence of fragments is kept, by default. RFC 1928 asks for at least 5 seconds.
+pub const FRAGMENT_REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Represents a datagram relayed over a SOCKS5 UDP association, i.e. a UDP request header and its payload.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Socks5UdpDatagram {
+    /// The position of the fragment, `0` if the datagram isn't fragmented.
+    pub frag: u8,
+    /// The destination (client to proxy) or source (proxy to client) of the datagram.
+    pub address: Address,
+    pub data: Vec<u8>,
+}
+
+impl Socks5UdpDatagram {
+    /// Creates a new, unfragmented, datagram.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - The destination or source of the datagram.
+    /// * `data` - The payload.
+    ///
+    /// # Returns
+    ///
+    /// A new `Socks5UdpDatagram` instance.
+    pub fn new(
+        address: Address,
+        data: Vec<u8>,
+    ) -> Self {
+        Socks5UdpDatagram { frag: 0, address, data }
+    }
+
+    /// Deserializes the datagram from bytes, e.g. a received UDP datagram.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The UDP request header, followed by the payload.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the datagram, or an error if the header is malformed.
+    pub fn from_socks_bytes(bytes: &[u8]) -> Result<Self> {
+        ensure!(bytes.len() >= 3, "Expected at least 3 bytes, got: {}", bytes.len());
+
+        let frag = bytes[2];
+        let mut remaining = &bytes[3..];
+        let address = Address::from_socks_bytes(&mut remaining)
+            .now_or_never()
+            .expect("Reading from a slice never blocks.")?;
+
+        Ok(Socks5UdpDatagram {
+            frag,
+            address,
+            data: remaining.to_vec(),
+        })
+    }
+
+    /// Serializes the datagram into bytes, fails if the address can't be encoded.
+    pub fn into_socks_bytes(self) -> Result<Vec<u8>> {
+        let mut buf = BytesMut::with_capacity(3 + self.address.encoded_len() + self.data.len());
+        buf.put_slice(&[SOCKS_RSV, SOCKS_RSV, self.frag]);
+        self.address.encode(&mut buf)?;
+        buf.put_slice(&self.data);
+
+        Ok(buf.into())
+    }
+
+    /// Returns whether the datagram is a fragment of a larger one.
+    pub fn is_fragment(&self) -> bool {
+        self.frag != 0
+    }
+}
+
+/// Reassembles fragmented datagrams of a single association, as described in RFC 1928.
+///
+/// Most implementations don't fragment, so by default fragments are rejected. If reassembly is enabled, fragments are
+/// queued until the one marking the end of the sequence arrives. A sequence is abandoned when a fragment arrives out
+/// of order, or when it isn't completed in time.
+#[derive(Debug)]
+pub struct FragmentReassembler {
+    reassemble_fragments: bool,
+    timeout: Duration,
+    queue: Vec<Socks5UdpDatagram>,
+    started: Option<Instant>,
+}
+
+impl Default for FragmentReassembler {
+    /// Creates a `FragmentReassembler` that rejects fragments.
+    fn default() -> Self {
+        Self::new(false)
+    }
+}
+
+impl FragmentReassembler {
+    /// Creates a new `FragmentReassembler`.
+    ///
+    /// # Arguments
+    ///
+    /// * `reassemble_fragments` - Whether fragments are reassembled, rather than rejected.
+    ///
+    /// # Returns
+    ///
+    /// A new `FragmentReassembler` instance, with an empty queue.
+    pub fn new(reassemble_fragments: bool) -> Self {
+        FragmentReassembler {
+            reassemble_fragments,
+            timeout: FRAGMENT_REASSEMBLY_TIMEOUT,
+            queue: vec![],
+            started: None,
+        }
+    }
+
+    /// Sets how long an incomplete sequence of fragments is kept, before it's discarded.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - The reassembly timeout, defaults to 5s.
+    pub fn set_timeout(
+        &mut self,
+        timeout: Duration,
+    ) {
+        self.timeout = timeout;
+    }
+
+    /// Returns the number of queued fragments, of an incomplete sequence.
+    pub fn pending(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Passes a received datagram to the reassembler.
+    ///
+    /// # Arguments
+    ///
+    /// * `datagram` - The received datagram, which may be a fragment.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the complete datagram if there is one, `None` if the fragment was queued (or dropped,
+    /// as it arrived out of order), or an error if it's a fragment and reassembly isn't enabled.
+    pub fn push(
+        &mut self,
+        datagram: Socks5UdpDatagram,
+    ) -> Result<Option<Socks5UdpDatagram>> {
+        // Any datagram with a lower position abandons the queue, which includes unfragmented ones.
+        if !datagram.is_fragment() {
+            self.reset();
+            return Ok(Some(datagram));
+        }
+
+        ensure!(
+            self.reassemble_fragments,
+            "Fragmented datagrams aren't reassembled, dropping fragment: {:#04x}",
+            datagram.frag
+        );
+
+        let position = (datagram.frag & !FRAG_END_OF_SEQUENCE) as usize;
+        ensure!(position > 0, "Invalid fragment position: {:#04x}", datagram.frag);
+
+        if self.started.is_some_and(|started| started.elapsed() > self.timeout) {
+            debug!("Discarding {} fragments of an incomplete datagram", self.queue.len());
+            self.reset();
+        }
+
+        if position != self.queue.len() + 1 {
+            self.reset();
+            if position != 1 {
+                debug!("Dropping fragment {} that arrived out of order", position);
+                return Ok(None);
+            }
+        }
+
+        if self.queue.is_empty() {
+            self.started = Some(Instant::now());
+        }
+        let end_of_sequence = datagram.frag & FRAG_END_OF_SEQUENCE != 0;
+        self.queue.push(datagram);
+
+        if !end_of_sequence {
+            return Ok(None);
+        }
+
+        let mut fragments = std::mem::take(&mut self.queue).into_iter();
+        self.started = None;
+
+        let first = fragments.next().expect("The queue holds at least the last fragment.");
+        let mut data = first.data;
+        fragments.for_each(|fragment| data.extend(fragment.data));
+
+        Ok(Some(Socks5UdpDatagram::new(first.address, data)))
+    }
+
+    /// Abandons the queued fragments, if any.
+    fn reset(&mut self) {
+        self.queue.clear();
+        self.started = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fragment(
+        frag: u8,
+        data: &[u8],
+    ) -> Socks5UdpDatagram {
+        Socks5UdpDatagram {
+            frag,
+            address: Address::new("192.0.2.1", 53),
+            data: data.to_vec(),
+        }
+    }
+
+    // Test that a datagram survives serialization, with the header laid out as in RFC 1928.
+    #[test]
+    fn test_datagram_roundtrip() -> Result<()> {
+        let datagram = fragment(0x81, &[1, 2, 3]);
+        let bytes = datagram.clone().into_socks_bytes()?;
+        assert_eq!(bytes[..4], [0, 0, 0x81, 0x01]);
+
+        assert_eq!(Socks5UdpDatagram::from_socks_bytes(&bytes)?, datagram);
+        assert!(Socks5UdpDatagram::from_socks_bytes(&[0, 0]).is_err());
+
+        Ok(())
+    }
+
+    // Tests that fragments are rejected by default, and otherwise reassembled in order.
+    #[tokio::test]
+    async fn test_reassembly() -> Result<()> {
+        let mut reassembler = FragmentReassembler::default();
+        assert!(reassembler.push(fragment(0x01, b"ab")).is_err());
+        assert_eq!(reassembler.push(fragment(0, b"ab"))?, Some(fragment(0, b"ab")));
+
+        let mut reassembler = FragmentReassembler::new(true);
+        assert_eq!(reassembler.push(fragment(0x01, b"ab"))?, None);
+        assert_eq!(reassembler.push(fragment(0x02, b"cd"))?, None);
+        assert_eq!(reassembler.push(fragment(0x83, b"ef"))?, Some(fragment(0, b"abcdef")));
+        assert_eq!(reassembler.pending(), 0);
+
+        // A fragment that arrives out of order abandons the sequence.
+        assert_eq!(reassembler.push(fragment(0x01, b"ab"))?, None);
+        assert_eq!(reassembler.push(fragment(0x83, b"ef"))?, None);
+        assert_eq!(reassembler.pending(), 0);
+
+        // So does a sequence that isn't completed in time.
+        reassembler.set_timeout(Duration::from_millis(10));
+        assert_eq!(reassembler.push(fragment(0x01, b"ab"))?, None);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(reassembler.push(fragment(0x82, b"cd"))?, None);
+        assert_eq!(reassembler.pending(), 0);
+
+        Ok(())
+    }
+}
@@ -1,3 +1,5 @@
+use std::net::IpAddr;
+
 use crate::addresses::ProxyAddress;
 use crate::socks6::options::{MetadataOption, SocksOption};
 
@@ -69,6 +71,18 @@ impl SocksChain {
         }
     }
 
+    /// Returns the first `ProxyAddress` that occurs more than once in the chain, if any.
+    /// A repeated link means that the chain would relay in circles. Hosts are compared as written (ignoring case and
+    /// the notation of IP addresses), links that only resolve to the same address are caught by the hop dialing them.
+    pub fn find_loop(&self) -> Option<&ProxyAddress> {
+        self.links.iter().enumerate().find_map(|(i, link)| {
+            self.links[..i]
+                .iter()
+                .any(|l| l.port == link.port && normalize_host(&l.host) == normalize_host(&link.host))
+                .then_some(link)
+        })
+    }
+
     /// Converts the `SocksChain` into a vector of `SocksOption`s.
     /// Adds metadata options to indicate the current index and total length of the chain.
     pub fn as_options(&self) -> Vec<SocksOption> {
@@ -87,6 +101,15 @@ impl SocksChain {
     }
 }
 
+// Normalizes a host for comparison, e.g. `[::1]` and `0::1`, or `Example.com.` and `example.com`, are the same.
+fn normalize_host(host: &str) -> String {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    match host.parse::<IpAddr>() {
+        Ok(ip) => ip.to_string(),
+        Err(_) => host.trim_end_matches('.').to_ascii_lowercase(),
+    }
+}
+
 // Test cases for `SocksChain`.
 #[cfg(test)]
 mod tests {
@@ -164,4 +187,30 @@ mod tests {
         let order: Vec<u16> = chain.links.iter().map(|l| l.port).collect();
         assert_eq!(order, vec![1, 2, 4, 5, 3]);
     }
+
+    // Tests that repeated links are reported as a loop.
+    #[test]
+    pub fn test_find_loop() {
+        let mut chain = SocksChain::new(
+            0,
+            vec![
+                ProxyAddress::new(6, String::from("localhost"), 1, None),
+                ProxyAddress::new(6, String::from("localhost"), 2, None),
+            ],
+        );
+        assert_eq!(chain.find_loop(), None);
+
+        chain.detour(&[ProxyAddress::new(6, String::from("localhost"), 2, None)]);
+        assert_eq!(chain.find_loop().map(|l| l.port), Some(2));
+
+        let chain = SocksChain::new(
+            0,
+            vec![
+                ProxyAddress::new(6, String::from("LocalHost."), 1, None),
+                ProxyAddress::new(6, String::from("[::1]"), 1, None),
+                ProxyAddress::new(6, String::from("0::1"), 1, None),
+            ],
+        );
+        assert_eq!(chain.find_loop().map(|l| l.host.as_str()), Some("0::1"));
+    }
 }
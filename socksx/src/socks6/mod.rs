@@ -1,20 +1,24 @@
 // General purpose SOCKS6 module.
 use std::collections::HashMap;
-use std::convert::TryInto;
+use std::convert::TryFrom;
 
-use anyhow::{ensure, Result};
+use anyhow::{ensure, Context, Result};
+use bytes::{BufMut, BytesMut};
+use futures::FutureExt;
 use num_traits::FromPrimitive;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 // Module imports
 pub use chain::SocksChain;
-pub use s6_client::Socks6Client;
-pub use s6_handler::Socks6Handler;
+pub use s6_client::{Socks6Client, Socks6ClientBuilder};
+pub use s6_handler::{RequestHook, Socks6Handler};
+pub use udp::{Socks6UdpAssociation, UdpFraming};
 
-use crate::{constants::*, ProxyAddress};
-use crate::addresses::{self, Address};
+use crate::{constants::*, Command, ProxyAddress, SocksError};
+use crate::addresses::Address;
 use crate::socks6::options::{
-    AuthMethodAdvertisementOption, AuthMethodSelectionOption, MetadataOption, SocksOption, UnrecognizedOption,
+    AuthMethodAdvertisementOption, AuthMethodSelectionOption, MetadataOption, SocksOption, StackOption,
+    UnknownOptionPolicy, UnrecognizedOption,
 };
 
 // Sub-modules
@@ -22,6 +26,7 @@ pub mod chain;
 pub mod options;
 mod s6_client;
 mod s6_handler;
+pub mod udp;
 
 /// Authentication methods supported.
 #[repr(u8)]
@@ -33,45 +38,55 @@ pub enum AuthMethod {
     NoAcceptableMethods = 0xFF,
 }
 
-/// Command types in SOCKS6.
-#[repr(u8)]
-#[derive(Clone, Debug, FromPrimitive, PartialEq)]
-pub enum Socks6Command {
-    NoOp = 0x00,
-    Connect = 0x01,
-    Bind = 0x02,
-    UdpAssociate = 0x03,
-}
-
 /// Represents a SOCKS6 request.
 #[derive(Clone, Debug)]
 pub struct Socks6Request {
-    pub command: Socks6Command,
+    pub command: Command,
     pub destination: Address,
     pub initial_data_length: u16,
     pub options: Vec<SocksOption>,
     pub metadata: HashMap<u16, String>,
+    /// Option bytes that are sent verbatim after `options`, e.g. options the crate doesn't model.
+    pub raw_options: Vec<u8>,
 }
 
 impl Socks6Request {
     /// Constructor for Socks6Request
     pub fn new(
-        command: u8,
+        command: Command,
         destination: Address,
         initial_data_length: u16,
         options: Vec<SocksOption>,
         metadata: Option<HashMap<u16, String>>,
     ) -> Self {
         Socks6Request {
-            command: Socks6Command::from_u8(command).unwrap(),
+            command,
             destination,
             initial_data_length,
             options,
             metadata: metadata.unwrap_or_default(),
+            raw_options: vec![],
+        }
+    }
+
+    /// Constructs a request whose options are given as raw bytes, which are sent verbatim, e.g. to test how a
+    /// server reacts to unknown or malformed options. Parsing the request yields them as `options` again, with
+    /// unknown kinds kept as `SocksOption::Unrecognized`.
+    pub fn with_raw_options(
+        command: Command,
+        destination: Address,
+        initial_data_length: u16,
+        raw_options: Vec<u8>,
+    ) -> Self {
+        Socks6Request {
+            raw_options,
+            ..Self::new(command, destination, initial_data_length, vec![], None)
         }
     }
 
     /// Chain function to link multiple proxies.
+    /// Fails if the chain in the metadata is incomplete or malformed, or if the resulting chain visits the same proxy
+    /// more than once.
     pub fn chain(
         &self,
         static_links: &[ProxyAddress],
@@ -79,14 +94,31 @@ impl Socks6Request {
         let length = self.metadata.get(&999u16);
 
         let mut chain = if let Some(length) = length {
-            let length: usize = length.parse()?;
-            let index = self.metadata.get(&998u16).unwrap().parse()?;
-
-            let links: Vec<ProxyAddress> = (1000..1000 + length)
-                .map(|i| i as u16)
-                .map(|i| self.metadata.get(&i).unwrap().clone())
-                .map(|x| x.try_into().unwrap())
-                .collect();
+            let length: usize = length.parse().context("Invalid proxy chain length.")?;
+            // Every link is a metadata entry of its own, so there can't be more links than entries.
+            ensure!(
+                length <= self.metadata.len(),
+                "Proxy chain of length {} exceeds the {} metadata entries of the request.",
+                length,
+                self.metadata.len()
+            );
+
+            let index = self
+                .metadata
+                .get(&998u16)
+                .ok_or_else(|| anyhow!("Proxy chain of length {} is missing its index.", length))?;
+            let index: usize = index.parse().context("Invalid proxy chain index.")?;
+            ensure!(index < length, "Proxy chain index {} is out of bounds for length {}.", index, length);
+
+            let links = (0..length as u16)
+                .map(|i| {
+                    let link = self
+                        .metadata
+                        .get(&(1000 + i))
+                        .ok_or_else(|| anyhow!("Proxy chain is missing link {} of {}.", i, length))?;
+                    ProxyAddress::try_from(link.clone()).with_context(|| format!("Invalid proxy chain link: {}", link))
+                })
+                .collect::<Result<Vec<_>>>()?;
 
             SocksChain::new(index, links)
         } else {
@@ -97,6 +129,10 @@ impl Socks6Request {
             chain.detour(static_links);
         }
 
+        if let Some(link) = chain.find_loop() {
+            bail!("Proxy chain contains a loop, {} appears more than once.", link);
+        }
+
         if chain.links.is_empty() {
             Ok(None)
         } else {
@@ -104,24 +140,69 @@ impl Socks6Request {
         }
     }
 
-    /// Convert the request into a byte sequence for SOCKS6.
-    pub fn into_socks_bytes(self) -> Vec<u8> {
-        let mut data = vec![SOCKS_VER_6, SOCKS_CMD_CONNECT];
-        data.extend(self.destination.as_socks_bytes());
-        data.push(SOCKS_PADDING);
+    /// Convert the request into a byte sequence for SOCKS6, fails if the destination can't be encoded.
+    pub fn into_socks_bytes(self) -> Result<Vec<u8>> {
+        let mut buf = BytesMut::new();
+        self.encode(&mut buf)?;
+
+        Ok(buf.into())
+    }
 
-        let options_bytes: Vec<_> = self.options.into_iter().flat_map(|o| o.as_socks_bytes()).collect();
-        let options_bytes_length = (options_bytes.len() as u16).to_be_bytes();
+    /// Appends the request, as by `into_socks_bytes`, to a caller-provided buffer.
+    /// The buffer can be reused across requests, so that encoding doesn't allocate once it has grown large enough.
+    pub fn encode(
+        &self,
+        buf: &mut BytesMut,
+    ) -> Result<()> {
+        buf.put_slice(&[SOCKS_VER_6, self.command.to_byte()]);
+        self.destination.encode(buf)?;
+        buf.put_u8(SOCKS_PADDING);
+        encode_options(buf, &self.options, &self.raw_options);
+
+        Ok(())
+    }
 
-        data.extend(options_bytes_length.iter());
-        data.extend(options_bytes.iter());
+    /// Parses a SOCKS6 request from an in-memory buffer, without the need for a socket.
+    /// Returns the request and the number of bytes it occupied, any initial data that follows is left untouched.
+    pub fn parse(buf: &[u8]) -> Result<(Self, usize)> {
+        let mut remaining = buf;
+        let request = read_request(&mut remaining)
+            .now_or_never()
+            .expect("Reading from a slice never blocks.")?;
 
-        data
+        Ok((request, buf.len() - remaining.len()))
     }
 }
 
 /// Reads a SOCKS6 request from the provided stream.
 pub async fn read_request<S>(stream: &mut S) -> Result<Socks6Request>
+where
+    S: AsyncRead + Unpin,
+{
+    read_request_with_policy(stream, UnknownOptionPolicy::default()).await
+}
+
+/// Reads a SOCKS6 request from the provided stream, handling unknown options according to `policy`.
+pub async fn read_request_with_policy<S>(
+    stream: &mut S,
+    policy: UnknownOptionPolicy,
+) -> Result<Socks6Request>
+where
+    S: AsyncRead + Unpin,
+{
+    read_request_with_limits(stream, policy, SOCKS_MAX_OPTIONS_LENGTH, SOCKS_MAX_OPTIONS_COUNT).await
+}
+
+/// Reads a SOCKS6 request from the provided stream, handling unknown options according to `policy`.
+/// Requests whose options are longer than `max_options_length` in total, or that announce more than
+/// 16384 bytes of initial data, are rejected before their options or initial data are read. Requests with more than
+/// `max_options_count` options are rejected once the option past the limit is reached.
+pub async fn read_request_with_limits<S>(
+    stream: &mut S,
+    policy: UnknownOptionPolicy,
+    max_options_length: u16,
+    max_options_count: u16,
+) -> Result<Socks6Request>
 where
     S: AsyncRead + Unpin,
 {
@@ -133,14 +214,14 @@ where
 
     // Validate the request.
     ensure!(version == SOCKS_VER_6, "Version mismatch!");
-    ensure!(command == SOCKS_CMD_CONNECT, "Only COMMAND is supported!");
+    let command = Command::from_byte(command).ok_or(SocksError::CommandNotSupported(command))?;
 
-    let destination = addresses::read_address(stream).await?;
+    let destination = Address::from_socks_bytes(stream).await?;
 
     let mut padding = [0; 1];
     stream.read_exact(&mut padding).await?;
 
-    let options = read_options(stream).await?;
+    let options = read_options_with_limits(stream, policy, max_options_length, max_options_count).await?;
 
     let mut initial_data_length = 0;
     let mut metadata = HashMap::new();
@@ -161,6 +242,13 @@ where
         }
     }
 
+    ensure!(
+        initial_data_length <= SOCKS_MAX_INITIAL_DATA_LENGTH,
+        "Initial data length of {} exceeds the maximum of {} bytes.",
+        initial_data_length,
+        SOCKS_MAX_INITIAL_DATA_LENGTH
+    );
+
     Ok(Socks6Request::new(
         command,
         destination,
@@ -172,6 +260,32 @@ where
 
 /// Reads the SOCKS6 options from the stream.
 pub async fn read_options<S>(stream: &mut S) -> Result<Vec<SocksOption>>
+where
+    S: AsyncRead + Unpin,
+{
+    read_options_with_policy(stream, UnknownOptionPolicy::default()).await
+}
+
+/// Reads the SOCKS6 options from the stream, handling unknown options according to `policy`.
+pub async fn read_options_with_policy<S>(
+    stream: &mut S,
+    policy: UnknownOptionPolicy,
+) -> Result<Vec<SocksOption>>
+where
+    S: AsyncRead + Unpin,
+{
+    read_options_with_limits(stream, policy, SOCKS_MAX_OPTIONS_LENGTH, SOCKS_MAX_OPTIONS_COUNT).await
+}
+
+/// Reads the SOCKS6 options from the stream, handling unknown options according to `policy`.
+/// Fails, before reading any option, if the options are longer than `max_options_length` in total, and before
+/// reading the option past the limit, if there are more than `max_options_count` options (including dropped ones).
+pub async fn read_options_with_limits<S>(
+    stream: &mut S,
+    policy: UnknownOptionPolicy,
+    max_options_length: u16,
+    max_options_count: u16,
+) -> Result<Vec<SocksOption>>
 where
     S: AsyncRead + Unpin,
 {
@@ -181,63 +295,118 @@ where
     stream.read_exact(&mut options_length).await?;
 
     let options_length = ((options_length[0] as u16) << 8) | options_length[1] as u16;
+    ensure!(
+        options_length <= max_options_length,
+        "Options length of {} exceeds the maximum of {} bytes.",
+        options_length,
+        max_options_length
+    );
+
     let mut options_bytes_read = 0;
+    let mut options_count = 0;
 
     while options_bytes_read < options_length {
+        options_count += 1;
+        ensure!(
+            options_count <= max_options_count,
+            "Options exceed the maximum of {} options.",
+            max_options_count
+        );
+
         let mut buffer = [0; 4];
         stream.read_exact(&mut buffer).await?;
 
         let [kind_0, kind_1, length_0, length_1] = buffer;
         let kind = ((kind_0 as u16) << 8) | kind_1 as u16;
         let length = ((length_0 as u16) << 8) | length_1 as u16;
+        ensure!(
+            length >= 4 && length <= options_length - options_bytes_read,
+            "Invalid length of option {}: {}.",
+            kind,
+            length
+        );
 
         // Read remaining bytes of this option.
         let mut options_data = vec![0; (length - 4) as usize];
         stream.read_exact(&mut options_data).await?;
 
+        options_bytes_read += length;
+
         let option = match kind {
+            0x0001 => StackOption::from_socks_bytes(options_data)?,
             0x0002 => AuthMethodAdvertisementOption::from_socks_bytes(options_data)?,
             0x0003 => AuthMethodSelectionOption::from_socks_bytes(options_data)?,
             0xFDE8 => MetadataOption::from_socks_bytes(options_data)?,
-            _ => UnrecognizedOption::new(kind, options_data.to_vec()).wrap(),
+            _ => match policy {
+                UnknownOptionPolicy::Preserve => UnrecognizedOption::new(kind, options_data.to_vec()).wrap(),
+                UnknownOptionPolicy::Drop => continue,
+                UnknownOptionPolicy::Reject => bail!("Unrecognized option kind: {}", kind),
+            },
         };
 
         options.push(option);
-        options_bytes_read += length;
     }
 
     Ok(options)
 }
 
-/// Reads the authentication response.
-pub async fn read_no_authentication<S>(stream: &mut S) -> Result<Vec<SocksOption>>
+/// Authentication reply types in SOCKS6.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, FromPrimitive, PartialEq)]
+pub enum Socks6AuthReplyType {
+    Success = 0x00,
+    Failure = 0x01,
+}
+
+/// Represents a SOCKS6 authentication reply.
+#[derive(Clone, Debug)]
+pub struct Socks6AuthReply {
+    pub reply_type: Socks6AuthReplyType,
+    pub options: Vec<SocksOption>,
+}
+
+impl Socks6AuthReply {
+    /// Returns the authentication method the proxy selected, if the reply carries a selection option.
+    pub fn selected_method(&self) -> Option<options::AuthMethod> {
+        self.options.iter().find_map(|option| match option {
+            SocksOption::AuthMethodSelection(selection) => Some(selection.method.clone()),
+            _ => None,
+        })
+    }
+}
+
+/// Reads an authentication reply, and the options it carries, from the stream.
+pub async fn read_authentication_reply<S>(stream: &mut S) -> Result<Socks6AuthReply, SocksError>
 where
     S: AsyncRead + Unpin,
 {
-    // Read auth reply
-    let mut reply = [0; 1];
+    let mut reply = [0; 2];
     stream.read_exact(&mut reply).await?;
 
-    let socks_version = reply[0];
-    ensure!(
-        socks_version == SOCKS_VER_6,
-        "Proxy uses a different SOCKS version: {}",
-        socks_version
-    );
+    let [socks_version, reply_type] = reply;
+    if socks_version != SOCKS_VER_6 {
+        return Err(SocksError::VersionMismatch(socks_version));
+    }
 
-    let mut reply = [0; 1];
-    stream.read_exact(&mut reply).await?;
+    let reply_type = Socks6AuthReplyType::from_u8(reply_type)
+        .ok_or_else(|| anyhow!("Proxy sent an unknown authentication reply type: {}.", reply_type))?;
+    let options = read_options(stream).await?;
 
-    let status = reply[0];
-    ensure!(
-        status == SOCKS_AUTH_SUCCESS,
-        "Authentication with proxy failed: {}",
-        status
-    );
+    Ok(Socks6AuthReply { reply_type, options })
+}
 
-    let options = read_options(stream).await?;
+/// Reads the authentication response, which must indicate success.
+/// Returns the options it carries, e.g. session or idempotence options.
+pub async fn read_no_authentication<S>(stream: &mut S) -> Result<Vec<SocksOption>, SocksError>
+where
+    S: AsyncRead + Unpin,
+{
+    let reply = read_authentication_reply(stream).await?;
+    if reply.reply_type != Socks6AuthReplyType::Success {
+        return Err(SocksError::AuthFailed);
+    }
 
-    Ok(options)
+    Ok(reply.options)
 }
 
 /// Writes a reply to indicate no authentication is needed.
@@ -247,7 +416,7 @@ where
 {
     // Write auth reply
     let auth_reply = [SOCKS_VER_6, SOCKS_AUTH_SUCCESS, 0x00u8, 0x00u8];
-    stream.write(&auth_reply).await?;
+    stream.write_all(&auth_reply).await?;
 
     Ok(())
 }
@@ -280,6 +449,31 @@ pub enum Socks6Reply {
     ConnectionAttemptTimeOut = 0x09,
 }
 
+impl Socks6Reply {
+    /// Selects the reply that tells the client why the destination (or the next hop) couldn't be reached.
+    pub fn from_dial_error(error: &anyhow::Error) -> Self {
+        use std::io::ErrorKind::*;
+
+        let kind = match error.downcast_ref::<SocksError>() {
+            // Pass on the reason an upstream proxy gave.
+            Some(SocksError::ReplyFailure(code)) => {
+                return Socks6Reply::from_u8(*code).unwrap_or(Socks6Reply::GeneralFailure);
+            }
+            Some(SocksError::Io(error)) => Some(error.kind()),
+            _ => error.downcast_ref::<std::io::Error>().map(|e| e.kind()),
+        };
+
+        match kind {
+            Some(ConnectionRefused) => Socks6Reply::ConnectionRefused,
+            Some(HostUnreachable) => Socks6Reply::HostUnreachable,
+            Some(NetworkUnreachable) => Socks6Reply::NetworkUnreachable,
+            Some(PermissionDenied) => Socks6Reply::ConnectionNotAllowed,
+            Some(TimedOut) => Socks6Reply::ConnectionAttemptTimeOut,
+            _ => Socks6Reply::GeneralFailure,
+        }
+    }
+}
+
 /// Writes a SOCKS6 reply to the stream.
 pub async fn write_reply<S>(
     stream: &mut S,
@@ -288,28 +482,48 @@ pub async fn write_reply<S>(
 where
     S: AsyncWrite + Unpin,
 {
-    let reply = [
-        SOCKS_VER_6,
-        reply as u8,
-        SOCKS_PADDING,
-        SOCKS_ATYP_IPV4,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-    ];
-
-    stream.write(&reply).await?;
+    write_reply_with_options(stream, reply, &[]).await
+}
+
+/// Writes a SOCKS6 reply, carrying the given options, to the stream.
+pub async fn write_reply_with_options<S>(
+    stream: &mut S,
+    reply: Socks6Reply,
+    options: &[SocksOption],
+) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let mut data = BytesMut::new();
+    data.put_slice(&[SOCKS_VER_6, reply as u8, SOCKS_PADDING]);
+    Address::new("0.0.0.0", 0).encode(&mut data)?;
+    encode_options(&mut data, options, &[]);
+
+    stream.write_all(&data).await?;
 
     Ok(())
 }
 
+/// Appends options, followed by raw option bytes, to the buffer, preceded by their total length.
+fn encode_options(
+    buf: &mut BytesMut,
+    options: &[SocksOption],
+    raw_options: &[u8],
+) {
+    let start = buf.len();
+    // The length is only known once the options are written, it's filled in below.
+    buf.put_u16(0);
+    for option in options {
+        option.encode(buf);
+    }
+    buf.put_slice(raw_options);
+
+    let options_length = (buf.len() - start - 2) as u16;
+    buf[start..start + 2].copy_from_slice(&options_length.to_be_bytes());
+}
+
 /// Reads a SOCKS6 reply from the stream.
-pub async fn read_reply<S>(stream: &mut S) -> Result<(Address, Vec<SocksOption>)>
+pub async fn read_reply<S>(stream: &mut S) -> Result<(Address, Vec<SocksOption>), SocksError>
 where
     S: AsyncRead + Unpin,
 {
@@ -317,13 +531,11 @@ where
     stream.read_exact(&mut operation_reply).await?;
 
     let reply_code = operation_reply[1];
-    ensure!(
-        reply_code == SOCKS_REP_SUCCEEDED,
-        "CONNECT operation failed: {:?}",
-        Socks6Reply::from_u8(reply_code)
-    );
+    if reply_code != SOCKS_REP_SUCCEEDED {
+        return Err(SocksError::ReplyFailure(reply_code));
+    }
 
-    let binding = addresses::read_address(stream).await?;
+    let binding = Address::from_socks_bytes(stream).await?;
     let options = read_options(stream).await?;
 
     Ok((binding, options))
@@ -337,7 +549,7 @@ mod tests {
     #[test]
     fn test_new_socks6_request() {
         let request = Socks6Request::new(
-            Socks6Command::Connect as u8,
+            Command::Connect,
             Address::new("192.168.1.1", 80),
             0,
             vec![],
@@ -345,7 +557,7 @@ mod tests {
         );
 
         // Ensure the fields are correctly set.
-        assert_eq!(request.command, Socks6Command::Connect);
+        assert_eq!(request.command, Command::Connect);
         assert_eq!(
             request.destination,
             Address::new("192.168.1.1", 80),
@@ -355,18 +567,283 @@ mod tests {
         assert_eq!(request.metadata.len(), 0);
     }
 
+    // Tests that chains in the metadata of a request are rejected if they're incomplete or malformed.
+    #[test]
+    fn test_chain_malformed_metadata() {
+        let chain = |entries: &[(u16, &str)]| {
+            let metadata = entries.iter().map(|(k, v)| (*k, v.to_string())).collect();
+            Socks6Request::new(Command::Connect, Address::new("10.0.0.1", 80), 0, vec![], Some(metadata)).chain(&[])
+        };
+
+        let link = "socks6://10.0.0.2:1080";
+        assert_eq!(chain(&[(999, "1"), (998, "0"), (1000, link)]).unwrap().unwrap().links.len(), 1);
+
+        // Missing index, index out of bounds, missing or unparsable links.
+        assert!(chain(&[(999, "1"), (1000, link)]).is_err());
+        assert!(chain(&[(999, "1"), (998, "5"), (1000, link)]).is_err());
+        assert!(chain(&[(999, "2"), (998, "0"), (1000, link), (1002, link)]).is_err());
+        assert!(chain(&[(999, "1"), (998, "0"), (1000, "not a link")]).is_err());
+        assert!(chain(&[(999, "65535"), (998, "0"), (1000, link)]).is_err());
+        assert!(chain(&[(999, "-1"), (998, "0"), (1000, link)]).is_err());
+    }
+
     // Test conversion of Socks6Request into a byte sequence.
     #[test]
     fn test_into_socks_bytes() {
         let request = Socks6Request::new(
-            Socks6Command::Connect as u8,
+            Command::Connect,
             Address::new("192.168.1.1", 80),
             0,
             vec![],
             None,
         );
-        let result = request.into_socks_bytes();
+        let result = request.into_socks_bytes().unwrap();
         let expected_result: Vec<u8> = vec![6, 1, 1, 192, 168, 1, 1, 0, 80, 0, 0, 0];
         assert_eq!(result, expected_result);
     }
-}
\ No newline at end of file
+
+    // Test that a reply without options is framed as before.
+    #[tokio::test]
+    async fn test_write_reply() {
+        let mut bytes = vec![];
+        write_reply(&mut bytes, Socks6Reply::Success).await.unwrap();
+        assert_eq!(bytes, vec![6, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    // Test that encoding appends to the buffer, the same bytes as `into_socks_bytes` returns.
+    #[test]
+    fn test_encode_appends() {
+        let request = Socks6Request::new(
+            Command::Connect,
+            Address::new("example.com", 443),
+            0,
+            vec![StackOption::tfo(512).wrap(), MetadataOption::new(1, String::from("value")).wrap()],
+            None,
+        );
+
+        let mut buf = BytesMut::from(&b"prefix"[..]);
+        request.encode(&mut buf).unwrap();
+        assert_eq!(&buf[..6], b"prefix");
+        assert_eq!(buf[6..], request.into_socks_bytes().unwrap()[..]);
+    }
+
+    // Test that raw options are sent verbatim, and parsed as (unrecognized) options.
+    #[test]
+    fn test_with_raw_options() {
+        let raw_options = vec![0x12, 0x34, 0x00, 0x08, 1, 2, 3, 4];
+        let request = Socks6Request::with_raw_options(Command::Connect, Address::new("10.0.0.1", 80), 0, raw_options);
+
+        let bytes = request.into_socks_bytes().unwrap();
+        assert_eq!(bytes[10..], [0x00, 0x08, 0x12, 0x34, 0x00, 0x08, 1, 2, 3, 4]);
+
+        let (request, _) = Socks6Request::parse(&bytes).unwrap();
+        assert!(matches!(&request.options[..], [SocksOption::Unrecognized(o)] if o.kind() == 0x1234));
+    }
+
+    // Test that options written in a reply are read back.
+    #[tokio::test]
+    async fn test_reply_with_options_roundtrip() {
+        let options = vec![UnrecognizedOption::new(0x1234, vec![1, 2, 3, 4]).wrap()];
+
+        let mut bytes = vec![];
+        write_reply_with_options(&mut bytes, Socks6Reply::Success, &options).await.unwrap();
+
+        let (binding, options) = read_reply(&mut &bytes[..]).await.unwrap();
+        assert_eq!(binding, Address::new("0.0.0.0", 0));
+        assert!(matches!(&options[..], [SocksOption::Unrecognized(o)] if o.kind() == 0x1234));
+    }
+
+    // Test that the options of a no authentication required reply are returned, e.g. a session ID.
+    #[tokio::test]
+    async fn test_read_no_authentication_with_options() {
+        let option = UnrecognizedOption::new(SOCKS_OKIND_SESSION_ID, vec![1, 2, 3, 4]).wrap().as_socks_bytes();
+        let mut bytes = vec![SOCKS_VER_6, SOCKS_AUTH_SUCCESS];
+        bytes.extend((option.len() as u16).to_be_bytes().iter());
+        bytes.extend(option);
+
+        let options = read_no_authentication(&mut &bytes[..]).await.unwrap();
+        assert!(matches!(&options[..], [SocksOption::Unrecognized(o)] if o.kind() == SOCKS_OKIND_SESSION_ID));
+    }
+
+    // Test that a reply is read completely, even if every byte arrives separately.
+    #[tokio::test]
+    async fn test_read_reply_one_byte_at_a_time() {
+        use crate::mock::Trickle;
+
+        let options = vec![MetadataOption::new(1, "a".repeat(255)).wrap(), StackOption::tfo(512).wrap()];
+        let mut reply = vec![];
+        write_reply_with_options(&mut reply, Socks6Reply::Success, &options).await.unwrap();
+
+        let (binding, options) = read_reply(&mut Trickle::new(reply)).await.unwrap();
+        assert_eq!(binding, Address::new("0.0.0.0", 0));
+        assert!(matches!(&options[..], [SocksOption::Metadata(o), SocksOption::Stack(_)] if o.value.len() == 255));
+    }
+
+    // Test that an IPv6 bound address is read whole, followed by the options.
+    #[tokio::test]
+    async fn test_read_reply_ipv6_binding() {
+        let binding = Address::new("2001:db8::1", 1080);
+        let mut bytes = vec![SOCKS_VER_6, SOCKS_REP_SUCCEEDED, SOCKS_PADDING];
+        bytes.extend(binding.to_socks_bytes().unwrap());
+        assert_eq!(bytes[3], SOCKS_ATYP_IPV6);
+        bytes.extend([0, 0].iter());
+
+        let (read_binding, options) = read_reply(&mut &bytes[..]).await.unwrap();
+        assert_eq!(read_binding, binding);
+        assert!(matches!(read_binding, Address::Ip(addr) if addr.is_ipv6()));
+        assert!(options.is_empty());
+    }
+
+    // Test that stack options echoed in a reply are read back.
+    #[tokio::test]
+    async fn test_reply_with_stack_options() {
+        use crate::socks6::options::{StackLeg, StackOptionType};
+
+        let options = vec![StackOption::tfo(512).wrap(), StackOption::mptcp(StackLeg::Both, true).wrap()];
+
+        let mut bytes = vec![];
+        write_reply_with_options(&mut bytes, Socks6Reply::Success, &options).await.unwrap();
+
+        let (_, options) = read_reply(&mut &bytes[..]).await.unwrap();
+        let types: Vec<_> = options
+            .iter()
+            .map(|o| match o {
+                SocksOption::Stack(o) => o.option_type(),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(types, vec![Some(StackOptionType::Tfo), Some(StackOptionType::Multipath)]);
+    }
+
+    // Test parsing a request with an unknown option under each policy.
+    #[tokio::test]
+    async fn test_unknown_option_policy() {
+        let request = Socks6Request::new(
+            Command::Connect,
+            Address::new("192.168.1.1", 80),
+            0,
+            vec![UnrecognizedOption::new(0x1234, vec![1, 2, 3, 4]).wrap()],
+            None,
+        );
+        let bytes = request.into_socks_bytes().unwrap();
+
+        let preserved = read_request_with_policy(&mut &bytes[..], UnknownOptionPolicy::Preserve).await.unwrap();
+        assert_eq!(preserved.options.len(), 1);
+        assert!(matches!(preserved.options[0], SocksOption::Unrecognized(_)));
+
+        let dropped = read_request_with_policy(&mut &bytes[..], UnknownOptionPolicy::Drop).await.unwrap();
+        assert!(dropped.options.is_empty());
+
+        let rejected = read_request_with_policy(&mut &bytes[..], UnknownOptionPolicy::Reject).await;
+        assert!(rejected.is_err());
+    }
+
+    // Test parsing a request from a buffer, leaving the trailing initial data untouched.
+    #[test]
+    fn test_parse_request() {
+        let request = Socks6Request::new(
+            Command::Connect,
+            Address::new("example.com", 443),
+            5,
+            vec![AuthMethodAdvertisementOption::new(5, vec![]).wrap()],
+            None,
+        );
+        let mut bytes = request.into_socks_bytes().unwrap();
+        let length = bytes.len();
+        bytes.extend(b"hello");
+
+        let (parsed, consumed) = Socks6Request::parse(&bytes).unwrap();
+        assert_eq!(parsed.destination, Address::new("example.com", 443));
+        assert_eq!(parsed.initial_data_length, 5);
+        assert_eq!(consumed, length);
+    }
+
+    // Test that a forged options length is rejected before the options are read.
+    #[tokio::test]
+    async fn test_read_request_oversized_options() {
+        let bytes = [6, 1, 1, 127, 0, 0, 1, 0, 80, 0, 0xFF, 0xFF];
+
+        let error = read_request(&mut &bytes[..]).await.unwrap_err();
+        assert!(error.to_string().contains("exceeds the maximum"));
+
+        // Without the limit, the options would be read (and the stream ends prematurely).
+        let policy = UnknownOptionPolicy::default();
+        let error = read_request_with_limits(&mut &bytes[..], policy, 0xFFFF, SOCKS_MAX_OPTIONS_COUNT)
+            .await
+            .unwrap_err();
+        assert!(error.downcast_ref::<std::io::Error>().is_some());
+    }
+
+    // Test that many tiny options are rejected, even when their total length is within the limit.
+    #[tokio::test]
+    async fn test_read_too_many_options() {
+        let count: u16 = 300;
+        let mut options = (count * 4).to_be_bytes().to_vec();
+        for _ in 0..count {
+            options.extend([0x12, 0x34, 0, 4].iter());
+        }
+
+        let mut request = vec![6, 1, 1, 127, 0, 0, 1, 0, 80, 0];
+        request.extend(&options);
+        let error = read_request_with_policy(&mut &request[..], UnknownOptionPolicy::Drop).await.unwrap_err();
+        assert!(error.to_string().contains("maximum of 64 options"), "{}", error);
+
+        let mut reply = vec![SOCKS_VER_6, SOCKS_REP_SUCCEEDED, SOCKS_PADDING, 1, 127, 0, 0, 1, 0, 80];
+        reply.extend(&options);
+        let error = read_reply(&mut &reply[..]).await.unwrap_err();
+        assert!(error.to_string().contains("maximum of 64 options"), "{}", error);
+
+        let mut request = vec![6, 1, 1, 127, 0, 0, 1, 0, 80, 0];
+        request.extend(&options);
+        let parsed = read_request_with_limits(&mut &request[..], UnknownOptionPolicy::Drop, 0xFFFF, count)
+            .await
+            .unwrap();
+        assert!(parsed.options.is_empty());
+    }
+
+    // Test that options whose length is too short, or overruns the option stack, are rejected.
+    #[tokio::test]
+    async fn test_read_options_invalid_length() {
+        // Length shorter than the option header.
+        let bytes = [0, 4, 0x12, 0x34, 0, 2];
+        assert!(read_options(&mut &bytes[..]).await.is_err());
+
+        // Length larger than the option stack.
+        let bytes = [0, 4, 0x12, 0x34, 0xFF, 0xFF, 0, 0];
+        let error = read_options(&mut &bytes[..]).await.unwrap_err();
+        assert!(error.to_string().contains("Invalid length"));
+    }
+
+    // Test that announcing more initial data than allowed is rejected.
+    #[test]
+    fn test_parse_oversized_initial_data() {
+        let request = Socks6Request::new(
+            Command::Connect,
+            Address::new("192.168.1.1", 80),
+            0,
+            vec![AuthMethodAdvertisementOption::new(SOCKS_MAX_INITIAL_DATA_LENGTH + 1, vec![]).wrap()],
+            None,
+        );
+        let bytes = request.into_socks_bytes().unwrap();
+
+        let error = Socks6Request::parse(&bytes).unwrap_err();
+        assert!(error.to_string().contains("Initial data length"));
+    }
+
+    // Test that a request with a truncated option list is rejected.
+    #[test]
+    fn test_parse_truncated_options() {
+        let request = Socks6Request::new(
+            Command::Connect,
+            Address::new("192.168.1.1", 80),
+            0,
+            vec![UnrecognizedOption::new(0x1234, vec![1, 2, 3, 4]).wrap()],
+            None,
+        );
+        let bytes = request.into_socks_bytes().unwrap();
+
+        for length in 0..bytes.len() {
+            assert!(Socks6Request::parse(&bytes[..length]).is_err());
+        }
+    }
+}
@@ -1,6 +1,9 @@
 use anyhow::Result;
+use bytes::{BufMut, BytesMut};
 use num_traits::FromPrimitive;
 
+use crate::constants::SOCKS_OKIND_STACK;
+
 /// Represents SOCKS authentication methods.
 #[repr(u8)]
 #[derive(Clone, Debug, FromPrimitive, PartialEq)]
@@ -11,9 +14,22 @@ pub enum AuthMethod {
     NoAcceptableMethods = 0xFF,
 }
 
+/// Determines how option kinds that aren't recognized are handled while parsing.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum UnknownOptionPolicy {
+    /// Keep unknown options as `SocksOption::Unrecognized`, for forward-compatibility.
+    #[default]
+    Preserve,
+    /// Silently discard unknown options.
+    Drop,
+    /// Reject the message that carries an unknown option.
+    Reject,
+}
+
 /// Enumerates the types of SOCKS options.
 #[derive(Clone, Debug)]
 pub enum SocksOption {
+    Stack(StackOption),
     AuthMethodAdvertisement(AuthMethodAdvertisementOption),
     AuthMethodSelection(AuthMethodSelectionOption),
     Metadata(MetadataOption),
@@ -23,13 +39,25 @@ pub enum SocksOption {
 impl SocksOption {
     /// Converts the SOCKS option to a vector of bytes.
     pub fn as_socks_bytes(&self) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        self.encode(&mut buf);
+
+        buf.into()
+    }
+
+    /// Appends the SOCKS option, as by `as_socks_bytes`, to a caller-provided buffer.
+    pub fn encode(
+        &self,
+        buf: &mut BytesMut,
+    ) {
         use SocksOption::*;
 
         match self {
-            AuthMethodAdvertisement(option) => option.clone().into_socks_bytes(),
-            AuthMethodSelection(option) => option.clone().into_socks_bytes(),
-            Metadata(option) => option.clone().into_socks_bytes(),
-            Unrecognized(option) => option.clone().into_socks_bytes(),
+            Stack(option) => option.encode(buf),
+            AuthMethodAdvertisement(option) => option.encode(buf),
+            AuthMethodSelection(option) => option.encode(buf),
+            Metadata(option) => option.encode(buf),
+            Unrecognized(option) => option.encode(buf),
         }
     }
 }
@@ -79,10 +107,21 @@ impl AuthMethodAdvertisementOption {
 
     /// Serializes the option into bytes.
     pub fn into_socks_bytes(self) -> Vec<u8> {
-        let mut data = self.initial_data_length.to_be_bytes().to_vec();
-        data.extend(self.methods.iter().cloned().map(|m| m as u8));
+        let mut buf = BytesMut::new();
+        self.encode(&mut buf);
 
-        combine_and_pad(0x02, data)
+        buf.into()
+    }
+
+    /// Appends the option to a caller-provided buffer.
+    pub fn encode(
+        &self,
+        buf: &mut BytesMut,
+    ) {
+        encode_padded(buf, 0x02, |buf| {
+            buf.put_u16(self.initial_data_length);
+            buf.extend(self.methods.iter().cloned().map(|m| m as u8));
+        });
     }
 }
 
@@ -117,9 +156,18 @@ impl AuthMethodSelectionOption {
 
     /// Serializes the option into bytes.
     pub fn into_socks_bytes(self) -> Vec<u8> {
-        let data = vec![self.method as u8];
+        let mut buf = BytesMut::new();
+        self.encode(&mut buf);
+
+        buf.into()
+    }
 
-        combine_and_pad(0x03, data)
+    /// Appends the option to a caller-provided buffer.
+    pub fn encode(
+        &self,
+        buf: &mut BytesMut,
+    ) {
+        encode_padded(buf, 0x03, |buf| buf.put_u8(self.method.clone() as u8));
     }
 }
 
@@ -160,12 +208,226 @@ impl MetadataOption {
 
     /// Serializes the option into bytes.
     pub fn into_socks_bytes(self) -> Vec<u8> {
-        let mut data = self.key.to_be_bytes().to_vec();
-        data.extend((self.value.len() as u16).to_be_bytes().iter());
-        data.extend(self.value.as_bytes().iter());
+        let mut buf = BytesMut::new();
+        self.encode(&mut buf);
 
+        buf.into()
+    }
+
+    /// Appends the option to a caller-provided buffer.
+    pub fn encode(
+        &self,
+        buf: &mut BytesMut,
+    ) {
         // kind: 65000
-        combine_and_pad(0xFDE8, data)
+        encode_padded(buf, 0xFDE8, |buf| {
+            buf.put_u16(self.key);
+            buf.put_u16(self.value.len() as u16);
+            buf.put_slice(self.value.as_bytes());
+        });
+    }
+}
+
+/// The leg of the connection a stack option applies to.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, FromPrimitive, PartialEq)]
+pub enum StackLeg {
+    ClientProxy = 0x01,
+    ProxyRemote = 0x02,
+    Both = 0x03,
+}
+
+/// The protocol level of a stack option.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, FromPrimitive, PartialEq)]
+pub enum StackLevel {
+    Ip = 0x01,
+    Ipv4 = 0x02,
+    Ipv6 = 0x03,
+    Tcp = 0x04,
+    Udp = 0x05,
+}
+
+/// The stack options defined by draft-11, identified by their level and code.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StackOptionType {
+    Tos,
+    HappyEyeballs,
+    Ttl,
+    NoFragmentation,
+    Tfo,
+    Multipath,
+    Backlog,
+    UdpError,
+    PortParity,
+}
+
+impl StackOptionType {
+    /// Returns the level and code that identify the option type on the wire.
+    pub fn level_and_code(self) -> (StackLevel, u8) {
+        use StackOptionType::*;
+
+        match self {
+            Tos => (StackLevel::Ip, 0x01),
+            HappyEyeballs => (StackLevel::Ip, 0x02),
+            Ttl => (StackLevel::Ip, 0x03),
+            NoFragmentation => (StackLevel::Ip, 0x04),
+            Tfo => (StackLevel::Tcp, 0x01),
+            Multipath => (StackLevel::Tcp, 0x02),
+            Backlog => (StackLevel::Tcp, 0x03),
+            UdpError => (StackLevel::Udp, 0x01),
+            PortParity => (StackLevel::Udp, 0x02),
+        }
+    }
+}
+
+/// Represents a stack option, which requests a protocol behavior on one or both legs of the connection.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StackOption {
+    pub leg: StackLeg,
+    pub level: StackLevel,
+    pub code: u8,
+    pub data: Vec<u8>,
+}
+
+impl StackOption {
+    /// Constructs a new `StackOption` of the given type.
+    pub fn new(
+        leg: StackLeg,
+        option_type: StackOptionType,
+        data: Vec<u8>,
+    ) -> Self {
+        let (level, code) = option_type.level_and_code();
+
+        Self { leg, level, code, data }
+    }
+
+    /// Requests the given IP type of service (TOS) byte.
+    pub fn tos(
+        leg: StackLeg,
+        tos: u8,
+    ) -> Self {
+        Self::new(leg, StackOptionType::Tos, vec![tos])
+    }
+
+    /// Requests (or refuses) Happy Eyeballs when the proxy connects to the remote host.
+    pub fn happy_eyeballs(enabled: bool) -> Self {
+        Self::new(StackLeg::ProxyRemote, StackOptionType::HappyEyeballs, vec![availability(enabled)])
+    }
+
+    /// Requests the given IP time-to-live (TTL).
+    pub fn ttl(
+        leg: StackLeg,
+        ttl: u8,
+    ) -> Self {
+        Self::new(leg, StackOptionType::Ttl, vec![ttl])
+    }
+
+    /// Requests (or refuses) that IP packets aren't fragmented.
+    pub fn no_fragmentation(
+        leg: StackLeg,
+        enabled: bool,
+    ) -> Self {
+        Self::new(leg, StackOptionType::NoFragmentation, vec![availability(enabled)])
+    }
+
+    /// Requests TCP Fast Open towards the remote host, carrying up to `payload_size` bytes of initial data in the SYN.
+    pub fn tfo(payload_size: u16) -> Self {
+        Self::new(StackLeg::ProxyRemote, StackOptionType::Tfo, payload_size.to_be_bytes().to_vec())
+    }
+
+    /// Requests (or refuses) Multipath TCP.
+    pub fn mptcp(
+        leg: StackLeg,
+        enabled: bool,
+    ) -> Self {
+        Self::new(leg, StackOptionType::Multipath, vec![availability(enabled)])
+    }
+
+    /// Requests the given listen backlog for a BIND operation.
+    pub fn backlog(backlog: u16) -> Self {
+        Self::new(StackLeg::ProxyRemote, StackOptionType::Backlog, backlog.to_be_bytes().to_vec())
+    }
+
+    /// Returns the type of the option, or `None` if its level and code aren't defined by draft-11.
+    pub fn option_type(&self) -> Option<StackOptionType> {
+        use StackOptionType::*;
+
+        [Tos, HappyEyeballs, Ttl, NoFragmentation, Tfo, Multipath, Backlog, UdpError, PortParity]
+            .iter()
+            .copied()
+            .find(|t| t.level_and_code() == (self.level, self.code))
+    }
+
+    /// Returns whether the behavior is available (or requested), for options that carry an availability byte.
+    pub fn availability(&self) -> Option<bool> {
+        match self.data.first() {
+            Some(0x01) => Some(true),
+            Some(0x02) => Some(false),
+            _ => None,
+        }
+    }
+
+    /// Returns the listen backlog that's requested (or granted, in a reply), for backlog options.
+    pub fn backlog_value(&self) -> Option<u16> {
+        if self.option_type() != Some(StackOptionType::Backlog) {
+            return None;
+        }
+
+        match self.data[..] {
+            [high, low, ..] => Some(u16::from_be_bytes([high, low])),
+            _ => None,
+        }
+    }
+
+    /// Wraps the instance into a `SocksOption`.
+    pub fn wrap(self) -> SocksOption {
+        SocksOption::Stack(self)
+    }
+
+    /// Deserializes the option from bytes, padding is preserved as part of the data.
+    pub fn from_socks_bytes(bytes: Vec<u8>) -> Result<SocksOption> {
+        ensure!(bytes.len() >= 2, "Expected at least two bytes, got: {}", bytes.len());
+
+        let leg = StackLeg::from_u8(bytes[0] >> 6).ok_or_else(|| anyhow!("Not a valid stack option leg: 0"))?;
+        let level = bytes[0] & 0x3F;
+        let level = StackLevel::from_u8(level).ok_or_else(|| anyhow!("Not a valid stack option level: {}", level))?;
+
+        Ok(Self {
+            leg,
+            level,
+            code: bytes[1],
+            data: bytes[2..].to_vec(),
+        }
+        .wrap())
+    }
+
+    /// Serializes the option into bytes.
+    pub fn into_socks_bytes(self) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        self.encode(&mut buf);
+
+        buf.into()
+    }
+
+    /// Appends the option to a caller-provided buffer.
+    pub fn encode(
+        &self,
+        buf: &mut BytesMut,
+    ) {
+        encode_padded(buf, SOCKS_OKIND_STACK, |buf| {
+            buf.put_slice(&[(self.leg as u8) << 6 | self.level as u8, self.code]);
+            buf.put_slice(&self.data);
+        });
+    }
+}
+
+/// Encodes the availability byte of stack options.
+fn availability(enabled: bool) -> u8 {
+    if enabled {
+        0x01
+    } else {
+        0x02
     }
 }
 
@@ -185,6 +447,16 @@ impl UnrecognizedOption {
         Self { kind, data }
     }
 
+    /// Returns the option kind.
+    pub fn kind(&self) -> u16 {
+        self.kind
+    }
+
+    /// Returns the raw data of the option, including any padding.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
     /// Wraps the instance into a `SocksOption`.
     pub fn wrap(self) -> SocksOption {
         SocksOption::Unrecognized(self)
@@ -192,37 +464,45 @@ impl UnrecognizedOption {
 
     /// Deserializes the option from bytes.
     pub fn into_socks_bytes(self) -> Vec<u8> {
-        combine_and_pad(self.kind, self.data)
+        let mut buf = BytesMut::new();
+        self.encode(&mut buf);
+
+        buf.into()
+    }
+
+    /// Appends the option to a caller-provided buffer.
+    pub fn encode(
+        &self,
+        buf: &mut BytesMut,
+    ) {
+        encode_padded(buf, self.kind, |buf| buf.put_slice(&self.data));
     }
 }
 
-/// Combines and pads the SOCKS option bytes.
+/// Appends a padded SOCKS option to the buffer.
 ///
 /// # Parameters
 ///
+/// - `buf`: The buffer to append the option to.
 /// - `kind`: The kind of the SOCKS option.
-/// - `data`: The data associated with the SOCKS option.
-///
-/// # Returns
-///
-/// A vector of bytes representing the padded SOCKS option.
-fn combine_and_pad(
+/// - `encode_data`: Appends the data associated with the SOCKS option.
+fn encode_padded(
+    buf: &mut BytesMut,
     kind: u16,
-    data: Vec<u8>,
-) -> Vec<u8> {
+    encode_data: impl FnOnce(&mut BytesMut),
+) {
+    let start = buf.len();
+    buf.put_u16(kind);
+    // The length is only known once the data is written, it's filled in below.
+    buf.put_u16(0);
+    encode_data(buf);
+
     // The total length of the option is the combined number of bytes of
     // the kind, length, and data fields, plus the number of padding bytes.
-    let option_length = data.len() + 2 + 2;
-    let padding_bytes = vec![0; 4 - (option_length % 4)];
-    let total_length: u16 = (option_length + padding_bytes.len()) as u16;
-
-    let mut bytes = vec![];
-    bytes.extend(kind.to_be_bytes().iter());
-    bytes.extend(total_length.to_be_bytes().iter());
-    bytes.extend(data);
-    bytes.extend(padding_bytes);
-
-    bytes
+    let option_length = buf.len() - start;
+    buf.put_bytes(0, 4 - (option_length % 4));
+    let total_length = (buf.len() - start) as u16;
+    buf[start + 2..start + 4].copy_from_slice(&total_length.to_be_bytes());
 }
 
 #[cfg(test)]
@@ -249,11 +529,33 @@ mod tests {
     fn test_auth_method_advertisement_option_wrap() {
         let option = AuthMethodAdvertisementOption::new(0, vec![]);
         let wrapped = option.wrap();
-        if let SocksOption::AuthMethodAdvertisement(_) = wrapped {
-            assert!(true);
-        } else {
-            assert!(false, "Expected AuthMethodAdvertisement variant");
+        assert!(
+            matches!(wrapped, SocksOption::AuthMethodAdvertisement(_)),
+            "Expected AuthMethodAdvertisement variant"
+        );
+    }
+
+    // Test that stack options encode their leg, level, and code, and survive parsing
+    #[test]
+    fn test_stack_option_roundtrip() {
+        let bytes = StackOption::tfo(1024).into_socks_bytes();
+        assert_eq!(&bytes[..2], &[0x00, 0x01]);
+        assert_eq!(&bytes[4..8], &[0x84, 0x01, 0x04, 0x00]);
+
+        let parsed = StackOption::from_socks_bytes(bytes[4..].to_vec()).unwrap();
+        assert!(matches!(parsed, SocksOption::Stack(ref o) if o.option_type() == Some(StackOptionType::Tfo)));
+
+        let option = StackOption::mptcp(StackLeg::Both, false);
+        let parsed = StackOption::from_socks_bytes(option.clone().into_socks_bytes()[4..].to_vec()).unwrap();
+        match parsed {
+            SocksOption::Stack(parsed) => {
+                assert_eq!((parsed.leg, parsed.level, parsed.code), (StackLeg::Both, StackLevel::Tcp, 0x02));
+                assert_eq!(parsed.availability(), Some(false));
+            }
+            _ => panic!("Expected Stack variant"),
         }
+
+        assert!(StackOption::from_socks_bytes(vec![0x04, 0x01]).is_err());
     }
 
     // Test the from_socks_bytes function for AuthMethodAdvertisementOption
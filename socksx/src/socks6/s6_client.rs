@@ -1,25 +1,39 @@
-use std::{convert::TryInto, net::SocketAddr};
+use std::{convert::TryInto, net::SocketAddr, time::{Duration, Instant}};
 
-use log::info;
-use anyhow::{ensure, Result};
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpStream;
+use anyhow::Result;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
 
-use crate::{Address, constants::*, Credentials};
-use crate::socks6::{self, Socks6Request};
-use crate::socks6::{
-    AuthMethod,
-    options::{AuthMethodAdvertisementOption, SocksOption},
-};
+use crate::{Address, Command, constants::*, Credentials, HandshakeStage, SocksError};
+use crate::addresses;
+use crate::{ProxyHeader, RetryPolicy, TcpOptions, WireHook};
+use crate::events::{connection_span, Instrument, record_destination, record_proxy};
+use crate::wire::WireTap;
+use crate::util::{connect_proxy, with_handshake_deadline, with_reply_timeout, HAPPY_EYEBALLS_DELAY};
+use crate::socks6::{self, Socks6AuthReplyType, Socks6Request, Socks6UdpAssociation, UdpFraming};
+use crate::socks6::options::{AuthMethod, AuthMethodAdvertisementOption, SocksOption, StackOption};
 
 /// Represents a SOCKS6 client.
 #[derive(Clone)]
 pub struct Socks6Client {
-    proxy_addr: SocketAddr,
+    proxy_addrs: Vec<SocketAddr>,
     credentials: Option<Credentials>,
+    happy_eyeballs_delay: Duration,
+    tcp_options: TcpOptions,
+    retry_policy: Option<RetryPolicy>,
+    reply_timeout: Option<Duration>,
+    handshake_deadline: Option<Duration>,
+    proxy_header: Option<ProxyHeader>,
+    on_wire: Option<WireHook>,
+    udp_framing: UdpFraming,
 }
 
 impl Socks6Client {
+    /// Returns a builder, to configure a `Socks6Client` with chained setters.
+    pub fn builder() -> Socks6ClientBuilder {
+        Socks6ClientBuilder::new()
+    }
+
     /// Creates a new Socks6Client.
     ///
     /// # Parameters
@@ -31,13 +45,176 @@ impl Socks6Client {
     pub async fn new<A: Into<String>>(
         proxy_addr: A,
         credentials: Option<Credentials>,
-    ) -> Result<Self> {
-        let proxy_addr = crate::resolve_addr(proxy_addr).await?;
+    ) -> Result<Self, SocksError> {
+        let proxy_addrs = crate::resolve_addrs(proxy_addr).await?;
+
+        Ok(Self::with_proxy_addrs(proxy_addrs, credentials))
+    }
+
+    /// Creates a new Socks6Client for a proxy whose address is already resolved, without a DNS lookup.
+    ///
+    /// # Parameters
+    /// - `proxy_addr`: The socket address of the SOCKS6 proxy.
+    /// - `credentials`: Optional credentials for authentication.
+    ///
+    /// # Returns
+    /// A new `Socks6Client`.
+    pub fn from_socket_addr(
+        proxy_addr: SocketAddr,
+        credentials: Option<Credentials>,
+    ) -> Self {
+        Self::with_proxy_addrs(vec![proxy_addr], credentials)
+    }
+
+    /// Creates a new Socks6Client for a proxy whose addresses are already resolved, e.g. cached ones, which are tried
+    /// in order (racing IPv4 and IPv6).
+    ///
+    /// # Parameters
+    /// - `proxy_addrs`: The socket addresses of the SOCKS6 proxy.
+    /// - `credentials`: Optional credentials for authentication.
+    ///
+    /// # Returns
+    /// A new `Socks6Client`.
+    pub fn from_socket_addrs(
+        proxy_addrs: Vec<SocketAddr>,
+        credentials: Option<Credentials>,
+    ) -> Self {
+        Self::with_proxy_addrs(proxy_addrs, credentials)
+    }
 
-        Ok(Socks6Client {
-            proxy_addr,
+    fn with_proxy_addrs(
+        proxy_addrs: Vec<SocketAddr>,
+        credentials: Option<Credentials>,
+    ) -> Self {
+        Socks6Client {
+            proxy_addrs,
             credentials,
-        })
+            happy_eyeballs_delay: HAPPY_EYEBALLS_DELAY,
+            tcp_options: TcpOptions::default(),
+            retry_policy: None,
+            reply_timeout: None,
+            handshake_deadline: None,
+            proxy_header: None,
+            on_wire: None,
+            udp_framing: UdpFraming::default(),
+        }
+    }
+
+    /// Returns the credentials used to authenticate with the proxy.
+    pub fn credentials(&self) -> Option<&Credentials> {
+        self.credentials.as_ref()
+    }
+
+    /// Sets the credentials used to authenticate with the proxy, e.g. to rotate a password.
+    /// Connections that are already established are unaffected, they keep using the old credentials.
+    ///
+    /// # Arguments
+    ///
+    /// * `credentials` - The new credentials, or `None` to stop authenticating.
+    pub fn set_credentials(
+        &mut self,
+        credentials: Option<Credentials>,
+    ) {
+        self.credentials = credentials;
+    }
+
+    /// Sets the delay between staggered IPv4/IPv6 connection attempts to the proxy.
+    ///
+    /// # Arguments
+    ///
+    /// * `delay` - The connection attempt delay, defaults to 250ms.
+    pub fn set_happy_eyeballs_delay(
+        &mut self,
+        delay: Duration,
+    ) {
+        self.happy_eyeballs_delay = delay;
+    }
+
+    /// Sets the socket options applied to the connection with the proxy.
+    ///
+    /// # Parameters
+    /// - `tcp_options`: The socket options, defaults to `TCP_NODELAY` without keepalive.
+    pub fn set_tcp_options(
+        &mut self,
+        tcp_options: TcpOptions,
+    ) {
+        self.tcp_options = tcp_options;
+    }
+
+    /// Sets the PROXY protocol header that is sent to the proxy before the request, e.g. to pass on the address of
+    /// the original client when chaining.
+    ///
+    /// # Parameters
+    /// - `proxy_header`: The header, defaults to `None` (no header is sent).
+    pub fn set_proxy_header(
+        &mut self,
+        proxy_header: Option<ProxyHeader>,
+    ) {
+        self.proxy_header = proxy_header;
+    }
+
+    /// Sets a callback that is passed the raw bytes of every handshake, as they're sent to and received from the
+    /// proxy, e.g. to diagnose interoperability issues without a packet capture. Relayed data isn't passed.
+    ///
+    /// # Parameters
+    /// - `on_wire`: The callback, defaults to `None` (the handshake isn't inspected).
+    pub fn set_on_wire(
+        &mut self,
+        on_wire: Option<WireHook>,
+    ) {
+        self.on_wire = on_wire;
+    }
+
+    /// Sets the policy for retrying when connecting to the proxy fails, e.g. because it's briefly unavailable.
+    /// Only the connection to the proxy is retried, authentication failures and rejected requests fail fast.
+    ///
+    /// # Arguments
+    ///
+    /// * `retry_policy` - The retry policy, defaults to `None` (no retries).
+    pub fn set_retry_policy(
+        &mut self,
+        retry_policy: Option<RetryPolicy>,
+    ) {
+        self.retry_policy = retry_policy;
+    }
+
+    /// Sets the time the proxy is given to send its operation reply, once the request is sent.
+    /// This is independent of how long connecting to the proxy may take.
+    ///
+    /// # Arguments
+    ///
+    /// * `reply_timeout` - The reply timeout, defaults to `None` (wait indefinitely).
+    pub fn set_reply_timeout(
+        &mut self,
+        reply_timeout: Option<Duration>,
+    ) {
+        self.reply_timeout = reply_timeout;
+    }
+
+    /// Sets the time the whole handshake is given, from connecting to the proxy until its reply is read. Unlike the
+    /// reply timeout, this also bounds a proxy that keeps the handshake going by sending it slowly.
+    ///
+    /// # Arguments
+    ///
+    /// * `handshake_deadline` - The deadline, defaults to `None` (no deadline).
+    pub fn set_handshake_deadline(
+        &mut self,
+        handshake_deadline: Option<Duration>,
+    ) {
+        self.handshake_deadline = handshake_deadline;
+    }
+
+    /// Sets how the datagrams of UDP associations are carried between client and proxy.
+    ///
+    /// # Arguments
+    ///
+    /// * `udp_framing` - The framing, defaults to `Udp`. With `Stream` the socket given to `udp_associate_from` or
+    ///   `udp_associate_with_socket` is unused, and `Dtls` fails with `SocksError::UnsupportedUdpFraming`.
+    pub fn set_udp_framing(
+        &mut self,
+        udp_framing: UdpFraming,
+    ) {
+        self.udp_framing = udp_framing;
     }
 
     /// Connects to a given destination through the SOCKS6 proxy.
@@ -54,16 +231,83 @@ impl Socks6Client {
         destination: A,
         initial_data: Option<Vec<u8>>,
         options: Option<Vec<SocksOption>>,
-    ) -> Result<(TcpStream, Address)>
+    ) -> Result<(TcpStream, Address), SocksError>
     where
-        A: TryInto<Address, Error = anyhow::Error>,
+        A: TryInto<Address>,
+        A::Error: Into<anyhow::Error>,
     {
-        let mut stream = TcpStream::connect(&self.proxy_addr).await?;
-        info!("Connecting to socks address at {}", stream.peer_addr()?);
-        let binding = self.handshake(destination, initial_data, options, &mut stream).await?;
+        let (stream, binding, _) = self.connect_negotiated(destination, initial_data, options).await?;
         Ok((stream, binding))
     }
 
+    /// Connects to a given destination through the SOCKS6 proxy, sending initial data that is borrowed rather than
+    /// owned, e.g. a request that's already in a buffer, so it isn't copied into a `Vec` for every connection.
+    ///
+    /// # Parameters
+    /// - `destination`: The destination to connect to.
+    /// - `initial_data`: The initial data to send, at most 16KiB.
+    /// - `options`: Optional SOCKS options.
+    ///
+    /// # Returns
+    /// A `Result` containing a tuple of the `TcpStream` and the bound `Address`, or an error.
+    pub async fn connect_with_initial_data<A>(
+        &self,
+        destination: A,
+        initial_data: &[u8],
+        options: Option<Vec<SocksOption>>,
+    ) -> Result<(TcpStream, Address), SocksError>
+    where
+        A: TryInto<Address>,
+        A::Error: Into<anyhow::Error>,
+    {
+        let destination = addresses::into_address(destination)?;
+
+        let handshake = async move {
+            let mut stream = self.connect_proxy().await?;
+            let (binding, _) = self
+                .handshake_command(Command::Connect, destination, initial_data, options, &mut stream)
+                .await?;
+
+            Ok((stream, binding))
+        };
+
+        with_handshake_deadline(handshake, self.handshake_deadline).instrument(connection_span()).await
+    }
+
+    /// Connects to a given destination through the SOCKS6 proxy, and returns the options granted by the proxy.
+    ///
+    /// # Parameters
+    /// - `destination`: The destination to connect to.
+    /// - `initial_data`: Optional initial data to send.
+    /// - `options`: Optional SOCKS options.
+    ///
+    /// # Returns
+    /// A `Result` containing a tuple of the `TcpStream`, the bound `Address`, and the granted options, or an error.
+    pub async fn connect_negotiated<A>(
+        &self,
+        destination: A,
+        initial_data: Option<Vec<u8>>,
+        options: Option<Vec<SocksOption>>,
+    ) -> Result<(TcpStream, Address, Vec<SocksOption>), SocksError>
+    where
+        A: TryInto<Address>,
+        A::Error: Into<anyhow::Error>,
+    {
+        let destination = addresses::into_address(destination)?;
+
+        let handshake = async move {
+            let mut stream = self.connect_proxy().await?;
+            let initial_data = initial_data.as_deref().unwrap_or_default();
+            let (binding, granted_options) = self
+                .handshake_command(Command::Connect, destination, initial_data, options, &mut stream)
+                .await?;
+
+            Ok((stream, binding, granted_options))
+        };
+
+        with_handshake_deadline(handshake, self.handshake_deadline).instrument(connection_span()).await
+    }
+
     /// Conducts the handshake process with the SOCKS6 proxy.
     ///
     /// This method implements the handshake protocol as per [socks6-draft11].
@@ -76,57 +320,964 @@ impl Socks6Client {
     /// - `stream`: The mutable reference to the `TcpStream`.
     ///
     /// # Returns
-    /// A `Result` containing the bound `Address` or an error.
+    /// A `Result` containing the bound `Address` and the granted options, or an error. These are the options of the
+    /// authentication reply (e.g. session or idempotence options), followed by those of the operation reply.
     pub async fn handshake<A>(
         &self,
         destination: A,
         initial_data: Option<Vec<u8>>,
         options: Option<Vec<SocksOption>>,
         stream: &mut TcpStream,
-    ) -> Result<Address>
+    ) -> Result<(Address, Vec<SocksOption>), SocksError>
+    where
+        A: TryInto<Address>,
+        A::Error: Into<anyhow::Error>,
+    {
+        let destination = addresses::into_address(destination)?;
+        let initial_data = initial_data.as_deref().unwrap_or_default();
+        self.handshake_command(Command::Connect, destination, initial_data, options, stream).await
+    }
+
+    /// Establishes a UDP association through the SOCKS6 proxy.
+    ///
+    /// # Parameters
+    /// - `options`: Optional SOCKS options.
+    ///
+    /// # Returns
+    /// A `Result` containing the association, over which datagrams are exchanged with any destination, or an error.
+    pub async fn udp_associate(
+        &self,
+        options: Option<Vec<SocksOption>>,
+    ) -> Result<Socks6UdpAssociation, SocksError> {
+        self.associate(None, options).await
+    }
+
+    /// Establishes a UDP association through the SOCKS6 proxy, sending datagrams from the given local address.
+    /// The address is advertised in the request, for proxies that require a concrete endpoint rather than `0.0.0.0:0`.
+    ///
+    /// # Parameters
+    /// - `local_addr`: The local address to send datagrams from, if its port is 0 the assigned port is advertised.
+    /// - `options`: Optional SOCKS options.
+    ///
+    /// # Returns
+    /// A `Result` containing the association, whose `relay_addr` is where datagrams are sent to, or an error.
+    pub async fn udp_associate_from(
+        &self,
+        local_addr: SocketAddr,
+        options: Option<Vec<SocksOption>>,
+    ) -> Result<Socks6UdpAssociation, SocksError> {
+        let socket = UdpSocket::bind(local_addr).await?;
+        self.associate(Some(socket), options).await
+    }
+
+    /// Establishes a UDP association through the SOCKS6 proxy, sending datagrams from an already bound socket, e.g.
+    /// one with specific buffer sizes or other socket options. Its address is advertised as by `udp_associate_from`.
+    ///
+    /// # Parameters
+    /// - `socket`: The socket to send datagrams from, which is connected to the proxy's relay.
+    /// - `options`: Optional SOCKS options.
+    ///
+    /// # Returns
+    /// A `Result` containing the association, whose `relay_addr` is where datagrams are sent to, or an error.
+    pub async fn udp_associate_with_socket(
+        &self,
+        socket: UdpSocket,
+        options: Option<Vec<SocksOption>>,
+    ) -> Result<Socks6UdpAssociation, SocksError> {
+        self.associate(Some(socket), options).await
+    }
+
+    /// Asks the SOCKS6 proxy to listen for an inbound connection (BIND).
+    ///
+    /// # Parameters
+    /// - `address`: The address advertised in the request, defaults to `0.0.0.0:0`.
+    /// - `options`: Optional SOCKS options.
+    ///
+    /// # Returns
+    /// A `Result` containing the control stream, the address the proxy listens on, and the granted options, or an
+    /// error. The inbound connection is awaited with `accept_bind`, after which the stream carries its data.
+    pub async fn bind<A>(
+        &self,
+        address: Option<A>,
+        options: Option<Vec<SocksOption>>,
+    ) -> Result<(TcpStream, Address, Vec<SocksOption>), SocksError>
+    where
+        A: TryInto<Address>,
+        A::Error: Into<anyhow::Error>,
+    {
+        let address = match address {
+            Some(address) => addresses::into_address(address)?,
+            None => Address::new("0.0.0.0", 0),
+        };
+
+        let handshake = async move {
+            let mut stream = self.connect_proxy().await?;
+            let (binding, granted_options) = self
+                .handshake_command(Command::Bind, address, &[], options, &mut stream)
+                .await?;
+
+            Ok((stream, binding, granted_options))
+        };
+
+        with_handshake_deadline(handshake, self.handshake_deadline).instrument(connection_span()).await
+    }
+
+    /// Asks the SOCKS6 proxy to listen for inbound connections (BIND), with a listen backlog so that several of them
+    /// can be pending at once.
+    ///
+    /// # Parameters
+    /// - `address`: The address advertised in the request, defaults to `0.0.0.0:0`.
+    /// - `backlog`: The requested number of pending inbound connections.
+    /// - `options`: Optional additional SOCKS options.
+    ///
+    /// # Returns
+    /// A `Result` containing the control stream, the address the proxy listens on, and the backlog it granted (if it
+    /// echoed one), or an error. The inbound connection is awaited with `accept_bind`.
+    pub async fn bind_with_backlog<A>(
+        &self,
+        address: Option<A>,
+        backlog: u16,
+        options: Option<Vec<SocksOption>>,
+    ) -> Result<(TcpStream, Address, Option<u16>), SocksError>
     where
-        A: TryInto<Address, Error = anyhow::Error>,
+        A: TryInto<Address>,
+        A::Error: Into<anyhow::Error>,
     {
-        if let Some(Credentials { username, password }) = &self.credentials {
-            ensure!(username.len() > 255, "Username MUST NOT be larger than 255 bytes.");
-            ensure!(password.len() > 255, "Password MUST NOT be larger than 255 bytes.");
+        let mut options = options.unwrap_or_default();
+        options.push(StackOption::backlog(backlog).wrap());
+
+        let (stream, binding, granted_options) = self.bind(address, Some(options)).await?;
+        let granted_backlog = granted_options.iter().find_map(|option| match option {
+            SocksOption::Stack(option) => option.backlog_value(),
+            _ => None,
+        });
+
+        Ok((stream, binding, granted_backlog))
+    }
+
+    /// Waits until the proxy accepted the inbound connection of a BIND, which it announces with a second reply.
+    /// No reply timeout applies, as the remote peer may connect at any time.
+    ///
+    /// # Parameters
+    /// - `stream`: The control stream, as returned by `bind`.
+    ///
+    /// # Returns
+    /// A `Result` containing the address of the remote peer, or an error.
+    pub async fn accept_bind(
+        &self,
+        stream: &mut TcpStream,
+    ) -> Result<Address, SocksError> {
+        let (peer, _) = socks6::read_reply(&mut WireTap::new(stream, self.on_wire.as_ref()))
+            .await
+            .map_err(|error| error.closed_during(HandshakeStage::Reply))?;
+        debug!("Proxy accepted inbound connection from {}", peer);
+
+        Ok(peer)
+    }
+
+    /// Checks that the SOCKS6 proxy is up, by sending a NOOP request and awaiting its authentication reply.
+    /// Nothing is requested from the destination side, the connection is closed right after.
+    ///
+    /// # Returns
+    /// A `Result` containing the time it took to connect and be authenticated, or an error.
+    pub async fn probe(&self) -> Result<Duration, SocksError> {
+        let start = Instant::now();
+        let mut stream = self.connect_proxy().await?;
+
+        // NOOP isn't a `Command`, as nothing is relayed for it, so it's patched into an otherwise empty request.
+        let options = vec![self.auth_methods_advertisement(0)];
+        let mut request = Socks6Request::new(Command::Connect, Address::new("0.0.0.0", 0), 0, options, None)
+            .into_socks_bytes()?;
+        request[1] = SOCKS_CMD_NOOP;
+        let mut tapped = WireTap::new(&mut stream, self.on_wire.as_ref());
+        tapped.write_all(&request).await?;
+
+        with_reply_timeout(self.authenticate_request(&mut tapped), self.reply_timeout)
+            .await
+            .map_err(|error| error.closed_during(HandshakeStage::Authentication))?;
+        let elapsed = start.elapsed();
+        stream.shutdown().await.ok();
+
+        Ok(elapsed)
+    }
+
+    /// Establishes a UDP association, advertising the address of the given socket if any, see `udp_associate`.
+    async fn associate(
+        &self,
+        socket: Option<UdpSocket>,
+        options: Option<Vec<SocksOption>>,
+    ) -> Result<Socks6UdpAssociation, SocksError> {
+        let handshake = async move {
+            let mut stream = self.connect_proxy().await?;
+            let local_addr = match &socket {
+                Some(socket) => Address::Ip(socket.local_addr()?),
+                None => Address::new("0.0.0.0", 0),
+            };
+            let (binding, _) = self
+                .handshake_command(Command::UdpAssociate, local_addr, &[], options, &mut stream)
+                .await?;
+
+            Socks6UdpAssociation::establish(stream, binding, socket, self.udp_framing).await
+        };
+
+        with_handshake_deadline(handshake, self.handshake_deadline).instrument(connection_span()).await
+    }
+
+    /// Connects to the SOCKS6 proxy, and records its address in the current span.
+    async fn connect_proxy(&self) -> Result<TcpStream, SocksError> {
+        let mut stream = connect_proxy(
+            &self.proxy_addrs,
+            self.happy_eyeballs_delay,
+            self.retry_policy.as_ref(),
+            &self.tcp_options,
+        )
+        .await?;
+        self.tcp_options.apply(&stream)?;
+        record_proxy(&stream.peer_addr()?);
+        info!("Connecting to socks address at {}", stream.peer_addr()?);
+
+        // The PROXY protocol header precedes anything else the proxy receives.
+        if let Some(proxy_header) = &self.proxy_header {
+            WireTap::new(&mut stream, self.on_wire.as_ref()).write_all(&proxy_header.to_bytes()).await?;
+        }
+
+        Ok(stream)
+    }
+
+    /// Conducts the handshake for the given command, see `handshake`.
+    async fn handshake_command(
+        &self,
+        command: Command,
+        destination: Address,
+        initial_data: &[u8],
+        options: Option<Vec<SocksOption>>,
+        stream: &mut TcpStream,
+    ) -> Result<(Address, Vec<SocksOption>), SocksError> {
+        record_destination(&destination);
+        let stream = &mut WireTap::new(stream, self.on_wire.as_ref());
+
+        // Credentials may have been constructed from their fields, rather than checked by `Credentials::new`.
+        if let Some(credentials) = &self.credentials {
+            credentials.validate()?;
         }
 
         // Prepare initial data.
-        let initial_data = initial_data.unwrap_or_default();
-        ensure!(
-            initial_data.len() <= 2 ^ 14,
-            "Initial data MUST NOT be larger than 16384 bytes."
-        );
+        if initial_data.len() > SOCKS_MAX_INITIAL_DATA_LENGTH as usize {
+            return Err(anyhow!("Initial data MUST NOT be larger than {} bytes.", SOCKS_MAX_INITIAL_DATA_LENGTH).into());
+        }
         let initial_data_length = initial_data.len() as u16;
 
         // Prepare SOCKS options.
+        let mut options = options.unwrap_or_default();
+        options.push(self.auth_methods_advertisement(initial_data_length));
+
+        // Create SOCKS6 request.
+        let request = Socks6Request::new(command, destination, initial_data_length, options, None);
+
+        // Send SOCKS request information, followed by the initial data.
+        let request_bytes = request.into_socks_bytes()?;
+        stream.write_all(&request_bytes).await?;
+        stream.write_all(initial_data).await?;
+        debug!("Sent request");
+
+        let mut granted_options = self
+            .authenticate_request(stream)
+            .await
+            .map_err(|error| error.closed_during(HandshakeStage::Authentication))?;
+
+        // Wait for the operation reply.
+        let (binding, options) = with_reply_timeout(socks6::read_reply(stream), self.reply_timeout)
+            .await
+            .map_err(|error| error.closed_during(HandshakeStage::Reply))?;
+        debug!("Received operation reply, bound to {}", binding);
+        granted_options.extend(options);
+
+        Ok((binding, granted_options))
+    }
+
+    /// Returns the option that advertises the authentication methods, i.e. username/password if credentials are set.
+    fn auth_methods_advertisement(
+        &self,
+        initial_data_length: u16,
+    ) -> SocksOption {
         let mut auth_methods = vec![];
         if self.credentials.is_some() {
             auth_methods.push(AuthMethod::UsernamePassword);
         }
 
-        let auth_methods_adv = AuthMethodAdvertisementOption::new(initial_data_length, vec![]);
-        let mut options = options.unwrap_or_default();
-        options.push(auth_methods_adv.wrap());
-
-        // Create SOCKS6 CONNECT request.
-        let request = Socks6Request::new(
-            SOCKS_CMD_CONNECT,
-            destination.try_into()?,
-            initial_data_length,
-            options,
-            None,
-        );
-
-        // Send SOCKS request information.
-        let request_bytes = request.into_socks_bytes();
-        stream.write(&request_bytes).await?;
-
-        // Wait for authentication and operation reply.
-        let _ = socks6::read_no_authentication(stream).await?;
-        let (binding, _) = socks6::read_reply(stream).await?;
-  
-        Ok(binding)
+        AuthMethodAdvertisementOption::new(initial_data_length, auth_methods).wrap()
+    }
+
+    /// Returns the authentication methods the requests offer, which always include not authenticating.
+    fn offered_auth_methods(&self) -> Vec<AuthMethod> {
+        let mut offered = vec![AuthMethod::NoAuthentication];
+        if self.credentials.is_some() {
+            offered.push(AuthMethod::UsernamePassword);
+        }
+
+        offered
+    }
+
+    /// Waits for the authentication reply to a request, the proxy may first ask for a sub-negotiation.
+    /// Returns the options of the successful reply.
+    async fn authenticate_request<S>(
+        &self,
+        stream: &mut S,
+    ) -> Result<Vec<SocksOption>, SocksError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut authenticated = false;
+        loop {
+            let reply = socks6::read_authentication_reply(stream).await?;
+            debug!("Received authentication reply: {:?}", reply.reply_type);
+            if reply.reply_type == Socks6AuthReplyType::Success {
+                return Ok(reply.options);
+            }
+
+            match (reply.selected_method(), &self.credentials) {
+                (Some(AuthMethod::UsernamePassword), Some(credentials)) if !authenticated => {
+                    self.authenticate(stream, credentials).await?;
+                    debug!("Authenticated with the proxy");
+                    authenticated = true;
+                }
+                (Some(AuthMethod::UsernamePassword), None) => return Err(SocksError::CredentialsRequired),
+                (Some(method @ AuthMethod::Gssapi), _) => return Err(SocksError::UnsupportedAuthMethod(method as u8)),
+                (Some(AuthMethod::NoAcceptableMethods), _) => {
+                    return Err(SocksError::AuthMethodRejected { offered: self.offered_auth_methods() });
+                }
+                _ => return Err(SocksError::AuthFailed),
+            }
+        }
+    }
+
+    /// Carries out the username/password sub-negotiation (RFC 1929) selected by the proxy.
+    ///
+    /// # Parameters
+    /// - `stream`: The mutable reference to the stream with the proxy.
+    /// - `credentials`: The authentication credentials.
+    ///
+    /// # Returns
+    /// A `Result` indicating success, or an error if the proxy rejected the credentials.
+    async fn authenticate<S>(
+        &self,
+        stream: &mut S,
+        credentials: &Credentials,
+    ) -> Result<(), SocksError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut request = vec![SOCKS_AUTH_VER];
+        request.extend(credentials.as_socks_bytes());
+
+        stream.write_all(&request).await?;
+
+        let mut reply = [0; 2];
+        stream.read_exact(&mut reply).await?;
+
+        let [auth_version, status] = reply;
+        if auth_version != SOCKS_AUTH_VER {
+            return Err(SocksError::AuthVersionMismatch(auth_version));
+        }
+        if status != SOCKS_AUTH_SUCCESS {
+            return Err(SocksError::AuthFailed);
+        }
+
+        Ok(())
+    }
+}
+
+/// Builds a `Socks6Client` with chained setters, for when more than the proxy address and credentials are configured.
+/// Options that aren't set keep the defaults of `Socks6Client::new`.
+#[derive(Clone)]
+pub struct Socks6ClientBuilder {
+    proxy_addr: Option<String>,
+    client: Socks6Client,
+}
+
+impl Default for Socks6ClientBuilder {
+    fn default() -> Self {
+        Socks6ClientBuilder {
+            proxy_addr: None,
+            client: Socks6Client::with_proxy_addrs(vec![], None),
+        }
+    }
+}
+
+impl Socks6ClientBuilder {
+    /// Creates a new `Socks6ClientBuilder`, without a proxy address.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the address of the SOCKS6 proxy, which is resolved by `build`.
+    pub fn proxy_addr<A: Into<String>>(
+        mut self,
+        proxy_addr: A,
+    ) -> Self {
+        self.proxy_addr = Some(proxy_addr.into());
+        self
+    }
+
+    /// Sets the already resolved socket addresses of the SOCKS6 proxy, instead of `proxy_addr`.
+    pub fn proxy_socket_addrs(
+        mut self,
+        proxy_addrs: Vec<SocketAddr>,
+    ) -> Self {
+        self.proxy_addr = None;
+        self.client.proxy_addrs = proxy_addrs;
+        self
+    }
+
+    /// Sets the credentials, see `Socks6Client::set_credentials`.
+    pub fn credentials(
+        mut self,
+        credentials: Credentials,
+    ) -> Self {
+        self.client.set_credentials(Some(credentials));
+        self
+    }
+
+    /// Sets the Happy Eyeballs delay, see `Socks6Client::set_happy_eyeballs_delay`.
+    pub fn happy_eyeballs_delay(
+        mut self,
+        delay: Duration,
+    ) -> Self {
+        self.client.set_happy_eyeballs_delay(delay);
+        self
+    }
+
+    /// Sets the socket options, see `Socks6Client::set_tcp_options`.
+    pub fn tcp_options(
+        mut self,
+        tcp_options: TcpOptions,
+    ) -> Self {
+        self.client.set_tcp_options(tcp_options);
+        self
+    }
+
+    /// Sets the PROXY protocol header, see `Socks6Client::set_proxy_header`.
+    pub fn proxy_header(
+        mut self,
+        proxy_header: ProxyHeader,
+    ) -> Self {
+        self.client.set_proxy_header(Some(proxy_header));
+        self
+    }
+
+    /// Sets the callback passed the raw bytes of handshakes, see `Socks6Client::set_on_wire`.
+    pub fn on_wire(
+        mut self,
+        on_wire: WireHook,
+    ) -> Self {
+        self.client.set_on_wire(Some(on_wire));
+        self
+    }
+
+    /// Sets the retry policy, see `Socks6Client::set_retry_policy`.
+    pub fn retry_policy(
+        mut self,
+        retry_policy: RetryPolicy,
+    ) -> Self {
+        self.client.set_retry_policy(Some(retry_policy));
+        self
+    }
+
+    /// Sets the reply timeout, see `Socks6Client::set_reply_timeout`.
+    pub fn reply_timeout(
+        mut self,
+        reply_timeout: Duration,
+    ) -> Self {
+        self.client.set_reply_timeout(Some(reply_timeout));
+        self
+    }
+
+    /// Sets the handshake deadline, see `Socks6Client::set_handshake_deadline`.
+    pub fn handshake_deadline(
+        mut self,
+        handshake_deadline: Duration,
+    ) -> Self {
+        self.client.set_handshake_deadline(Some(handshake_deadline));
+        self
+    }
+
+    /// Sets the framing of UDP associations, see `Socks6Client::set_udp_framing`.
+    pub fn udp_framing(
+        mut self,
+        udp_framing: UdpFraming,
+    ) -> Self {
+        self.client.set_udp_framing(udp_framing);
+        self
+    }
+
+    /// Builds the client, resolving the proxy address if it was given by `proxy_addr`.
+    ///
+    /// # Returns
+    /// A `Result` containing the new `Socks6Client`, or an error if no proxy address is set or it can't be resolved.
+    pub async fn build(self) -> Result<Socks6Client, SocksError> {
+        let mut client = self.client;
+        if let Some(proxy_addr) = self.proxy_addr {
+            client.proxy_addrs = crate::resolve_addrs(proxy_addr).await?;
+        }
+
+        if client.proxy_addrs.is_empty() {
+            return Err(anyhow!("No proxy address is set.").into());
+        }
+
+        Ok(client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::socks6::options::{AuthMethodSelectionOption, UnrecognizedOption};
+    use crate::socks6::Socks6Reply;
+
+    // Spawns a proxy that reads a request, and then answers with the given authentication replies.
+    async fn spawn_proxy(auth_replies: Vec<(Socks6AuthReplyType, Vec<SocksOption>)>) -> Result<SocketAddr> {
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await.unwrap();
+            socks6::read_request(&mut source).await.unwrap();
+
+            for (reply_type, options) in auth_replies {
+                let options: Vec<u8> = options.iter().flat_map(|o| o.as_socks_bytes()).collect();
+                let mut reply = vec![SOCKS_VER_6, reply_type as u8];
+                reply.extend((options.len() as u16).to_be_bytes().iter());
+                reply.extend(options);
+                source.write_all(&reply).await.unwrap();
+            }
+
+            socks6::write_reply(&mut source, Socks6Reply::Success).await.unwrap();
+        });
+
+        Ok(proxy_addr)
+    }
+
+    // Tests that options in the authentication reply don't desynchronize the operation reply.
+    #[tokio::test]
+    async fn test_handshake_auth_reply_with_options() -> Result<()> {
+        let selection = AuthMethodSelectionOption::new(AuthMethod::NoAuthentication).wrap();
+        let proxy_addr = spawn_proxy(vec![(Socks6AuthReplyType::Success, vec![selection])]).await?;
+
+        let client = Socks6Client::new(proxy_addr.to_string(), None).await?;
+        let (_, binding) = client.connect(String::from("127.0.0.1:80"), None, None).await?;
+        assert_eq!(binding, Address::new("0.0.0.0", 0));
+
+        Ok(())
+    }
+
+    // Tests that the options of the authentication reply are returned along with those of the operation reply.
+    #[tokio::test]
+    async fn test_connect_negotiated_auth_reply_options() -> Result<()> {
+        let session_id = UnrecognizedOption::new(SOCKS_OKIND_SESSION_ID, vec![1, 2, 3, 4]).wrap();
+        let proxy_addr = spawn_proxy(vec![(Socks6AuthReplyType::Success, vec![session_id])]).await?;
+
+        let client = Socks6Client::new(proxy_addr.to_string(), None).await?;
+        let (_, _, granted_options) = client.connect_negotiated(String::from("127.0.0.1:80"), None, None).await?;
+        assert!(matches!(&granted_options[..], [SocksOption::Unrecognized(o)] if o.kind() == SOCKS_OKIND_SESSION_ID));
+
+        Ok(())
+    }
+
+    // Tests that datagrams are exchanged over an association with the proxy's relay.
+    #[tokio::test]
+    async fn test_udp_associate() -> Result<()> {
+        use tokio::net::UdpSocket;
+
+        use crate::socks6::udp::{UdpMessage, UdpMessageType};
+
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+        let relay = UdpSocket::bind("127.0.0.1:0").await?;
+        let relay_addr = relay.local_addr()?;
+
+        tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await.unwrap();
+            let request = socks6::read_request(&mut source).await.unwrap();
+            assert_eq!(request.command, Command::UdpAssociate);
+
+            socks6::write_no_authentication(&mut source).await.unwrap();
+            let mut reply = vec![SOCKS_VER_6, SOCKS_REP_SUCCEEDED, SOCKS_PADDING];
+            reply.extend(Address::Ip(relay_addr).to_socks_bytes().unwrap());
+            reply.extend([0, 0].iter());
+            source.write_all(&reply).await.unwrap();
+            let init = UdpMessage::new(UdpMessageType::AssociationInit, 42).into_socks_bytes().unwrap();
+            source.write_all(&init).await.unwrap();
+
+            // Echo the datagram back, as if it were answered by the destination.
+            let mut datagram = vec![0; 1024];
+            let (length, client_addr) = relay.recv_from(&mut datagram).await.unwrap();
+            let message = UdpMessage::from_socks_bytes(&datagram[..length]).unwrap();
+            let echo = UdpMessage::datagram(42, message.address.unwrap(), message.data);
+            relay.send_to(&echo.into_socks_bytes().unwrap(), client_addr).await.unwrap();
+
+            let ack = UdpMessage::new(UdpMessageType::AssociationAck, 42).into_socks_bytes().unwrap();
+            source.write_all(&ack).await.unwrap();
+            let _ = source.read(&mut [0; 1]).await;
+        });
+
+        let client = Socks6Client::from_socket_addr(proxy_addr, None);
+        let mut association = client.udp_associate(None).await?;
+        assert_eq!(association.association_id(), 42);
+        assert_eq!(association.relay_addr()?, relay_addr);
+
+        association.send_to(b"ping", String::from("10.0.0.1:53")).await?;
+        association.read_confirmation().await?;
+
+        let mut buf = [0; 16];
+        let (length, source) = association.recv_from(&mut buf).await?;
+        assert_eq!(&buf[..length], b"ping");
+        assert_eq!(source, Address::new("10.0.0.1", 53));
+
+        Ok(())
+    }
+
+    // Tests that datagrams are exchanged over the control stream with stream framing, which the ack is skipped on.
+    #[tokio::test]
+    async fn test_udp_associate_stream_framing() -> Result<()> {
+        use crate::socks6::udp::{self, UdpMessage, UdpMessageType};
+
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await?;
+            socks6::read_request(&mut source).await?;
+            socks6::write_no_authentication(&mut source).await?;
+            let mut reply = vec![SOCKS_VER_6, SOCKS_REP_SUCCEEDED, SOCKS_PADDING];
+            reply.extend(Address::new("0.0.0.0", 0).to_socks_bytes()?);
+            reply.extend([0, 0].iter());
+            source.write_all(&reply).await?;
+            source.write_all(&UdpMessage::new(UdpMessageType::AssociationInit, 42).into_socks_bytes()?).await?;
+
+            // Echo the datagram back, after acknowledging it.
+            let message = udp::read_message(&mut source).await?;
+            source.write_all(&UdpMessage::new(UdpMessageType::AssociationAck, 42).into_socks_bytes()?).await?;
+            let echo = UdpMessage::datagram(42, message.address.unwrap(), message.data);
+            source.write_all(&echo.into_socks_bytes()?).await?;
+            let _ = source.read(&mut [0; 1]).await;
+
+            Ok::<_, anyhow::Error>(())
+        });
+
+        let client = Socks6Client::builder()
+            .proxy_socket_addrs(vec![proxy_addr])
+            .udp_framing(UdpFraming::Stream)
+            .build()
+            .await?;
+        let mut association = client.udp_associate(None).await?;
+        assert_eq!(association.framing(), UdpFraming::Stream);
+        assert_eq!(association.relay_addr()?, proxy_addr);
+
+        association.send_to(b"ping", String::from("10.0.0.1:53")).await?;
+        let mut buf = [0; 16];
+        let (length, source) = association.recv_from(&mut buf).await?;
+        assert_eq!(&buf[..length], b"ping");
+        assert_eq!(source, Address::new("10.0.0.1", 53));
+
+        Ok(())
+    }
+
+    // Tests that associations fail with a typed error if DTLS is asked for, as it isn't supported.
+    #[tokio::test]
+    async fn test_udp_associate_dtls_unsupported() -> Result<()> {
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await?;
+            socks6::read_request(&mut source).await?;
+            socks6::write_no_authentication(&mut source).await?;
+            let mut reply = vec![SOCKS_VER_6, SOCKS_REP_SUCCEEDED, SOCKS_PADDING];
+            reply.extend(Address::new("127.0.0.1", 9).to_socks_bytes()?);
+            reply.extend([0, 0].iter());
+            source.write_all(&reply).await?;
+
+            Ok::<_, anyhow::Error>(())
+        });
+
+        let mut client = Socks6Client::from_socket_addr(proxy_addr, None);
+        client.set_udp_framing(UdpFraming::Dtls);
+        let error = client.udp_associate(None).await.err().unwrap();
+        assert!(matches!(error, SocksError::UnsupportedUdpFraming(UdpFraming::Dtls)));
+
+        Ok(())
+    }
+
+    // Tests that the given local address is advertised, and that datagrams are sent from it to the relay.
+    #[tokio::test]
+    async fn test_udp_associate_from() -> Result<()> {
+        use tokio::net::UdpSocket;
+
+        use crate::socks6::udp::{UdpMessage, UdpMessageType};
+
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+        let relay = UdpSocket::bind("127.0.0.1:0").await?;
+        let relay_addr = relay.local_addr()?;
+
+        let proxy = tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await?;
+            let request = socks6::read_request(&mut source).await?;
+
+            socks6::write_no_authentication(&mut source).await?;
+            let mut reply = vec![SOCKS_VER_6, SOCKS_REP_SUCCEEDED, SOCKS_PADDING];
+            reply.extend(Address::Ip(relay_addr).to_socks_bytes()?);
+            reply.extend([0, 0].iter());
+            source.write_all(&reply).await?;
+            source.write_all(&UdpMessage::new(UdpMessageType::AssociationInit, 42).into_socks_bytes()?).await?;
+
+            let (_, client_addr) = relay.recv_from(&mut [0; 1024]).await?;
+            Ok::<_, anyhow::Error>((request.destination, client_addr))
+        });
+
+        let client = Socks6Client::from_socket_addr(proxy_addr, None);
+        let mut association = client.udp_associate_from("127.0.0.1:0".parse()?, None).await?;
+        let local_addr = association.local_addr()?;
+        assert_ne!(local_addr.port(), 0);
+        assert_eq!(association.relay_addr()?, relay_addr);
+
+        association.send_to(b"ping", String::from("10.0.0.1:53")).await?;
+        assert_eq!(proxy.await??, (Address::Ip(local_addr), local_addr));
+
+        Ok(())
+    }
+
+    // Tests that an already bound socket is used, and its address advertised.
+    #[tokio::test]
+    async fn test_udp_associate_with_socket() -> Result<()> {
+        use tokio::net::UdpSocket;
+
+        use crate::socks6::udp::{UdpMessage, UdpMessageType};
+
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+        let relay = UdpSocket::bind("127.0.0.1:0").await?;
+        let relay_addr = relay.local_addr()?;
+
+        let proxy = tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await?;
+            let request = socks6::read_request(&mut source).await?;
+
+            socks6::write_no_authentication(&mut source).await?;
+            let mut reply = vec![SOCKS_VER_6, SOCKS_REP_SUCCEEDED, SOCKS_PADDING];
+            reply.extend(Address::Ip(relay_addr).to_socks_bytes()?);
+            reply.extend([0, 0].iter());
+            source.write_all(&reply).await?;
+            source.write_all(&UdpMessage::new(UdpMessageType::AssociationInit, 7).into_socks_bytes()?).await?;
+
+            let (_, client_addr) = relay.recv_from(&mut [0; 1024]).await?;
+            Ok::<_, anyhow::Error>((request.destination, client_addr))
+        });
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await?;
+        let socket_addr = socket.local_addr()?;
+
+        let client = Socks6Client::from_socket_addr(proxy_addr, None);
+        let mut association = client.udp_associate_with_socket(socket, None).await?;
+        assert_eq!(association.local_addr()?, socket_addr);
+        assert_eq!(association.relay_addr()?, relay_addr);
+
+        association.send_to(b"ping", String::from("10.0.0.1:53")).await?;
+        assert_eq!(proxy.await??, (Address::Ip(socket_addr), socket_addr));
+
+        Ok(())
+    }
+
+    // Tests that the address the proxy listens on, and later the remote peer, are returned.
+    #[tokio::test]
+    async fn test_bind() -> Result<()> {
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        let proxy = tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await?;
+            let request = socks6::read_request(&mut source).await?;
+            socks6::write_no_authentication(&mut source).await?;
+
+            for binding in [Address::new("192.0.2.1", 4000), Address::new("198.51.100.1", 5000)] {
+                let mut reply = vec![SOCKS_VER_6, SOCKS_REP_SUCCEEDED, SOCKS_PADDING];
+                reply.extend(binding.to_socks_bytes()?);
+                reply.extend([0, 0].iter());
+                source.write_all(&reply).await?;
+            }
+
+            Ok::<_, anyhow::Error>(request)
+        });
+
+        let client = Socks6Client::from_socket_addr(proxy_addr, None);
+        let (mut stream, binding, _) = client.bind(Some("127.0.0.1:6000"), None).await?;
+        assert_eq!(binding, Address::new("192.0.2.1", 4000));
+        assert_eq!(client.accept_bind(&mut stream).await?, Address::new("198.51.100.1", 5000));
+
+        let request = proxy.await??;
+        assert_eq!(request.command, Command::Bind);
+        assert_eq!(request.destination, Address::new("127.0.0.1", 6000));
+
+        Ok(())
+    }
+
+    // Tests that borrowed initial data, and owned initial data, follow the request and reach the destination.
+    #[tokio::test]
+    async fn test_connect_with_initial_data() -> Result<()> {
+        use crate::Socks6Handler;
+
+        let destination = TcpListener::bind("127.0.0.1:0").await?;
+        let destination_addr = destination.local_addr()?;
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        tokio::spawn(async move {
+            loop {
+                let (mut source, _) = proxy.accept().await.unwrap();
+                tokio::spawn(async move { Socks6Handler::default().setup(&mut source).await.unwrap() });
+            }
+        });
+
+        let request = b"GET / HTTP/1.1\r\n\r\n";
+        let client = Socks6Client::from_socket_addr(proxy_addr, None);
+        client.connect_with_initial_data(destination_addr, request, None).await?;
+        client.connect(destination_addr, Some(request.to_vec()), None).await?;
+
+        for _ in 0..2 {
+            let (mut incoming, _) = destination.accept().await?;
+            let mut initial_data = [0; 18];
+            incoming.read_exact(&mut initial_data).await?;
+            assert_eq!(&initial_data, request);
+        }
+
+        Ok(())
+    }
+
+    // Tests that a backlog is requested for a BIND, and that the one granted by the proxy is returned.
+    #[tokio::test]
+    async fn test_bind_with_backlog() -> Result<()> {
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        let proxy = tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await?;
+            let request = socks6::read_request(&mut source).await?;
+            socks6::write_no_authentication(&mut source).await?;
+
+            let granted = StackOption::backlog(5).into_socks_bytes();
+            let mut reply = vec![SOCKS_VER_6, SOCKS_REP_SUCCEEDED, SOCKS_PADDING];
+            reply.extend(Address::new("192.0.2.1", 4000).to_socks_bytes()?);
+            reply.extend((granted.len() as u16).to_be_bytes().iter());
+            reply.extend(granted);
+            source.write_all(&reply).await?;
+
+            Ok::<_, anyhow::Error>(request)
+        });
+
+        let client = Socks6Client::from_socket_addr(proxy_addr, None);
+        let (_, binding, granted_backlog) = client.bind_with_backlog(None::<Address>, 10, None).await?;
+        assert_eq!(binding, Address::new("192.0.2.1", 4000));
+        assert_eq!(granted_backlog, Some(5));
+
+        let request = proxy.await??;
+        let requested_backlog = request.options.iter().find_map(|option| match option {
+            SocksOption::Stack(option) => option.backlog_value(),
+            _ => None,
+        });
+        assert_eq!(requested_backlog, Some(10));
+
+        Ok(())
+    }
+
+    // Tests that a built client has the configured options, and that building fails without a proxy address.
+    #[tokio::test]
+    async fn test_builder() -> Result<()> {
+        let proxy_addr: SocketAddr = "127.0.0.1:1080".parse()?;
+        let credentials = Credentials::new("user", "pass")?;
+
+        let client = Socks6Client::builder()
+            .proxy_addr(proxy_addr.to_string())
+            .credentials(credentials.clone())
+            .handshake_deadline(Duration::from_secs(5))
+            .build()
+            .await?;
+        assert_eq!(client.proxy_addrs, vec![proxy_addr]);
+        assert_eq!(client.credentials(), Some(&credentials));
+        assert_eq!(client.handshake_deadline, Some(Duration::from_secs(5)));
+
+        let client = Socks6Client::builder().proxy_socket_addrs(vec![proxy_addr]).build().await?;
+        assert_eq!(client.proxy_addrs, vec![proxy_addr]);
+        assert!(Socks6Client::builder().build().await.is_err());
+
+        Ok(())
+    }
+
+    // Tests that a probe sends a NOOP request, and only waits for the authentication reply.
+    #[tokio::test]
+    async fn test_probe() -> Result<()> {
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        let proxy = tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await?;
+            let mut header = [0; 2];
+            source.read_exact(&mut header).await?;
+            socks6::write_no_authentication(&mut source).await?;
+
+            source.read_to_end(&mut vec![]).await?;
+            Ok::<_, anyhow::Error>(header)
+        });
+
+        let client = Socks6Client::from_socket_addr(proxy_addr, None);
+        client.probe().await?;
+        assert_eq!(proxy.await??, [SOCKS_VER_6, SOCKS_CMD_NOOP]);
+
+        Ok(())
+    }
+
+    // Tests that connecting is aborted once the deadline passed, without a reply timeout.
+    #[tokio::test]
+    async fn test_connect_handshake_deadline() -> Result<()> {
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        tokio::spawn(async move {
+            let (_source, _) = proxy.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        });
+
+        let mut client = Socks6Client::from_socket_addr(proxy_addr, None);
+        client.set_handshake_deadline(Some(Duration::from_millis(100)));
+
+        let error = client.connect(String::from("127.0.0.1:80"), None, None).await.unwrap_err();
+        assert!(matches!(error, SocksError::Io(e) if e.kind() == std::io::ErrorKind::TimedOut));
+
+        Ok(())
+    }
+
+    // Tests that a failed authentication reply is reported according to the selected method.
+    #[tokio::test]
+    async fn test_handshake_auth_reply_failure() -> Result<()> {
+        let proxy_addr = spawn_proxy(vec![(Socks6AuthReplyType::Failure, vec![])]).await?;
+        let client = Socks6Client::new(proxy_addr.to_string(), None).await?;
+        let error = client.connect(String::from("127.0.0.1:80"), None, None).await.unwrap_err();
+        assert!(matches!(error, SocksError::AuthFailed));
+
+        let selection = AuthMethodSelectionOption::new(AuthMethod::UsernamePassword).wrap();
+        let proxy_addr = spawn_proxy(vec![(Socks6AuthReplyType::Failure, vec![selection])]).await?;
+        let client = Socks6Client::new(proxy_addr.to_string(), None).await?;
+        let error = client.connect(String::from("127.0.0.1:80"), None, None).await.unwrap_err();
+        assert!(matches!(error, SocksError::CredentialsRequired));
+
+        let selection = AuthMethodSelectionOption::new(AuthMethod::NoAcceptableMethods).wrap();
+        let proxy_addr = spawn_proxy(vec![(Socks6AuthReplyType::Failure, vec![selection])]).await?;
+        let client = Socks6Client::new(proxy_addr.to_string(), Some(Credentials::new("user", "pass")?)).await?;
+        let error = client.connect(String::from("127.0.0.1:80"), None, None).await.unwrap_err();
+        let offered = [AuthMethod::NoAuthentication, AuthMethod::UsernamePassword];
+        assert!(matches!(error, SocksError::AuthMethodRejected { offered: o } if o == offered));
+
+        Ok(())
     }
 }
@@ -1,17 +1,62 @@
-use anyhow::Result;
-use async_trait::async_trait;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{ensure, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
-use log::info;
+use tokio::time::Instant;
+
+use crate::{
+    Command, ConnectionEvent, DestinationRewriter, EventHandler, RelayOptions, Socks5Client, Socks6Client, SocksError,
+    NativeSocksHandler, ProxyHeader, Resolver, RetryPolicy, RuleSet, SocksSource, TcpOptions, TransferStats,
+};
+use crate::addresses::{Address, ProxyAddress, SocksVersion};
+use crate::constants::{SOCKS_MAX_OPTIONS_COUNT, SOCKS_MAX_OPTIONS_LENGTH};
+use crate::socks6::{self, Socks6Reply, Socks6Request};
+use crate::socks6::options::{SocksOption, StackLeg, StackOption, StackOptionType, UnknownOptionPolicy};
+use crate::events::record_destination;
+use crate::resolver::resolve_address;
+use crate::tunnel::MAX_BUFFER_SIZE;
+use crate::util::{connect_happy_eyeballs_with_options, HAPPY_EYEBALLS_DELAY};
+
+/// The size of the chunks initial data is forwarded in, which bounds the memory a connection holds for it.
+const INITIAL_DATA_CHUNK_SIZE: usize = 2048;
+
+/// How long the resolved addresses of a configured link are reused, by default.
+const LINK_CACHE_TTL: Duration = Duration::from_secs(60);
 
-use crate::{Socks6Client, SocksHandler};
-use crate::addresses::ProxyAddress;
-use crate::socks6::{self, Socks6Reply};
+/// A callback that is passed every request the handler reads, e.g. to log or route by custom options. The request
+/// carries all of its parsed options, including those of unrecognized kinds as raw bytes, unless the unknown option
+/// policy drops them.
+pub type RequestHook = Arc<dyn Fn(&Socks6Request) + Send + Sync>;
 
 /// Implements a SOCKS6 handler.
+///
+/// Clones share the configured links and rules, rather than copying them, so a clone per connection is cheap.
 #[derive(Clone)]
 pub struct Socks6Handler {
-    static_links: Vec<ProxyAddress>,
+    static_links: Arc<[ProxyAddress]>,
+    link_cache: LinkCache,
+    link_cache_ttl: Option<Duration>,
+    resolver: Option<Arc<dyn Resolver>>,
+    rule_set: Option<Arc<RuleSet>>,
+    happy_eyeballs_delay: Duration,
+    dial_retry_policy: Option<RetryPolicy>,
+    dial_timeout: Option<Duration>,
+    event_handler: Option<EventHandler>,
+    request_hook: Option<RequestHook>,
+    destination_rewriter: Option<DestinationRewriter>,
+    unknown_option_policy: UnknownOptionPolicy,
+    max_options_length: u16,
+    max_options_count: u16,
+    request_timeout: Option<Duration>,
+    tcp_options: TcpOptions,
+    relay_options: RelayOptions,
+    forward_source: bool,
 }
 
 impl Default for Socks6Handler {
@@ -30,94 +75,1487 @@ impl Socks6Handler {
     /// # Returns
     /// A new `Socks6Handler`.
     pub fn new(static_links: Vec<ProxyAddress>) -> Self {
-        Socks6Handler { static_links }
+        Socks6Handler {
+            static_links: static_links.into(),
+            link_cache: LinkCache::default(),
+            link_cache_ttl: Some(LINK_CACHE_TTL),
+            resolver: None,
+            rule_set: None,
+            happy_eyeballs_delay: HAPPY_EYEBALLS_DELAY,
+            dial_retry_policy: None,
+            dial_timeout: None,
+            event_handler: None,
+            request_hook: None,
+            destination_rewriter: None,
+            unknown_option_policy: UnknownOptionPolicy::default(),
+            max_options_length: SOCKS_MAX_OPTIONS_LENGTH,
+            max_options_count: SOCKS_MAX_OPTIONS_COUNT,
+            request_timeout: None,
+            tcp_options: TcpOptions::default(),
+            relay_options: RelayOptions::default(),
+            forward_source: false,
+        }
     }
-}
 
-#[async_trait]
-impl SocksHandler for Socks6Handler {
-    /// Accepts a request from the source and sets up a tunnel to the destination.
+    /// Sets the rules that select the upstream chain by destination, which take the place of the static links.
     ///
     /// # Parameters
-    /// - `source`: A mutable reference to the source TCP stream.
+    /// - `rule_set`: The rules, or `None` (the default) to use the static links for every request.
+    pub fn set_rule_set(
+        &mut self,
+        rule_set: Option<RuleSet>,
+    ) {
+        self.rule_set = rule_set.map(Arc::new);
+    }
+
+    /// Sets how long the resolved addresses of the configured links (the static links, or those of the rules) are
+    /// reused, so that not every tunnel through the chain resolves the next hop again. Links that are requested by
+    /// clients are always resolved.
+    ///
+    /// # Parameters
+    /// - `ttl`: The time to live of a resolution, defaults to 60s, or `None` to resolve the next hop for every tunnel.
+    pub fn set_link_cache_ttl(
+        &mut self,
+        ttl: Option<Duration>,
+    ) {
+        self.link_cache_ttl = ttl;
+    }
+
+    /// Forgets the resolved addresses of the configured links, so they're resolved again on their next use, e.g. once
+    /// their DNS records changed. Clones of the handler share the cached addresses, so it applies to them too.
+    pub fn refresh_links(&self) {
+        self.link_cache.clear();
+    }
+
+    /// Sets the resolver of destination domain names, that are dialed directly (rather than through a chain).
+    ///
+    /// # Parameters
+    /// - `resolver`: The resolver, e.g. a shared `DnsCache`, or `None` (the default) to resolve every connection with
+    ///   the resolver of the operating system.
+    pub fn set_resolver(
+        &mut self,
+        resolver: Option<Arc<dyn Resolver>>,
+    ) {
+        self.resolver = resolver;
+    }
+
+    /// Sets the socket options applied to connections with the destination or the next hop.
+    ///
+    /// # Parameters
+    /// - `tcp_options`: The socket options, defaults to `TCP_NODELAY` without keepalive.
+    pub fn set_tcp_options(
+        &mut self,
+        tcp_options: TcpOptions,
+    ) {
+        self.tcp_options = tcp_options;
+    }
+
+    /// Sets the mark (`SO_MARK`) of connections with the destination or the next hop, see `TcpOptions::fwmark`.
+    ///
+    /// # Parameters
+    /// - `fwmark`: The mark, or `None` (the default) to leave packets unmarked.
+    pub fn set_fwmark(
+        &mut self,
+        fwmark: Option<u32>,
+    ) {
+        self.tcp_options.fwmark = fwmark;
+    }
+
+    /// Sets whether the address of the client is passed on to the next hop when chaining, with a PROXY protocol v2
+    /// header ahead of the handshake. The next hop has to expect the header.
+    ///
+    /// # Parameters
+    /// - `forward_source`: Whether to send the header, defaults to `false`.
+    pub fn set_forward_source(
+        &mut self,
+        forward_source: bool,
+    ) {
+        self.forward_source = forward_source;
+    }
+
+    /// Sets how options with an unrecognized kind are treated in client requests.
+    ///
+    /// # Parameters
+    /// - `policy`: The policy, defaults to preserving unknown options.
+    pub fn set_unknown_option_policy(
+        &mut self,
+        policy: UnknownOptionPolicy,
+    ) {
+        self.unknown_option_policy = policy;
+    }
+
+    /// Sets the maximum total length of the options in client requests, longer requests are rejected.
+    ///
+    /// # Parameters
+    /// - `max_options_length`: The maximum length in bytes, defaults to 16384.
+    pub fn set_max_options_length(
+        &mut self,
+        max_options_length: u16,
+    ) {
+        self.max_options_length = max_options_length;
+    }
+
+    /// Sets the maximum number of options in client requests, requests with more options are rejected. This bounds
+    /// the work spent on a request of many tiny options, which its total length doesn't.
+    ///
+    /// # Parameters
+    /// - `max_options_count`: The maximum number of options, defaults to 64.
+    pub fn set_max_options_count(
+        &mut self,
+        max_options_count: u16,
+    ) {
+        self.max_options_count = max_options_count;
+    }
+
+    /// Sets the time connecting to the destination (or the next proxy, including its handshake) is given, after
+    /// which the client is replied to with `ConnectionAttemptTimeOut` and the connection is closed. This keeps
+    /// destinations that never complete the connection, or black-holed routes, from holding a task. The time includes
+    /// any retries.
+    ///
+    /// # Parameters
+    /// - `dial_timeout`: The dial timeout, defaults to `None` (wait as long as the operating system does).
+    pub fn set_dial_timeout(
+        &mut self,
+        dial_timeout: Option<Duration>,
+    ) {
+        self.dial_timeout = dial_timeout;
+    }
+
+    /// Sets the time a client is given to send its complete request once connected, after which it's replied to with
+    /// `GeneralFailure` and the connection is closed. This keeps clients that connect and then stall (or trickle their
+    /// request) from holding a task.
+    ///
+    /// # Parameters
+    /// - `request_timeout`: The request timeout, defaults to `None` (wait indefinitely).
+    pub fn set_request_timeout(
+        &mut self,
+        request_timeout: Option<Duration>,
+    ) {
+        self.request_timeout = request_timeout;
+    }
+
+    /// Sets a callback that receives a `ConnectionEvent` for every connection that was set up.
+    ///
+    /// # Parameters
+    /// - `event_handler`: The callback, or `None` to stop emitting events.
+    pub fn set_event_handler(
+        &mut self,
+        event_handler: Option<EventHandler>,
+    ) {
+        self.event_handler = event_handler;
+    }
+
+    /// Sets a callback that is passed every request, with all of its options, once it's read.
+    ///
+    /// # Parameters
+    /// - `request_hook`: The callback, or `None` (the default) to not pass requests on.
+    pub fn set_request_hook(
+        &mut self,
+        request_hook: Option<RequestHook>,
+    ) {
+        self.request_hook = request_hook;
+    }
+
+    /// Sets the maximum time a tunnel is kept open, after which it's closed regardless of activity.
+    ///
+    /// # Parameters
+    /// - `max_lifetime`: The maximum lifetime, or `None` (the default) to keep tunnels open until they're closed.
+    pub fn set_max_lifetime(
+        &mut self,
+        max_lifetime: Option<Duration>,
+    ) {
+        self.relay_options.max_lifetime = max_lifetime;
+    }
+
+    /// Sets the size of the buffer used for each direction of a tunnel.
+    ///
+    /// # Parameters
+    /// - `buffer_size`: The size in bytes, defaults to 8KB and is capped at 256KB.
+    pub fn set_buffer_size(
+        &mut self,
+        buffer_size: usize,
+    ) {
+        self.relay_options.buffer_size = buffer_size.min(MAX_BUFFER_SIZE);
+    }
+
+    /// Sets whether tunnels move data with `splice(2)` instead of copying it through a buffer (Linux only).
+    ///
+    /// # Parameters
+    /// - `zero_copy`: Whether to use zero-copy, defaults to `false`, ignored on other platforms.
+    pub fn set_zero_copy(
+        &mut self,
+        zero_copy: bool,
+    ) {
+        self.relay_options.zero_copy = zero_copy;
+    }
+
+    /// Sets a callback that rewrites the destination of every request before it's dialed, e.g. to redirect it.
+    ///
+    /// # Parameters
+    /// - `destination_rewriter`: The callback, returning `None` refuses the request, or `None` to dial as requested.
+    pub fn set_destination_rewriter(
+        &mut self,
+        destination_rewriter: Option<DestinationRewriter>,
+    ) {
+        self.destination_rewriter = destination_rewriter;
+    }
+
+    /// Sets the delay between staggered IPv4/IPv6 connection attempts to the next hop.
+    ///
+    /// # Parameters
+    /// - `delay`: The connection attempt delay, defaults to 250ms.
+    pub fn set_happy_eyeballs_delay(
+        &mut self,
+        delay: Duration,
+    ) {
+        self.happy_eyeballs_delay = delay;
+    }
+
+    /// Sets the policy for retrying when connecting directly to the destination fails, e.g. because it's briefly
+    /// unavailable. Only refused and timed out connections are retried, not failures to resolve the destination.
+    /// This is separate from the retries of the next hop, and if the last attempt fails the client is told why.
+    ///
+    /// # Parameters
+    /// - `dial_retry_policy`: The retry policy, defaults to `None` (no retries).
+    pub fn set_dial_retry_policy(
+        &mut self,
+        dial_retry_policy: Option<RetryPolicy>,
+    ) {
+        self.dial_retry_policy = dial_retry_policy;
+    }
+
+    /// Resolves the address of the next hop, reusing an earlier resolution for configured links.
+    async fn resolve_link(
+        &self,
+        link: &ProxyAddress,
+        configured: bool,
+    ) -> Result<Vec<SocketAddr>> {
+        let proxy_addr = format!("{}:{}", link.host, link.port);
+        match self.link_cache_ttl {
+            Some(ttl) if configured => self.link_cache.resolve(proxy_addr, ttl).await,
+            _ => crate::resolve_addrs(proxy_addr).await,
+        }
+    }
+
+    /// Resolves the destination, failures are reported to the client as an unreachable host.
+    async fn resolve_destination(
+        &self,
+        destination: &Address,
+    ) -> Result<Vec<SocketAddr>> {
+        let addrs = resolve_address(destination, self.resolver.as_deref()).await.map_err(|error| {
+            let message = format!("Failed to resolve {}: {}", destination, error);
+            io::Error::new(io::ErrorKind::HostUnreachable, message)
+        })?;
+
+        Ok(addrs)
+    }
+
+    /// Connects directly to the destination, racing IPv4 and IPv6 candidates.
+    async fn connect_direct(
+        &self,
+        destination: &Address,
+    ) -> Result<TcpStream> {
+        let addrs = self.resolve_destination(destination).await?;
+        let stream = self.dial(&addrs).await?;
+        self.tcp_options.apply(&stream)?;
+
+        Ok(stream)
+    }
+
+    /// Connects to one of the resolved addresses of the destination, retrying according to the dial retry policy.
+    async fn dial(
+        &self,
+        addrs: &[SocketAddr],
+    ) -> Result<TcpStream> {
+        let connect = || connect_happy_eyeballs_with_options(addrs, self.happy_eyeballs_delay, &self.tcp_options);
+        match &self.dial_retry_policy {
+            Some(retry_policy) => retry_policy.retry_if(connect, is_retryable_dial_error).await,
+            None => connect().await,
+        }
+    }
+
+    /// Connects directly to the destination with TCP Fast Open, carrying the initial data in the SYN.
+    /// Falls back to connecting and then writing the initial data if TCP Fast Open is unavailable or fails.
     ///
     /// # Returns
-    /// An `Ok(())` if the tunnel is successfully set up, otherwise an error.
-    async fn accept_request(
+    /// A `Result` containing the destination `TcpStream`, and whether TCP Fast Open was used.
+    async fn connect_fast_open(
         &self,
-        source: &mut TcpStream,
-    ) -> Result<()> {
-        let mut destination = self.setup(source).await?;
+        destination: &Address,
+        initial_data: &[u8],
+    ) -> Result<(TcpStream, bool)> {
+        let addrs = self.tcp_options.family.filter(&self.resolve_destination(destination).await?)?;
+        let addr = addrs
+            .first()
+            .copied()
+            .ok_or_else(|| anyhow!("Destination has no address to connect to."))?;
+        match crate::socket::connect_fast_open(addr, initial_data, &self.tcp_options).await {
+            Ok(Some(stream)) => {
+                self.tcp_options.apply(&stream)?;
+                return Ok((stream, true));
+            }
+            Ok(None) => {}
+            Err(error) => debug!("TCP Fast Open to {} failed, falling back: {}", addr, error),
+        }
 
-        // Start bidirectional copy, after this the connection closes.
-        tokio::io::copy_bidirectional(source, &mut destination).await?;
+        let mut stream = self.dial(&addrs).await?;
+        self.tcp_options.apply(&stream)?;
+        stream.write_all(initial_data).await?;
 
-        Ok(())
+        Ok((stream, false))
     }
 
-    /// Refuses a request from the source.
+    /// Refuses a request from the source, telling it why, e.g. `CommandNotSupported` for a command that isn't
+    /// allowed. `refuse_request` refuses with `ConnectionRefused`.
     ///
     /// # Parameters
-    /// - `source`: A mutable reference to the source TCP stream.
+    /// - `source`: A mutable reference to the source stream.
+    /// - `reply`: The reply that is sent to the source.
     ///
     /// # Returns
     /// An `Ok(())` if the source is successfully notified of the refusal, otherwise an error.
-    async fn refuse_request(
+    pub async fn refuse_request_with<S: SocksSource>(
         &self,
-        source: &mut TcpStream,
+        source: &mut S,
+        reply: Socks6Reply,
     ) -> Result<()> {
         // Notify source that the connection is refused.
-        socks6::write_reply(source, Socks6Reply::ConnectionRefused).await?;
+        socks6::write_reply(source, reply).await?;
 
         Ok(())
     }
 
-    /// Sets up the connection to the destination.
+    /// Sets up the connection to the destination, like `setup`.
     ///
     /// # Parameters
-    /// - `source`: A mutable reference to the source TCP stream.
+    /// - `source`: A mutable reference to the source stream.
     ///
     /// # Returns
-    /// A `Result` containing the destination `TcpStream` if successful, otherwise an error.
-    async fn setup(
+    /// A `Result` containing the destination `TcpStream`, and the destination it's connected to (as rewritten, if a
+    /// rewriter is set), if successful, otherwise an error.
+    async fn setup_destination<S: SocksSource>(
         &self,
-        source: &mut TcpStream,
-    ) -> Result<TcpStream> {
+        source: &mut S,
+    ) -> Result<(TcpStream, Address)> {
+        let negotiated = self.negotiate_destination(source).await;
+
+        // Close the connection gracefully, after any reply, so the client doesn't see a reset instead.
+        if negotiated.is_err() {
+            source.shutdown().await.ok();
+        }
+
+        negotiated
+    }
+
+    /// Reads the request of a client and connects to the destination it requests, for `setup_destination`.
+    async fn negotiate_destination<S: SocksSource>(
+        &self,
+        source: &mut S,
+    ) -> Result<(TcpStream, Address)> {
+        let start_time = Instant::now();
+
         // Receive SOCKS request, and allow unauthenticated access.
-        let request = socks6::read_request(source).await?;
+        let request = socks6::read_request_with_limits(
+            source,
+            self.unknown_option_policy,
+            self.max_options_length,
+            self.max_options_count,
+        );
+        let request = match self.request_timeout {
+            Some(request_timeout) => tokio::time::timeout(request_timeout, request).await.unwrap_or_else(|_| {
+                let message = format!("Client didn't send its request within {}ms.", request_timeout.as_millis());
+                Err(io::Error::new(io::ErrorKind::TimedOut, message).into())
+            }),
+            None => request.await,
+        };
+        let request = match request {
+            Ok(request) => request,
+            Err(error) => {
+                let reply = match error.downcast_ref() {
+                    Some(SocksError::CommandNotSupported(_)) => Socks6Reply::CommandNotSupported,
+                    Some(SocksError::AddressTypeNotSupported(_)) => Socks6Reply::AddressTypeNotSupported,
+                    _ => Socks6Reply::GeneralFailure,
+                };
+                // The client may be gone already, e.g. if it closed mid-request, then the request's error is the one
+                // worth returning.
+                if socks6::write_no_authentication(source).await.is_ok() {
+                    socks6::write_reply(source, reply).await.ok();
+                }
+
+                return Err(error);
+            }
+        };
+        let handshake_latency = start_time.elapsed();
+        record_destination(&request.destination);
+        if let Some(request_hook) = &self.request_hook {
+            request_hook(&request);
+        }
         socks6::write_no_authentication(source).await?;
+        debug!("Sent authentication reply");
+
+        if request.command != Command::Connect {
+            socks6::write_reply(source, Socks6Reply::CommandNotSupported).await?;
+            bail!("Only CONNECT is supported, got: {:?}.", request.command);
+        }
 
-        let destination = request.destination.to_string();
+        let target = match &self.destination_rewriter {
+            Some(rewriter) => match rewriter(request.destination.clone()) {
+                Some(target) => target,
+                None => {
+                    socks6::write_reply(source, Socks6Reply::ConnectionNotAllowed).await?;
+                    bail!("Destination {} was refused by the rewriter.", request.destination);
+                }
+            },
+            None => request.destination.clone(),
+        };
+
+        let links = match &self.rule_set {
+            Some(rule_set) => rule_set.route(&target).links(),
+            None => &self.static_links[..],
+        };
+        let destination = target.to_string();
         info!("Connecting to destination - {}", destination);
-        let chain = request.chain(&self.static_links)?;
+        let mut chain = match request.chain(links) {
+            Ok(chain) => chain,
+            Err(error) => {
+                socks6::write_reply(source, Socks6Reply::GeneralFailure).await?;
+                return Err(error);
+            }
+        };
 
-        let mut destination = if let Some(mut chain) = chain {
-            if let Some(next) = chain.next_link() {
-                let next = next.clone();
+        let next = chain.as_mut().and_then(|chain| chain.next_link().cloned());
+        let configured = next.as_ref().is_some_and(|next| links.contains(next));
+        // A source without a local address, e.g. an in-memory one, can't be reached by a link anyway.
+        let local_addr = source.local_addr().ok();
 
-                let proxy_addr = format!("{}:{}", next.host, next.port);
-                let client = Socks6Client::new(proxy_addr, next.credentials).await?;
+        // TCP Fast Open needs the initial data before connecting, as it's carried along with the handshake. So unlike
+        // other requests, where it's forwarded in chunks once connected, it's read into a single buffer.
+        let mut fast_open_data = None;
+        if next.is_none() && request.initial_data_length > 0 && requests_fast_open(&request.options) {
+            let mut initial_data = vec![0; request.initial_data_length as usize];
+            source.read_exact(&mut initial_data).await?;
+            fast_open_data = Some(initial_data);
+        }
 
-                let (outgoing, _) = client.connect(destination, None, Some(chain.as_options())).await?;
-                outgoing
+        let proxy_header = if self.forward_source {
+            Some(ProxyHeader::new(source.peer_addr()?, source.local_addr()?))
+        } else {
+            None
+        };
+
+        let dial = async {
+            if let (Some(next), Some(chain)) = (next, &chain) {
+                let proxy_addrs = self.resolve_link(&next, configured).await?;
+                // The link may name this proxy by any of its names, so it's the resolved addresses that are compared.
+                if local_addr.is_some_and(|local_addr| proxy_addrs.contains(&local_addr)) {
+                    let message = format!("Proxy chain loops back to this proxy at {}.", next);
+                    return Err(io::Error::new(io::ErrorKind::PermissionDenied, message).into());
+                }
+
+                if next.protocol() == Some(SocksVersion::Socks5) {
+                    // SOCKS5 can't carry the chain (or any other option), so the hop has to be the last one.
+                    // Initial data is sent once the tunnel is established, which is the same for either version.
+                    ensure!(!chain.has_next(), "SOCKS5 proxy {} can't forward the remainder of the chain.", next);
+
+                    let mut client = Socks5Client::from_socket_addrs(proxy_addrs, next.credentials);
+                    client.set_happy_eyeballs_delay(self.happy_eyeballs_delay);
+                    client.set_tcp_options(self.tcp_options);
+                    client.set_proxy_header(proxy_header);
+
+                    let (outgoing, _) = client.connect(destination).await?;
+                    return Ok((outgoing, vec![]));
+                }
+
+                let mut client = Socks6Client::from_socket_addrs(proxy_addrs, next.credentials);
+                client.set_happy_eyeballs_delay(self.happy_eyeballs_delay);
+                client.set_tcp_options(self.tcp_options);
+                client.set_proxy_header(proxy_header);
+
+                let (outgoing, _, granted_options) =
+                    client.connect_negotiated(destination, None, Some(chain.as_options())).await?;
+                Ok((outgoing, relayable_options(granted_options)))
+            } else if let Some(initial_data) = &fast_open_data {
+                let (outgoing, fast_open) = self.connect_fast_open(&target, initial_data).await?;
+                let granted_options = if fast_open {
+                    vec![StackOption::tfo(initial_data.len() as u16).wrap()]
+                } else {
+                    vec![]
+                };
+
+                Ok((outgoing, granted_options))
             } else {
-                TcpStream::connect(destination).await?
+                Ok((self.connect_direct(&target).await?, vec![]))
             }
-        } else {
-            TcpStream::connect(destination).await?
+        };
+        let dialed: Result<_> = match self.dial_timeout {
+            Some(dial_timeout) => tokio::time::timeout(dial_timeout, dial).await.unwrap_or_else(|_| {
+                let message = format!("Connecting to {} took longer than {}ms.", target, dial_timeout.as_millis());
+                Err(io::Error::new(io::ErrorKind::TimedOut, message).into())
+            }),
+            None => dial.await,
         };
 
-        // Send initial data
-        if request.initial_data_length > 0 {
-            let mut initial_data = vec![0; request.initial_data_length as usize];
-            source.read_exact(&mut initial_data).await?;
-            destination.write(&initial_data).await?;
+        // Tell the source why the destination couldn't be reached, before closing the connection.
+        let (mut destination, granted_options) = match dialed {
+            Ok(dialed) => dialed,
+            Err(error) => {
+                socks6::write_reply(source, Socks6Reply::from_dial_error(&error)).await?;
+                source.flush().await?;
+                return Err(error);
+            }
+        };
+
+        let event = ConnectionEvent {
+            destination: request.destination.clone(),
+            target: target.clone(),
+            handshake_latency,
+            setup_latency: start_time.elapsed(),
+        };
+        info!(
+            "Destination established in {}ms (handshake took {}ms)",
+            event.setup_latency.as_millis(),
+            event.handshake_latency.as_millis()
+        );
+        if let Some(event_handler) = &self.event_handler {
+            event_handler(&event);
         }
 
-        // Notify source that the connection has been set up.
-        socks6::write_reply(source, Socks6Reply::Success).await?;
+        // Send initial data, unless it was already sent while connecting.
+        if request.initial_data_length > 0 && fast_open_data.is_none() {
+            forward_initial_data(source, &mut destination, request.initial_data_length as usize).await?;
+        }
+
+        // Notify source that the connection has been set up, passing on what the upstream granted.
+        socks6::write_reply_with_options(source, Socks6Reply::Success, &granted_options).await?;
         source.flush().await?;
+        debug!("Sent operation reply");
+
+        Ok((destination, target))
+    }
+}
+
+impl Socks6Handler {
+    /// Accepts a request from the source and sets up a tunnel to the destination.
+    ///
+    /// # Parameters
+    /// - `source`: A mutable reference to the source stream.
+    ///
+    /// # Returns
+    /// A `Result` containing the destination the source was connected to, and the data relayed, if the tunnel is
+    /// successfully set up, otherwise an error.
+    pub async fn accept_request<S: SocksSource>(
+        &self,
+        source: &mut S,
+    ) -> Result<(Address, TransferStats)> {
+        let (mut destination, target) = self.setup_destination(source).await?;
+
+        // Relay in both directions, passing on half-closes, until both directions are closed.
+        let stats = crate::tunnel::relay_source(source, &mut destination, &self.relay_options).await?;
+
+        Ok((target, stats))
+    }
+
+    /// Refuses a request from the source.
+    ///
+    /// # Parameters
+    /// - `source`: A mutable reference to the source stream.
+    ///
+    /// # Returns
+    /// An `Ok(())` if the source is successfully notified of the refusal, otherwise an error.
+    pub async fn refuse_request<S: SocksSource>(
+        &self,
+        source: &mut S,
+    ) -> Result<()> {
+        self.refuse_request_with(source, Socks6Reply::ConnectionRefused).await
+    }
+
+    /// Sets up the connection to the destination.
+    ///
+    /// # Parameters
+    /// - `source`: A mutable reference to the source stream.
+    ///
+    /// # Returns
+    /// A `Result` containing the destination `TcpStream` if successful, otherwise an error.
+    pub async fn setup<S: SocksSource>(
+        &self,
+        source: &mut S,
+    ) -> Result<TcpStream> {
+        let (destination, _) = self.setup_destination(source).await?;
 
         Ok(destination)
     }
 }
+
+impl NativeSocksHandler for Socks6Handler {
+    fn accept_request<S: SocksSource>(
+        &self,
+        source: &mut S,
+    ) -> impl Future<Output = Result<(Address, TransferStats)>> + Send {
+        Socks6Handler::accept_request(self, source)
+    }
+
+    fn refuse_request<S: SocksSource>(
+        &self,
+        source: &mut S,
+    ) -> impl Future<Output = Result<()>> + Send {
+        Socks6Handler::refuse_request(self, source)
+    }
+
+    fn setup<S: SocksSource>(
+        &self,
+        source: &mut S,
+    ) -> impl Future<Output = Result<TcpStream>> + Send {
+        Socks6Handler::setup(self, source)
+    }
+}
+
+/// Forwards the initial data of a request from the source to the destination, in chunks as it arrives.
+/// Fails if the source closes the connection before all of the advertised `length` is received.
+async fn forward_initial_data<R, W>(
+    source: &mut R,
+    destination: &mut W,
+    length: usize,
+) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut chunk = [0; INITIAL_DATA_CHUNK_SIZE];
+    let mut remaining = length;
+    while remaining > 0 {
+        let read = source.read(&mut chunk[..remaining.min(INITIAL_DATA_CHUNK_SIZE)]).await?;
+        ensure!(read > 0, "Source closed after {} of {} bytes of initial data.", length - remaining, length);
+
+        destination.write_all(&chunk[..read]).await?;
+        remaining -= read;
+    }
+
+    Ok(())
+}
+
+/// Determines whether the client requested TCP Fast Open towards the destination.
+fn requests_fast_open(options: &[SocksOption]) -> bool {
+    options.iter().any(|o| {
+        matches!(
+            o,
+            SocksOption::Stack(o) if o.option_type() == Some(StackOptionType::Tfo) && o.leg != StackLeg::ClientProxy
+        )
+    })
+}
+
+/// Selects the options granted by an upstream proxy that are relevant to the original client.
+/// Authentication and metadata (e.g. chain) options only concern the hop they were received on.
+fn relayable_options(options: Vec<SocksOption>) -> Vec<SocksOption> {
+    options
+        .into_iter()
+        .filter(|o| {
+            !matches!(
+                o,
+                SocksOption::AuthMethodAdvertisement(_) | SocksOption::AuthMethodSelection(_) | SocksOption::Metadata(_)
+            )
+        })
+        .collect()
+}
+
+/// The resolved addresses of links by their `host:port`, along with the time they were resolved.
+type LinkEntries = HashMap<String, (Vec<SocketAddr>, Instant)>;
+
+/// The resolved addresses of links, shared by the clones of a handler.
+#[derive(Clone, Default)]
+struct LinkCache {
+    entries: Arc<Mutex<LinkEntries>>,
+}
+
+impl LinkCache {
+    /// Returns the addresses of the link, resolving them if they aren't cached or are older than `ttl`.
+    /// Failed resolutions aren't cached, so the next tunnel tries again.
+    async fn resolve(
+        &self,
+        proxy_addr: String,
+        ttl: Duration,
+    ) -> Result<Vec<SocketAddr>> {
+        if let Some((addrs, resolved)) = self.entries.lock().unwrap().get(&proxy_addr) {
+            if resolved.elapsed() < ttl {
+                return Ok(addrs.clone());
+            }
+        }
+
+        let addrs = crate::resolve_addrs(proxy_addr.clone()).await?;
+        self.entries.lock().unwrap().insert(proxy_addr, (addrs.clone(), Instant::now()));
+
+        Ok(addrs)
+    }
+
+    /// Forgets all resolved addresses.
+    fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+/// Determines whether a failure to dial the destination is worth another attempt, i.e. whether the connection was
+/// refused or timed out. Failures to resolve the destination are reported as `HostUnreachable`, so they aren't.
+fn is_retryable_dial_error(error: &anyhow::Error) -> bool {
+    match error.downcast_ref::<io::Error>() {
+        Some(error) => matches!(error.kind(), io::ErrorKind::ConnectionRefused | io::ErrorKind::TimedOut),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    // Tests that the setup latency is reported, and covers the handshake.
+    #[tokio::test]
+    async fn test_setup_latency_event() -> Result<()> {
+        let destination = TcpListener::bind("127.0.0.1:0").await?;
+        let destination_addr = destination.local_addr()?;
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        let events = Arc::new(Mutex::new(vec![]));
+        let mut handler = Socks6Handler::new(vec![]);
+        let sink = Arc::clone(&events);
+        handler.set_event_handler(Some(Arc::new(move |e: &ConnectionEvent| {
+            sink.lock().unwrap().push(e.clone())
+        })));
+
+        tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await.unwrap();
+            handler.setup(&mut source).await.unwrap();
+        });
+
+        let client = Socks6Client::new(proxy_addr.to_string(), None).await?;
+        client.connect(destination_addr.to_string(), None, None).await?;
+        destination.accept().await?;
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].destination, crate::Address::Ip(destination_addr));
+        assert_eq!(events[0].target, events[0].destination);
+        assert!(events[0].setup_latency >= events[0].handshake_latency);
+        assert!(events[0].setup_latency < Duration::from_secs(5));
+
+        Ok(())
+    }
+
+    // Tests that the request hook is passed the options of requests, including those of custom kinds.
+    #[tokio::test]
+    async fn test_request_hook() -> Result<()> {
+        use crate::socks6::options::UnrecognizedOption;
+
+        let destination = TcpListener::bind("127.0.0.1:0").await?;
+        let destination_addr = destination.local_addr()?;
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        let tenants = Arc::new(Mutex::new(vec![]));
+        let mut handler = Socks6Handler::new(vec![]);
+        let sink = Arc::clone(&tenants);
+        handler.set_request_hook(Some(Arc::new(move |request: &Socks6Request| {
+            for option in &request.options {
+                if let SocksOption::Unrecognized(option) = option {
+                    sink.lock().unwrap().push((option.kind(), option.data().to_vec()));
+                }
+            }
+        })));
+
+        tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await.unwrap();
+            handler.setup(&mut source).await.unwrap();
+        });
+
+        let tenant = UnrecognizedOption::new(0xF000, b"acme".to_vec()).wrap();
+        let client = Socks6Client::new(proxy_addr.to_string(), None).await?;
+        client.connect(destination_addr.to_string(), None, Some(vec![tenant])).await?;
+        destination.accept().await?;
+
+        let tenants = tenants.lock().unwrap();
+        assert_eq!(tenants.len(), 1);
+        assert_eq!(tenants[0].0, 0xF000);
+        assert!(tenants[0].1.starts_with(b"acme"));
+
+        Ok(())
+    }
+
+    // Tests that the address of the client is passed on to the next hop, ahead of the request.
+    #[tokio::test]
+    async fn test_forward_source() -> Result<()> {
+        let upstream = TcpListener::bind("127.0.0.1:0").await?;
+        let upstream_addr = upstream.local_addr()?;
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        let upstream = tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await?;
+            let mut header = vec![0; 28];
+            stream.read_exact(&mut header).await?;
+            socks6::read_request(&mut stream).await?;
+            socks6::write_no_authentication(&mut stream).await?;
+            socks6::write_reply(&mut stream, Socks6Reply::Success).await?;
+
+            Ok::<_, anyhow::Error>(header)
+        });
+
+        let link = ProxyAddress::new(6, upstream_addr.ip().to_string(), upstream_addr.port(), None);
+        let mut handler = Socks6Handler::new(vec![link]);
+        handler.set_forward_source(true);
+        tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await.unwrap();
+            handler.setup(&mut source).await.unwrap();
+        });
+
+        let client = Socks6Client::new(proxy_addr.to_string(), None).await?;
+        let (stream, _) = client.connect("10.0.0.1:80".to_string(), None, None).await?;
+
+        let expected = ProxyHeader::new(stream.local_addr()?, proxy_addr).to_bytes();
+        assert_eq!(upstream.await??, expected);
+
+        Ok(())
+    }
+
+    // Tests that domain names of destinations are resolved with the configured resolver.
+    #[tokio::test]
+    async fn test_resolver() -> Result<()> {
+        use crate::DnsCache;
+
+        struct FixedResolver(SocketAddr);
+
+        #[async_trait::async_trait]
+        impl Resolver for FixedResolver {
+            async fn resolve(
+                &self,
+                _host: &str,
+                _port: u16,
+            ) -> Result<Vec<SocketAddr>> {
+                Ok(vec![self.0])
+            }
+        }
+
+        let destination = TcpListener::bind("127.0.0.1:0").await?;
+        let destination_addr = destination.local_addr()?;
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        let cache = Arc::new(DnsCache::new(Arc::new(FixedResolver(destination_addr)), 16));
+        let mut handler = Socks6Handler::new(vec![]);
+        handler.set_resolver(Some(cache.clone()));
+        tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await.unwrap();
+            handler.setup(&mut source).await.unwrap();
+        });
+
+        let client = Socks6Client::new(proxy_addr.to_string(), None).await?;
+        client.connect(String::from("destination.test:80"), None, None).await?;
+        destination.accept().await?;
+        assert_eq!(cache.len(), 1);
+
+        Ok(())
+    }
+
+    // Tests that the address of a static link is cached for the clones of the handler, until it's refreshed.
+    #[tokio::test]
+    async fn test_static_link_cache() -> Result<()> {
+        let upstream = TcpListener::bind("127.0.0.1:0").await?;
+        let upstream_addr = upstream.local_addr()?;
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            socks6::read_request(&mut stream).await.unwrap();
+            socks6::write_no_authentication(&mut stream).await.unwrap();
+            socks6::write_reply(&mut stream, Socks6Reply::Success).await.unwrap();
+        });
+
+        let link = ProxyAddress::new(6, upstream_addr.ip().to_string(), upstream_addr.port(), None);
+        let handler = Socks6Handler::new(vec![link]);
+        let shared = handler.clone();
+        tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await.unwrap();
+            handler.setup(&mut source).await.unwrap();
+        });
+
+        let client = Socks6Client::new(proxy_addr.to_string(), None).await?;
+        client.connect("10.0.0.1:80".to_string(), None, None).await?;
+
+        let entries = shared.link_cache.entries.lock().unwrap().clone();
+        assert_eq!(entries[&upstream_addr.to_string()].0, vec![upstream_addr]);
+
+        shared.refresh_links();
+        assert!(shared.link_cache.entries.lock().unwrap().is_empty());
+
+        Ok(())
+    }
+
+    // Tests that clones share the links and rules, rather than copying them.
+    #[test]
+    fn test_clone_shares_config() {
+        let link = ProxyAddress::new(6, "127.0.0.1".to_string(), 1080, None);
+        let mut handler = Socks6Handler::new(vec![link]);
+        handler.set_rule_set(Some(RuleSet::new(crate::Route::Direct)));
+
+        let clone = handler.clone();
+        assert!(Arc::ptr_eq(&handler.static_links, &clone.static_links));
+        assert!(Arc::ptr_eq(handler.rule_set.as_ref().unwrap(), clone.rule_set.as_ref().unwrap()));
+    }
+
+    // Tests that a cached resolution is reused until it expires.
+    #[tokio::test]
+    async fn test_link_cache_expiry() -> Result<()> {
+        let cache = LinkCache::default();
+        let stale: SocketAddr = "192.0.2.1:1080".parse()?;
+        cache.entries.lock().unwrap().insert(String::from("127.0.0.1:1080"), (vec![stale], Instant::now()));
+
+        let addrs = cache.resolve(String::from("127.0.0.1:1080"), Duration::from_secs(60)).await?;
+        assert_eq!(addrs, vec![stale]);
+        let addrs = cache.resolve(String::from("127.0.0.1:1080"), Duration::ZERO).await?;
+        assert_eq!(addrs, vec!["127.0.0.1:1080".parse()?]);
+
+        Ok(())
+    }
+
+    // Tests that an option granted by the upstream proxy is relayed to the original client.
+    #[tokio::test]
+    async fn test_relay_granted_options() -> Result<()> {
+        use crate::socks6::options::UnrecognizedOption;
+
+        let upstream = TcpListener::bind("127.0.0.1:0").await?;
+        let upstream_addr = upstream.local_addr()?;
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            socks6::read_request(&mut stream).await.unwrap();
+            socks6::write_no_authentication(&mut stream).await.unwrap();
+
+            let granted = vec![UnrecognizedOption::new(0x1234, vec![1, 2, 3, 4]).wrap()];
+            socks6::write_reply_with_options(&mut stream, Socks6Reply::Success, &granted).await.unwrap();
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        });
+
+        let link = ProxyAddress::new(6, upstream_addr.ip().to_string(), upstream_addr.port(), None);
+        let handler = Socks6Handler::new(vec![link]);
+        tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await.unwrap();
+            handler.setup(&mut source).await.unwrap();
+        });
+
+        let client = Socks6Client::new(proxy_addr.to_string(), None).await?;
+        let (_, _, granted) = client.connect_negotiated("10.0.0.1:80".to_string(), None, None).await?;
+        assert!(matches!(&granted[..], [SocksOption::Unrecognized(o)] if o.kind() == 0x1234));
+
+        Ok(())
+    }
+
+    // Tests that the rewriter can redirect a request to another destination, or refuse it.
+    #[tokio::test]
+    async fn test_destination_rewriter() -> Result<()> {
+        use crate::Address;
+
+        let destination = TcpListener::bind("127.0.0.1:0").await?;
+        let destination_addr = destination.local_addr()?;
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        let mut handler = Socks6Handler::default();
+        handler.set_destination_rewriter(Some(Arc::new(move |requested: Address| match requested.host().as_ref() {
+            "internal.test" => Some(Address::Ip(destination_addr)),
+            _ => None,
+        })));
+        tokio::spawn(async move {
+            loop {
+                let (mut source, _) = proxy.accept().await.unwrap();
+                let handler = handler.clone();
+                tokio::spawn(async move { handler.setup(&mut source).await });
+            }
+        });
+
+        let client = Socks6Client::new(proxy_addr.to_string(), None).await?;
+        client.connect("internal.test:80".to_string(), None, None).await?;
+        destination.accept().await?;
+
+        let error = client.connect("example.com:80".to_string(), None, None).await.unwrap_err();
+        assert!(matches!(error, SocksError::ReplyFailure(code) if code == Socks6Reply::ConnectionNotAllowed as u8));
+
+        Ok(())
+    }
+
+    // Tests that accepting a request reports the destination it was connected to, and the data relayed to it.
+    #[tokio::test]
+    async fn test_accept_request_record() -> Result<()> {
+        let destination = TcpListener::bind("127.0.0.1:0").await?;
+        let destination_addr = destination.local_addr()?;
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        let mut handler = Socks6Handler::default();
+        handler.set_destination_rewriter(Some(Arc::new(move |_| Some(Address::Ip(destination_addr)))));
+        let accepted = tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await.unwrap();
+            handler.accept_request(&mut source).await.unwrap()
+        });
+
+        let client = Socks6Client::new(proxy_addr.to_string(), None).await?;
+        let (mut stream, _) = client.connect("internal.test:80".to_string(), None, None).await?;
+        let (mut outgoing, _) = destination.accept().await?;
+        stream.write_all(b"ping").await?;
+        stream.shutdown().await?;
+        outgoing.read_exact(&mut [0; 4]).await?;
+        drop(outgoing);
+
+        let (target, stats) = accepted.await?;
+        assert_eq!(target, Address::Ip(destination_addr));
+        assert_eq!((stats.sent, stats.received), (4, 0));
+
+        Ok(())
+    }
+
+    // Tests that the rule set takes the place of the static links.
+    #[tokio::test]
+    async fn test_rule_set_route() -> Result<()> {
+        use crate::{Matcher, Route};
+
+        let destination = TcpListener::bind("127.0.0.1:0").await?;
+        let destination_addr = destination.local_addr()?;
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        // The static link, and the default route, lead nowhere.
+        let unreachable = ProxyAddress::new(6, String::from("127.0.0.1"), 1, None);
+        let mut rule_set = RuleSet::new(Route::Chain(vec![unreachable.clone()]));
+        rule_set.add_rule(Matcher::cidr("127.0.0.0/8")?, Route::Direct);
+
+        let mut handler = Socks6Handler::new(vec![unreachable]);
+        handler.set_rule_set(Some(rule_set));
+        tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await.unwrap();
+            handler.setup(&mut source).await.unwrap();
+        });
+
+        let client = Socks6Client::new(proxy_addr.to_string(), None).await?;
+        client.connect(destination_addr.to_string(), None, None).await?;
+        destination.accept().await?;
+
+        Ok(())
+    }
+
+    // Tests that a SOCKS5 proxy can be the last hop, with the initial data sent through the established tunnel.
+    #[tokio::test]
+    async fn test_socks5_hop() -> Result<()> {
+        use crate::socks6::options::AuthMethodAdvertisementOption;
+        use crate::socks6::Socks6Request;
+        use crate::Socks5Handler;
+
+        let destination = TcpListener::bind("127.0.0.1:0").await?;
+        let destination_addr = destination.local_addr()?;
+        let upstream = TcpListener::bind("127.0.0.1:0").await?;
+        let upstream_addr = upstream.local_addr()?;
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        tokio::spawn(async move {
+            let (mut source, _) = upstream.accept().await.unwrap();
+            Socks5Handler::default().accept_request(&mut source).await.unwrap();
+        });
+
+        let link = ProxyAddress::new(5, upstream_addr.ip().to_string(), upstream_addr.port(), None);
+        tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await.unwrap();
+            Socks6Handler::new(vec![link]).accept_request(&mut source).await.unwrap();
+        });
+
+        let initial_data = b"hello".to_vec();
+        let length = initial_data.len() as u16;
+        let advertisement = AuthMethodAdvertisementOption::new(length, vec![]).wrap();
+        let request = Socks6Request::new(
+            Command::Connect,
+            crate::Address::Ip(destination_addr),
+            length,
+            vec![advertisement],
+            None,
+        );
+
+        let mut stream = TcpStream::connect(proxy_addr).await?;
+        stream.write_all(&request.into_socks_bytes()?).await?;
+        stream.write_all(&initial_data).await?;
+        socks6::read_no_authentication(&mut stream).await?;
+        socks6::read_reply(&mut stream).await?;
+
+        let (mut outgoing, _) = destination.accept().await?;
+        let mut received = vec![0; initial_data.len()];
+        outgoing.read_exact(&mut received).await?;
+        assert_eq!(received, initial_data);
+
+        Ok(())
+    }
+
+    // Tests that a link naming the handler itself by hostname is refused, instead of connecting to itself.
+    #[tokio::test]
+    async fn test_chain_loops_back() -> Result<()> {
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        let links = vec![ProxyAddress::new(6, String::from("localhost"), proxy_addr.port(), None)];
+        tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await.unwrap();
+            assert!(Socks6Handler::new(links).setup(&mut source).await.is_err());
+        });
+
+        let client = Socks6Client::new(proxy_addr.to_string(), None).await?;
+        let connect = client.connect("10.0.0.1:80".to_string(), None, None);
+        let error = tokio::time::timeout(Duration::from_secs(5), connect).await?.unwrap_err();
+        assert!(matches!(error, SocksError::ReplyFailure(code) if code == Socks6Reply::ConnectionNotAllowed as u8));
+
+        Ok(())
+    }
+
+    // Tests that a SOCKS5 hop in the middle of a chain is rejected, as it can't forward the remainder.
+    #[tokio::test]
+    async fn test_socks5_hop_not_last() -> Result<()> {
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        let links = vec![
+            ProxyAddress::new(5, String::from("127.0.0.1"), 1, None),
+            ProxyAddress::new(6, String::from("127.0.0.1"), 2, None),
+        ];
+        tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await.unwrap();
+            assert!(Socks6Handler::new(links).setup(&mut source).await.is_err());
+        });
+
+        let client = Socks6Client::new(proxy_addr.to_string(), None).await?;
+        let error = client.connect("10.0.0.1:80".to_string(), None, None).await.unwrap_err();
+        assert!(matches!(error, SocksError::ReplyFailure(code) if code == Socks6Reply::GeneralFailure as u8));
+
+        Ok(())
+    }
+
+    // Tests that the complete initial data reaches the destination, even if the destination reads slowly.
+    #[tokio::test]
+    async fn test_initial_data_backpressure() -> Result<()> {
+        use socket2::SockRef;
+
+        use crate::socks6::options::AuthMethodAdvertisementOption;
+        use crate::socks6::Socks6Request;
+
+        let destination = TcpListener::bind("127.0.0.1:0").await?;
+        SockRef::from(&destination).set_recv_buffer_size(1024)?;
+        let destination_addr = destination.local_addr()?;
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await.unwrap();
+            Socks6Handler::default().setup(&mut source).await.unwrap();
+        });
+
+        let initial_data: Vec<u8> = (0..16384).map(|i| i as u8).collect();
+        let receiver = tokio::spawn(async move {
+            let (mut outgoing, _) = destination.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+
+            let mut received = vec![0; 16384];
+            outgoing.read_exact(&mut received).await.unwrap();
+            received
+        });
+
+        let length = initial_data.len() as u16;
+        let advertisement = AuthMethodAdvertisementOption::new(length, vec![]).wrap();
+        let request = Socks6Request::new(
+            Command::Connect,
+            crate::Address::Ip(destination_addr),
+            length,
+            vec![advertisement],
+            None,
+        );
+
+        let mut stream = TcpStream::connect(proxy_addr).await?;
+        stream.write_all(&request.into_socks_bytes()?).await?;
+        stream.write_all(&initial_data).await?;
+        socks6::read_no_authentication(&mut stream).await?;
+        socks6::read_reply(&mut stream).await?;
+
+        assert_eq!(receiver.await?, initial_data);
+
+        Ok(())
+    }
+
+    // Tests that initial data sent along with a TCP Fast Open request reaches the destination, exactly once.
+    #[tokio::test]
+    async fn test_initial_data_fast_open() -> Result<()> {
+        use crate::socks6::options::AuthMethodAdvertisementOption;
+        use crate::socks6::Socks6Request;
+
+        let destination = TcpListener::bind("127.0.0.1:0").await?;
+        let destination_addr = destination.local_addr()?;
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await.unwrap();
+            let mut outgoing = Socks6Handler::default().setup(&mut source).await.unwrap();
+            outgoing.shutdown().await.unwrap();
+        });
+
+        let initial_data = b"GET / HTTP/1.1\r\n\r\n".to_vec();
+        let length = initial_data.len() as u16;
+        let options = vec![
+            AuthMethodAdvertisementOption::new(length, vec![]).wrap(),
+            StackOption::tfo(length).wrap(),
+        ];
+        let request = Socks6Request::new(Command::Connect, crate::Address::Ip(destination_addr), length, options, None);
+
+        let mut stream = TcpStream::connect(proxy_addr).await?;
+        stream.write_all(&request.into_socks_bytes()?).await?;
+        stream.write_all(&initial_data).await?;
+        socks6::read_no_authentication(&mut stream).await?;
+
+        let (_, granted) = socks6::read_reply(&mut stream).await?;
+        assert!(granted
+            .iter()
+            .all(|o| matches!(o, SocksOption::Stack(o) if o.option_type() == Some(StackOptionType::Tfo))));
+
+        let (mut outgoing, _) = destination.accept().await?;
+        let mut received = vec![];
+        outgoing.read_to_end(&mut received).await?;
+        assert_eq!(received, initial_data);
+
+        Ok(())
+    }
+
+    // Tests that a refused destination is reported to the client with a matching reply.
+    #[tokio::test]
+    async fn test_dial_failure_reply() -> Result<()> {
+        let destination_addr = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await.unwrap();
+            assert!(Socks6Handler::default().setup(&mut source).await.is_err());
+        });
+
+        let client = Socks6Client::new(proxy_addr.to_string(), None).await?;
+        let error = client.connect(destination_addr.to_string(), None, None).await.unwrap_err();
+        assert!(matches!(error, crate::SocksError::ReplyFailure(code) if code == Socks6Reply::ConnectionRefused as u8));
+
+        Ok(())
+    }
+
+    // Tests that a next proxy that accepts the connection but never replies is given up on, replying to the client.
+    #[tokio::test]
+    async fn test_dial_timeout() -> Result<()> {
+        let upstream = TcpListener::bind("127.0.0.1:0").await?;
+        let upstream_addr = upstream.local_addr()?;
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        tokio::spawn(async move {
+            let (_stream, _) = upstream.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(60)).await;
+        });
+
+        let link = ProxyAddress::new(6, upstream_addr.ip().to_string(), upstream_addr.port(), None);
+        let mut handler = Socks6Handler::new(vec![link]);
+        handler.set_dial_timeout(Some(Duration::from_millis(50)));
+        tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await.unwrap();
+            assert!(handler.setup(&mut source).await.is_err());
+        });
+
+        let client = Socks6Client::new(proxy_addr.to_string(), None).await?;
+        let connect = client.connect("10.0.0.1:80".to_string(), None, None);
+        let error = tokio::time::timeout(Duration::from_secs(5), connect).await?.unwrap_err();
+        assert!(matches!(error, crate::SocksError::ReplyFailure(code) if code == Socks6Reply::ConnectionAttemptTimeOut as u8));
+
+        Ok(())
+    }
+
+    // Tests that a refused destination is dialed again, until it accepts the connection.
+    #[tokio::test]
+    async fn test_dial_retry() -> Result<()> {
+        let destination_addr = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await.unwrap();
+            let mut handler = Socks6Handler::default();
+            handler.set_dial_retry_policy(Some(RetryPolicy {
+                max_attempts: 20,
+                base_delay: Duration::from_millis(10),
+                max_delay: Duration::from_millis(20),
+            }));
+            handler.setup(&mut source).await.unwrap();
+        });
+
+        // The destination only starts listening after the first attempt was refused.
+        let destination = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let listener = TcpListener::bind(destination_addr).await.unwrap();
+            listener.accept().await.unwrap();
+        });
+
+        let client = Socks6Client::new(proxy_addr.to_string(), None).await?;
+        client.connect(destination_addr.to_string(), None, None).await?;
+        destination.await?;
+
+        let refused = anyhow::Error::from(io::Error::from(io::ErrorKind::ConnectionRefused));
+        assert!(is_retryable_dial_error(&refused));
+        let unresolved = anyhow::Error::from(io::Error::new(io::ErrorKind::HostUnreachable, "Failed to resolve"));
+        assert!(!is_retryable_dial_error(&unresolved));
+
+        Ok(())
+    }
+
+    // Tests that a client that sends part of its request and then stalls is replied to and disconnected.
+    #[tokio::test]
+    async fn test_request_timeout() -> Result<()> {
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        let handled = tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await.unwrap();
+            let mut handler = Socks6Handler::default();
+            handler.set_request_timeout(Some(Duration::from_millis(50)));
+            handler.setup(&mut source).await.unwrap_err()
+        });
+
+        let mut stream = TcpStream::connect(proxy_addr).await?;
+        stream.write_all(&[6]).await?;
+
+        let error = tokio::time::timeout(Duration::from_secs(5), handled).await??;
+        assert_eq!(error.downcast_ref::<io::Error>().map(|error| error.kind()), Some(io::ErrorKind::TimedOut));
+        socks6::read_no_authentication(&mut stream).await?;
+        let error = socks6::read_reply(&mut stream).await.unwrap_err();
+        assert!(matches!(error, SocksError::ReplyFailure(code) if code == Socks6Reply::GeneralFailure as u8));
+
+        Ok(())
+    }
+
+    // Tests that a request with an unknown command is answered with a reply, instead of being dropped.
+    #[tokio::test]
+    async fn test_unknown_command_reply() -> Result<()> {
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await.unwrap();
+            assert!(Socks6Handler::default().setup(&mut source).await.is_err());
+        });
+
+        let mut stream = TcpStream::connect(proxy_addr).await?;
+        stream.write_all(&[6, 0x09]).await?;
+        socks6::read_no_authentication(&mut stream).await?;
+
+        let error = socks6::read_reply(&mut stream).await.unwrap_err();
+        assert!(matches!(error, SocksError::ReplyFailure(code) if code == Socks6Reply::CommandNotSupported as u8));
+
+        Ok(())
+    }
+
+    // Tests that a request with an unknown address type is answered with a reply, instead of being dropped.
+    #[tokio::test]
+    async fn test_unknown_address_type_reply() -> Result<()> {
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await.unwrap();
+            assert!(Socks6Handler::default().setup(&mut source).await.is_err());
+        });
+
+        let mut stream = TcpStream::connect(proxy_addr).await?;
+        stream.write_all(&[6, 0x01, 0x02]).await?;
+        socks6::read_no_authentication(&mut stream).await?;
+
+        let error = socks6::read_reply(&mut stream).await.unwrap_err();
+        assert!(matches!(error, SocksError::ReplyFailure(code) if code == Socks6Reply::AddressTypeNotSupported as u8));
+
+        Ok(())
+    }
+
+    // Tests that a request rejected for its unknown options is answered with a reply, instead of being dropped.
+    #[tokio::test]
+    async fn test_unknown_option_rejected_reply() -> Result<()> {
+        use crate::socks6::options::UnrecognizedOption;
+        use crate::socks6::Socks6Request;
+
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        let mut handler = Socks6Handler::default();
+        handler.set_unknown_option_policy(UnknownOptionPolicy::Reject);
+        tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await.unwrap();
+            assert!(handler.setup(&mut source).await.is_err());
+        });
+
+        let options = vec![UnrecognizedOption::new(0x1234, vec![1, 2, 3, 4]).wrap()];
+        let request = Socks6Request::new(Command::Connect, Address::new("192.0.2.1", 80), 0, options, None);
+        let mut stream = TcpStream::connect(proxy_addr).await?;
+        stream.write_all(&request.into_socks_bytes()?).await?;
+        socks6::read_no_authentication(&mut stream).await?;
+
+        let error = socks6::read_reply(&mut stream).await.unwrap_err();
+        assert!(matches!(error, SocksError::ReplyFailure(code) if code == Socks6Reply::GeneralFailure as u8));
+        stream.read_to_end(&mut vec![]).await?;
+
+        Ok(())
+    }
+
+    // Tests that a request is refused with the given reply.
+    #[tokio::test]
+    async fn test_refuse_request_with() -> Result<()> {
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        tokio::spawn(async move {
+            let (mut source, _) = proxy.accept().await.unwrap();
+            let handler = Socks6Handler::default();
+            handler.refuse_request_with(&mut source, Socks6Reply::ConnectionNotAllowed).await.unwrap();
+        });
+
+        let mut stream = TcpStream::connect(proxy_addr).await?;
+        let error = socks6::read_reply(&mut stream).await.unwrap_err();
+        assert!(matches!(error, SocksError::ReplyFailure(code) if code == Socks6Reply::ConnectionNotAllowed as u8));
+
+        Ok(())
+    }
+
+    // Tests that initial data is forwarded in chunks, and that it has to be as long as advertised.
+    #[tokio::test]
+    async fn test_forward_initial_data() -> Result<()> {
+        let initial_data: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+
+        let mut destination = vec![];
+        forward_initial_data(&mut &initial_data[..], &mut destination, 4096).await?;
+        assert_eq!(destination, initial_data[..4096]);
+
+        let mut destination = vec![];
+        let error = forward_initial_data(&mut &initial_data[..], &mut destination, 6000).await.unwrap_err();
+        assert_eq!(error.to_string(), "Source closed after 5000 of 6000 bytes of initial data.");
+
+        Ok(())
+    }
+
+    // Tests that dial errors are mapped to the reply that describes them.
+    #[test]
+    fn test_reply_from_dial_error() {
+        let error = anyhow::Error::from(io::Error::from(io::ErrorKind::TimedOut));
+        assert_eq!(Socks6Reply::from_dial_error(&error), Socks6Reply::ConnectionAttemptTimeOut);
+
+        let error = anyhow::Error::from(crate::SocksError::ReplyFailure(Socks6Reply::HostUnreachable as u8));
+        assert_eq!(Socks6Reply::from_dial_error(&error), Socks6Reply::HostUnreachable);
+
+        let error = anyhow!("Something else went wrong.");
+        assert_eq!(Socks6Reply::from_dial_error(&error), Socks6Reply::GeneralFailure);
+    }
+}
@@ -0,0 +1,453 @@
+// SOCKS6 UDP association messages and handle.
+use std::convert::{TryFrom, TryInto};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use anyhow::Result;
+use futures::FutureExt;
+use num_traits::FromPrimitive;
+use socket2::{SockRef, TcpKeepalive};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+
+use crate::{Address, SocksError};
+use crate::addresses;
+use crate::constants::SOCKS_VER_6;
+
+/// Length of the fixed part of the UDP message header: version, message type, header length, and association ID.
+const UDP_HEADER_LENGTH: usize = 12;
+
+/// Largest datagram that can be received over an association.
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// Ways the datagrams of a SOCKS6 UDP association are carried between client and proxy.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum UdpFraming {
+    /// As UDP datagrams, sent to the relay at the bound address of the operation reply.
+    #[default]
+    Udp,
+    /// As messages on the control stream, e.g. where UDP to the proxy is blocked.
+    Stream,
+    /// As DTLS datagrams, which isn't supported.
+    Dtls,
+}
+
+/// Message types of the SOCKS6 UDP association protocol.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, FromPrimitive, PartialEq)]
+pub enum UdpMessageType {
+    AssociationInit = 0x01,
+    AssociationAck = 0x02,
+    Datagram = 0x03,
+    Error = 0x04,
+}
+
+/// Represents a message exchanged over a SOCKS6 UDP association.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UdpMessage {
+    pub message_type: UdpMessageType,
+    pub association_id: u64,
+    /// The destination (client to proxy) or source (proxy to client) of a datagram.
+    pub address: Option<Address>,
+    pub data: Vec<u8>,
+}
+
+impl UdpMessage {
+    /// Constructs a new control message, without address or data.
+    pub fn new(
+        message_type: UdpMessageType,
+        association_id: u64,
+    ) -> Self {
+        Self {
+            message_type,
+            association_id,
+            address: None,
+            data: vec![],
+        }
+    }
+
+    /// Constructs a new datagram message.
+    pub fn datagram(
+        association_id: u64,
+        address: Address,
+        data: Vec<u8>,
+    ) -> Self {
+        Self {
+            message_type: UdpMessageType::Datagram,
+            association_id,
+            address: Some(address),
+            data,
+        }
+    }
+
+    /// Deserializes the message from bytes, e.g. a received UDP datagram. Bytes beyond the length of the message
+    /// are ignored.
+    pub fn from_socks_bytes(bytes: &[u8]) -> Result<Self> {
+        ensure!(
+            bytes.len() >= UDP_HEADER_LENGTH,
+            "Expected at least {} bytes, got: {}",
+            UDP_HEADER_LENGTH,
+            bytes.len()
+        );
+
+        let (message_type, message_length, association_id) = parse_header(&bytes[..UDP_HEADER_LENGTH])?;
+        ensure!(bytes.len() >= message_length, "UDP message is truncated.");
+
+        let mut body = &bytes[UDP_HEADER_LENGTH..message_length];
+        let address = if message_type == UdpMessageType::Datagram {
+            let address = Address::from_socks_bytes(&mut body)
+                .now_or_never()
+                .expect("Reading from a slice never blocks.")?;
+
+            Some(address)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            message_type,
+            association_id,
+            address,
+            data: body.to_vec(),
+        })
+    }
+
+    /// Serializes the message into bytes, fails if the address can't be encoded or the message is too long.
+    pub fn into_socks_bytes(self) -> Result<Vec<u8>> {
+        let address = match self.address {
+            Some(address) => address.to_socks_bytes()?,
+            None => vec![],
+        };
+        let message_length = UDP_HEADER_LENGTH + address.len() + self.data.len();
+        let message_length = u16::try_from(message_length)
+            .map_err(|_| anyhow!("UDP message of {} bytes exceeds the maximum of {}.", message_length, u16::MAX))?;
+
+        let mut data = vec![SOCKS_VER_6, self.message_type as u8];
+        data.extend(message_length.to_be_bytes().iter());
+        data.extend(self.association_id.to_be_bytes().iter());
+        data.extend(address);
+        data.extend(self.data);
+
+        Ok(data)
+    }
+}
+
+/// Parses the fixed part of a UDP message header into its type, total message length, and association ID.
+/// The length covers the whole message, so that messages can be delimited on the control stream.
+fn parse_header(header: &[u8]) -> Result<(UdpMessageType, usize, u64)> {
+    let version = header[0];
+    ensure!(version == SOCKS_VER_6, "Version mismatch!");
+
+    let message_type = header[1];
+    let message_type = UdpMessageType::from_u8(message_type)
+        .ok_or_else(|| anyhow!("Unknown UDP message type: {}", message_type))?;
+
+    let message_length = u16::from_be_bytes([header[2], header[3]]) as usize;
+    ensure!(
+        message_length >= UDP_HEADER_LENGTH,
+        "UDP message MUST be at least {} bytes, got: {}",
+        UDP_HEADER_LENGTH,
+        message_length
+    );
+
+    let association_id = u64::from_be_bytes(header[4..UDP_HEADER_LENGTH].try_into()?);
+
+    Ok((message_type, message_length, association_id))
+}
+
+/// Reads a message from the control stream, a control message (e.g. association init or ack) or a datagram.
+pub async fn read_message<S>(stream: &mut S) -> Result<UdpMessage>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut bytes = vec![0; UDP_HEADER_LENGTH];
+    stream.read_exact(&mut bytes).await?;
+
+    let (_, message_length, _) = parse_header(&bytes)?;
+    bytes.resize(message_length, 0);
+    stream.read_exact(&mut bytes[UDP_HEADER_LENGTH..]).await?;
+
+    UdpMessage::from_socks_bytes(&bytes)
+}
+
+/// Represents an established SOCKS6 UDP association.
+///
+/// Datagrams are relayed over UDP, or carried over the control stream, see `UdpFraming`. DTLS isn't supported.
+/// The association ends when the handle, which owns the control stream, is dropped.
+pub struct Socks6UdpAssociation {
+    control: TcpStream,
+    /// The socket connected to the relay, or `None` if datagrams are carried over the control stream.
+    socket: Option<UdpSocket>,
+    association_id: u64,
+    binding: Address,
+    /// Receives datagrams from the relay, reused across `recv_from` calls.
+    buffer: Vec<u8>,
+}
+
+impl Socks6UdpAssociation {
+    /// Completes the association, which the proxy initiates on the control stream after its operation reply.
+    ///
+    /// # Parameters
+    /// - `control`: The control stream, on which the UDP ASSOCIATE request has been granted.
+    /// - `binding`: The bound address from the operation reply, where the proxy relays datagrams.
+    /// - `socket`: The socket to send datagrams from, or `None` to bind one for the relay's address family.
+    /// - `framing`: How datagrams are carried, the socket is unused unless they're carried over UDP.
+    ///
+    /// # Returns
+    /// A `Result` containing the association, or an error, e.g. `UnsupportedUdpFraming` for DTLS.
+    pub(crate) async fn establish(
+        mut control: TcpStream,
+        binding: Address,
+        socket: Option<UdpSocket>,
+        framing: UdpFraming,
+    ) -> Result<Self, SocksError> {
+        if framing == UdpFraming::Dtls {
+            return Err(SocksError::UnsupportedUdpFraming(framing));
+        }
+
+        let message = read_message(&mut control).await?;
+        if message.message_type != UdpMessageType::AssociationInit {
+            return Err(anyhow!("Expected an association init message, got: {:?}.", message.message_type).into());
+        }
+
+        let socket = if framing == UdpFraming::Udp {
+            // An unspecified address means the relay is on the proxy itself.
+            let relay_addr = match binding.clone().replace_unspecified(control.peer_addr()?.ip()) {
+                Address::Ip(addr) => addr,
+                relay @ Address::Domainname { .. } => crate::resolve_addr(relay.to_string()).await?,
+            };
+
+            let socket = match socket {
+                Some(socket) => socket,
+                None => UdpSocket::bind(if relay_addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }).await?,
+            };
+            socket.connect(relay_addr).await?;
+
+            Some(socket)
+        } else {
+            None
+        };
+
+        // Datagrams on the control stream are read message by message, so only a relay needs a buffer.
+        let buffer = if socket.is_some() { vec![0; MAX_DATAGRAM_SIZE] } else { vec![] };
+
+        Ok(Self {
+            control,
+            socket,
+            association_id: message.association_id,
+            binding,
+            buffer,
+        })
+    }
+
+    /// Returns the association ID assigned by the proxy.
+    pub fn association_id(&self) -> u64 {
+        self.association_id
+    }
+
+    /// Returns the bound address, as reported by the proxy in its operation reply.
+    pub fn binding(&self) -> &Address {
+        &self.binding
+    }
+
+    /// Returns how datagrams are carried, which is either `Udp` or `Stream`.
+    pub fn framing(&self) -> UdpFraming {
+        if self.socket.is_some() {
+            UdpFraming::Udp
+        } else {
+            UdpFraming::Stream
+        }
+    }
+
+    /// Returns the local address datagrams are sent from, that of the control stream if they're carried on it.
+    pub fn local_addr(&self) -> Result<SocketAddr, SocksError> {
+        match &self.socket {
+            Some(socket) => Ok(socket.local_addr()?),
+            None => Ok(self.control.local_addr()?),
+        }
+    }
+
+    /// Returns the address datagrams are sent to, i.e. the proxy's UDP relay, or the proxy itself if they're carried
+    /// on the control stream.
+    pub fn relay_addr(&self) -> Result<SocketAddr, SocksError> {
+        match &self.socket {
+            Some(socket) => Ok(socket.peer_addr()?),
+            None => Ok(self.control.peer_addr()?),
+        }
+    }
+
+    /// Sets the TCP keepalive of the control stream, which the association only lives as long as. A quiet association
+    /// otherwise sends nothing on it, so NATs and firewalls may drop it as idle, severing the association.
+    ///
+    /// # Parameters
+    /// - `idle`: How long the control stream is idle before keepalive probes are sent, or `None` to disable them
+    ///   (the default).
+    ///
+    /// # Returns
+    /// A `Result` indicating whether the keepalive could be set.
+    pub fn set_keepalive(
+        &self,
+        idle: Option<Duration>,
+    ) -> Result<(), SocksError> {
+        let socket = SockRef::from(&self.control);
+        match idle {
+            Some(idle) => socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(idle))?,
+            None => socket.set_keepalive(false)?,
+        }
+
+        Ok(())
+    }
+
+    /// Sends a datagram to the given destination through the proxy.
+    ///
+    /// # Parameters
+    /// - `data`: The datagram payload.
+    /// - `destination`: The destination of the datagram.
+    ///
+    /// # Returns
+    /// A `Result` containing the number of payload bytes sent, or an error.
+    pub async fn send_to<A>(
+        &mut self,
+        data: &[u8],
+        destination: A,
+    ) -> Result<usize, SocksError>
+    where
+        A: TryInto<Address>,
+        A::Error: Into<anyhow::Error>,
+    {
+        let message = UdpMessage::datagram(self.association_id, addresses::into_address(destination)?, data.to_vec());
+        let message = message.into_socks_bytes()?;
+        match &self.socket {
+            Some(socket) => {
+                socket.send(&message).await?;
+            }
+            None => self.control.write_all(&message).await?,
+        }
+
+        Ok(data.len())
+    }
+
+    /// Receives a datagram relayed by the proxy, messages of other associations are ignored. If datagrams are carried
+    /// on the control stream, so are the proxy's control messages, which are skipped.
+    ///
+    /// # Parameters
+    /// - `buf`: The buffer for the payload, excess bytes are discarded.
+    ///
+    /// # Returns
+    /// A `Result` containing the number of payload bytes received and the source of the datagram, or an error.
+    pub async fn recv_from(
+        &mut self,
+        buf: &mut [u8],
+    ) -> Result<(usize, Address), SocksError> {
+        loop {
+            let message = match &self.socket {
+                Some(socket) => {
+                    let length = socket.recv(&mut self.buffer).await?;
+                    UdpMessage::from_socks_bytes(&self.buffer[..length])?
+                }
+                None => read_message(&mut self.control).await?,
+            };
+            if message.association_id != self.association_id {
+                continue;
+            }
+
+            match (message.message_type, message.address) {
+                (UdpMessageType::Datagram, Some(source)) => {
+                    let length = message.data.len().min(buf.len());
+                    buf[..length].copy_from_slice(&message.data[..length]);
+
+                    return Ok((length, source));
+                }
+                (UdpMessageType::Error, _) => return Err(anyhow!("Proxy reported an error on the association.").into()),
+                _ => continue,
+            }
+        }
+    }
+
+    /// Waits for the proxy to confirm, on the control stream, that it received a datagram of this association.
+    ///
+    /// # Returns
+    /// A `Result` indicating the association is confirmed, or an error.
+    pub async fn read_confirmation(&mut self) -> Result<(), SocksError> {
+        let message = read_message(&mut self.control).await?;
+        if message.message_type != UdpMessageType::AssociationAck || message.association_id != self.association_id {
+            return Err(anyhow!("Expected an association ack message, got: {:?}.", message.message_type).into());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test that a datagram message survives serialization.
+    #[test]
+    fn test_datagram_roundtrip() -> Result<()> {
+        let message = UdpMessage::datagram(42, Address::new("example.com", 53), vec![1, 2, 3]);
+        let parsed = UdpMessage::from_socks_bytes(&message.clone().into_socks_bytes()?)?;
+        assert_eq!(parsed, message);
+
+        Ok(())
+    }
+
+    // Tests that datagrams on the control stream are delimited by their length.
+    #[tokio::test]
+    async fn test_read_datagrams_from_stream() -> Result<()> {
+        let first = UdpMessage::datagram(7, Address::new("10.0.0.1", 53), vec![1, 2, 3]);
+        let second = UdpMessage::datagram(7, Address::new("example.com", 53), vec![4; 300]);
+
+        let mut bytes = first.clone().into_socks_bytes()?;
+        bytes.extend(second.clone().into_socks_bytes()?);
+        let mut stream = &bytes[..];
+        assert_eq!(read_message(&mut stream).await?, first);
+        assert_eq!(read_message(&mut stream).await?, second);
+        assert!(stream.is_empty());
+
+        Ok(())
+    }
+
+    // Tests that a datagram which doesn't fit the length field is refused.
+    #[test]
+    fn test_datagram_too_long() {
+        let message = UdpMessage::datagram(7, Address::new("10.0.0.1", 53), vec![0; MAX_DATAGRAM_SIZE]);
+        assert!(message.into_socks_bytes().is_err());
+    }
+
+    // Test reading a control message from a stream.
+    #[tokio::test]
+    async fn test_read_control_message() -> Result<()> {
+        let bytes = UdpMessage::new(UdpMessageType::AssociationInit, 7).into_socks_bytes()?;
+        assert_eq!(bytes, vec![6, 1, 0, 12, 0, 0, 0, 0, 0, 0, 0, 7]);
+
+        let message = read_message(&mut &bytes[..]).await?;
+        assert_eq!(message, UdpMessage::new(UdpMessageType::AssociationInit, 7));
+
+        Ok(())
+    }
+
+    // Tests that keepalive probes on the control stream are off by default, and can be toggled.
+    #[tokio::test]
+    async fn test_set_keepalive() -> Result<()> {
+        use tokio::io::AsyncWriteExt;
+        use tokio::net::TcpListener;
+
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let control = TcpStream::connect(proxy.local_addr()?).await?;
+        let (mut source, _) = proxy.accept().await?;
+        let init = UdpMessage::new(UdpMessageType::AssociationInit, 7).into_socks_bytes()?;
+        source.write_all(&init).await?;
+
+        let binding = Address::new("127.0.0.1", 9);
+        let association = Socks6UdpAssociation::establish(control, binding, None, UdpFraming::Udp).await?;
+        assert!(!SockRef::from(&association.control).keepalive()?);
+
+        association.set_keepalive(Some(Duration::from_secs(30)))?;
+        assert!(SockRef::from(&association.control).keepalive()?);
+        association.set_keepalive(None)?;
+        assert!(!SockRef::from(&association.control).keepalive()?);
+
+        Ok(())
+    }
+}